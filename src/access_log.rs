@@ -0,0 +1,245 @@
+//! Access log of the connections proxied by a server, one line each when they close.
+//!
+//! Lines are in a format close to the Common Log Format, with the peer's address, the
+//! identity of the client owning the tunnel, the time the connection arrived, the
+//! tunnel, and then the duration, the bytes received from and sent to the peer, why the
+//! connection closed, and its ID, which the client logs too:
+//!
+//! ```text
+//! 203.0.113.7:51234 alice [2026-10-14T09:30:00Z] "TCP 9000" 1250ms 512 2048 "closed" 67e55044-10b1-426f-9247-bb680e5fe0c8
+//! ```
+//!
+//! Lines are handed to a writer thread through a bounded channel, so that the proxy
+//! never waits on the disk. If the writer falls behind, lines are dropped with a warning.
+//! The file can be reopened after it was rotated with [`AccessLog::reopen`], or rotated
+//! by the writer itself once it reaches a maximum size, keeping a few older files next
+//! to it as `<path>.1`, `<path>.2` and so on, with `<path>.1` being the most recent.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::warn;
+
+use crate::server::ConnectionRecord;
+use crate::shared::{Endpoint, Protocol};
+
+/// Number of lines that may wait for the writer before new ones are dropped.
+const QUEUE_LENGTH: usize = 4096;
+
+/// Number of older files kept when the log is rotated at its maximum size.
+const ROTATED_FILES: usize = 5;
+
+/// The access log of a server, which writes nothing until it is opened.
+#[derive(Default)]
+pub struct AccessLog(Mutex<Option<Writer>>);
+
+/// The channel to the writer thread of an open log, and the thread itself.
+struct Writer {
+    sender: mpsc::Sender<Line>,
+    thread: JoinHandle<()>,
+}
+
+/// A request to the writer thread.
+enum Line {
+    /// Append a line to the file.
+    Write(String),
+
+    /// Reopen the file, such as after it was renamed by logrotate.
+    Reopen,
+}
+
+impl AccessLog {
+    /// Open the log file for appending, creating it if needed, and start its writer.
+    ///
+    /// With a maximum size, the file is rotated before a line would take it over.
+    pub fn open(&self, path: PathBuf, max_size: Option<u64>) -> Result<()> {
+        let file = LogFile::open(path, max_size)?;
+        let (sender, receiver) = mpsc::channel(QUEUE_LENGTH);
+        let thread = thread::Builder::new()
+            .name("bore-access-log".into())
+            .spawn(move || write_lines(file, receiver))
+            .context("could not start access log writer")?;
+        *self.0.lock().unwrap() = Some(Writer { sender, thread });
+        Ok(())
+    }
+
+    /// Queue a line for a closed connection, if the log is open.
+    pub fn log(&self, record: &ConnectionRecord) {
+        if let Some(writer) = self.0.lock().unwrap().as_ref() {
+            let line = Line::Write(format_line(record));
+            if let Err(TrySendError::Full(_)) = writer.sender.try_send(line) {
+                warn!("access log writer is behind, dropping a line");
+            }
+        }
+    }
+
+    /// Reopen the log file at the same path, so that lines go to a new file after the
+    /// old one was moved away.
+    pub fn reopen(&self) {
+        if let Some(writer) = self.0.lock().unwrap().as_ref() {
+            if let Err(TrySendError::Full(_)) = writer.sender.try_send(Line::Reopen) {
+                warn!("access log writer is behind, could not reopen it");
+            }
+        }
+    }
+
+    /// Stop writing, waiting for the lines queued so far to be written.
+    pub fn close(&self) {
+        let writer = self.0.lock().unwrap().take();
+        if let Some(Writer { sender, thread }) = writer {
+            drop(sender);
+            _ = thread.join();
+        }
+    }
+}
+
+/// An open log file, along with its size so far.
+struct LogFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    max_size: Option<u64>,
+}
+
+impl LogFile {
+    /// Open a log file for appending.
+    fn open(path: PathBuf, max_size: Option<u64>) -> Result<Self> {
+        let (file, size) = open(&path)?;
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    /// Append a line, first rotating the file if the line would take it over its
+    /// maximum size.
+    fn write(&mut self, line: &str) -> Result<()> {
+        let len = line.len() as u64 + 1;
+        if self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + len > max)
+        {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{line}")?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Reopen the file at the same path.
+    fn reopen(&mut self) -> Result<()> {
+        _ = self.file.flush();
+        (self.file, self.size) = open(&self.path)?;
+        Ok(())
+    }
+
+    /// Move the file to `<path>.1`, after moving older files one number up and
+    /// dropping the oldest, and start a new one.
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+        for n in (1..ROTATED_FILES).rev() {
+            _ = fs::rename(rotated(&self.path, n), rotated(&self.path, n + 1));
+        }
+        fs::rename(&self.path, rotated(&self.path, 1))
+            .with_context(|| format!("could not rotate access log {}", self.path.display()))?;
+        self.reopen()
+    }
+}
+
+/// The path of the `n`-th most recent rotated file of a log.
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path);
+    name.push(format!(".{n}"));
+    name.into()
+}
+
+/// Open a log file for appending, returning it along with its current size.
+fn open(path: &Path) -> Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("could not open access log {}", path.display()))?;
+    let size = file.metadata().map_or(0, |metadata| metadata.len());
+    Ok((BufWriter::new(file), size))
+}
+
+/// Write queued lines to the file until the log is closed, flushing whenever the queue
+/// runs empty.
+fn write_lines(mut file: LogFile, mut receiver: mpsc::Receiver<Line>) {
+    while let Some(line) = receiver.blocking_recv() {
+        let result = match line {
+            Line::Write(line) => file.write(&line),
+            Line::Reopen => file.reopen(),
+        };
+        if let Err(err) = result {
+            warn!(%err, "could not write access log");
+        }
+        if receiver.is_empty() {
+            if let Err(err) = file.file.flush() {
+                warn!(%err, "could not write access log");
+            }
+        }
+    }
+    _ = file.file.flush();
+}
+
+/// Format a closed connection as a line of the log.
+fn format_line(record: &ConnectionRecord) -> String {
+    let peer = record
+        .peer
+        .map_or_else(|| "-".into(), |peer| peer.to_string());
+    let identity = record.owner.as_deref().unwrap_or("-");
+    let protocol = match record.protocol {
+        Protocol::Tcp => "TCP",
+        Protocol::Udp => "UDP",
+    };
+    let tunnel = match &record.tunnel {
+        Endpoint::Port(port) => port.to_string(),
+        Endpoint::Socket(path) => path.display().to_string(),
+    };
+    let reason = record.reason.as_deref().unwrap_or("closed");
+    format!(
+        "{peer} {identity} [{}] \"{protocol} {tunnel}\" {}ms {} {} {:?} {}",
+        format_time(record.opened),
+        record.duration.as_millis(),
+        record.bytes_down,
+        record.bytes_up,
+        reason,
+        record.id,
+    )
+}
+
+/// Format a time in UTC as in RFC 3339, like `2026-10-14T09:30:00Z`.
+fn format_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Convert days since the epoch to a civil date, after Howard Hinnant's algorithm.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+    )
+}
@@ -0,0 +1,471 @@
+//! Admin API for listing and closing the tunnels of a running server.
+//!
+//! The API is served over HTTP with JSON bodies, on a loopback address or a Unix
+//! socket only: `GET /tunnels` lists the open tunnels, and `DELETE /tunnels/{port}`
+//! closes the control connection of the client forwarding a port. `GET /clients` lists
+//! the number of tunnels that each client has open. `GET /quotas` lists the bytes
+//! proxied for each client, and `POST /quotas/reset` resets them. Requests with an
+//! `Origin` header, or a `Host` other than the local host, are refused, so that web
+//! pages open in a browser on the same machine cannot use the API.
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::metrics::TunnelBytes;
+use crate::quota::{QuotaInfo, Quotas};
+use crate::shared::{Protocol, NETWORK_TIMEOUT};
+
+/// Maximum byte length of an HTTP request or response of the admin API.
+const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+/// Tunnels that are open on a server, as seen by the admin API.
+#[derive(Default)]
+pub struct Tunnels {
+    entries: DashMap<(u16, Protocol), Entry>,
+
+    /// Number of tunnels requested by each client, by identity or address, whose
+    /// control connections are still open.
+    clients: DashMap<String, usize>,
+
+    /// Number of tunnels requested by all clients together.
+    total: AtomicUsize,
+}
+
+/// The limit that kept a client from opening more tunnels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TunnelLimit {
+    /// The client has as many tunnels open as it may.
+    Client,
+
+    /// The server has as many tunnels open as it may, across all clients.
+    Server,
+}
+
+/// State of an open tunnel, shared with the connection that it belongs to.
+struct Entry {
+    identity: Option<String>,
+    opened: SystemTime,
+    conns: Arc<AtomicUsize>,
+    bytes: TunnelBytes,
+    close: CancellationToken,
+    reclaim: CancellationToken,
+}
+
+/// A tunnel open on the server, as listed by the admin API.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelInfo {
+    /// The forwarded port.
+    pub port: u16,
+
+    /// Protocol of the forwarded port.
+    pub protocol: Protocol,
+
+    /// Name of the client forwarding the port, if it authenticated.
+    pub identity: Option<String>,
+
+    /// Seconds since the tunnel was opened.
+    pub uptime_secs: u64,
+
+    /// Number of connections and UDP flows open on the port.
+    pub connections: usize,
+
+    /// Bytes sent from the client out to the public port.
+    pub bytes_up: u64,
+
+    /// Bytes received on the public port and sent to the client.
+    pub bytes_down: u64,
+}
+
+/// The tunnels of a client, as listed by the admin API.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    /// Name of the client, or its address if it did not authenticate.
+    pub client: String,
+
+    /// Number of tunnels that the client has open.
+    pub tunnels: usize,
+}
+
+impl Tunnels {
+    /// Register a newly opened tunnel, which is removed when the guard is dropped.
+    ///
+    /// Closing the tunnel through the admin API cancels `close`, and reclaiming its port
+    /// from a new connection of the same client cancels `reclaim`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn open(
+        &self,
+        port: u16,
+        protocol: Protocol,
+        identity: Option<String>,
+        conns: &Arc<AtomicUsize>,
+        bytes: &TunnelBytes,
+        close: &CancellationToken,
+        reclaim: &CancellationToken,
+    ) -> TunnelGuard<'_> {
+        let entry = Entry {
+            identity,
+            opened: SystemTime::now(),
+            conns: Arc::clone(conns),
+            bytes: bytes.clone(),
+            close: close.clone(),
+            reclaim: reclaim.clone(),
+        };
+        self.entries.insert((port, protocol), entry);
+        TunnelGuard {
+            tunnels: self,
+            key: (port, protocol),
+        }
+    }
+
+    /// List the open tunnels, ordered by port.
+    pub fn list(&self) -> Vec<TunnelInfo> {
+        let mut list: Vec<_> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let ((port, protocol), tunnel) = entry.pair();
+                TunnelInfo {
+                    port: *port,
+                    protocol: *protocol,
+                    identity: tunnel.identity.clone(),
+                    uptime_secs: tunnel.opened.elapsed().unwrap_or_default().as_secs(),
+                    connections: tunnel.conns.load(Ordering::Relaxed),
+                    bytes_up: tunnel.bytes.up.load(Ordering::Relaxed),
+                    bytes_down: tunnel.bytes.down.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+        list.sort_by_key(|tunnel| (tunnel.port, tunnel.protocol == Protocol::Udp));
+        list
+    }
+
+    /// Whether a tunnel is open on a port, for either protocol.
+    pub fn is_open(&self, port: u16) -> bool {
+        [Protocol::Tcp, Protocol::Udp]
+            .into_iter()
+            .any(|protocol| self.entries.contains_key(&(port, protocol)))
+    }
+
+    /// Close the tunnels forwarding a port, returning whether there were any.
+    pub fn close(&self, port: u16) -> bool {
+        let mut closed = false;
+        for entry in self.entries.iter().filter(|entry| entry.key().0 == port) {
+            entry.close.cancel();
+            closed = true;
+        }
+        closed
+    }
+
+    /// Close the tunnels forwarding a port for a client with the given identity, so that
+    /// another connection of the client can take the port over, returning whether there
+    /// were any.
+    pub fn reclaim(&self, port: u16, identity: &str) -> bool {
+        let mut reclaimed = false;
+        for entry in self.entries.iter().filter(|entry| entry.key().0 == port) {
+            if entry.identity.as_deref() == Some(identity) {
+                entry.reclaim.cancel();
+                reclaimed = true;
+            }
+        }
+        reclaimed
+    }
+
+    /// Count tunnels requested by a client, unless that would take it over `max` or all
+    /// clients together over `total_max`, until the guard is dropped.
+    pub fn reserve(
+        &self,
+        client: String,
+        tunnels: usize,
+        max: usize,
+        total_max: usize,
+    ) -> Result<ClientGuard<'_>, TunnelLimit> {
+        self.total
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+                total
+                    .checked_add(tunnels)
+                    .filter(|&total| total <= total_max)
+            })
+            .map_err(|_| TunnelLimit::Server)?;
+        let mut count = self.clients.entry(client.clone()).or_default();
+        if count.saturating_add(tunnels) > max {
+            let empty = *count == 0;
+            drop(count);
+            if empty {
+                self.clients.remove_if(&client, |_, count| *count == 0);
+            }
+            self.total.fetch_sub(tunnels, Ordering::Relaxed);
+            return Err(TunnelLimit::Client);
+        }
+        *count += tunnels;
+        drop(count);
+        Ok(ClientGuard {
+            tunnels: self,
+            client,
+            count: tunnels,
+        })
+    }
+
+    /// List the clients with tunnels open, by name.
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        let mut list: Vec<_> = self
+            .clients
+            .iter()
+            .map(|entry| ClientInfo {
+                client: entry.key().clone(),
+                tunnels: *entry.value(),
+            })
+            .collect();
+        list.sort_by(|a, b| a.client.cmp(&b.client));
+        list
+    }
+}
+
+/// Keeps a tunnel listed in the admin API while it is open.
+pub struct TunnelGuard<'a> {
+    tunnels: &'a Tunnels,
+    key: (u16, Protocol),
+}
+
+impl Drop for TunnelGuard<'_> {
+    fn drop(&mut self) {
+        self.tunnels.entries.remove(&self.key);
+    }
+}
+
+/// Keeps a client's tunnels counted while its control connection is open.
+pub struct ClientGuard<'a> {
+    tunnels: &'a Tunnels,
+    client: String,
+    count: usize,
+}
+
+impl Drop for ClientGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(mut count) = self.tunnels.clients.get_mut(&self.client) {
+            *count -= self.count;
+        }
+        self.tunnels
+            .clients
+            .remove_if(&self.client, |_, count| *count == 0);
+        self.tunnels.total.fetch_sub(self.count, Ordering::Relaxed);
+    }
+}
+
+/// Serve the admin API over HTTP on a listener.
+pub async fn serve(
+    tunnels: Arc<Tunnels>,
+    quotas: Arc<Quotas>,
+    listener: TcpListener,
+) -> Result<()> {
+    info!(addr = ?listener.local_addr()?, "admin listening");
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        if !addr.ip().is_loopback() {
+            warn!(?addr, "rejected admin request from a remote address");
+            continue;
+        }
+        let tunnels = Arc::clone(&tunnels);
+        let quotas = Arc::clone(&quotas);
+        tokio::spawn(async move {
+            if let Err(err) = respond(&tunnels, &quotas, stream).await {
+                warn!(%err, ?addr, "admin request failed");
+            }
+        });
+    }
+}
+
+/// Answer a single HTTP request to the admin API, then close the connection.
+pub async fn respond<S>(tunnels: &Tunnels, quotas: &Quotas, mut stream: S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = timeout(NETWORK_TIMEOUT, stream.read(&mut buf)).await??;
+        if n == 0 || request.len() + n > MAX_MESSAGE_LENGTH {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+    let port = path.strip_prefix("/tunnels/").map(str::parse::<u16>);
+    let headers = request
+        .lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'));
+    let header = |name: &str| {
+        let mut headers = headers.clone();
+        let header = headers.find(|(key, _)| key.trim().eq_ignore_ascii_case(name));
+        header.map(|(_, value)| value.trim())
+    };
+    // Browsers send an `Origin` with cross-origin requests, and keep the name of the
+    // page in `Host` after DNS rebinding, so neither lets a web page use the API.
+    let from_browser = header("Origin").is_some() || !header("Host").is_some_and(is_local_host);
+    let (status, body) = match (method, path, port) {
+        _ if from_browser => {
+            warn!(
+                host = header("Host"),
+                origin = header("Origin"),
+                "rejected admin request from a web page"
+            );
+            (
+                "403 Forbidden",
+                json!({ "error": "admin requests must come from the local host" }),
+            )
+        }
+        ("GET", "/tunnels", _) => ("200 OK", json!(tunnels.list())),
+        ("GET", "/clients", _) => ("200 OK", json!(tunnels.clients())),
+        ("GET", "/quotas", _) => ("200 OK", json!(quotas.list())),
+        ("POST", "/quotas/reset", _) => {
+            quotas.reset();
+            ("200 OK", json!({ "reset": true }))
+        }
+        ("DELETE", _, Some(Ok(port))) if tunnels.close(port) => {
+            info!(
+                event = "tunnel_closed",
+                tunnel_port = port,
+                "closing tunnel"
+            );
+            ("200 OK", json!({ "closed": port }))
+        }
+        ("DELETE", _, Some(Ok(port))) => (
+            "404 Not Found",
+            json!({ "error": format!("no tunnel on port {port}") }),
+        ),
+        _ => ("404 Not Found", json!({ "error": "not found" })),
+    };
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len(),
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Whether the `Host` header of a request names the local host, with any port.
+fn is_local_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    name.eq_ignore_ascii_case("localhost") || name.parse().is_ok_and(|ip: IpAddr| ip.is_loopback())
+}
+
+/// Address of the admin API of a server.
+#[derive(Clone, Debug)]
+pub enum AdminAddr {
+    /// A loopback address and port.
+    Tcp(SocketAddr),
+
+    /// The path of a Unix socket.
+    Unix(PathBuf),
+}
+
+/// List the tunnels open on a server.
+pub async fn list(addr: &AdminAddr) -> Result<Vec<TunnelInfo>> {
+    let body = request(addr, "GET", "/tunnels").await?;
+    serde_json::from_str(&body).context("invalid response from admin API")
+}
+
+/// Close the tunnels forwarding a port of a server, along with the rest of the tunnels
+/// of the client that owns them.
+pub async fn close(addr: &AdminAddr, port: u16) -> Result<()> {
+    request(addr, "DELETE", &format!("/tunnels/{port}")).await?;
+    Ok(())
+}
+
+/// List the clients of a server with tunnels open, and how many each has.
+pub async fn clients(addr: &AdminAddr) -> Result<Vec<ClientInfo>> {
+    let body = request(addr, "GET", "/clients").await?;
+    serde_json::from_str(&body).context("invalid response from admin API")
+}
+
+/// List the bytes proxied for the clients of a server, along with their quotas.
+pub async fn quotas(addr: &AdminAddr) -> Result<Vec<QuotaInfo>> {
+    let body = request(addr, "GET", "/quotas").await?;
+    serde_json::from_str(&body).context("invalid response from admin API")
+}
+
+/// Reset the bytes proxied for all clients of a server, starting a new quota period.
+pub async fn reset_quotas(addr: &AdminAddr) -> Result<()> {
+    request(addr, "POST", "/quotas/reset").await?;
+    Ok(())
+}
+
+/// Send a request to the admin API, returning the body of a successful response.
+async fn request(addr: &AdminAddr, method: &str, path: &str) -> Result<String> {
+    let request =
+        format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    let response = match addr {
+        AdminAddr::Tcp(addr) => {
+            let stream = timeout(NETWORK_TIMEOUT, TcpStream::connect(addr))
+                .await
+                .with_context(|| format!("timed out connecting to admin API at {addr}"))?
+                .with_context(|| format!("could not connect to admin API at {addr}"))?;
+            exchange(stream, &request).await?
+        }
+        #[cfg(unix)]
+        AdminAddr::Unix(path) => {
+            let stream = tokio::net::UnixStream::connect(path)
+                .await
+                .with_context(|| format!("could not connect to admin API at {}", path.display()))?;
+            exchange(stream, &request).await?
+        }
+        #[cfg(not(unix))]
+        AdminAddr::Unix(_) => bail!("Unix sockets are not supported on this platform"),
+    };
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .context("invalid response from admin API")?;
+    let status = head.split(' ').nth(1).unwrap_or_default();
+    if status != "200" {
+        #[derive(Deserialize)]
+        struct Error {
+            error: String,
+        }
+        match serde_json::from_str::<Error>(body) {
+            Ok(Error { error }) => bail!("{error}"),
+            Err(_) => bail!("admin API responded with status {status}"),
+        }
+    }
+    Ok(body.to_string())
+}
+
+/// Write a request to a stream and read the response until the server closes it.
+async fn exchange<S>(mut stream: S, request: &str) -> Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    let mut stream = stream.take(MAX_MESSAGE_LENGTH as u64);
+    timeout(NETWORK_TIMEOUT, stream.read_to_end(&mut response))
+        .await
+        .context("timed out waiting for admin API")??;
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
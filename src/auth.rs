@@ -1,21 +1,186 @@
 //! Auth implementation for bore client and server.
 
-use anyhow::{bail, ensure, Result};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use futures_util::future::{self, BoxFuture};
 use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use tokio::io::{AsyncRead, AsyncWrite};
 use uuid::Uuid;
 
+use crate::crypto::DataKey;
 use crate::shared::{ClientMessage, Delimited, ServerMessage};
 
+/// A way for the server to check who a client is, from its reply to a challenge.
+///
+/// The server sends each new connection a random challenge, and the client replies
+/// with a string derived from its credentials, such as an HMAC of the challenge or a
+/// signed token.
+pub trait Authenticator: Send + Sync {
+    /// Check a reply to a challenge, returning the identity of the client.
+    ///
+    /// The error is sent to the client as is, so it should not reveal any secrets.
+    fn verify(&self, challenge: &Uuid, reply: &str) -> Result<Identity>;
+
+    /// The key that the data of a client may be encrypted with, from the secret that it
+    /// replied to a challenge with, if it has one.
+    fn data_key(&self, _challenge: &Uuid, _reply: &str) -> Option<DataKey> {
+        None
+    }
+}
+
+/// What a client presented to authenticate, for an [`Authorizer`] to decide on.
+#[derive(Clone, Debug)]
+pub struct ClientAuth {
+    /// The challenge that the server sent to the client.
+    pub challenge: Uuid,
+
+    /// The client's reply to the challenge, such as a bearer token.
+    pub reply: String,
+
+    /// Address that the client connected from.
+    pub addr: IpAddr,
+}
+
+/// Whether a client may use the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthDecision {
+    /// Let the client in with an identity, which may restrict the ports it can forward.
+    Allow(Identity),
+
+    /// Reject the client, with a reason that is sent to it.
+    Deny(String),
+}
+
+/// A check of clients during the handshake that may take a while, such as a request to
+/// an external auth service.
+///
+/// Every [`Authenticator`] is also an authorizer, and an async function can be made into
+/// one with [`AuthCallback`].
+pub trait Authorizer: Send + Sync {
+    /// Decide whether a client may use the server.
+    ///
+    /// Errors are logged, and the client is rejected without being told the cause.
+    fn authorize(&self, auth: ClientAuth) -> BoxFuture<'_, Result<AuthDecision>>;
+
+    /// The key that the data of a client may be encrypted with, if it authenticated
+    /// with a secret that the server knows.
+    fn data_key(&self, _auth: &ClientAuth) -> Option<DataKey> {
+        None
+    }
+}
+
+impl<A: Authenticator> Authorizer for A {
+    fn authorize(&self, auth: ClientAuth) -> BoxFuture<'_, Result<AuthDecision>> {
+        let decision = match self.verify(&auth.challenge, &auth.reply) {
+            Ok(identity) => AuthDecision::Allow(identity),
+            Err(err) => AuthDecision::Deny(err.to_string()),
+        };
+        Box::pin(future::ready(Ok(decision)))
+    }
+
+    fn data_key(&self, auth: &ClientAuth) -> Option<DataKey> {
+        Authenticator::data_key(self, &auth.challenge, &auth.reply)
+    }
+}
+
+/// An [`Authorizer`] that calls an async function for each client.
+pub struct AuthCallback<F>(pub F);
+
+impl<F, Fut> Authorizer for AuthCallback<F>
+where
+    F: Fn(ClientAuth) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<AuthDecision>> + Send + 'static,
+{
+    fn authorize(&self, auth: ClientAuth) -> BoxFuture<'_, Result<AuthDecision>> {
+        Box::pin((self.0)(auth))
+    }
+}
+
+/// Who a client authenticated as.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    /// Name of the client, which is safe to log.
+    pub name: String,
+
+    /// Range of ports that the client is restricted to, if any.
+    pub ports: Option<RangeInclusive<u16>>,
+}
+
+impl Identity {
+    /// An identity that is not restricted to any ports.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ports: None,
+        }
+    }
+}
+
+/// Credentials that a client answers the server's challenge with.
+#[derive(Clone)]
+pub enum Credentials {
+    /// A secret shared with the server.
+    Secret(HmacAuthenticator),
+
+    /// A token signed with the server's token key.
+    Token(String),
+}
+
+impl Credentials {
+    /// Generate a reply message for a challenge.
+    pub fn answer(&self, challenge: &Uuid) -> String {
+        match self {
+            Credentials::Secret(auth) => auth.answer(challenge),
+            Credentials::Token(token) => token.clone(),
+        }
+    }
+
+    /// The key that data may be encrypted with, which only a secret has.
+    pub fn data_key(&self) -> Option<&DataKey> {
+        match self {
+            Credentials::Secret(auth) => Some(auth.data_key()),
+            Credentials::Token(_) => None,
+        }
+    }
+
+    /// As the client, answer a challenge to attempt to authenticate with the server.
+    pub async fn client_handshake<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut Delimited<T>,
+    ) -> Result<()> {
+        let challenge = client_challenge(stream).await?;
+        let reply = self.answer(&challenge);
+        stream.send(ClientMessage::Authenticate(reply)).await?;
+        Ok(())
+    }
+}
+
 /// Wrapper around a MAC used for authenticating clients that have a secret.
-pub struct Authenticator(Hmac<Sha256>);
+#[derive(Clone)]
+pub struct HmacAuthenticator(Hmac<Sha256>, DataKey);
 
-impl Authenticator {
+impl HmacAuthenticator {
     /// Generate an authenticator from a secret.
     pub fn new(secret: &str) -> Self {
         let hashed_secret = Sha256::new().chain_update(secret).finalize();
-        Self(Hmac::new_from_slice(&hashed_secret).expect("HMAC can take key of any size"))
+        Self(
+            Hmac::new_from_slice(&hashed_secret).expect("HMAC can take key of any size"),
+            DataKey::new(secret),
+        )
+    }
+
+    /// The key that data may be encrypted with, derived from the same secret.
+    pub fn data_key(&self) -> &DataKey {
+        &self.1
     }
 
     /// Generate a reply message for a challenge.
@@ -28,10 +193,10 @@ impl Authenticator {
     /// Validate a reply to a challenge.
     ///
     /// ```
-    /// use bore_cli::auth::Authenticator;
+    /// use bore_cli::auth::HmacAuthenticator;
     /// use uuid::Uuid;
     ///
-    /// let auth = Authenticator::new("secret");
+    /// let auth = HmacAuthenticator::new("secret");
     /// let challenge = Uuid::new_v4();
     ///
     /// assert!(auth.validate(&challenge, &auth.answer(&challenge)));
@@ -52,15 +217,9 @@ impl Authenticator {
         &self,
         stream: &mut Delimited<T>,
     ) -> Result<()> {
-        let challenge = Uuid::new_v4();
-        stream.send(ServerMessage::Challenge(challenge)).await?;
-        match stream.recv_timeout().await? {
-            Some(ClientMessage::Authenticate(tag)) => {
-                ensure!(self.validate(&challenge, &tag), "invalid secret");
-                Ok(())
-            }
-            _ => bail!("server requires secret, but no secret was provided"),
-        }
+        let (challenge, tag) = server_challenge(stream).await?;
+        ensure!(self.validate(&challenge, &tag), "invalid secret");
+        Ok(())
     }
 
     /// As the client, answer a challenge to attempt to authenticate with the server.
@@ -68,12 +227,252 @@ impl Authenticator {
         &self,
         stream: &mut Delimited<T>,
     ) -> Result<()> {
-        let challenge = match stream.recv_timeout().await? {
-            Some(ServerMessage::Challenge(challenge)) => challenge,
-            _ => bail!("expected authentication challenge, but no secret was required"),
-        };
-        let tag = self.answer(&challenge);
-        stream.send(ClientMessage::Authenticate(tag)).await?;
+        Credentials::Secret(self.clone())
+            .client_handshake(stream)
+            .await
+    }
+}
+
+/// A set of secrets accepted by the server, each known by an identity for logging.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    /// Accepted secrets, with their identities.
+    secrets: Vec<(String, HmacAuthenticator)>,
+
+    /// Ranges of ports that the clients with some identities are restricted to.
+    ports: HashMap<String, RangeInclusive<u16>>,
+}
+
+impl Keyring {
+    /// Create a keyring from a list of secrets.
+    ///
+    /// Each secret is identified by its [`fingerprint`], which is safe to log.
+    pub fn new<S: AsRef<str>>(secrets: impl IntoIterator<Item = S>) -> Self {
+        let mut keyring = Self::default();
+        for secret in secrets {
+            let secret = secret.as_ref();
+            keyring
+                .secrets
+                .push((fingerprint(secret), HmacAuthenticator::new(secret)));
+        }
+        keyring
+    }
+
+    /// Add a secret that is identified by a label, such as the name of its owner.
+    pub fn insert(&mut self, label: &str, secret: &str) -> Result<()> {
+        ensure!(
+            self.secrets.iter().all(|(identity, _)| identity != label),
+            "duplicate label {label:?}"
+        );
+        self.secrets
+            .push((label.to_string(), HmacAuthenticator::new(secret)));
         Ok(())
     }
+
+    /// Restrict the clients with an identity to forwarding ports in a range.
+    ///
+    /// When they ask for any port, they are assigned one from this range.
+    pub fn restrict_ports(&mut self, identity: &str, ports: RangeInclusive<u16>) {
+        self.ports.insert(identity.to_string(), ports);
+    }
+
+    /// The range of ports that the clients with an identity are restricted to, if any.
+    pub fn allowed_ports(&self, identity: &str) -> Option<&RangeInclusive<u16>> {
+        self.ports.get(identity)
+    }
+
+    /// Number of secrets in the keyring.
+    pub fn len(&self) -> usize {
+        self.secrets.len()
+    }
+
+    /// Returns true if the keyring accepts no secrets at all.
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+
+    /// Find the secret that a reply to a challenge was made with, if any.
+    ///
+    /// ```
+    /// use bore_cli::auth::{fingerprint, HmacAuthenticator, Keyring};
+    /// use uuid::Uuid;
+    ///
+    /// let keyring = Keyring::new(["alice's secret", "bob's secret"]);
+    /// let challenge = Uuid::new_v4();
+    /// let tag = HmacAuthenticator::new("bob's secret").answer(&challenge);
+    ///
+    /// let (identity, _) = keyring.identify(&challenge, &tag).unwrap();
+    /// assert_eq!(identity, fingerprint("bob's secret"));
+    /// assert!(keyring.identify(&challenge, "wrong answer").is_none());
+    /// ```
+    pub fn identify(&self, challenge: &Uuid, tag: &str) -> Option<(&str, &HmacAuthenticator)> {
+        self.secrets
+            .iter()
+            .find(|(_, auth)| auth.validate(challenge, tag))
+            .map(|(identity, auth)| (identity.as_str(), auth))
+    }
+
+    /// As the server, send a challenge to the client and validate their response
+    /// against each secret, returning the identity of the one that matched.
+    pub async fn server_handshake<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut Delimited<T>,
+    ) -> Result<&str> {
+        let (challenge, tag) = server_challenge(stream).await?;
+        match self.identify(&challenge, &tag) {
+            Some((identity, _)) => Ok(identity),
+            None => bail!("invalid secret"),
+        }
+    }
+}
+
+impl Authenticator for Keyring {
+    fn verify(&self, challenge: &Uuid, reply: &str) -> Result<Identity> {
+        match self.identify(challenge, reply) {
+            Some((identity, _)) => Ok(Identity {
+                name: identity.to_string(),
+                ports: self.allowed_ports(identity).cloned(),
+            }),
+            None => bail!("invalid secret"),
+        }
+    }
+
+    fn data_key(&self, challenge: &Uuid, reply: &str) -> Option<DataKey> {
+        let (_, auth) = self.identify(challenge, reply)?;
+        Some(auth.data_key().clone())
+    }
+}
+
+/// Claims of a token that a client authenticates with, signed by the server's operator.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    /// Name of the client, which is logged by the server.
+    pub identity: String,
+
+    /// Time after which the token is no longer accepted, in seconds since the Unix epoch.
+    pub expires: u64,
+
+    /// Range of ports that the client is restricted to, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ports: Option<RangeInclusive<u16>>,
+}
+
+impl Claims {
+    /// Sign the claims with an Ed25519 private key, returning a token.
+    ///
+    /// The token consists of the claims in JSON and their signature, both encoded
+    /// with URL-safe base64 and separated by a dot.
+    pub fn sign(&self, key: &[u8; 32]) -> String {
+        let claims = serde_json::to_vec(self).expect("claims can be serialized");
+        let claims = URL_SAFE_NO_PAD.encode(claims);
+        let signature = SigningKey::from_bytes(key).sign(claims.as_bytes());
+        format!("{claims}.{}", URL_SAFE_NO_PAD.encode(signature.to_bytes()))
+    }
+}
+
+/// Public key that the server checks the tokens of clients with.
+///
+/// Tokens are bearer credentials: the client replies to the challenge with its token as
+/// is, so anyone who sees one on a plaintext control connection can reuse it until it
+/// expires. Servers that accept tokens should require TLS.
+///
+/// ```
+/// use bore_cli::auth::{Authenticator, Claims, TokenKey};
+/// use uuid::Uuid;
+///
+/// let private_key = [7; 32];
+/// let key = TokenKey::from_private_key(&private_key);
+/// let claims = Claims {
+///     identity: "alice".into(),
+///     expires: u64::MAX,
+///     ports: None,
+/// };
+///
+/// let identity = key.verify(&Uuid::new_v4(), &claims.sign(&private_key)).unwrap();
+/// assert_eq!(identity.name, "alice");
+/// assert!(key.verify(&Uuid::new_v4(), "not a token").is_err());
+/// ```
+#[derive(Clone, Debug)]
+pub struct TokenKey(VerifyingKey);
+
+impl TokenKey {
+    /// Parse an Ed25519 public key from hex.
+    pub fn new(public_key: &str) -> Result<Self> {
+        let bytes: [u8; 32] = hex::decode(public_key.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .context("token key must be 32 bytes in hex")?;
+        let key = VerifyingKey::from_bytes(&bytes).context("invalid token key")?;
+        Ok(Self(key))
+    }
+
+    /// The public key that verifies tokens signed with a private key.
+    pub fn from_private_key(key: &[u8; 32]) -> Self {
+        Self(SigningKey::from_bytes(key).verifying_key())
+    }
+
+    /// This key in hex, as accepted by [`TokenKey::new`].
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0.as_bytes())
+    }
+}
+
+impl Authenticator for TokenKey {
+    fn verify(&self, _challenge: &Uuid, reply: &str) -> Result<Identity> {
+        let (claims, signature) = reply
+            .split_once('.')
+            .and_then(|(claims, signature)| {
+                let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+                Some((claims, Signature::from_slice(&signature).ok()?))
+            })
+            .ok_or_else(|| anyhow!("malformed token"))?;
+        ensure!(
+            self.0.verify(claims.as_bytes(), &signature).is_ok(),
+            "invalid token signature"
+        );
+        let claims: Claims = URL_SAFE_NO_PAD
+            .decode(claims)
+            .ok()
+            .and_then(|claims| serde_json::from_slice(&claims).ok())
+            .ok_or_else(|| anyhow!("malformed token"))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        ensure!(now < claims.expires, "token expired");
+        Ok(Identity {
+            name: claims.identity,
+            ports: claims.ports,
+        })
+    }
+}
+
+/// Short identifier for a secret, which can be logged without revealing it.
+pub fn fingerprint(secret: &str) -> String {
+    let hashed_secret = Sha256::new().chain_update(secret).finalize();
+    let hash = Sha256::new().chain_update(hashed_secret).finalize();
+    hex::encode(&hash[..4])
+}
+
+/// As the server, send a challenge to the client and wait for its reply.
+pub async fn server_challenge<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut Delimited<T>,
+) -> Result<(Uuid, String)> {
+    let challenge = Uuid::new_v4();
+    stream.send(ServerMessage::Challenge(challenge)).await?;
+    match stream.recv_timeout().await? {
+        Some(ClientMessage::Authenticate(tag)) => Ok((challenge, tag)),
+        _ => bail!("server requires secret, but no secret was provided"),
+    }
+}
+
+/// As the client, wait for the server's challenge.
+async fn client_challenge<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut Delimited<T>,
+) -> Result<Uuid> {
+    match stream.recv_timeout().await? {
+        Some(ServerMessage::Challenge(challenge)) => Ok(challenge),
+        Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+        _ => bail!("expected authentication challenge, but no secret was required"),
+    }
 }
@@ -1,40 +1,451 @@
 //! Client implementation for the `bore` service.
 
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::{fmt, io};
+use std::{ops::RangeInclusive, sync::Arc, time::Duration};
 
-use anyhow::{bail, Context, Result};
-use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
-use tracing::{error, info, info_span, warn, Instrument};
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use futures_util::{SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::time::{interval, sleep, timeout, timeout_at, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 use uuid::Uuid;
 
-use crate::auth::Authenticator;
+use crate::auth::{Credentials, HmacAuthenticator};
+use crate::crypto::{DataKey, Encrypted, Side};
+use crate::proxy::Proxy;
 use crate::shared::{
-    proxy, ClientMessage, Delimited, ServerMessage, CONTROL_PORT, NETWORK_TIMEOUT,
+    proxy_with, tls_record_complete, tls_server_name, ClientMessage, Compressed, Delimited,
+    Direction, Drain, Endpoint, Events, IpFamily, IpFilter, PortRequest, Protocol, RateLimiter,
+    ServerMessage, SourceAddr, TcpOptions, Transport, TunnelStats, TunnelStatus, WebSocket,
+    COMPRESSION_METHODS, CONTROL_PORT, DATA_CHANNEL_TIMEOUT, DRAIN_TIMEOUT, HEARTBEAT_INTERVAL,
+    HEARTBEAT_TIMEOUT, MAX_DATAGRAM_LENGTH, MAX_TLS_RECORD_LENGTH, NETWORK_TIMEOUT,
+    PROTOCOL_VERSION,
 };
+use crate::tls::ClientTls;
+
+/// Local service that a mapping forwards connections to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LocalTarget {
+    /// A TCP or UDP port on a host.
+    Tcp(String, u16),
+
+    /// A Unix domain socket, which is only supported on Unix platforms.
+    Unix(PathBuf),
+}
+
+/// A local port to forward, along with the port requested on the remote.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    /// Local host that is forwarded.
+    pub local_host: String,
+
+    /// Local port that is forwarded.
+    pub local_port: u16,
+
+    /// Optional Unix domain socket to forward TCP connections to, instead of the local
+    /// host and port.
+    pub local_socket: Option<PathBuf>,
+
+    /// Port on the remote server, or 0 to let the server choose one.
+    ///
+    /// Once the client is connected, this holds the port that was actually assigned.
+    pub remote_port: u16,
+
+    /// Optional range to choose the remote port from, when `remote_port` is 0.
+    pub remote_range: Option<RangeInclusive<u16>>,
+
+    /// Optional address of a server interface to bind the remote port on, which the
+    /// server must allow.
+    pub bind_addr: Option<IpAddr>,
+
+    /// Optional address family to bind the remote port on, out of the addresses that the
+    /// server binds tunnels on.
+    pub family: Option<IpFamily>,
+
+    /// Optional key that the server derives the remote port from when `remote_port` is 0,
+    /// along with the client's secret, so that the same port is chosen every time.
+    pub sticky: Option<String>,
+
+    /// Optional subdomain to receive HTTP requests for on the server's shared HTTP port,
+    /// instead of forwarding a port of its own.
+    pub subdomain: Option<String>,
+
+    /// Optional prefix of a random subdomain for the server to choose, which may be
+    /// empty, if `subdomain` is not set. Once the client is connected, `subdomain` holds
+    /// the one that was chosen.
+    pub subdomain_prefix: Option<String>,
+
+    /// Optional name of a Unix socket to serve the tunnel on in the server's socket
+    /// directory, instead of a public port.
+    ///
+    /// Once the client is connected, this holds the full path of the socket.
+    pub remote_socket: Option<PathBuf>,
+
+    /// Transport protocol to forward.
+    pub protocol: Protocol,
+
+    /// Remote addresses that may connect, checked before connecting to the local host.
+    pub ip_filter: IpFilter,
+
+    /// Optional PROXY protocol header to send with the remote peer's address at the
+    /// start of each local TCP connection.
+    pub proxy_protocol: Option<ProxyProtocol>,
+
+    /// Optional limit in bytes per second on the traffic in each direction, shared by
+    /// all of the mapping's connections.
+    pub max_rate: Option<u64>,
+
+    /// Whether to compress the data of TCP connections on their way to the server.
+    ///
+    /// Once the client is connected, this is cleared if the server did not agree.
+    pub compress: bool,
+
+    /// Whether to encrypt the data of TCP connections on their way to the server, with
+    /// keys derived from the client's secret.
+    ///
+    /// Once the client is connected, this is cleared if the server did not agree.
+    pub encrypt: bool,
+
+    /// Whether to take over the remote port from a stale tunnel that the server still
+    /// holds for a client with the same secret, such as this one before it crashed.
+    pub reclaim: bool,
+
+    /// Optional stable name of the tunnel, for the server to give it the same remote
+    /// port as the last time it connected, if it asks for any port.
+    pub name: Option<String>,
+
+    /// Other local hosts and ports to spread TCP connections over, taking turns with
+    /// `local_host` and `local_port`. A backend that refuses a connection is skipped for
+    /// the next one, and is only tried after the others for a while.
+    pub backends: Vec<(String, u16)>,
+
+    /// Optional first port of the block of mappings that this one is part of, which the
+    /// server establishes all or none of, like those created by [`Mapping::block`].
+    pub block: Option<u16>,
+
+    /// Optional interval for the server to send the statistics of the tunnel on, which
+    /// the client logs and reports as [`ClientEvent::Stats`]. Older servers never send
+    /// them, and servers send them at most once a second.
+    pub stats_interval: Option<Duration>,
+
+    /// Server names to route TLS connections by, to local ports on `local_host` instead
+    /// of `local_port`. The ClientHello of each connection is read to find its name, and
+    /// the connection is forwarded intact, without terminating TLS.
+    pub sni_routes: Vec<(String, u16)>,
+
+    /// Optional local port for connections whose server name has no route, or that are
+    /// not TLS, once `sni_routes` is set. Without it, they are closed.
+    pub sni_default: Option<u16>,
+}
+
+impl Mapping {
+    /// Create a new mapping from a local address to a remote port.
+    pub fn new(local_host: &str, local_port: u16, remote_port: u16) -> Self {
+        Mapping {
+            local_host: local_host.to_string(),
+            local_port,
+            local_socket: None,
+            remote_port,
+            remote_range: None,
+            bind_addr: None,
+            family: None,
+            sticky: None,
+            subdomain: None,
+            subdomain_prefix: None,
+            remote_socket: None,
+            protocol: Protocol::Tcp,
+            ip_filter: IpFilter::default(),
+            proxy_protocol: None,
+            max_rate: None,
+            compress: false,
+            encrypt: false,
+            reclaim: false,
+            name: None,
+            backends: Vec::new(),
+            block: None,
+            stats_interval: None,
+            sni_routes: Vec::new(),
+            sni_default: None,
+        }
+    }
+
+    /// Create a new mapping of TCP connections to a local target.
+    pub fn with_target(target: LocalTarget, remote_port: u16) -> Self {
+        match target {
+            LocalTarget::Tcp(host, port) => Mapping::new(&host, port, remote_port),
+            LocalTarget::Unix(path) => Mapping {
+                local_socket: Some(path),
+                ..Mapping::new("localhost", 0, remote_port)
+            },
+        }
+    }
+
+    /// Create a new mapping that forwards UDP datagrams instead of TCP connections.
+    pub fn udp(local_host: &str, local_port: u16, remote_port: u16) -> Self {
+        Mapping {
+            protocol: Protocol::Udp,
+            ..Mapping::new(local_host, local_port, remote_port)
+        }
+    }
+
+    /// Create mappings of a block of consecutive local ports to the same ports on the
+    /// remote, which the server establishes all or none of.
+    pub fn block(local_host: &str, ports: RangeInclusive<u16>) -> Vec<Self> {
+        let start = *ports.start();
+        ports
+            .map(|port| Mapping {
+                block: Some(start),
+                ..Mapping::new(local_host, port, port)
+            })
+            .collect()
+    }
+}
+
+/// Version of the PROXY protocol, used to tell local services the real peer address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// Human-readable header, version 1.
+    V1,
+
+    /// Binary header, version 2.
+    V2,
+}
+
+impl ProxyProtocol {
+    /// Encode a header for a TCP connection from `source` to `destination`.
+    ///
+    /// Both addresses are encoded as IPv6 if they are not of the same family.
+    pub fn header(self, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+        let (source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => (IpAddr::V4(src), IpAddr::V4(dst)),
+            (src, dst) => (to_ipv6(src).into(), to_ipv6(dst).into()),
+        };
+        let (source_port, destination_port) = (source.port(), destination.port());
+        match self {
+            ProxyProtocol::V1 => {
+                let family = if source_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+                let addresses = format!("{source_ip} {destination_ip}");
+                let ports = format!("{source_port} {destination_port}");
+                format!("PROXY {family} {addresses} {ports}\r\n").into_bytes()
+            }
+            ProxyProtocol::V2 => {
+                let mut addresses = octets(source_ip);
+                addresses.extend(octets(destination_ip));
+                addresses.extend(source_port.to_be_bytes());
+                addresses.extend(destination_port.to_be_bytes());
+
+                let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+                header.push(0x21); // version 2, PROXY command
+                header.push(if source_ip.is_ipv4() { 0x11 } else { 0x21 }); // TCP over IPv4 or IPv6
+                header.extend((addresses.len() as u16).to_be_bytes());
+                header.extend(addresses);
+                header
+            }
+        }
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// Errors from connecting to a server that callers may want to handle, which can be
+/// found with [`anyhow::Error::downcast_ref`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ClientError {
+    /// The specific remote port that was requested is already in use on the server.
+    ///
+    /// Asking for port 0 instead lets the server choose a free one.
+    PortInUse(u16),
+
+    /// The server could not be reached, such as when the connection was refused.
+    Unreachable,
+
+    /// The server refused the client's secret or token, or required one.
+    AuthFailed,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::PortInUse(port) => {
+                write!(f, "port {port} is already in use on the server")
+            }
+            ClientError::Unreachable => write!(f, "could not reach the server"),
+            ClientError::AuthFailed => write!(f, "authentication failed"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Transport that the client carries its connections to the server over.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransportKind {
+    /// Plain TCP connections to the control port.
+    #[default]
+    Tcp,
+
+    /// WebSocket connections to a port of the server, such as 80 or 443, for networks
+    /// that only let HTTP out.
+    WebSocket(u16),
+}
+
+/// Settings for opening connections to the server.
+#[derive(Clone)]
+struct Connector {
+    /// Optional proxy to open connections through.
+    proxy: Option<Proxy>,
+
+    /// Optional TLS settings for connections to the server.
+    tls: Option<ClientTls>,
+
+    /// Transport that connections are carried over.
+    transport: TransportKind,
+
+    /// Time to wait for connections and handshakes to complete.
+    timeout: Duration,
+
+    /// Time to wait for TCP connections to open, if different from `timeout`.
+    connect_timeout: Option<Duration>,
+
+    /// Time to wait for connections to local targets to open, if different from
+    /// `connect_timeout`.
+    local_connect_timeout: Option<Duration>,
+
+    /// Time to wait for a connection back to the server that accepts a forwarded
+    /// connection to be ready, including its handshake.
+    data_channel_timeout: Duration,
+
+    /// Socket options for connections to the server and to local targets.
+    tcp: TcpOptions,
+
+    /// Local address and interface to open connections to the server from.
+    source: SourceAddr,
+
+    /// Local address to open connections to local targets from.
+    local_source: SourceAddr,
+
+    /// Optional time to keep retrying local targets that refuse connections.
+    local_retry: Option<Duration>,
+
+    /// Delays between retries of local targets, and an optional limit on their number.
+    local_backoff: Backoff,
+}
+
+impl Default for Connector {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            tls: None,
+            transport: TransportKind::default(),
+            timeout: NETWORK_TIMEOUT,
+            connect_timeout: None,
+            local_connect_timeout: None,
+            data_channel_timeout: DATA_CHANNEL_TIMEOUT,
+            tcp: TcpOptions::default(),
+            source: SourceAddr::default(),
+            local_source: SourceAddr::default(),
+            local_retry: None,
+            local_backoff: LOCAL_BACKOFF,
+        }
+    }
+}
 
 /// State structure for the client.
 pub struct Client {
     /// Control connection to the server.
-    conn: Option<Delimited<TcpStream>>,
+    conn: Option<Delimited<Box<dyn Transport>>>,
 
     /// Destination address of the server.
     to: String,
 
-    // Local host that is forwarded.
-    local_host: String,
+    /// Mappings that were successfully established, with their assigned remote ports.
+    mappings: Vec<Mapping>,
 
-    /// Local port that is forwarded.
-    local_port: u16,
+    /// Rate limits of the established mappings, in the same order, sent and received.
+    limits: Vec<(Direction, Direction)>,
 
-    /// Port that is publicly available on the remote.
-    remote_port: u16,
+    /// Turns of the established mappings' local backends, in the same order.
+    balancers: Vec<Arc<Balancer>>,
+
+    /// Mappings that the server could not establish, with the reported error.
+    failures: Vec<(Mapping, String)>,
+
+    /// Optional credentials used to authenticate with the server.
+    auth: Option<Credentials>,
 
-    /// Optional secret used to authenticate clients.
-    auth: Option<Authenticator>,
+    /// Whether the server challenges connections although the client has no credentials,
+    /// as a server that also lets in clients without them does.
+    challenged: bool,
+
+    /// Settings for connections to the server.
+    connector: Connector,
+
+    /// Number of remote connections rejected by the mappings' IP filters.
+    rejected: Arc<AtomicU64>,
+
+    /// Tasks for proxied connections, which can be drained on shutdown.
+    drain: Drain,
+
+    /// Signal to stop forwarding new connections and shut down.
+    shutdown: CancellationToken,
+
+    /// Time to wait for proxied connections to finish when shutting down.
+    drain_timeout: Duration,
+
+    /// Interval between heartbeats sent to the server.
+    heartbeat_interval: Duration,
+
+    /// Time without a message from the server after which the connection is considered lost.
+    heartbeat_timeout: Duration,
+
+    /// Optional interval between pings sent to the server, and time to wait for each
+    /// pong before the connection is considered lost.
+    ping: Option<(Duration, Duration)>,
+
+    /// Optional callback for changes in the state of the tunnels.
+    on_event: Option<EventCallback>,
+
+    /// Streams of the same events, from [`Client::events`].
+    events: Events<ClientEvent>,
+
+    /// Optional backoff to reconnect with when the control connection is lost, and
+    /// whether to insist on getting the same remote ports back.
+    reconnect: Option<(Backoff, bool)>,
+
+    /// Requested ports that the server reported as already in use.
+    ports_in_use: Vec<u16>,
 }
 
+/// Callback for client events, shared across reconnections.
+type EventCallback = Arc<Mutex<dyn FnMut(ClientEvent) + Send>>;
+
 impl Client {
     /// Create a new client.
+    ///
+    /// This is a shorthand for [`ClientBuilder`], which has more options.
+    #[deprecated(note = "use `ClientBuilder` instead")]
     pub async fn new(
         local_host: &str,
         local_port: u16,
@@ -42,60 +453,522 @@ impl Client {
         port: u16,
         secret: Option<&str>,
     ) -> Result<Self> {
-        let mut stream = Delimited::new(connect_with_timeout(to, CONTROL_PORT).await?);
-        let auth = secret.map(Authenticator::new);
+        let mut builder = ClientBuilder::new(to)
+            .local_host(local_host)
+            .local_port(local_port)
+            .remote_port(port);
+        if let Some(secret) = secret {
+            builder = builder.secret(secret);
+        }
+        builder.build().await
+    }
+
+    /// Create a new client forwarding several local ports over one control connection.
+    ///
+    /// The server allocates each requested port independently. If `strict` is set, a
+    /// failure to allocate any of them is an error; otherwise the failure is logged and
+    /// the remaining mappings are still forwarded.
+    ///
+    /// If `tls` is provided, all connections to the server are encrypted with TLS.
+    #[deprecated(note = "use `ClientBuilder` instead")]
+    pub async fn with_mappings(
+        mappings: Vec<Mapping>,
+        to: &str,
+        secret: Option<&str>,
+        tls: Option<ClientTls>,
+        strict: bool,
+    ) -> Result<Self> {
+        let connector = Connector {
+            tls,
+            ..Connector::default()
+        };
+        let auth = secret.map(|secret| Credentials::Secret(HmacAuthenticator::new(secret)));
+        Self::with_connector(mappings, to, auth, connector, strict).await
+    }
+
+    async fn with_connector(
+        mappings: Vec<Mapping>,
+        to: &str,
+        auth: Option<Credentials>,
+        connector: Connector,
+        strict: bool,
+    ) -> Result<Self> {
+        let client = Self::connect(mappings, to, auth, connector).await?;
+        if let Some((mapping, message)) = client.failures.first() {
+            if strict || client.mappings.is_empty() {
+                if client.ports_in_use.contains(&mapping.remote_port) {
+                    return Err(ClientError::PortInUse(mapping.remote_port).into());
+                }
+                bail!("server error: {message}");
+            }
+        }
+        Ok(client)
+    }
+
+    /// Perform the handshake, returning a client even if some mappings were rejected.
+    async fn connect(
+        mappings: Vec<Mapping>,
+        to: &str,
+        auth: Option<Credentials>,
+        connector: Connector,
+    ) -> Result<Self> {
+        if mappings.is_empty() {
+            bail!("no ports to forward");
+        }
+        if mappings
+            .iter()
+            .any(|m| m.protocol == Protocol::Udp && m.local_socket.is_some())
+        {
+            bail!("UDP datagrams cannot be forwarded to a Unix socket");
+        }
+        let mut stream = connector.connect(to).await?;
         if let Some(auth) = &auth {
             auth.client_handshake(&mut stream).await?;
         }
 
-        stream.send(ClientMessage::Hello(port)).await?;
-        let remote_port = match stream.recv_timeout().await? {
-            Some(ServerMessage::Hello(remote_port)) => remote_port,
+        let requests = mappings
+            .iter()
+            .map(|m| PortRequest {
+                port: m.remote_port,
+                protocol: m.protocol,
+                range: m.remote_range.clone(),
+                bind_addr: m.bind_addr,
+                family: m.family,
+                sticky: m.sticky.clone(),
+                subdomain: m.subdomain.clone(),
+                subdomain_prefix: m.subdomain_prefix.clone(),
+                compression: match m.compress {
+                    true => COMPRESSION_METHODS.to_vec(),
+                    false => Vec::new(),
+                },
+                encrypt: m.encrypt && auth.as_ref().is_some_and(|auth| auth.data_key().is_some()),
+                socket: m
+                    .remote_socket
+                    .as_ref()
+                    .map(|path| path.file_name().unwrap_or(path.as_os_str()))
+                    .map(|name| name.to_string_lossy().into_owned()),
+                reclaim: m.reclaim,
+                name: m.name.clone(),
+                block: m.block,
+                stats_interval_ms: m.stats_interval.map(|interval| interval.as_millis() as u64),
+                version: Some(PROTOCOL_VERSION),
+            })
+            .collect();
+        stream.send(ClientMessage::Hello(requests)).await?;
+        let mut compress = false;
+        let mut encrypt = false;
+        let mut ports_in_use = Vec::new();
+        let mut reassigned = Vec::new();
+        let mut chosen_subdomain = None;
+        let mut challenged = false;
+        let mut message = stream.recv_timeout().await?;
+        loop {
+            match message {
+                // A server that lets in clients without credentials still challenges them.
+                Some(ServerMessage::Challenge(_)) if auth.is_none() && !challenged => {
+                    challenged = true;
+                }
+                Some(ServerMessage::Compress(_)) => compress = true,
+                Some(ServerMessage::Encrypt) => encrypt = true,
+                Some(ServerMessage::PortInUse(port)) => ports_in_use.push(port),
+                Some(ServerMessage::PortReassigned(name)) => reassigned.push(name),
+                Some(ServerMessage::Subdomain(subdomain)) => chosen_subdomain = Some(subdomain),
+                _ => break,
+            }
+            message = stream.recv_timeout().await?;
+        }
+        let results = match message {
+            Some(ServerMessage::Hello(results)) => results,
             Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
             Some(ServerMessage::Challenge(_)) => {
-                bail!("server requires authentication, but no client secret was provided");
+                bail!("server requires authentication, but no client secret or token was provided");
             }
             Some(_) => bail!("unexpected initial non-hello message"),
             None => bail!("unexpected EOF"),
         };
-        info!(remote_port, "connected to server");
-        info!("listening at {to}:{remote_port}");
+        if results.len() != mappings.len() {
+            bail!(
+                "server replied with {} ports, expected {}",
+                results.len(),
+                mappings.len()
+            );
+        }
 
+        let mut established = Vec::new();
+        let mut failures = Vec::new();
+        for (mut mapping, result) in mappings.into_iter().zip(results) {
+            if result.is_ok() && mapping.compress && !compress {
+                warn!(
+                    local_port = mapping.local_port,
+                    "server does not support compression, sending data uncompressed",
+                );
+                mapping.compress = false;
+            }
+            if result.is_ok() && mapping.encrypt && !encrypt {
+                warn!(
+                    local_port = mapping.local_port,
+                    "server does not support encryption, sending data unencrypted",
+                );
+                mapping.encrypt = false;
+            }
+            match result {
+                Ok(Endpoint::Port(remote_port)) => {
+                    if mapping.subdomain_prefix.is_some() && mapping.subdomain.is_none() {
+                        mapping.subdomain = chosen_subdomain.take();
+                        if mapping.subdomain.is_none() {
+                            warn!(
+                                local_port = mapping.local_port,
+                                "server does not choose subdomains, forwarding a port instead",
+                            );
+                            mapping.subdomain_prefix = None;
+                        }
+                    }
+                    if mapping
+                        .name
+                        .as_ref()
+                        .is_some_and(|name| reassigned.contains(name))
+                    {
+                        warn!(
+                            name = mapping.name,
+                            "previous port of the tunnel was taken, got a new one",
+                        );
+                    }
+                    info!(
+                        event = "port_allocated",
+                        tunnel_port = remote_port,
+                        protocol = ?mapping.protocol,
+                        "connected to server",
+                    );
+                    match &mapping.subdomain {
+                        Some(subdomain) => {
+                            info!("listening at http://{subdomain}.{to}:{remote_port}")
+                        }
+                        None => info!("listening at {to}:{remote_port}"),
+                    }
+                    mapping.remote_port = remote_port;
+                    established.push(mapping);
+                }
+                Ok(Endpoint::Socket(path)) => {
+                    info!(
+                        event = "port_allocated",
+                        tunnel_socket = %path.display(),
+                        "connected to server",
+                    );
+                    info!("listening at {to}:{}", path.display());
+                    mapping.remote_socket = Some(path);
+                    established.push(mapping);
+                }
+                Err(message) => {
+                    warn!(local_port = mapping.local_port, %message, "failed to forward port");
+                    failures.push((mapping, message));
+                }
+            }
+        }
+        let limits = established
+            .iter()
+            .map(|mapping| {
+                let direction = || Direction {
+                    limit: mapping.max_rate.map(RateLimiter::new),
+                    bytes: None,
+                };
+                (direction(), direction())
+            })
+            .collect();
+        let balancers = established.iter().map(|_| Arc::default()).collect();
         Ok(Client {
             conn: Some(stream),
             to: to.to_string(),
-            local_host: local_host.to_string(),
-            local_port,
-            remote_port,
+            mappings: established,
+            limits,
+            balancers,
+            failures,
             auth,
+            challenged,
+            connector,
+            rejected: Arc::default(),
+            drain: Drain::default(),
+            shutdown: CancellationToken::new(),
+            drain_timeout: DRAIN_TIMEOUT,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            heartbeat_timeout: HEARTBEAT_TIMEOUT,
+            ping: None,
+            on_event: None,
+            events: Events::default(),
+            reconnect: None,
+            ports_in_use,
         })
     }
 
     /// Returns the port publicly available on the remote.
+    ///
+    /// This is the port that the server assigned during the handshake, which is useful
+    /// when the client asked for port 0. If several ports are forwarded, this is the
+    /// port of the first mapping; see [`Client::mappings`] for the others.
     pub fn remote_port(&self) -> u16 {
-        self.remote_port
+        self.mappings[0].remote_port
+    }
+
+    /// Returns the forwarded mappings, with the ports assigned on the remote.
+    pub fn mappings(&self) -> &[Mapping] {
+        &self.mappings
+    }
+
+    /// Returns the mappings that the server failed to establish, with the reason.
+    ///
+    /// This is only ever non-empty for clients created with `strict` unset.
+    pub fn failures(&self) -> &[(Mapping, String)] {
+        &self.failures
+    }
+
+    /// Use a token to shut down the client gracefully.
+    ///
+    /// Once the token is cancelled, the client stops accepting new connections, waits
+    /// for proxied connections to finish up to the drain timeout, and then closes the
+    /// control connection, with [`Client::listen`] returning `Ok(())`.
+    pub fn set_shutdown(&mut self, token: CancellationToken) {
+        self.shutdown = token;
+    }
+
+    /// Set how long to wait for proxied connections to finish when shutting down.
+    pub fn set_drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    /// Set how often heartbeats are sent to the server on the control connection.
+    pub fn set_heartbeat_interval(&mut self, interval: Duration) {
+        assert!(!interval.is_zero(), "heartbeat interval must be positive");
+        self.heartbeat_interval = interval;
+    }
+
+    /// Treat the control connection as lost if the server sends nothing for this long.
+    ///
+    /// The server sends heartbeats on an interval, so this should be a few times longer
+    /// than the server's heartbeat interval.
+    pub fn set_heartbeat_timeout(&mut self, timeout: Duration) {
+        self.heartbeat_timeout = timeout;
+    }
+
+    /// Send a ping with a random number to the server on this interval, and treat the
+    /// control connection as lost if the server does not echo it back within `timeout`.
+    ///
+    /// Unlike heartbeats, which each side sends on its own, this checks that messages
+    /// make the round trip, which catches links that silently drop one direction. It
+    /// needs a server that answers pings on tunnel connections, so it is off by default.
+    pub fn set_ping_interval(&mut self, interval: Duration, timeout: Duration) {
+        assert!(!interval.is_zero(), "ping interval must be positive");
+        self.ping = Some((interval, timeout));
+    }
+
+    /// Stream the client's events from now on, the same ones that are reported to
+    /// [`ClientBuilder::on_event`].
+    ///
+    /// This should be called before [`Client::listen`], which consumes the client. The
+    /// stream ends once the client stops, and skips events if it falls far behind.
+    pub fn events(&self) -> impl Stream<Item = ClientEvent> + Send + Unpin + 'static {
+        self.events.subscribe()
     }
 
-    /// Start the client, listening for new connections.
+    /// Report an event to the callback, if any, and to the event streams.
+    fn report(&self, event: ClientEvent) {
+        report_event(&self.on_event, &self.events, event);
+    }
+
+    /// Report an event for each of the forwarded mappings.
+    fn emit(&self, event: impl Fn(String, Mapping) -> ClientEvent) {
+        for mapping in &self.mappings {
+            self.report(event(self.to.clone(), mapping.clone()));
+        }
+    }
+
+    /// Report the end of a proxied connection, with the bytes it carried if it did not
+    /// fail, in which case the error is logged.
+    fn finish_connection(&self, id: Uuid, result: Result<(u64, u64)>) {
+        let (bytes_out, bytes_in) = result.unwrap_or_else(|err| {
+            warn!(%err, "connection exited with error");
+            (0, 0)
+        });
+        self.report(ClientEvent::ConnectionClosed {
+            id,
+            bytes_in,
+            bytes_out,
+        });
+    }
+
+    /// Start the client, listening for new connections until it is shut down.
+    ///
+    /// The handshake is already done by the time a client is created, so the assigned
+    /// ports can be read with [`Client::remote_port`] before this is called, say to
+    /// advertise them somewhere, and the future spawned afterwards.
+    ///
+    /// If the client was built with [`ClientBuilder::reconnect`], this reconnects
+    /// whenever the control connection is lost, like [`Client::listen_with_retry`].
     pub async fn listen(mut self) -> Result<()> {
-        let mut conn = self.conn.take().unwrap();
+        match self.reconnect.take() {
+            Some((backoff, strict_port)) => self.listen_with_retry(backoff, strict_port).await,
+            None => self.listen_once().await,
+        }
+    }
+
+    /// Listen for new connections until the control connection closes.
+    async fn listen_once(mut self) -> Result<()> {
+        let conn = self.conn.take().unwrap();
+        self.emit(|to, mapping| ClientEvent::Connected { to, mapping });
         let this = Arc::new(self);
+        let result = Self::forward(Arc::clone(&this), conn).await;
+        let reason = result.as_ref().err().map(|err| format!("{err:#}"));
+        this.emit(|to, mapping| ClientEvent::Disconnected {
+            to,
+            mapping,
+            reason: reason.clone(),
+        });
+        result
+    }
+
+    /// Handle messages on the control connection until it closes.
+    async fn forward(this: Arc<Self>, mut conn: Delimited<Box<dyn Transport>>) -> Result<()> {
+        let drain = this.drain.clone();
+        let mut heartbeat = interval(this.heartbeat_interval);
+        let liveness = sleep(this.heartbeat_timeout);
+        tokio::pin!(liveness);
+        let (ping_interval, ping_timeout) = this.ping.unwrap_or_default();
+        let mut ping = interval(this.ping.map_or(this.heartbeat_interval, |_| ping_interval));
+        let mut pending_ping = None;
+        let pong_deadline = sleep(Duration::ZERO);
+        tokio::pin!(pong_deadline);
+        // A server that is shutting down stops sending heartbeats and answering pings.
+        let mut server_shutting_down = false;
         loop {
-            match conn.recv().await? {
+            let message = tokio::select! {
+                message = conn.recv() => message?,
+                _ = heartbeat.tick() => {
+                    conn.send(ClientMessage::Heartbeat).await?;
+                    continue;
+                }
+                _ = ping.tick(), if this.ping.is_some() && pending_ping.is_none() => {
+                    let nonce = fastrand::u32(..);
+                    conn.send(ClientMessage::Ping(nonce)).await?;
+                    pending_ping = Some((nonce, Instant::now()));
+                    pong_deadline.as_mut().reset(Instant::now() + ping_timeout);
+                    continue;
+                }
+                _ = &mut pong_deadline, if pending_ping.is_some() && !server_shutting_down => {
+                    warn!(event = "ping_timeout", "no pong from server");
+                    bail!("no pong from server within {ping_timeout:?}");
+                }
+                _ = &mut liveness, if !server_shutting_down => {
+                    warn!(event = "heartbeat_timeout", "no heartbeat from server");
+                    bail!("no heartbeat from server for {:?}", this.heartbeat_timeout);
+                }
+                _ = this.shutdown.cancelled() => {
+                    info!("shutting down, draining connections");
+                    if !drain.wait(this.drain_timeout).await {
+                        warn!(remaining = drain.len(), "drain timeout elapsed");
+                    }
+                    return Ok(());
+                }
+            };
+            liveness
+                .as_mut()
+                .reset(Instant::now() + this.heartbeat_timeout);
+            match message {
                 Some(ServerMessage::Hello(_)) => warn!("unexpected hello"),
+                Some(ServerMessage::Compress(_)) => warn!("unexpected compress"),
+                Some(ServerMessage::Encrypt) => warn!("unexpected encrypt"),
+                Some(ServerMessage::PortInUse(_)) => warn!("unexpected port in use"),
+                Some(ServerMessage::PortReassigned(_)) => warn!("unexpected port reassignment"),
+                Some(ServerMessage::Subdomain(_)) => warn!("unexpected subdomain"),
                 Some(ServerMessage::Challenge(_)) => warn!("unexpected challenge"),
+                Some(ServerMessage::Status(_)) => warn!("unexpected status"),
+                Some(ServerMessage::Pong(nonce)) => match pending_ping {
+                    Some((sent, at)) if sent == nonce => {
+                        debug!(rtt = ?at.elapsed(), "pong from server");
+                        pending_ping = None;
+                    }
+                    _ => warn!("unexpected pong"),
+                },
                 Some(ServerMessage::Heartbeat) => (),
-                Some(ServerMessage::Connection(id)) => {
+                Some(ServerMessage::Stats(stats)) => {
+                    let tunnel = match &stats.tunnel {
+                        Endpoint::Port(port) => port.to_string(),
+                        Endpoint::Socket(path) => path.display().to_string(),
+                    };
+                    info!(
+                        event = "tunnel_stats",
+                        tunnel,
+                        active_connections = stats.active_connections,
+                        total_connections = stats.total_connections,
+                        bytes_in = stats.bytes_in,
+                        bytes_out = stats.bytes_out,
+                        "tunnel statistics",
+                    );
+                    this.report(ClientEvent::Stats(stats));
+                }
+                Some(ServerMessage::ShuttingDown { grace_secs }) => {
+                    warn!(
+                        grace_secs,
+                        "server is shutting down, waiting for it to close the connection"
+                    );
+                    server_shutting_down = true;
+                }
+                Some(ServerMessage::Connection(id, port, peer)) => {
+                    let this = Arc::clone(&this);
+                    // The local backend is recorded once it accepts the connection.
+                    let span =
+                        info_span!("proxy", %id, port, peer_addr = %peer, backend = field::Empty);
+                    drain.spawn(
+                        async move {
+                            info!(
+                                event = "connection_opened",
+                                tunnel_port = port,
+                                peer_addr = %peer,
+                                "new connection",
+                            );
+                            let event = ClientEvent::ConnectionOpened {
+                                id,
+                                peer: Some(peer),
+                            };
+                            this.report(event);
+                            let result = this.handle_connection(id, port, peer).await;
+                            this.finish_connection(id, result);
+                        }
+                        .instrument(span),
+                    );
+                }
+                Some(ServerMessage::SocketConnection(id, path)) => {
+                    let this = Arc::clone(&this);
+                    drain.spawn(
+                        async move {
+                            info!(
+                                event = "connection_opened",
+                                tunnel_socket = %path.display(),
+                                "new connection",
+                            );
+                            this.report(ClientEvent::ConnectionOpened { id, peer: None });
+                            let result = this.handle_socket_connection(id, &path).await;
+                            this.finish_connection(id, result);
+                        }
+                        .instrument(info_span!("proxy", %id, backend = field::Empty)),
+                    );
+                }
+                Some(ServerMessage::UdpConnection(id, port, peer)) => {
                     let this = Arc::clone(&this);
-                    tokio::spawn(
+                    drain.spawn(
                         async move {
-                            info!("new connection");
-                            match this.handle_connection(id).await {
-                                Ok(_) => info!("connection exited"),
-                                Err(err) => warn!(%err, "connection exited with error"),
-                            }
+                            info!(
+                                event = "connection_opened",
+                                tunnel_port = port,
+                                peer_addr = %peer,
+                                "new udp flow",
+                            );
+                            let event = ClientEvent::ConnectionOpened {
+                                id,
+                                peer: Some(peer),
+                            };
+                            this.report(event);
+                            let result = this.handle_udp_connection(id, port, peer).await;
+                            this.finish_connection(id, result);
                         }
-                        .instrument(info_span!("proxy", %id)),
+                        .instrument(info_span!("proxy", %id, port, peer_addr = %peer)),
                     );
                 }
                 Some(ServerMessage::Error(err)) => error!(%err, "server error"),
@@ -104,26 +977,1179 @@ impl Client {
         }
     }
 
-    async fn handle_connection(&self, id: Uuid) -> Result<()> {
-        let mut remote_conn =
-            Delimited::new(connect_with_timeout(&self.to[..], CONTROL_PORT).await?);
+    /// Start the client, reconnecting with exponential backoff whenever the control
+    /// connection is lost.
+    ///
+    /// On each reconnection, the client asks for the same remote ports it was assigned
+    /// before. If one of them is no longer available, this returns an error when
+    /// `strict_port` is set, and otherwise forwards that mapping on a newly assigned
+    /// port, except for blocks of ports, which are asked for again as they were.
+    /// Proxied connections that are already established are not interrupted.
+    pub async fn listen_with_retry(self, backoff: Backoff, strict_port: bool) -> Result<()> {
+        let mut client = self;
+        loop {
+            let to = client.to.clone();
+            let auth = client.auth.clone();
+            let connector = client.connector.clone();
+            let mappings = client.mappings.clone();
+            let limits = client.limits.clone();
+            let balancers = client.balancers.clone();
+            let rejected = Arc::clone(&client.rejected);
+            let drain = client.drain.clone();
+            let shutdown = client.shutdown.clone();
+            let drain_timeout = client.drain_timeout;
+            let heartbeat = (client.heartbeat_interval, client.heartbeat_timeout);
+            let ping = client.ping;
+            let on_event = client.on_event.clone();
+            let events = client.events.clone();
+            let result = client.listen_once().await;
+            if shutdown.is_cancelled() {
+                return result;
+            }
+            match result {
+                Ok(()) => warn!("control connection closed by server"),
+                Err(err) => warn!(%err, "control connection lost"),
+            }
+            let report = |event| report_event(&on_event, &events, event);
+            let reconnect = Self::reconnect(
+                mappings,
+                &to,
+                auth,
+                connector,
+                &backoff,
+                strict_port,
+                &report,
+            );
+            client = tokio::select! {
+                client = reconnect => client?,
+                _ = shutdown.cancelled() => {
+                    if !drain.wait(drain_timeout).await {
+                        warn!(remaining = drain.len(), "drain timeout elapsed");
+                    }
+                    return Ok(());
+                }
+            };
+            // Reconnecting only succeeds once every mapping is forwarded again, in the
+            // same order, so new connections share the limits of the old ones.
+            client.limits = limits;
+            client.balancers = balancers;
+            client.rejected = rejected;
+            client.drain = drain;
+            client.shutdown = shutdown;
+            client.drain_timeout = drain_timeout;
+            (client.heartbeat_interval, client.heartbeat_timeout) = heartbeat;
+            client.ping = ping;
+            client.on_event = on_event;
+            client.events = events;
+        }
+    }
+
+    async fn reconnect(
+        mappings: Vec<Mapping>,
+        to: &str,
+        auth: Option<Credentials>,
+        connector: Connector,
+        backoff: &Backoff,
+        strict_port: bool,
+        report: &(dyn Fn(ClientEvent) + Sync),
+    ) -> Result<Self> {
+        let mut mappings = mappings;
+        let mut attempt = 0;
+        loop {
+            let delay = backoff.delay(attempt);
+            attempt += 1;
+            info!(attempt, ?delay, "reconnecting to server");
+            report(ClientEvent::Reconnecting {
+                to: to.into(),
+                attempt,
+                delay,
+            });
+            sleep(delay).await;
+
+            let connect = Self::connect(mappings.clone(), to, auth.clone(), connector.clone());
+            let client = match connect.await {
+                Ok(client) => client,
+                Err(err) => {
+                    if backoff.max_attempts.is_some_and(|max| attempt >= max) {
+                        return Err(err.context(format!("gave up after {attempt} attempts")));
+                    }
+                    warn!(%err, "failed to reconnect");
+                    continue;
+                }
+            };
+            if client.failures.is_empty() {
+                return Ok(client);
+            }
+
+            // Some of the previously assigned ports were taken in the meantime.
+            if strict_port {
+                let (mapping, message) = &client.failures[0];
+                bail!("could not reclaim port {}: {message}", mapping.remote_port);
+            }
+            for mapping in &mut mappings {
+                if mapping.block.is_some() {
+                    // A block is only useful on its own ports, so ask for them again.
+                    continue;
+                }
+                if client.failures.iter().any(|(m, _)| m == mapping) {
+                    warn!(
+                        port = mapping.remote_port,
+                        "port no longer available, requesting a new one"
+                    );
+                    mapping.remote_port = 0;
+                    if mapping.subdomain_prefix.is_some() {
+                        mapping.subdomain = None;
+                    }
+                }
+            }
+            attempt = 0;
+        }
+    }
+
+    /// Find the mapping of a remote port, along with its rate limits and the turns of its
+    /// local backends.
+    fn find_mapping(
+        &self,
+        port: u16,
+        protocol: Protocol,
+    ) -> Result<(&Mapping, &(Direction, Direction), &Balancer)> {
+        match self
+            .mappings
+            .iter()
+            .position(|m| m.remote_port == port && m.protocol == protocol)
+        {
+            Some(index) => Ok((
+                &self.mappings[index],
+                &self.limits[index],
+                &self.balancers[index],
+            )),
+            None => bail!("connection for unknown port {port}"),
+        }
+    }
+
+    /// Open a new stream to the server and accept a forwarded connection on it.
+    async fn accept_remote(&self, id: Uuid) -> Result<Delimited<Box<dyn Transport>>> {
+        let accept = async {
+            let mut remote_conn = self.connector.connect(&self.to).await?;
+            if let Some(auth) = &self.auth {
+                auth.client_handshake(&mut remote_conn).await?;
+            } else if self.challenged {
+                // Skip the challenge, so that it doesn't end up in the forwarded data.
+                match remote_conn.recv_timeout().await? {
+                    Some(ServerMessage::Challenge(_)) => (),
+                    _ => bail!("expected authentication challenge"),
+                }
+            }
+            remote_conn.send(ClientMessage::Accept(id)).await?;
+            Ok(remote_conn)
+        };
+        let duration = self.connector.data_channel_timeout;
+        timeout(duration, accept).await.with_context(|| {
+            format!("timed out opening a data channel for connection {id} after {duration:?}")
+        })?
+    }
+
+    /// The key to encrypt a connection of a mapping with, along with its ID, if the data
+    /// of the mapping is encrypted.
+    fn encryption(&self, mapping: &Mapping, id: Uuid) -> Option<(&DataKey, Uuid)> {
+        let key = self.auth.as_ref()?.data_key()?;
+        mapping.encrypt.then_some((key, id))
+    }
+
+    /// Check a remote peer against the IP filter of its mapping, counting rejections.
+    ///
+    /// Rejected connections are still accepted from the server, so that dropping them
+    /// closes the peer's connection right away.
+    fn check_peer(&self, mapping: &Mapping, peer: SocketAddr) -> bool {
+        if mapping.ip_filter.allows(peer.ip()) {
+            return true;
+        }
+        let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+        debug!(
+            event = "connection_rejected",
+            tunnel_port = mapping.remote_port,
+            peer_addr = %peer,
+            rejected,
+            "rejected connection from filtered address",
+        );
+        false
+    }
+
+    /// Handle a connection to a tunnel port, returning the bytes sent each way.
+    async fn handle_connection(&self, id: Uuid, port: u16, peer: SocketAddr) -> Result<(u64, u64)> {
+        let opened = Instant::now();
+        let (mapping, limits, balancer) = self.find_mapping(port, Protocol::Tcp)?;
+        let remote_conn = self.accept_remote(id).await?;
+        if !self.check_peer(mapping, peer) {
+            return Ok((0, 0));
+        }
+        let encrypt = self.encryption(mapping, id);
+        let (bytes_up, bytes_down) = forward_local(
+            mapping,
+            balancer,
+            remote_conn,
+            encrypt,
+            Some(peer),
+            &self.connector,
+            limits,
+        )
+        .await?;
+        connection_closed(port, peer, opened, bytes_up, bytes_down);
+        Ok((bytes_up, bytes_down))
+    }
+
+    /// Handle a connection to a tunnel on a Unix socket of the server, which has no
+    /// peer address to filter or to send in a PROXY header.
+    async fn handle_socket_connection(&self, id: Uuid, path: &Path) -> Result<(u64, u64)> {
+        let opened = Instant::now();
+        let Some(index) = self
+            .mappings
+            .iter()
+            .position(|m| m.remote_socket.as_deref() == Some(path))
+        else {
+            bail!("connection for unknown socket {}", path.display());
+        };
+        let mapping = &self.mappings[index];
+        let remote_conn = self.accept_remote(id).await?;
+        let encrypt = self.encryption(mapping, id);
+        let (bytes_up, bytes_down) = forward_local(
+            mapping,
+            &self.balancers[index],
+            remote_conn,
+            encrypt,
+            None,
+            &self.connector,
+            &self.limits[index],
+        )
+        .await?;
+        info!(
+            event = "connection_closed",
+            tunnel_socket = %path.display(),
+            duration_ms = opened.elapsed().as_millis() as u64,
+            bytes = bytes_up + bytes_down,
+            bytes_up,
+            bytes_down,
+            "connection exited",
+        );
+        Ok((bytes_up, bytes_down))
+    }
+
+    /// Handle a UDP flow from a remote peer, returning the bytes sent each way.
+    async fn handle_udp_connection(
+        &self,
+        id: Uuid,
+        port: u16,
+        peer: SocketAddr,
+    ) -> Result<(u64, u64)> {
+        let opened = Instant::now();
+        let (mapping, (up, down), _) = self.find_mapping(port, Protocol::Udp)?;
+        let remote_conn = self.accept_remote(id).await?;
+        if !self.check_peer(mapping, peer) {
+            return Ok((0, 0));
+        }
+
+        // Each flow gets its own local socket, so that replies reach the right peer.
+        let local_addr = lookup_host((&mapping.local_host[..], mapping.local_port))
+            .await?
+            .next()
+            .with_context(|| format!("could not resolve {}", mapping.local_host))?;
+        let bind_addr: SocketAddr = match local_addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(local_addr).await?;
+
+        let mut remote_conn = remote_conn.into_datagrams();
+        let mut buf = vec![0; MAX_DATAGRAM_LENGTH];
+        let (mut bytes_up, mut bytes_down) = (0, 0);
+        // The server closes the stream once the flow has been idle for too long.
+        loop {
+            tokio::select! {
+                datagram = remote_conn.next() => match datagram {
+                    Some(datagram) => {
+                        let datagram = datagram?;
+                        down.consume(datagram.len()).await;
+                        bytes_down += datagram.len() as u64;
+                        socket.send(&datagram).await?;
+                    }
+                    None => break,
+                },
+                result = socket.recv(&mut buf) => {
+                    let len = result?;
+                    up.consume(len).await;
+                    bytes_up += len as u64;
+                    remote_conn.send(Bytes::copy_from_slice(&buf[..len])).await?;
+                }
+            }
+        }
+        connection_closed(port, peer, opened, bytes_up, bytes_down);
+        Ok((bytes_up, bytes_down))
+    }
+}
+
+/// Connect to the local target of a mapping and proxy a connection accepted from the
+/// server with it, sending a PROXY header with the peer's address if there is one.
+async fn forward_local(
+    mapping: &Mapping,
+    balancer: &Balancer,
+    remote_conn: Delimited<Box<dyn Transport>>,
+    encrypt: Option<(&DataKey, Uuid)>,
+    peer: Option<SocketAddr>,
+    connector: &Connector,
+    (up, down): &(Direction, Direction),
+) -> Result<(u64, u64)> {
+    match &mapping.local_socket {
+        Some(path) => {
+            let timeout = connector.local_connect_timeout();
+            let local_conn = retry_local(connector, || connect_unix(path, timeout)).await?;
+            splice(local_conn, remote_conn, mapping.compress, encrypt, up, down).await
+        }
+        None if !mapping.sni_routes.is_empty() => {
+            let (mut remote_conn, mut head) = unwrap_remote(remote_conn, mapping.compress, encrypt);
+            read_client_hello(&mut remote_conn, &mut head).await;
+            let name = tls_server_name(&head);
+            let route = name.and_then(|name| {
+                let mut routes = mapping.sni_routes.iter();
+                routes.find_map(|(route, port)| route.eq_ignore_ascii_case(name).then_some(*port))
+            });
+            let Some(port) = route.or(mapping.sni_default) else {
+                debug!(server_name = name, "no local port for server name, closing");
+                return Ok((0, 0));
+            };
+            let timeout = connector.local_connect_timeout();
+            let host = &mapping.local_host;
+            let source = &connector.local_source;
+            let mut local_conn = retry_local(connector, || {
+                connect_with_timeout(host, port, timeout, source)
+            })
+            .await?;
+            Span::current().record("backend", format!("{host}:{port}"));
+            connector.tcp.apply(&local_conn)?;
+            if let Some((proxy_protocol, peer)) = mapping.proxy_protocol.zip(peer) {
+                let header = proxy_protocol.header(peer, local_conn.peer_addr()?);
+                local_conn.write_all(&header).await?;
+            }
+            splice_head(local_conn, remote_conn, &head, up, down).await
+        }
+        None => {
+            let mut local_conn =
+                retry_local(connector, || balancer.connect(mapping, connector)).await?;
+            connector.tcp.apply(&local_conn)?;
+            if let Some((proxy_protocol, peer)) = mapping.proxy_protocol.zip(peer) {
+                let header = proxy_protocol.header(peer, local_conn.peer_addr()?);
+                local_conn.write_all(&header).await?;
+            }
+            splice(local_conn, remote_conn, mapping.compress, encrypt, up, down).await
+        }
+    }
+}
+
+/// Turns of the local backends of a mapping, which are tried in a round-robin order.
+#[derive(Debug, Default)]
+struct Balancer {
+    /// Number of connections so far, which picks the backend to try first.
+    next: AtomicUsize,
+
+    /// When each backend that refused a connection last did so, by its index.
+    failed: Mutex<HashMap<usize, Instant>>,
+}
+
+impl Balancer {
+    /// Connect to the first local backend of a mapping that accepts, starting from the
+    /// next one in turn, but leaving those that failed recently for last.
+    ///
+    /// The chosen backend is recorded in the `backend` field of the current span.
+    async fn connect(&self, mapping: &Mapping, connector: &Connector) -> Result<TcpStream> {
+        let backends: Vec<_> = std::iter::once((&mapping.local_host, mapping.local_port))
+            .chain(mapping.backends.iter().map(|(host, port)| (host, *port)))
+            .collect();
+        let timeout = connector.local_connect_timeout();
+        let mut last_err = None;
+        for index in self.order(backends.len()) {
+            let (host, port) = backends[index];
+            match connect_with_timeout(host, port, timeout, &connector.local_source).await {
+                Ok(conn) => {
+                    self.failed.lock().unwrap().remove(&index);
+                    Span::current().record("backend", format!("{host}:{port}"));
+                    return Ok(conn);
+                }
+                Err(err) => {
+                    if backends.len() > 1 {
+                        debug!(%err, host, port, "local backend failed, trying the next one");
+                    }
+                    self.failed.lock().unwrap().insert(index, Instant::now());
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("mappings have at least one backend"))
+    }
+
+    /// The order to try `count` backends in for a new connection.
+    fn order(&self, count: usize) -> Vec<usize> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let failed = self.failed.lock().unwrap();
+        let recent = |index: &usize| {
+            failed
+                .get(index)
+                .is_some_and(|at| at.elapsed() < BACKEND_COOLDOWN)
+        };
+        let (mut order, later): (Vec<_>, Vec<_>) = (0..count)
+            .map(|offset| (start + offset) % count)
+            .partition(|index| !recent(index));
+        order.extend(later);
+        order
+    }
+}
+
+/// Open a connection to a local target, retrying while it refuses connections, such as
+/// when the local service is still starting, for as long and as often as configured.
+async fn retry_local<T, F>(connector: &Connector, mut connect: impl FnMut() -> F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let backoff = &connector.local_backoff;
+    let deadline = connector.local_retry.map(|window| Instant::now() + window);
+    let retry = deadline.is_some() || backoff.max_attempts.is_some();
+    let mut attempt = 0;
+    loop {
+        let err = match connect().await {
+            Ok(conn) => return Ok(conn),
+            Err(err) => err,
+        };
+        let delay = backoff.delay(attempt);
+        let late = deadline.is_some_and(|deadline| Instant::now() + delay >= deadline);
+        let exhausted = backoff.max_attempts.is_some_and(|max| attempt >= max);
+        if !retry || late || exhausted || !local_unavailable(&err) {
+            return Err(err);
+        }
+        debug!(%err, ?delay, "local target unavailable, retrying");
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Whether connecting to a local target failed because nothing is listening on it yet.
+fn local_unavailable(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|err| {
+            matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound
+            )
+        })
+}
+
+/// Wait until the local target of a TCP mapping accepts connections, or each of the
+/// local ports that it routes to by server name.
+async fn wait_for_local(mapping: &Mapping, connector: &Connector) {
+    let timeout = connector.local_connect_timeout();
+    let ports: Vec<_> = match mapping.sni_routes.is_empty() {
+        true => vec![mapping.local_port],
+        false => (mapping.sni_routes.iter().map(|(_, port)| *port))
+            .chain(mapping.sni_default)
+            .collect(),
+    };
+    for port in ports {
+        let mut attempt = 0;
+        loop {
+            let result = match &mapping.local_socket {
+                Some(path) => connect_unix(path, timeout).await.map(drop),
+                None => {
+                    let host = &mapping.local_host;
+                    let source = &connector.local_source;
+                    connect_with_timeout(host, port, timeout, source)
+                        .await
+                        .map(drop)
+                }
+            };
+            match result {
+                Ok(()) => break,
+                Err(err) if attempt == 0 => info!(%err, "waiting for local target"),
+                Err(err) => debug!(%err, "local target is not up yet"),
+            }
+            sleep(connector.local_backoff.delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Log the end of a proxied connection, with its duration and the bytes sent in each
+/// direction.
+fn connection_closed(port: u16, peer: SocketAddr, opened: Instant, bytes_up: u64, bytes_down: u64) {
+    info!(
+        event = "connection_closed",
+        tunnel_port = port,
+        peer_addr = %peer,
+        duration_ms = opened.elapsed().as_millis() as u64,
+        bytes = bytes_up + bytes_down,
+        bytes_up,
+        bytes_down,
+        "connection exited",
+    );
+}
+
+/// A change in the state of a forwarded mapping or one of its connections, reported to
+/// [`ClientBuilder::on_event`] and [`Client::events`].
+#[derive(Clone, Debug)]
+pub enum ClientEvent {
+    /// The client started forwarding a mapping on the port assigned by the server, after
+    /// connecting or reconnecting to `to`.
+    Connected {
+        /// Address of the server.
+        to: String,
+        /// The mapping, with its assigned remote port.
+        mapping: Mapping,
+    },
+
+    /// The control connection closed, so the mapping no longer receives connections.
+    Disconnected {
+        /// Address of the server.
+        to: String,
+        /// The mapping, with the remote port it was assigned.
+        mapping: Mapping,
+        /// The error that the connection was lost to, unless the server closed it or
+        /// the client was shut down.
+        reason: Option<String>,
+    },
+
+    /// The server forwarded a new connection, or UDP flow, to one of the mappings.
+    ConnectionOpened {
+        /// ID of the connection, which its [`ClientEvent::ConnectionClosed`] shares.
+        id: Uuid,
+        /// Address of the remote peer, unless it connected on a Unix socket.
+        peer: Option<SocketAddr>,
+    },
+
+    /// A forwarded connection closed.
+    ConnectionClosed {
+        /// ID of the connection.
+        id: Uuid,
+        /// Bytes received from the remote peer, or 0 if the connection failed.
+        bytes_in: u64,
+        /// Bytes sent back to the remote peer, or 0 if the connection failed.
+        bytes_out: u64,
+    },
+
+    /// The client is about to try reconnecting to the server after losing the control
+    /// connection, or after a failed attempt.
+    Reconnecting {
+        /// Address of the server.
+        to: String,
+        /// Number of the attempt, starting from 1 after each lost connection.
+        attempt: u32,
+        /// Time waited before the attempt.
+        delay: Duration,
+    },
+
+    /// The server sent the statistics of one of the mappings' tunnels, on the interval
+    /// that the mapping asked for.
+    Stats(TunnelStats),
+}
+
+/// Report an event to a callback, if any, and to event streams.
+fn report_event(
+    on_event: &Option<EventCallback>,
+    events: &Events<ClientEvent>,
+    event: ClientEvent,
+) {
+    if let Some(on_event) = on_event {
+        (on_event.lock().unwrap())(event.clone());
+    }
+    events.send(event);
+}
+
+/// Builder for a [`Client`], for settings that must be in place before it connects.
+///
+/// A single local port can be forwarded with [`ClientBuilder::local_port`] or
+/// [`ClientBuilder::local_target`], and more with [`ClientBuilder::mapping`].
+pub struct ClientBuilder {
+    local_host: String,
+    local_port: Option<u16>,
+    local_socket: Option<PathBuf>,
+    remote_port: u16,
+    mappings: Vec<Mapping>,
+    to: String,
+    auth: Option<Credentials>,
+    connector: Connector,
+    strict: bool,
+    on_event: Option<EventCallback>,
+    reconnect: Option<Backoff>,
+    strict_port: bool,
+    wait_local: bool,
+    shutdown: Option<CancellationToken>,
+    drain_timeout: Duration,
+    heartbeat_interval: Duration,
+    heartbeat_timeout: Duration,
+    ping: Option<(Duration, Duration)>,
+}
+
+impl ClientBuilder {
+    /// Start building a client for the server at `to`, with no mappings yet.
+    pub fn new(to: &str) -> Self {
+        ClientBuilder {
+            local_host: "localhost".into(),
+            local_port: None,
+            local_socket: None,
+            remote_port: 0,
+            mappings: Vec::new(),
+            to: to.to_string(),
+            auth: None,
+            connector: Connector::default(),
+            strict: true,
+            on_event: None,
+            reconnect: None,
+            strict_port: false,
+            wait_local: false,
+            shutdown: None,
+            drain_timeout: DRAIN_TIMEOUT,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            heartbeat_timeout: HEARTBEAT_TIMEOUT,
+            ping: None,
+        }
+    }
+
+    /// Set the host of the local port, which is `localhost` by default.
+    pub fn local_host(mut self, local_host: &str) -> Self {
+        self.local_host = local_host.to_string();
+        self
+    }
+
+    /// Forward a local port, on the local host.
+    pub fn local_port(mut self, local_port: u16) -> Self {
+        self.local_port = Some(local_port);
+        self
+    }
+
+    /// Forward a local target, which may be a Unix socket instead of a local port.
+    ///
+    /// A Unix socket takes the place of any local port that is set.
+    pub fn local_target(mut self, target: LocalTarget) -> Self {
+        match target {
+            LocalTarget::Tcp(host, port) => {
+                self.local_host = host;
+                self.local_port = Some(port);
+            }
+            LocalTarget::Unix(path) => self.local_socket = Some(path),
+        }
+        self
+    }
+
+    /// Request a port on the remote for the local port, or 0 to let the server choose
+    /// one, which is the default.
+    pub fn remote_port(mut self, remote_port: u16) -> Self {
+        self.remote_port = remote_port;
+        self
+    }
+
+    /// Add a local port to forward, along with any added before.
+    pub fn mapping(mut self, mapping: Mapping) -> Self {
+        self.mappings.push(mapping);
+        self
+    }
+
+    /// Authenticate with the server using a secret.
+    pub fn secret(mut self, secret: &str) -> Self {
+        self.auth = Some(Credentials::Secret(HmacAuthenticator::new(secret)));
+        self
+    }
+
+    /// Authenticate with the server using a signed token, for servers that check
+    /// tokens instead of secrets.
+    pub fn token(mut self, token: &str) -> Self {
+        self.auth = Some(Credentials::Token(token.to_string()));
+        self
+    }
+
+    /// Encrypt all connections to the server with TLS.
+    pub fn tls(mut self, tls: ClientTls) -> Self {
+        self.connector.tls = Some(tls);
+        self
+    }
+
+    /// Open all connections to the server through a proxy.
+    ///
+    /// Other settings like TLS and the transport apply inside of the proxied connection.
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.connector.proxy = Some(proxy);
+        self
+    }
+
+    /// Carry connections to the server over a different transport than plain TCP.
+    ///
+    /// Connections are encrypted with TLS inside of the transport, if enabled.
+    pub fn transport(mut self, transport: TransportKind) -> Self {
+        self.connector.transport = transport;
+        self
+    }
+
+    /// Set how long to wait for connections and handshakes with the server and local
+    /// targets to complete, which is 3 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.connector.timeout = timeout;
+        self
+    }
+
+    /// Set how long to wait for TCP connections to the server and local targets to
+    /// open, separately from handshakes. This is the same as the timeout by default.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connector.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Set how long to wait for connections to local targets to open, overriding
+    /// [`ClientBuilder::connect_timeout`] for them.
+    pub fn local_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connector.local_connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Set how long to wait for each connection back to the server that accepts a
+    /// forwarded connection, from opening it to sending the acceptance, which is 10
+    /// seconds by default. A connection that takes longer is dropped with an error
+    /// naming its ID.
+    pub fn data_channel_timeout(mut self, timeout: Duration) -> Self {
+        self.connector.data_channel_timeout = timeout;
+        self
+    }
+
+    /// Set socket options, such as TCP keepalives, for connections to the server and
+    /// to local targets.
+    pub fn tcp_options(mut self, options: TcpOptions) -> Self {
+        self.connector.tcp = options;
+        self
+    }
+
+    /// Open connections to the server, and to the proxy if there is one, from a local
+    /// address, such as to send them over one of several uplinks.
+    pub fn source_addr(mut self, addr: IpAddr) -> Self {
+        self.connector.source.addr = Some(addr);
+        self
+    }
+
+    /// Send connections to the server, and to the proxy if there is one, over a
+    /// network interface, such as `eth1`. This is only supported on Linux.
+    pub fn bind_interface(mut self, interface: &str) -> Self {
+        self.connector.source.interface = Some(interface.into());
+        self
+    }
+
+    /// Open connections to local targets from a local address.
+    pub fn local_bind_addr(mut self, addr: IpAddr) -> Self {
+        self.connector.local_source.addr = Some(addr);
+        self
+    }
+
+    /// Keep retrying local targets that refuse connections for up to this long, with a
+    /// short backoff, instead of failing the proxied connection right away.
+    pub fn local_retry(mut self, window: Duration) -> Self {
+        self.connector.local_retry = Some(window);
+        self
+    }
+
+    /// Retry local targets that refuse connections at most this many times for each
+    /// proxied connection, within the [`ClientBuilder::local_retry`] window if set.
+    pub fn local_retry_attempts(mut self, attempts: u32) -> Self {
+        self.connector.local_backoff.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Set the delay before the first retry of a local target, which doubles with each
+    /// retry up to a second, or the delay itself if longer. This is 50ms by default.
+    pub fn local_retry_delay(mut self, delay: Duration) -> Self {
+        self.connector.local_backoff.initial_delay = delay;
+        self.connector.local_backoff.max_delay = delay.max(LOCAL_BACKOFF.max_delay);
+        self
+    }
+
+    /// Set whether to wait for the local targets of TCP mappings to accept connections
+    /// before connecting to the server, so that no remote port is opened before the
+    /// service is up. This is off by default.
+    pub fn wait_local(mut self, wait_local: bool) -> Self {
+        self.wait_local = wait_local;
+        self
+    }
+
+    /// Set whether a failure to allocate any mapping is an error, which is the default.
+    ///
+    /// See [`Client::with_mappings`].
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Call a function whenever a mapping starts or stops forwarding, and whenever one
+    /// of its connections opens or closes.
+    ///
+    /// The callback runs on the tasks of the client's connections, so it should return
+    /// quickly. See [`Client::events`] for a stream of the same events.
+    pub fn on_event(mut self, on_event: impl FnMut(ClientEvent) + Send + 'static) -> Self {
+        self.on_event = Some(Arc::new(Mutex::new(on_event)));
+        self
+    }
+
+    /// Reconnect with exponential backoff whenever the control connection is lost.
+    ///
+    /// See [`Client::listen_with_retry`].
+    pub fn reconnect(mut self, backoff: Backoff) -> Self {
+        self.reconnect = Some(backoff);
+        self
+    }
+
+    /// Set whether reconnecting fails if a remote port was taken in the meantime,
+    /// instead of asking for a new one, which is the default.
+    ///
+    /// This only has an effect together with [`ClientBuilder::reconnect`].
+    pub fn strict_port(mut self, strict_port: bool) -> Self {
+        self.strict_port = strict_port;
+        self
+    }
+
+    /// Shut down the client when a token is cancelled, like [`Client::set_shutdown`].
+    pub fn shutdown(mut self, token: CancellationToken) -> Self {
+        self.shutdown = Some(token);
+        self
+    }
+
+    /// Set how long to wait for proxied connections to finish when shutting down, like
+    /// [`Client::set_drain_timeout`].
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Set the interval between heartbeats sent to the server, like
+    /// [`Client::set_heartbeat_interval`].
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// Set how long the server may send nothing before the control connection is
+    /// considered lost, like [`Client::set_heartbeat_timeout`].
+    pub fn heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.heartbeat_timeout = timeout;
+        self
+    }
+
+    /// Ping the server on an interval and treat the control connection as lost if a
+    /// ping is not answered within `timeout`, like [`Client::set_ping_interval`].
+    pub fn ping_interval(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.ping = Some((interval, timeout));
+        self
+    }
+
+    /// Connect to the server and perform the handshake.
+    pub async fn build(self) -> Result<Client> {
+        let mut mappings = self.mappings;
+        let target = match (self.local_socket, self.local_port) {
+            (Some(path), _) => Some(LocalTarget::Unix(path)),
+            (None, Some(port)) => Some(LocalTarget::Tcp(self.local_host, port)),
+            (None, None) => None,
+        };
+        if let Some(target) = target {
+            mappings.insert(0, Mapping::with_target(target, self.remote_port));
+        }
+        if self.wait_local {
+            for mapping in mappings.iter().filter(|m| m.protocol == Protocol::Tcp) {
+                wait_for_local(mapping, &self.connector).await;
+            }
+        }
+        let mut client =
+            Client::with_connector(mappings, &self.to, self.auth, self.connector, self.strict)
+                .await?;
+        client.on_event = self.on_event;
+        client.reconnect = self.reconnect.map(|backoff| (backoff, self.strict_port));
+        if let Some(token) = self.shutdown {
+            client.set_shutdown(token);
+        }
+        client.set_drain_timeout(self.drain_timeout);
+        client.set_heartbeat_interval(self.heartbeat_interval);
+        client.set_heartbeat_timeout(self.heartbeat_timeout);
+        if let Some((interval, timeout)) = self.ping {
+            client.set_ping_interval(interval, timeout);
+        }
+        Ok(client)
+    }
+
+    /// Connect to the server and time `count` pings on the control connection, without
+    /// opening a tunnel, returning the round-trip time of each.
+    ///
+    /// A server that cannot be reached or that refuses the client's credentials fails
+    /// with an error whose context is [`ClientError::Unreachable`] or
+    /// [`ClientError::AuthFailed`], found with [`anyhow::Error::downcast_ref`].
+    pub async fn ping(self, count: u32) -> Result<Vec<Duration>> {
+        let mut stream = self
+            .connector
+            .connect(&self.to)
+            .await
+            .context(ClientError::Unreachable)?;
         if let Some(auth) = &self.auth {
-            auth.client_handshake(&mut remote_conn).await?;
+            auth.client_handshake(&mut stream)
+                .await
+                .context(ClientError::AuthFailed)?;
         }
-        remote_conn.send(ClientMessage::Accept(id)).await?;
-        let mut local_conn = connect_with_timeout(&self.local_host, self.local_port).await?;
-        let parts = remote_conn.into_parts();
-        debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
-        local_conn.write_all(&parts.read_buf).await?; // mostly of the cases, this will be empty
-        proxy(local_conn, parts.io).await?;
-        Ok(())
+        let mut rtts = Vec::new();
+        for seq in 0..count {
+            let start = Instant::now();
+            stream.send(ClientMessage::Ping(seq)).await?;
+            match stream.recv_timeout().await? {
+                Some(ServerMessage::Pong(n)) if n == seq => rtts.push(start.elapsed()),
+                // The server only refuses a ping if it refused the client first.
+                Some(ServerMessage::Error(message)) => {
+                    let err = anyhow!("server error: {message}");
+                    return Err(err.context(ClientError::AuthFailed));
+                }
+                Some(ServerMessage::Challenge(_)) => {
+                    let err = anyhow!(
+                        "server requires authentication, but no client secret or token was provided"
+                    );
+                    return Err(err.context(ClientError::AuthFailed));
+                }
+                Some(_) => bail!("unexpected reply to ping"),
+                None => bail!("server closed the connection, it may not support pings"),
+            }
+        }
+        Ok(rtts)
+    }
+
+    /// Connect to the server and list the tunnels that are open on it, without opening
+    /// one, ignoring any mappings.
+    pub async fn status(self) -> Result<Vec<TunnelStatus>> {
+        let mut stream = self.connector.connect(&self.to).await?;
+        if let Some(auth) = &self.auth {
+            auth.client_handshake(&mut stream).await?;
+        }
+        stream.send(ClientMessage::Status).await?;
+        let mut tunnels = Vec::new();
+        loop {
+            match stream.recv_timeout().await? {
+                Some(ServerMessage::Status(batch)) if batch.is_empty() => return Ok(tunnels),
+                Some(ServerMessage::Status(batch)) => tunnels.extend(batch),
+                Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+                Some(ServerMessage::Challenge(_)) => {
+                    bail!("server requires authentication, but no client secret or token was provided");
+                }
+                Some(_) => bail!("unexpected reply to status request"),
+                None => bail!("server closed the connection, it may not support status requests"),
+            }
+        }
+    }
+}
+
+/// Time to wait for the ClientHello of a connection routed by server name, before
+/// sending it to the default local port instead.
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time for which a local backend that refused a connection is tried after the others.
+const BACKEND_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// Backoff between attempts to connect to a local target that is not up yet.
+const LOCAL_BACKOFF: Backoff = Backoff {
+    initial_delay: Duration::from_millis(50),
+    max_delay: Duration::from_secs(1),
+    max_attempts: None,
+};
+
+/// Parameters for exponential backoff between reconnection attempts.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    /// Delay before the first reconnection attempt.
+    pub initial_delay: Duration,
+
+    /// Upper bound on the delay between attempts.
+    pub max_delay: Duration,
+
+    /// Number of consecutive failed attempts after which to give up, if any.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before a given attempt, doubling each time with up to 50% random jitter.
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        delay.mul_f64(0.5 + fastrand::f64() / 2.0)
+    }
+}
+
+impl Connector {
+    /// Time to wait for TCP connections to open.
+    fn connect_timeout(&self) -> Duration {
+        self.connect_timeout.unwrap_or(self.timeout)
+    }
+
+    /// Time to wait for TCP connections to local targets to open.
+    fn local_connect_timeout(&self) -> Duration {
+        self.local_connect_timeout
+            .unwrap_or_else(|| self.connect_timeout())
+    }
+
+    /// Open a connection to the control port of the server, with TLS if configured,
+    /// ready for its first message.
+    async fn connect(&self, to: &str) -> Result<Delimited<Box<dyn Transport>>> {
+        let port = match self.transport {
+            TransportKind::Tcp => CONTROL_PORT,
+            TransportKind::WebSocket(port) => port,
+        };
+        let stream = match &self.proxy {
+            Some(proxy) => timeout(
+                self.connect_timeout(),
+                proxy.connect_from(&self.source, to, port),
+            )
+            .await
+            .with_context(|| format!("timed out connecting through proxy {proxy}"))??,
+            None => connect_with_timeout(to, port, self.connect_timeout(), &self.source).await?,
+        };
+        self.tcp.apply(&stream)?;
+        let stream: Box<dyn Transport> = match &self.tls {
+            Some(tls) => Box::new(
+                timeout(self.timeout, tls.connect(to, stream))
+                    .await
+                    .context("timed out waiting for TLS handshake")?
+                    .with_context(|| {
+                        format!("TLS handshake with {to} failed, is TLS enabled on the server?")
+                    })?,
+            ),
+            None => Box::new(stream),
+        };
+        let stream: Box<dyn Transport> = match self.transport {
+            TransportKind::Tcp => stream,
+            TransportKind::WebSocket(_) => {
+                let scheme = if self.tls.is_some() { "wss" } else { "ws" };
+                let url = format!("{scheme}://{to}:{port}/");
+                let (stream, _) =
+                    timeout(self.timeout, tokio_tungstenite::client_async(url, stream))
+                        .await
+                        .context("timed out waiting for WebSocket handshake")?
+                        .with_context(|| format!("WebSocket handshake with {to}:{port} failed"))?;
+                Box::new(WebSocket::new(stream))
+            }
+        };
+        let mut stream = Delimited::new(stream);
+        stream.set_timeout(self.timeout);
+        Ok(stream)
     }
 }
 
-async fn connect_with_timeout(to: &str, port: u16) -> Result<TcpStream> {
-    match timeout(NETWORK_TIMEOUT, TcpStream::connect((to, port))).await {
-        Ok(res) => res,
-        Err(err) => Err(err.into()),
+/// Proxy a remote connection to a local one, returning the number of bytes sent each way.
+async fn splice<S: AsyncRead + AsyncWrite + Unpin>(
+    local_conn: S,
+    remote_conn: Delimited<Box<dyn Transport>>,
+    compress: bool,
+    encrypt: Option<(&DataKey, Uuid)>,
+    up: &Direction,
+    down: &Direction,
+) -> Result<(u64, u64)> {
+    let (remote_conn, head) = unwrap_remote(remote_conn, compress, encrypt);
+    splice_head(local_conn, remote_conn, &head, up, down).await
+}
+
+/// Take the data stream out of a remote connection, with its encryption and compression
+/// applied, along with any of its data that was already read.
+fn unwrap_remote(
+    remote_conn: Delimited<Box<dyn Transport>>,
+    compress: bool,
+    encrypt: Option<(&DataKey, Uuid)>,
+) -> (Box<dyn Transport>, Vec<u8>) {
+    let parts = remote_conn.into_parts();
+    debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
+    let buffered = parts.read_buf.to_vec();
+    match (encrypt, compress) {
+        // Data is compressed before it is encrypted, since sealed chunks look random.
+        (Some((key, id)), true) => {
+            let remote_conn = Encrypted::new(parts.io, buffered, key, id, Side::Client);
+            (
+                Box::new(Compressed::new(remote_conn, Vec::new())),
+                Vec::new(),
+            )
+        }
+        (Some((key, id)), false) => {
+            let remote_conn = Encrypted::new(parts.io, buffered, key, id, Side::Client);
+            (Box::new(remote_conn), Vec::new())
+        }
+        (None, true) => (Box::new(Compressed::new(parts.io, buffered)), Vec::new()),
+        (None, false) => (parts.io, buffered),
     }
-    .with_context(|| format!("could not connect to {to}:{port}"))
+}
+
+/// Proxy the data stream of a remote connection to a local one, after some of its data
+/// that was already read, returning the number of bytes sent each way.
+async fn splice_head<S: AsyncRead + AsyncWrite + Unpin>(
+    mut local_conn: S,
+    remote_conn: Box<dyn Transport>,
+    head: &[u8],
+    up: &Direction,
+    down: &Direction,
+) -> Result<(u64, u64)> {
+    down.consume(head.len()).await;
+    local_conn.write_all(head).await?; // mostly empty, unless read for a server name
+    let (bytes_up, bytes_down) = proxy_with(local_conn, remote_conn, up, down).await?;
+    Ok((bytes_up, head.len() as u64 + bytes_down))
+}
+
+/// Read the first TLS record of a connection after its head, which holds the
+/// ClientHello, stopping early if the data is not TLS, or if it does not arrive in time,
+/// such as for a protocol where the server speaks first.
+async fn read_client_hello(stream: &mut Box<dyn Transport>, head: &mut Vec<u8>) {
+    let deadline = Instant::now() + CLIENT_HELLO_TIMEOUT;
+    let mut buf = [0; 1024];
+    while !tls_record_complete(head) && head.len() < MAX_TLS_RECORD_LENGTH {
+        if head.first().is_some_and(|&b| b != 0x16) {
+            return;
+        }
+        match timeout_at(deadline, stream.read(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => head.extend_from_slice(&buf[..n]),
+            _ => return,
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &Path, duration: Duration) -> Result<tokio::net::UnixStream> {
+    timeout(duration, tokio::net::UnixStream::connect(path))
+        .await
+        .with_context(|| {
+            format!(
+                "timed out connecting to {} after {duration:?}",
+                path.display()
+            )
+        })?
+        .with_context(|| format!("could not connect to {}", path.display()))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(path: &Path, _: Duration) -> Result<TcpStream> {
+    bail!(
+        "could not connect to {}, Unix sockets are not supported on this platform",
+        path.display()
+    )
+}
+
+/// Open a TCP connection, telling apart a destination that does not answer in time
+/// from one that refuses the connection.
+async fn connect_with_timeout(
+    to: &str,
+    port: u16,
+    duration: Duration,
+    source: &SourceAddr,
+) -> Result<TcpStream> {
+    timeout(duration, source.connect(to, port))
+        .await
+        .with_context(|| format!("timed out connecting to {to}:{port} after {duration:?}"))?
+        .with_context(|| format!("could not connect to {to}:{port}"))
 }
@@ -0,0 +1,251 @@
+//! Encryption of the data of proxied connections between the client and the server,
+//! keyed from the client's secret.
+//!
+//! Each connection gets a key for each direction, derived with HKDF-SHA256 from the
+//! secret and the ID of the connection. The data is sent in chunks of at most
+//! [`MAX_CHUNK_LENGTH`] bytes, each sealed with ChaCha20-Poly1305 under a counter nonce
+//! and prefixed with its sealed length as a big-endian `u16`. An empty chunk marks the
+//! end of the data, so that a stream cut short is not mistaken for a complete one.
+//!
+//! This only protects the data on its way between the client and the server, which
+//! decrypts it to forward it to the public port.
+
+use std::fmt;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use sha2::{Digest, Sha256};
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use uuid::Uuid;
+
+/// Maximum byte length of the data in one sealed chunk.
+pub const MAX_CHUNK_LENGTH: usize = 16 * 1024;
+
+/// Key material that a client shares with the server through its secret, from which the
+/// keys of each of its connections are derived.
+#[derive(Clone)]
+pub struct DataKey([u8; 32]);
+
+impl DataKey {
+    /// Create the key material for a secret.
+    pub fn new(secret: &str) -> Self {
+        Self(Sha256::new().chain_update(secret).finalize().into())
+    }
+
+    /// Derive the key of one direction of a connection.
+    fn derive(&self, id: &Uuid, direction: &[u8]) -> LessSafeKey {
+        let prk = Salt::new(HKDF_SHA256, id.as_bytes()).extract(&self.0);
+        let info = [b"bore data ", direction];
+        let okm = prk
+            .expand(&info, &CHACHA20_POLY1305)
+            .expect("key length is valid for HKDF");
+        LessSafeKey::new(UnboundKey::from(okm))
+    }
+}
+
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DataKey(..)")
+    }
+}
+
+/// Which end of a connection a stream is encrypted for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    /// The client, which sends the data that the public port receives.
+    Client,
+
+    /// The server, which sends the data that the public port received.
+    Server,
+}
+
+/// A key with the counter of the next nonce to use with it.
+struct Cipher {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Cipher {
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+}
+
+/// A stream whose data is encrypted and authenticated in both directions.
+///
+/// Reading fails with [`io::ErrorKind::InvalidData`] if a chunk was tampered with, and
+/// with [`io::ErrorKind::UnexpectedEof`] if the stream ends before its end marker.
+pub struct Encrypted<S> {
+    stream: S,
+    sealer: Cipher,
+    opener: Cipher,
+
+    /// Sealed chunks that are still to be written, and how much of them was.
+    output: Vec<u8>,
+    written: usize,
+
+    /// Whether the end marker was sealed.
+    closed: bool,
+
+    /// Sealed data that was read but does not make up a whole chunk yet.
+    input: Vec<u8>,
+
+    /// Data of the last opened chunk, and how much of it was read.
+    plain: Vec<u8>,
+    consumed: usize,
+
+    /// Whether the end marker was received.
+    finished: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Encrypted<S> {
+    /// Wrap the stream of a connection, after some sealed data that was already read
+    /// from it.
+    pub fn new(stream: S, buffered: Vec<u8>, key: &DataKey, id: Uuid, side: Side) -> Self {
+        let client = key.derive(&id, b"client to server");
+        let server = key.derive(&id, b"server to client");
+        let (sealer, opener) = match side {
+            Side::Client => (client, server),
+            Side::Server => (server, client),
+        };
+        Self {
+            stream,
+            sealer: Cipher {
+                key: sealer,
+                counter: 0,
+            },
+            opener: Cipher {
+                key: opener,
+                counter: 0,
+            },
+            output: Vec::new(),
+            written: 0,
+            closed: false,
+            input: buffered,
+            plain: Vec::new(),
+            consumed: 0,
+            finished: false,
+        }
+    }
+
+    /// Seal a chunk of data, to be written by [`Encrypted::poll_drain`].
+    fn seal(&mut self, data: &[u8]) {
+        let mut chunk = data.to_vec();
+        let nonce = self.sealer.next_nonce();
+        self.sealer
+            .key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut chunk)
+            .expect("chunk is not too long to seal");
+        self.output
+            .extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+        self.output.extend_from_slice(&chunk);
+    }
+
+    /// Open the next chunk of the sealed data read so far, if all of it was read.
+    fn open(&mut self) -> io::Result<bool> {
+        let Some(header) = self.input.get(..2) else {
+            return Ok(false);
+        };
+        let len = u16::from_be_bytes([header[0], header[1]]) as usize;
+        if self.input.len() < 2 + len {
+            return Ok(false);
+        }
+        let mut chunk: Vec<u8> = self.input.drain(..2 + len).skip(2).collect();
+        let nonce = self.opener.next_nonce();
+        let data = self
+            .opener
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "corrupted encrypted chunk"))?;
+        let len = data.len();
+        chunk.truncate(len);
+        self.finished = chunk.is_empty();
+        self.plain = chunk;
+        self.consumed = 0;
+        Ok(true)
+    }
+
+    /// Write out the sealed chunks that are still pending.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.output.len() {
+            let output = &self.output[self.written..];
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, output))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.output.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Encrypted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.consumed < this.plain.len() {
+                let data = &this.plain[this.consumed..];
+                let n = data.len().min(buf.remaining());
+                buf.put_slice(&data[..n]);
+                this.consumed += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.finished {
+                return Poll::Ready(Ok(()));
+            }
+            if this.open()? {
+                continue;
+            }
+            let mut sealed = [0; 8192];
+            let mut sealed = ReadBuf::new(&mut sealed);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut sealed))?;
+            if sealed.filled().is_empty() {
+                let message = "encrypted stream ended before its end marker";
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, message)));
+            }
+            this.input.extend_from_slice(sealed.filled());
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Encrypted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_drain(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(MAX_CHUNK_LENGTH);
+        self.seal(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.closed {
+            ready!(self.poll_drain(cx))?;
+            self.seal(&[]);
+            self.closed = true;
+        }
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,556 @@
+//! Running the client in the background, with a pid file to find and stop it by, or as
+//! a Windows service.
+//!
+//! Detaching forks the process, so it must happen before the Tokio runtime or any other
+//! thread is started.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// Interval between checks of whether a stopped process has exited.
+#[cfg(unix)]
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Detach from the terminal with a double fork and `setsid`, so that the process keeps
+/// running after the terminal is closed, with its standard streams on `/dev/null`.
+///
+/// Only the detached process returns; the original one exits successfully.
+#[cfg(unix)]
+pub fn detach() -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("could not open /dev/null")?;
+    // SAFETY: this runs before any other thread is started, so the forked children
+    // don't inherit locks held by threads that no longer exist in them.
+    unsafe {
+        fork()?;
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error()).context("could not start a new session");
+        }
+        // Forking again keeps the daemon from ever acquiring a controlling terminal.
+        fork()?;
+        for fd in 0..3 {
+            if libc::dup2(null.as_raw_fd(), fd) == -1 {
+                return Err(io::Error::last_os_error()).context("could not redirect output");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fork the process, exiting in the parent.
+#[cfg(unix)]
+unsafe fn fork() -> Result<()> {
+    match libc::fork() {
+        -1 => Err(io::Error::last_os_error()).context("could not fork"),
+        0 => Ok(()),
+        _ => libc::_exit(0),
+    }
+}
+
+/// Running in the background is not supported on other platforms. On Windows, the
+/// client runs as a service instead, with `--install-service`.
+#[cfg(not(unix))]
+pub fn detach() -> Result<()> {
+    bail!("running in the background is only supported on Unix, use --install-service on Windows");
+}
+
+/// Whether a process with this ID is running.
+#[cfg(unix)]
+fn is_running(pid: i32) -> bool {
+    // SAFETY: signal 0 only checks that the process exists and may be signaled.
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_: i32) -> bool {
+    false
+}
+
+/// Read the process ID in a pid file, if it exists.
+///
+/// IDs of 1 or less are rejected, since signaling them would reach init, the whole
+/// process group, or every process that may be signaled.
+fn read_pid(path: &Path) -> Result<Option<i32>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(err).with_context(|| format!("could not read pid file {}", path.display()))
+        }
+    };
+    let pid = text
+        .trim()
+        .parse()
+        .ok()
+        .filter(|&pid: &i32| pid > 1)
+        .with_context(|| format!("invalid pid file {}", path.display()))?;
+    Ok(Some(pid))
+}
+
+/// A pid file holding the ID of this process, which is removed when it is dropped.
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Check that a pid file does not name another process that is still running. A
+    /// file left behind by a process that is gone is fine, and will be replaced, but one
+    /// that cannot be read or holds no valid pid is an error.
+    pub fn check(path: &Path) -> Result<()> {
+        if let Some(pid) = read_pid(path)? {
+            if is_running(pid) && pid != std::process::id() as i32 {
+                bail!("already running with pid {pid}, see {}", path.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the ID of this process to a pid file, after checking it like
+    /// [`PidFile::check`].
+    pub fn create(path: &Path) -> Result<Self> {
+        Self::check(path)?;
+        fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("could not write pid file {}", path.display()))?;
+        Ok(Self(path.into()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Ask the process in a pid file to shut down, and wait up to a timeout for it to exit.
+///
+/// A pid file whose process is gone is removed, and reported as an error.
+#[cfg(unix)]
+pub fn stop(path: &Path, timeout: Duration) -> Result<()> {
+    let pid = read_pid(path)?.with_context(|| format!("no pid file at {}", path.display()))?;
+    if !is_running(pid) {
+        _ = fs::remove_file(path);
+        bail!("no process with pid {pid} is running, removed stale pid file");
+    }
+    // SAFETY: sending a signal has no memory safety requirements.
+    if unsafe { libc::kill(pid, libc::SIGTERM) } == -1 {
+        return Err(io::Error::last_os_error()).with_context(|| format!("could not stop {pid}"));
+    }
+    let deadline = std::time::Instant::now() + timeout;
+    while is_running(pid) {
+        if std::time::Instant::now() >= deadline {
+            bail!("process {pid} did not exit within {timeout:?}");
+        }
+        std::thread::sleep(STOP_POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn stop(_: &Path, _: Duration) -> Result<()> {
+    bail!("stopping a background client is only supported on Unix");
+}
+
+/// Build the command line that a Windows service runs: this program with the same
+/// arguments, except for `--install-service`, and with `--service` to connect to the
+/// service control manager.
+///
+/// Arguments are quoted the way `CommandLineToArgvW` splits them.
+pub fn service_command(
+    exe: &Path,
+    args: impl IntoIterator<Item = OsString>,
+    name: &str,
+) -> Result<String> {
+    let mut line = format!("\"{}\"", exe.display());
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let arg = arg
+            .into_string()
+            .map_err(|arg| anyhow!("argument {arg:?} is not valid Unicode"))?;
+        if arg == "--install-service" {
+            args.next();
+            continue;
+        }
+        if arg.starts_with("--install-service=") {
+            continue;
+        }
+        line.push(' ');
+        quote_arg(&arg, &mut line);
+    }
+    line.push_str(" --service ");
+    quote_arg(name, &mut line);
+    Ok(line)
+}
+
+/// Append an argument to a Windows command line, quoting it if needed.
+fn quote_arg(arg: &str, line: &mut String) {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        line.push_str(arg);
+        return;
+    }
+    line.push('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            // Backslashes are only special before a quote, where they are escaped too.
+            '"' => {
+                line.push_str(&"\\".repeat(backslashes + 1));
+                backslashes = 0;
+            }
+            _ => backslashes = 0,
+        }
+        line.push(c);
+    }
+    line.push_str(&"\\".repeat(backslashes));
+    line.push('"');
+}
+
+#[cfg(all(windows, feature = "windows-service"))]
+pub use service::{install_service, run_service, service_stopped, uninstall_service};
+
+#[cfg(not(all(windows, feature = "windows-service")))]
+pub fn install_service(_: &str, _: &str) -> Result<()> {
+    bail!("Windows services are only supported on Windows, with the `windows-service` feature");
+}
+
+#[cfg(not(all(windows, feature = "windows-service")))]
+pub fn uninstall_service(_: &str) -> Result<()> {
+    bail!("Windows services are only supported on Windows, with the `windows-service` feature");
+}
+
+#[cfg(not(all(windows, feature = "windows-service")))]
+pub fn run_service(_: &str, _: impl FnOnce() -> Result<()> + Send + 'static) -> Result<()> {
+    bail!("Windows services are only supported on Windows, with the `windows-service` feature");
+}
+
+/// Without service support, the service control manager never asks to stop.
+#[cfg(not(any(unix, all(windows, feature = "windows-service"))))]
+pub async fn service_stopped() {
+    std::future::pending().await
+}
+
+/// Registering and running the client as a Windows service.
+#[cfg(all(windows, feature = "windows-service"))]
+mod service {
+    use std::ffi::c_void;
+    use std::io;
+    use std::ptr;
+    use std::sync::atomic::{AtomicIsize, Ordering};
+    use std::sync::{Mutex, PoisonError};
+
+    use anyhow::{Context, Result};
+    use tokio::sync::Notify;
+    use windows_sys::core::PWSTR;
+    use windows_sys::Win32::Foundation::{
+        ERROR_CALL_NOT_IMPLEMENTED, ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR,
+    };
+    use windows_sys::Win32::Security::SC_HANDLE;
+    use windows_sys::Win32::System::Services::{
+        CloseServiceHandle, ControlService, CreateServiceW, DeleteService, OpenSCManagerW,
+        OpenServiceW, RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+        SC_MANAGER_CONNECT, SC_MANAGER_CREATE_SERVICE, SERVICE_ACCEPT_SHUTDOWN,
+        SERVICE_ACCEPT_STOP, SERVICE_AUTO_START, SERVICE_CONTROL_INTERROGATE,
+        SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP, SERVICE_ERROR_NORMAL, SERVICE_QUERY_STATUS,
+        SERVICE_RUNNING, SERVICE_STATUS, SERVICE_STATUS_CURRENT_STATE, SERVICE_STOP,
+        SERVICE_STOPPED, SERVICE_STOP_PENDING, SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+    };
+
+    /// Standard access right to delete an object, such as a service.
+    const DELETE: u32 = 0x0001_0000;
+
+    /// How long the service control manager should expect stopping to take, which
+    /// covers draining connections for the default drain timeout.
+    const STOP_WAIT_HINT: u32 = 30_000;
+
+    /// What the service runs, until `service_main` takes it.
+    type Body = Box<dyn FnOnce() -> Result<()> + Send>;
+    static BODY: Mutex<Option<Body>> = Mutex::new(None);
+
+    /// The result of the body, for `run_service` to return once the dispatcher does.
+    static RESULT: Mutex<Option<Result<()>>> = Mutex::new(None);
+
+    /// Handle to report the status of the service with.
+    static STATUS: AtomicIsize = AtomicIsize::new(0);
+
+    /// Notified when the service control manager asks the service to stop.
+    static STOP: Notify = Notify::const_new();
+
+    /// A handle to the service control manager or a service, closed when dropped.
+    struct Handle(SC_HANDLE);
+
+    impl Handle {
+        fn new(handle: SC_HANDLE) -> io::Result<Self> {
+            match handle {
+                0 => Err(io::Error::last_os_error()),
+                handle => Ok(Self(handle)),
+            }
+        }
+    }
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            // SAFETY: the handle is open, and only closed here.
+            unsafe { CloseServiceHandle(self.0) };
+        }
+    }
+
+    /// Encode a string for Windows, with a terminating null.
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain([0]).collect()
+    }
+
+    fn open_manager(access: u32) -> Result<Handle> {
+        // SAFETY: null names select the local machine and its active database.
+        Handle::new(unsafe { OpenSCManagerW(ptr::null(), ptr::null(), access) })
+            .context("could not connect to the service control manager")
+    }
+
+    /// Register a service that starts with Windows and runs `command` as LocalSystem.
+    pub fn install_service(name: &str, command: &str) -> Result<()> {
+        let manager = open_manager(SC_MANAGER_CREATE_SERVICE)?;
+        let (name_w, command_w) = (wide(name), wide(command));
+        // SAFETY: the strings are null-terminated and outlive the call, and null
+        // pointers are allowed for the optional arguments.
+        let service = unsafe {
+            CreateServiceW(
+                manager.0,
+                name_w.as_ptr(),
+                name_w.as_ptr(),
+                SERVICE_QUERY_STATUS,
+                SERVICE_WIN32_OWN_PROCESS,
+                SERVICE_AUTO_START,
+                SERVICE_ERROR_NORMAL,
+                command_w.as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+            )
+        };
+        Handle::new(service).with_context(|| format!("could not create service {name}"))?;
+        Ok(())
+    }
+
+    /// Ask a service to stop, and remove it once it has.
+    pub fn uninstall_service(name: &str) -> Result<()> {
+        let manager = open_manager(SC_MANAGER_CONNECT)?;
+        let name_w = wide(name);
+        // SAFETY: the name is null-terminated and outlives the call.
+        let service = unsafe { OpenServiceW(manager.0, name_w.as_ptr(), SERVICE_STOP | DELETE) };
+        let service =
+            Handle::new(service).with_context(|| format!("could not open service {name}"))?;
+        // SAFETY: an all-zero status is valid, and it outlives the calls.
+        unsafe {
+            let mut status: SERVICE_STATUS = std::mem::zeroed();
+            // A service that is not running refuses to stop, which is fine here.
+            ControlService(service.0, SERVICE_CONTROL_STOP, &mut status);
+            if DeleteService(service.0) == 0 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| format!("could not delete service {name}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `body` as the service, once the service control manager starts it, and
+    /// return its result after reporting that the service has stopped.
+    ///
+    /// This fails unless the process was started by the service control manager.
+    pub fn run_service(
+        name: &str,
+        body: impl FnOnce() -> Result<()> + Send + 'static,
+    ) -> Result<()> {
+        *BODY.lock().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(body));
+        let mut name = wide(name);
+        let table = [
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: name.as_mut_ptr(),
+                lpServiceProc: Some(service_main),
+            },
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: ptr::null_mut(),
+                lpServiceProc: None,
+            },
+        ];
+        // SAFETY: the table ends with a null entry, and outlives the call, which
+        // returns once the service has stopped.
+        if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+            return Err(io::Error::last_os_error())
+                .context("could not connect to the service control manager");
+        }
+        let result = RESULT.lock().unwrap_or_else(PoisonError::into_inner).take();
+        result.unwrap_or(Ok(()))
+    }
+
+    /// Wait for the service control manager to ask the service to stop.
+    pub async fn service_stopped() {
+        STOP.notified().await;
+    }
+
+    /// Entry point of the service, called by the dispatcher on a thread of its own.
+    unsafe extern "system" fn service_main(argc: u32, argv: *mut PWSTR) {
+        let name = if argc > 0 { *argv } else { ptr::null_mut() };
+        let handle = RegisterServiceCtrlHandlerExW(name, Some(handler), ptr::null());
+        let result = match handle {
+            0 => Err(io::Error::last_os_error())
+                .context("could not register a service control handler"),
+            handle => {
+                STATUS.store(handle, Ordering::Relaxed);
+                set_status(SERVICE_RUNNING, false);
+                let body = BODY.lock().unwrap_or_else(PoisonError::into_inner).take();
+                body.map_or(Ok(()), |body| body())
+            }
+        };
+        let failed = result.is_err();
+        // Store the result first, since the process may exit once the service stops.
+        *RESULT.lock().unwrap_or_else(PoisonError::into_inner) = Some(result);
+        if handle != 0 {
+            set_status(SERVICE_STOPPED, failed);
+        }
+    }
+
+    /// Handle a request from the service control manager.
+    unsafe extern "system" fn handler(control: u32, _: u32, _: *mut c_void, _: *mut c_void) -> u32 {
+        match control {
+            SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+                set_status(SERVICE_STOP_PENDING, false);
+                // This stores a permit if the client is not waiting yet.
+                STOP.notify_one();
+                NO_ERROR
+            }
+            SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+            _ => ERROR_CALL_NOT_IMPLEMENTED,
+        }
+    }
+
+    /// Report the state of the service to the service control manager.
+    fn set_status(state: SERVICE_STATUS_CURRENT_STATE, failed: bool) {
+        let status = SERVICE_STATUS {
+            dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+            dwCurrentState: state,
+            dwControlsAccepted: match state {
+                SERVICE_RUNNING => SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN,
+                _ => 0,
+            },
+            dwWin32ExitCode: if failed {
+                ERROR_SERVICE_SPECIFIC_ERROR
+            } else {
+                NO_ERROR
+            },
+            dwServiceSpecificExitCode: failed.into(),
+            dwCheckPoint: 0,
+            dwWaitHint: match state {
+                SERVICE_STOP_PENDING => STOP_WAIT_HINT,
+                _ => 0,
+            },
+        };
+        // SAFETY: the handle came from `RegisterServiceCtrlHandlerExW`, and the status
+        // outlives the call.
+        unsafe { SetServiceStatus(STATUS.load(Ordering::Relaxed), &status) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn pid_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("bore-test-{}-{name}.pid", std::process::id()))
+    }
+
+    #[test]
+    fn read_pid_rejects_special_ids() -> Result<()> {
+        let path = pid_path("special");
+        for (text, pid) in [
+            ("1234\n", Some(1234)),
+            ("abc", None),
+            ("0", None),
+            ("1", None),
+            ("-1", None),
+        ] {
+            fs::write(&path, text)?;
+            match pid {
+                Some(pid) => assert_eq!(read_pid(&path)?, Some(pid)),
+                None => assert!(read_pid(&path).is_err(), "accepted {text:?}"),
+            }
+        }
+        assert!(PidFile::check(&path).is_err());
+        #[cfg(unix)]
+        assert!(stop(&path, Duration::from_secs(1)).is_err());
+        fs::remove_file(&path)?;
+        assert_eq!(read_pid(&path)?, None);
+        PidFile::check(&path)?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_live_and_stale_pids() -> Result<()> {
+        let path = pid_path("live");
+        let mut child = std::process::Command::new("sleep").arg("30").spawn()?;
+        fs::write(&path, format!("{}\n", child.id()))?;
+        let err = PidFile::check(&path).unwrap_err();
+        assert!(err.to_string().starts_with("already running with pid"));
+        child.kill()?;
+        child.wait()?;
+        // The process is gone, so its pid file is stale and may be replaced.
+        PidFile::check(&path)?;
+        let pid_file = PidFile::create(&path)?;
+        assert_eq!(read_pid(&path)?, Some(std::process::id() as i32));
+        drop(pid_file);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn service_command_line() -> Result<()> {
+        let args = [
+            "local",
+            "8000",
+            "--install-service",
+            "bore",
+            "--to",
+            "bore.pub",
+            "--install-service=other",
+            "--secret",
+            "two words",
+            "--log-file",
+            r"C:\logs dir\",
+            r#"a\"b"#,
+            "",
+        ];
+        let line = service_command(
+            Path::new(r"C:\Program Files\bore.exe"),
+            args.map(OsString::from),
+            "bore tunnel",
+        )?;
+        assert_eq!(
+            line,
+            r#""C:\Program Files\bore.exe" local 8000 --to bore.pub --secret "two words" --log-file "C:\logs dir\\" "a\\\"b" "" --service "bore tunnel""#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_unreadable_file() -> Result<()> {
+        let path = pid_path("unreadable");
+        fs::create_dir_all(&path)?;
+        let result = PidFile::check(&path);
+        fs::remove_dir(&path)?;
+        let err = result.unwrap_err();
+        assert!(err.to_string().starts_with("could not read pid file"));
+        Ok(())
+    }
+}
@@ -0,0 +1,306 @@
+//! Filtering of connections to forwarded ports by the country of their address.
+//!
+//! Countries are looked up in a database in the MaxMind DB format, such as GeoLite2
+//! Country. [`GeoIpDb::open`] reads the file into memory, while
+//! [`GeoIpDb::from_source`] takes contents held elsewhere, such as a memory map of the
+//! file, as `bore server` uses on Unix so that only the pages that lookups touch are
+//! loaded. The database is checked once when it is loaded, and lookups walk its search
+//! tree and read the country code of a record without allocating.
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::{fs, str};
+
+use anyhow::{bail, Context, Result};
+
+/// Marker that the metadata section at the end of a database follows.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// Maximum depth of nested maps and arrays that are skipped over in a record.
+const MAX_DEPTH: usize = 32;
+
+/// Types of the values in the data and metadata sections.
+const POINTER: u8 = 1;
+const STRING: u8 = 2;
+const UINT16: u8 = 5;
+const UINT32: u8 = 6;
+const MAP: u8 = 7;
+const UINT64: u8 = 9;
+const UINT128: u8 = 10;
+const ARRAY: u8 = 11;
+const BOOLEAN: u8 = 14;
+
+/// A database of the countries of IP addresses, in the MaxMind DB format.
+pub struct GeoIpDb {
+    data: Box<dyn AsRef<[u8]> + Send + Sync>,
+
+    /// Number of nodes in the search tree at the start of the file.
+    node_count: usize,
+
+    /// Size in bits of each of the two records of a node.
+    record_size: usize,
+
+    /// Offset of the data section, after the search tree and a 16-byte separator.
+    data_start: usize,
+
+    /// Node that IPv4 addresses are looked up from, which is below 96 zero bits in a
+    /// database of IPv6 addresses.
+    ipv4_start: usize,
+
+    /// Version of the addresses in the search tree, 4 or 6.
+    ip_version: u64,
+}
+
+impl GeoIpDb {
+    /// Read a database from a file, such as `GeoLite2-Country.mmdb`.
+    pub fn open(path: &Path) -> Result<Self> {
+        let data = fs::read(path)
+            .with_context(|| format!("could not read GeoIP database {}", path.display()))?;
+        Self::from_bytes(data).with_context(|| format!("invalid GeoIP database {}", path.display()))
+    }
+
+    /// Load a database from its contents, checking that its search tree and data fit.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self> {
+        Self::from_source(data)
+    }
+
+    /// Load a database from contents that are held elsewhere, such as a memory map of
+    /// its file, checking them like [`GeoIpDb::from_bytes`].
+    pub fn from_source(source: impl AsRef<[u8]> + Send + Sync + 'static) -> Result<Self> {
+        let data = source.as_ref();
+        let marker = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .context("no metadata section")?;
+        let metadata = Section(&data[marker + METADATA_MARKER.len()..]);
+        let field = |key| {
+            let offset = metadata.get(0, key)?;
+            metadata.uint(offset)
+        };
+        let node_count = field("node_count").context("no node count in metadata")? as usize;
+        let record_size = field("record_size").context("no record size in metadata")? as usize;
+        let ip_version = field("ip_version").context("no IP version in metadata")?;
+        if ![24, 28, 32].contains(&record_size) {
+            bail!("unsupported record size {record_size}");
+        }
+        if ![4, 6].contains(&ip_version) {
+            bail!("unsupported IP version {ip_version}");
+        }
+        let data_start = node_count
+            .checked_mul(record_size / 4)
+            .and_then(|tree_size| tree_size.checked_add(16))
+            .filter(|&data_start| data_start <= marker)
+            .context("search tree is larger than the file")?;
+        let mut db = Self {
+            data: Box::new(source),
+            node_count,
+            record_size,
+            data_start,
+            ipv4_start: 0,
+            ip_version,
+        };
+        if ip_version == 6 {
+            for _ in 0..96 {
+                if db.ipv4_start >= node_count {
+                    break;
+                }
+                db.ipv4_start = db.record(db.ipv4_start, false).context("truncated tree")?;
+            }
+        }
+        Ok(db)
+    }
+
+    /// The ISO code of the country of an address, such as `US`, or of the country it is
+    /// registered in if the database has no other, or `None` if it is not in the
+    /// database, as with private addresses.
+    pub fn country(&self, ip: IpAddr) -> Option<&str> {
+        let record = self.lookup(ip)?;
+        let data = Section(&self.bytes()[self.data_start..]);
+        ["country", "registered_country"].iter().find_map(|key| {
+            let country = data.get(record, key)?;
+            data.string(data.get(country, "iso_code")?)
+        })
+    }
+
+    /// Find the offset of the record of an address in the data section.
+    fn lookup(&self, ip: IpAddr) -> Option<usize> {
+        let (addr, bits, mut node) = match ip.to_canonical() {
+            IpAddr::V4(ip) => (u32::from(ip).into(), 32, self.ipv4_start),
+            IpAddr::V6(ip) if self.ip_version == 6 => (u128::from(ip), 128, 0),
+            IpAddr::V6(_) => return None,
+        };
+        for bit in (0..bits).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, addr >> bit & 1 == 1)?;
+        }
+        // The node count itself marks an address without a record.
+        let offset = node.checked_sub(self.node_count + 16)?;
+        (self.data_start + offset < self.bytes().len()).then_some(offset)
+    }
+
+    fn bytes(&self) -> &[u8] {
+        (*self.data).as_ref()
+    }
+
+    /// Read the left or right record of a node of the search tree.
+    fn record(&self, node: usize, right: bool) -> Option<usize> {
+        let len = self.record_size / 4;
+        let bytes = self.bytes().get(node * len..(node + 1) * len)?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |n, &b| n << 8 | b as usize);
+        Some(match (self.record_size, right) {
+            (24, false) => be(&bytes[..3]),
+            (24, true) => be(&bytes[3..]),
+            // The middle byte holds the high bits of both records.
+            (28, false) => (bytes[3] as usize >> 4) << 24 | be(&bytes[..3]),
+            (28, true) => (bytes[3] as usize & 0x0f) << 24 | be(&bytes[4..]),
+            (_, false) => be(&bytes[..4]),
+            (_, true) => be(&bytes[4..]),
+        })
+    }
+}
+
+/// A check of addresses against lists of allowed and denied countries.
+///
+/// Denied countries take precedence. If there are no allowed countries, any country
+/// that is not denied is allowed. Addresses without a country, such as private ones,
+/// are allowed unless the filter is set to deny them.
+pub struct GeoIpFilter {
+    db: GeoIpDb,
+    allow: Vec<String>,
+    deny: Vec<String>,
+    deny_unknown: bool,
+}
+
+impl GeoIpFilter {
+    /// Create a new filter from lists of allowed and denied ISO country codes, which are
+    /// compared without regard to case.
+    pub fn new(db: GeoIpDb, allow: Vec<String>, deny: Vec<String>) -> Self {
+        let upper = |codes: Vec<String>| codes.iter().map(|c| c.to_ascii_uppercase()).collect();
+        Self {
+            db,
+            allow: upper(allow),
+            deny: upper(deny),
+            deny_unknown: false,
+        }
+    }
+
+    /// Set whether addresses without a country in the database are denied, instead of
+    /// allowed as by default.
+    pub fn set_deny_unknown(&mut self, deny_unknown: bool) {
+        self.deny_unknown = deny_unknown;
+    }
+
+    /// Check whether an address may connect.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let Some(country) = self.db.country(ip) else {
+            return !self.deny_unknown;
+        };
+        let listed = |codes: &[String]| codes.iter().any(|code| code == country);
+        !listed(&self.deny) && (self.allow.is_empty() || listed(&self.allow))
+    }
+}
+
+/// A section of a database made of values, which pointers give offsets from its start.
+#[derive(Clone, Copy)]
+struct Section<'a>(&'a [u8]);
+
+impl<'a> Section<'a> {
+    /// Read the control bytes of the value at an offset, returning its type, its size
+    /// and the offset of its payload. For a pointer, the size is the offset it points
+    /// to, and the payload is the next value.
+    fn control(&self, offset: usize) -> Option<(u8, usize, usize)> {
+        let be = |offset: usize, len: usize| {
+            let bytes = self.0.get(offset..offset + len)?;
+            Some(bytes.iter().fold(0, |n, &b| n << 8 | b as usize))
+        };
+        let control = *self.0.get(offset)?;
+        let mut offset = offset + 1;
+        let mut kind = control >> 5;
+        if kind == POINTER {
+            // The size bits give the length of the offset, and some of its high bits.
+            let len = (control >> 3 & 3) as usize + 1;
+            let low = be(offset, len)?;
+            let high = (control & 7) as usize;
+            let target = match len {
+                1 => high << 8 | low,
+                2 => (high << 16 | low) + 2048,
+                3 => (high << 24 | low) + 526336,
+                _ => low,
+            };
+            return Some((POINTER, target, offset + len));
+        }
+        if kind == 0 {
+            kind = self.0.get(offset)?.checked_add(7)?;
+            offset += 1;
+        }
+        let size = match control & 0x1f {
+            29 => 29 + be(offset, 1)?,
+            30 => 285 + be(offset, 2)?,
+            31 => 65821 + be(offset, 3)?,
+            size => return Some((kind, size as usize, offset)),
+        };
+        let extra = (control & 0x1f) as usize - 28;
+        Some((kind, size, offset + extra))
+    }
+
+    /// Read the control bytes of the value at an offset, after following the pointer
+    /// there, if it is one.
+    fn resolve(&self, offset: usize) -> Option<(u8, usize, usize)> {
+        match self.control(offset)? {
+            (POINTER, target, _) => self.control(target).filter(|(kind, ..)| *kind != POINTER),
+            value => Some(value),
+        }
+    }
+
+    /// The offset of the value after the one at an offset.
+    fn skip(&self, offset: usize, depth: usize) -> Option<usize> {
+        let (kind, size, payload) = self.control(offset)?;
+        match kind {
+            POINTER | BOOLEAN => Some(payload),
+            MAP | ARRAY => {
+                let count = if kind == MAP { size * 2 } else { size };
+                let depth = depth.checked_sub(1)?;
+                (0..count).try_fold(payload, |offset, _| self.skip(offset, depth))
+            }
+            _ => Some(payload + size),
+        }
+    }
+
+    /// The offset of the value of a key in the map at an offset.
+    fn get(&self, offset: usize, key: &str) -> Option<usize> {
+        let (kind, size, mut offset) = self.resolve(offset)?;
+        if kind != MAP {
+            return None;
+        }
+        for _ in 0..size {
+            let name = self.string(offset)?;
+            let value = self.skip(offset, MAX_DEPTH)?;
+            if name == key {
+                return Some(value);
+            }
+            offset = self.skip(value, MAX_DEPTH)?;
+        }
+        None
+    }
+
+    /// The string at an offset.
+    fn string(&self, offset: usize) -> Option<&'a str> {
+        match self.resolve(offset)? {
+            (STRING, size, offset) => str::from_utf8(self.0.get(offset..offset + size)?).ok(),
+            _ => None,
+        }
+    }
+
+    /// The unsigned integer at an offset, if it fits in 64 bits.
+    fn uint(&self, offset: usize) -> Option<u64> {
+        match self.resolve(offset)? {
+            (UINT16 | UINT32 | UINT64 | UINT128, size, offset) if size <= 8 => {
+                let bytes = self.0.get(offset..offset + size)?;
+                Some(bytes.iter().fold(0, |n, &b| n << 8 | b as u64))
+            }
+            _ => None,
+        }
+    }
+}
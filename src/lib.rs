@@ -10,12 +10,25 @@
 //!
 //! There are two components to the crate, offering implementations of the
 //! server network daemon and client local forwarding proxy. Both are public
-//! members and can be run programmatically with a Tokio 1.0 runtime.
+//! members and can be run programmatically with a Tokio 1.0 runtime, configured with
+//! [`client::ClientBuilder`] and [`server::ServerBuilder`] and observed through the
+//! streams of [`client::Client::events`] and [`server::Server::events`]. The messages
+//! that they exchange are in [`protocol`].
 
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+pub mod access_log;
+pub mod admin;
 pub mod auth;
 pub mod client;
+pub mod crypto;
+pub mod geoip;
+pub mod metrics;
+pub mod protocol;
+pub mod proxy;
+pub mod quota;
 pub mod server;
 pub mod shared;
+mod systemd;
+pub mod tls;
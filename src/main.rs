@@ -1,39 +1,441 @@
-use anyhow::Result;
-use bore_cli::{client::Client, server::Server};
-use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+mod daemon;
+#[cfg(unix)]
+mod mmap;
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::{env, fs, io};
+use std::{ops::RangeInclusive, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bore_cli::{
+    admin::{self, AdminAddr, ClientInfo, TunnelInfo},
+    auth::{fingerprint, Keyring, TokenKey},
+    client::{
+        Backoff, ClientBuilder, ClientError, ClientEvent, LocalTarget, Mapping, ProxyProtocol,
+        TransportKind,
+    },
+    geoip::{GeoIpDb, GeoIpFilter},
+    proxy::Proxy,
+    quota::QuotaInfo,
+    server::{self, Server},
+    shared::{IpFamily, IpFilter, Protocol, TcpOptions, TunnelStatus},
+    tls::{ClientTls, ServerTls},
+};
+use clap::parser::ValueSource;
+use clap::{error::ErrorKind, ArgMatches, CommandFactory, Parser, Subcommand, ValueEnum};
+use daemon::PidFile;
+use ipnet::IpNet;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 struct Args {
     #[clap(subcommand)]
     command: Command,
+
+    /// Format of the log output.
+    #[clap(
+        long,
+        value_name = "FORMAT",
+        value_enum,
+        global = true,
+        env = "BORE_LOG_FORMAT"
+    )]
+    #[clap(default_value_t)]
+    log_format: LogFormat,
+
+    /// Read options from a TOML file, overridden by command-line flags.
+    #[clap(long, value_name = "FILE", global = true, env = "BORE_CONFIG")]
+    config: Option<PathBuf>,
+}
+
+/// Transport that the client carries its connections to the server over.
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+enum Transport {
+    #[default]
+    Tcp,
+    #[value(alias = "websocket")]
+    Ws,
 }
 
+/// Address family that the client asks the server to bind its remote ports on.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum RemoteBind {
+    V4only,
+    V6only,
+}
+
+/// How the server authenticates clients.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum AuthMode {
+    #[default]
+    Secret,
+    Token,
+}
+
+/// How the server chooses ports for tunnels that don't ask for a specific one.
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+enum PortStrategy {
+    #[default]
+    Random,
+    Sequential,
+    Lru,
+}
+
+/// Format in which log events are written, as human-readable lines or JSON objects.
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+enum LogFormat {
+    #[default]
+    Text,
+    Json,
+    Pretty,
+    Compact,
+}
+
+/// Format in which the client writes its lifecycle events on stdout.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum EventFormat {
+    Json,
+}
+
+/// Example units for running the server under systemd, shown in `bore server --help`.
+#[cfg(feature = "systemd")]
+const SYSTEMD_HELP: &str = "\
+Running under systemd:
+  With socket activation, systemd holds the control port while the server restarts,
+  and the server reports readiness with Type=notify. For example:
+
+  # /etc/systemd/system/bore.socket
+  [Socket]
+  ListenStream=7835
+
+  [Install]
+  WantedBy=sockets.target
+
+  # /etc/systemd/system/bore.service
+  [Service]
+  Type=notify
+  ExecStart=/usr/local/bin/bore server
+  Restart=on-failure";
+
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
 enum Command {
     /// Starts a local proxy to the remote server.
     Local {
         /// The local port to expose.
-        local_port: u16,
+        #[clap(group = "local", required_unless_present_any = ["map", "port_block", "local_socket", "sni_route", "stop", "uninstall_service"])]
+        local_port: Option<u16>,
+
+        /// Expose a Unix domain socket instead of a local port.
+        #[clap(long, value_name = "PATH", group = "local")]
+        #[clap(conflicts_with_all = ["udp", "proxy_protocol"])]
+        local_socket: Option<PathBuf>,
 
         /// The local host to expose.
         #[clap(short, long, value_name = "HOST", default_value = "localhost")]
         local_host: String,
 
+        /// Another local port to spread connections over in turn, as `[HOST:]PORT`.
+        #[clap(long, value_name = "BACKEND", value_parser = parse_backend)]
+        #[clap(requires = "local_port", conflicts_with = "udp")]
+        backend: Vec<(Option<String>, u16)>,
+
+        /// Route TLS connections for a server name to a local port, as `NAME=PORT`.
+        #[clap(long, value_name = "ROUTE", value_parser = parse_sni_route)]
+        #[clap(group = "local", conflicts_with_all = ["udp", "local_socket"])]
+        sni_route: Vec<(String, u16)>,
+
+        /// Local port for connections that are not TLS or whose server name has no route.
+        #[clap(long, value_name = "PORT", requires = "sni_route")]
+        sni_default: Option<u16>,
+
         /// Address of the remote server to expose local ports to.
-        #[clap(short, long, env = "BORE_SERVER")]
-        to: String,
+        #[clap(short, long, env = "BORE_SERVER", required_unless_present_any = ["stop", "uninstall_service"])]
+        to: Option<String>,
 
         /// Optional port on the remote server to select.
         #[clap(short, long, default_value_t = 0)]
         port: u16,
 
-        /// Optional secret for authentication.
-        #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
-        secret: Option<String>,
+        /// Range of ports on the remote server to select from, as `START-END`.
+        #[clap(long, value_name = "RANGE", value_parser = parse_port_range)]
+        #[clap(conflicts_with = "port", requires = "local")]
+        port_range: Option<RangeInclusive<u16>>,
+
+        /// Additional ports to expose, as `[HOST:]LOCAL_PORT:REMOTE_PORT[@BIND_ADDR]`.
+        #[clap(short, long, value_name = "MAPPING", value_parser = parse_mapping)]
+        map: Vec<(Option<String>, u16, u16, Option<IpAddr>)>,
+
+        /// Expose a block of local ports on the same ports of the remote server, as
+        /// `START-END`, which are forwarded all or none.
+        #[clap(long, value_name = "RANGE", value_parser = parse_port_range)]
+        port_block: Vec<RangeInclusive<u16>>,
+
+        /// Address of a server interface to bind remote ports on, if the server allows it.
+        #[clap(long, value_name = "ADDR")]
+        bind_addr: Option<IpAddr>,
+
+        /// Bind remote ports on only the IPv4 or IPv6 addresses that the server uses.
+        #[clap(long, value_name = "FAMILY", value_enum)]
+        remote_bind: Option<RemoteBind>,
+
+        /// Exit if any of the requested remote ports cannot be allocated.
+        #[clap(long)]
+        strict: bool,
+
+        /// Forward UDP datagrams instead of TCP connections.
+        #[clap(long)]
+        udp: bool,
+
+        /// Send a PROXY protocol header with the remote peer's address to the local service.
+        #[clap(long, value_name = "VERSION", value_parser = parse_proxy_protocol)]
+        #[clap(conflicts_with = "udp")]
+        proxy_protocol: Option<ProxyProtocol>,
+
+        /// Limit each tunnel to this many bytes per second in each direction, like `5MB`.
+        #[clap(long, value_name = "RATE", value_parser = parse_rate)]
+        max_rate: Option<u64>,
+
+        /// Ask the server for the connections and bytes of each tunnel on this interval.
+        #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+        stats_interval: Option<Duration>,
+
+        /// Compress TCP connections to the server with gzip, if the server allows it.
+        #[clap(long, conflicts_with = "udp")]
+        compress: bool,
+
+        /// Encrypt TCP connections to the server with keys derived from the secret, if the
+        /// server allows it.
+        #[clap(long, conflicts_with = "udp", requires = "secret_source")]
+        encrypt_data: bool,
+
+        /// Receive HTTP requests for this subdomain on the server's HTTP port instead, or
+        /// for a random one without a name.
+        #[clap(long, value_name = "NAME", conflicts_with = "udp", requires = "local")]
+        #[clap(num_args = 0..=1, default_missing_value = "")]
+        subdomain: Option<String>,
+
+        /// Prefix of the random subdomain for the server to choose, like `myteam`.
+        #[clap(
+            long,
+            value_name = "PREFIX",
+            conflicts_with = "udp",
+            requires = "local"
+        )]
+        subdomain_prefix: Option<String>,
+
+        /// Serve the tunnel on a Unix socket of this name on the server, instead of a port.
+        #[clap(long, value_name = "NAME", requires = "local")]
+        #[clap(conflicts_with_all = ["udp", "subdomain", "subdomain_prefix", "port", "port_range", "sticky"])]
+        remote_socket: Option<String>,
+
+        /// Have the server derive the remote port from the secret, so it stays the same.
+        #[clap(long)]
+        sticky: bool,
+
+        /// Extra key to derive the sticky port from, to get different ports for one secret.
+        #[clap(long, value_name = "KEY", requires = "sticky")]
+        sticky_key: Option<String>,
+
+        /// Take over the remote ports from stale tunnels that were opened with the same secret.
+        #[clap(long)]
+        reclaim: bool,
+
+        /// Stable name of the tunnel, for the server to give it back its last remote port.
+        #[clap(long, value_name = "NAME", requires = "local")]
+        name: Option<String>,
+
+        /// Only forward connections from this network (repeatable).
+        #[clap(long, value_name = "CIDR", value_parser = parse_cidr)]
+        allow_cidr: Vec<IpNet>,
+
+        /// Reject connections from this network (repeatable).
+        #[clap(long, value_name = "CIDR", value_parser = parse_cidr)]
+        deny_cidr: Vec<IpNet>,
+
+        /// Reconnect with exponential backoff if the connection to the server is lost.
+        #[clap(long)]
+        reconnect: bool,
+
+        /// When reconnecting, exit instead of accepting a new port if the old one is taken.
+        #[clap(long, requires = "reconnect")]
+        strict_port: bool,
+
+        /// Give up after this many consecutive failed reconnection attempts.
+        #[clap(long, value_name = "N", requires = "reconnect")]
+        max_retries: Option<u32>,
+
+        #[clap(flatten)]
+        secret: SecretArgs,
+
+        /// Signed token for authentication, for servers started with `--auth-mode token`.
+        /// It is sent as is, so use it with `--tls`.
+        #[clap(
+            long,
+            env = "BORE_TOKEN",
+            hide_env_values = true,
+            conflicts_with = "secret_source"
+        )]
+        token: Option<String>,
+
+        /// Connect to the server over TLS.
+        #[clap(long)]
+        tls: bool,
+
+        /// Verify the server's certificate against a CA certificate file (PEM).
+        #[clap(long, value_name = "FILE", requires = "tls")]
+        tls_ca: Option<PathBuf>,
+
+        /// Skip verification of the server's certificate.
+        #[clap(long, requires = "tls", conflicts_with = "tls_ca")]
+        tls_insecure: bool,
+
+        /// Connect to the server through an HTTP or SOCKS5 proxy [default: $BORE_SOCKS5, $HTTPS_PROXY or $ALL_PROXY].
+        #[clap(long, value_name = "URL", value_parser = Proxy::parse)]
+        proxy: Option<Proxy>,
+
+        /// Connect to the server through a SOCKS5 proxy, as `[USER:PASS@]HOST:PORT`.
+        #[clap(long, value_name = "ADDR", value_parser = Proxy::socks5, conflicts_with = "proxy")]
+        socks5: Option<Proxy>,
+
+        /// Carry connections to the server over plain TCP or WebSocket.
+        #[clap(long, value_name = "TRANSPORT", value_enum, default_value_t)]
+        transport: Transport,
+
+        /// Port of the server's WebSocket address [default: 443 with TLS, 80 without].
+        #[clap(long, value_name = "PORT")]
+        ws_port: Option<u16>,
+
+        /// Time to wait for connections and handshakes to complete.
+        #[clap(long, value_name = "DURATION", default_value = "3s", value_parser = parse_duration)]
+        timeout: Duration,
+
+        /// Time to wait for TCP connections to open [default: the --timeout].
+        #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+        connect_timeout: Option<Duration>,
+
+        /// Time to wait for connections to the local target to open [default: the --connect-timeout].
+        #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+        local_timeout: Option<Duration>,
+
+        /// Time to wait for each connection back to the server to accept a forwarded one.
+        #[clap(long, value_name = "DURATION", default_value = "10s", value_parser = parse_duration)]
+        #[clap(alias = "data-channel-timeout")]
+        accept_timeout: Duration,
+
+        /// Keep retrying the local target for this long while it refuses connections.
+        #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+        local_retry: Option<Duration>,
+
+        /// Retry the local target at most this many times for each connection.
+        #[clap(long, value_name = "RETRIES")]
+        local_retry_attempts: Option<u32>,
+
+        /// Delay before the first retry of the local target, doubling for each retry.
+        #[clap(long, value_name = "DURATION", default_value = "50ms", value_parser = parse_duration)]
+        local_retry_delay: Duration,
+
+        /// Wait for the local target to accept connections before opening the tunnel.
+        #[clap(long)]
+        wait_local: bool,
+
+        /// Send TCP keepalive probes on connections that are idle for this long.
+        #[clap(long, value_name = "DURATION", value_parser = parse_interval)]
+        tcp_keepalive: Option<Duration>,
+
+        /// Disable Nagle's algorithm on TCP connections.
+        #[clap(long)]
+        tcp_nodelay: bool,
+
+        /// Local address to open connections to the server and the proxy from.
+        #[clap(long, value_name = "ADDR")]
+        source_addr: Option<IpAddr>,
+
+        /// Network interface to send connections to the server and the proxy over (Linux only).
+        #[clap(long, value_name = "NAME")]
+        bind_interface: Option<String>,
+
+        /// Local address to open connections to the local target from.
+        #[clap(long, value_name = "ADDR")]
+        local_bind_addr: Option<IpAddr>,
+
+        /// Interval between heartbeats sent to the server.
+        #[clap(long, value_name = "DURATION", default_value = "500ms", value_parser = parse_interval)]
+        heartbeat_interval: Duration,
+
+        /// Reconnect or exit if the server sends nothing for this long.
+        #[clap(long, value_name = "DURATION", default_value = "10s", value_parser = parse_duration)]
+        heartbeat_timeout: Duration,
+
+        /// Ping the server on this interval, to detect links that only work one way.
+        #[clap(long, value_name = "DURATION", value_parser = parse_interval)]
+        ping_interval: Option<Duration>,
+
+        /// Reconnect or exit if a ping is not answered within this long.
+        #[clap(long, value_name = "DURATION", default_value = "5s", value_parser = parse_duration)]
+        #[clap(requires = "ping_interval")]
+        ping_timeout: Duration,
+
+        /// Time to wait for open connections to finish when shutting down.
+        #[clap(long, value_name = "DURATION", default_value = "30s", value_parser = parse_duration)]
+        drain_timeout: Duration,
+
+        /// Print each tunnel's address as a JSON object on stdout, with logs on stderr.
+        #[clap(long)]
+        json: bool,
+
+        /// Write lifecycle events, such as connections opening and closing, on stdout as
+        /// newline-delimited JSON, with logs on stderr.
+        #[clap(long, value_name = "FORMAT", value_enum, conflicts_with = "json")]
+        events: Option<EventFormat>,
+
+        /// Shell command to run for each tunnel once it is assigned a remote port.
+        #[clap(long, value_name = "CMD")]
+        on_connect: Option<String>,
+
+        /// Shell command to run for each tunnel when the connection to the server closes.
+        #[clap(long, value_name = "CMD")]
+        on_disconnect: Option<String>,
+
+        /// Run in the background, detached from the terminal (Unix only).
+        #[clap(long)]
+        daemon: bool,
+
+        /// Write the process ID to this file while running, for `--stop`.
+        #[clap(long, value_name = "PATH")]
+        pid_file: Option<PathBuf>,
+
+        /// Append logs to this file instead of writing them to the terminal.
+        #[clap(long, value_name = "PATH")]
+        log_file: Option<PathBuf>,
+
+        /// Shut down the client whose process ID is in the --pid-file, and exit.
+        #[clap(long, requires = "pid_file", conflicts_with = "daemon")]
+        stop: bool,
+
+        /// Register a Windows service that runs the client with these options, and exit.
+        #[clap(long, value_name = "NAME", conflicts_with_all = ["daemon", "stop", "service"])]
+        install_service: Option<String>,
+
+        /// Stop and remove a Windows service registered with --install-service, and exit.
+        #[clap(long, value_name = "NAME", conflicts_with_all = ["install_service", "stop"])]
+        uninstall_service: Option<String>,
+
+        /// Run as the Windows service of this name, started by the service control manager.
+        #[clap(long, value_name = "NAME", hide = true, conflicts_with = "daemon")]
+        service: Option<String>,
     },
 
     /// Runs the remote proxy server.
+    #[cfg_attr(feature = "systemd", clap(after_help = SYSTEMD_HELP))]
     Server {
         /// Minimum accepted TCP port number.
         #[clap(long, default_value_t = 1024)]
@@ -43,10 +445,632 @@ enum Command {
         #[clap(long, default_value_t = 65535)]
         max_port: u16,
 
-        /// Optional secret for authentication.
-        #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
-        secret: Option<String>,
+        /// Let clients without a secret open tunnels on random ports in this range, as `START-END`.
+        #[clap(long, value_name = "RANGE", value_parser = parse_port_range)]
+        open_port_range: Option<RangeInclusive<u16>>,
+
+        #[clap(flatten)]
+        secret: SecretArgs,
+
+        /// File of accepted secrets, one `LABEL = SECRET` per line, reloaded on SIGHUP.
+        #[clap(long, value_name = "FILE")]
+        secrets_file: Option<PathBuf>,
+
+        /// Keep accepting the secrets replaced by a reload of the secrets file for a while.
+        #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+        #[clap(alias = "secret-rotation-overlap", requires = "secrets_file")]
+        secret_overlap: Option<Duration>,
+
+        /// Authenticate clients with secrets, or with tokens signed for `--token-key`.
+        #[clap(long, value_enum, default_value_t)]
+        auth_mode: AuthMode,
+
+        /// Ed25519 public key in hex that client tokens must be signed with. Tokens are
+        /// sent as is, so use it with `--tls-cert`.
+        #[clap(long, value_name = "KEY", value_parser = TokenKey::new)]
+        #[clap(required_if_eq("auth_mode", "token"), conflicts_with_all = ["secret_source", "secrets_file"])]
+        token_key: Option<TokenKey>,
+
+        /// Allow clients to forward UDP ports.
+        #[clap(long)]
+        udp: bool,
+
+        /// Allow clients to compress their TCP connections with gzip.
+        #[clap(long)]
+        compress: bool,
+
+        /// Allow clients with a secret to encrypt their TCP connections with keys derived
+        /// from it.
+        #[clap(long)]
+        encrypt_data: bool,
+
+        /// Time after which a UDP flow without traffic is dropped.
+        #[clap(long, value_name = "DURATION", default_value = "30s", value_parser = parse_duration)]
+        udp_idle_timeout: Duration,
+
+        /// Certificate chain to serve TLS on the control port (PEM).
+        #[clap(long, value_name = "FILE", requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+
+        /// Private key for the TLS certificate (PEM).
+        #[clap(long, value_name = "FILE", requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+
+        /// Limit each tunnel to this many bytes per second in each direction, like `5MB`.
+        #[clap(long, value_name = "RATE", value_parser = parse_rate, alias = "per-tunnel-max-rate")]
+        rate_limit: Option<u64>,
+
+        /// Limit on bytes per second sent from clients out to the public ports.
+        #[clap(long, value_name = "RATE", value_parser = parse_rate)]
+        rate_limit_up: Option<u64>,
+
+        /// Limit on bytes per second received from the public ports for clients.
+        #[clap(long, value_name = "RATE", value_parser = parse_rate)]
+        rate_limit_down: Option<u64>,
+
+        /// How to choose ports for clients that don't ask for a specific one.
+        #[clap(long, value_name = "STRATEGY", value_enum, default_value_t)]
+        port_strategy: PortStrategy,
+
+        /// Let the system choose any free port for clients that don't ask for a specific
+        /// one, even outside the port range, as with `--min-port 0 --max-port 0`.
+        #[clap(long, conflicts_with = "port_strategy")]
+        ephemeral: bool,
+
+        /// Avoid choosing a port again for this long after its tunnel closes.
+        #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+        port_cooldown: Option<Duration>,
+
+        /// Keep the port of a named tunnel for this long after it closes, for its client.
+        #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+        port_retention: Option<Duration>,
+
+        /// Reserve ports for the clients with a secret's label, as `LABEL=PORT[-PORT]` (repeatable).
+        #[clap(long, value_name = "RESERVATION", value_parser = parse_reservation)]
+        reserve: Vec<(String, RangeInclusive<u16>)>,
+
+        /// Limit the bytes proxied for the clients with a secret's label, as `LABEL=SIZE` (repeatable).
+        #[clap(long, value_name = "QUOTA", value_parser = parse_quota)]
+        quota: Vec<(String, u64)>,
+
+        /// Limit the open tunnels of the clients with a secret's label, as `LABEL=TUNNELS` (repeatable).
+        #[clap(long, value_name = "LIMIT", value_parser = parse_tunnel_limit)]
+        tunnel_limit: Vec<(String, usize)>,
+
+        /// Reset the bytes counted against quotas after each period of this length.
+        #[clap(long, value_name = "DURATION", value_parser = parse_interval)]
+        quota_period: Option<Duration>,
+
+        /// File to save the bytes counted against quotas in, across restarts.
+        #[clap(long, value_name = "PATH")]
+        quota_file: Option<PathBuf>,
+
+        /// File to append a line to for each proxied connection, reopened on SIGUSR1.
+        #[clap(long, value_name = "PATH")]
+        access_log: Option<PathBuf>,
+
+        /// Rotate the access log before it grows past this size, like `100MB`.
+        #[clap(long, value_name = "SIZE", value_parser = parse_rate, requires = "access_log")]
+        access_log_max_size: Option<u64>,
+
+        /// Address to listen for control connections on, such as `::` (repeatable) [default: 0.0.0.0].
+        #[clap(long, value_name = "ADDR")]
+        control_addr: Vec<IpAddr>,
+
+        /// Address to bind each tunnel port on, such as `::` (repeatable) [default: 0.0.0.0].
+        #[clap(long, value_name = "ADDR")]
+        tunnels_addr: Vec<IpAddr>,
+
+        /// Whether IPv6 sockets refuse IPv4 connections [default: only alongside IPv4 addresses].
+        #[clap(long, value_name = "BOOL")]
+        v6only: Option<bool>,
+
+        /// Addresses of this server's interfaces that clients may bind tunnels on.
+        #[clap(long, value_name = "ADDR", value_delimiter = ',')]
+        allowed_tunnel_addrs: Vec<IpAddr>,
+
+        /// Only allow connections to forwarded ports from this network (repeatable).
+        #[clap(long, value_name = "CIDR", value_parser = parse_cidr)]
+        allow: Vec<IpNet>,
+
+        /// Reject connections to forwarded ports from this network (repeatable).
+        #[clap(long, value_name = "CIDR", value_parser = parse_cidr)]
+        deny: Vec<IpNet>,
+
+        /// GeoIP database in the MaxMind DB format to look up the country of connections in.
+        #[clap(long, value_name = "FILE")]
+        geoip_db: Option<PathBuf>,
+
+        /// Only allow connections to forwarded ports from these countries, like `US,DE`.
+        #[clap(
+            long,
+            value_name = "CODES",
+            value_delimiter = ',',
+            requires = "geoip_db"
+        )]
+        geoip_allow: Vec<String>,
+
+        /// Reject connections to forwarded ports from these countries.
+        #[clap(
+            long,
+            value_name = "CODES",
+            value_delimiter = ',',
+            requires = "geoip_db"
+        )]
+        geoip_deny: Vec<String>,
+
+        /// Reject connections from addresses without a country, such as private ones.
+        #[clap(long, requires = "geoip_db")]
+        geoip_deny_unknown: bool,
+
+        /// Close a client's tunnels after they have been open for this long.
+        #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+        max_tunnel_lifetime: Option<Duration>,
+
+        /// Close a client's tunnels if they receive no new connections for this long.
+        #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+        idle_timeout: Option<Duration>,
+
+        /// Close a client's tunnels if none of them have open connections for this long.
+        #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+        tunnel_idle_timeout: Option<Duration>,
+
+        /// Limit each tunnel to this many open connections at once.
+        #[clap(long, value_name = "CONNS", alias = "max-connections-per-tunnel")]
+        max_conns_per_tunnel: Option<usize>,
+
+        /// Limit each client, by identity or address, to this many open tunnels at once.
+        #[clap(long, value_name = "TUNNELS")]
+        max_tunnels_per_client: Option<usize>,
+
+        /// Limit all clients together to this many open tunnels at once.
+        #[clap(long, value_name = "TUNNELS")]
+        max_tunnels: Option<usize>,
+
+        /// Hold at most this many connections per tunnel for the client to accept,
+        /// pausing accepts beyond it.
+        #[clap(
+            long,
+            value_name = "CONNS",
+            default_value_t = 128,
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        pending_conns_limit: u32,
+
+        /// Limit new control port connections from each address to this many per second.
+        #[clap(long, value_name = "PER_SEC", value_parser = clap::value_parser!(u32).range(1..))]
+        handshake_rate_limit: Option<u32>,
+
+        /// Ban an address after this many authentication failures in a row.
+        #[clap(long, value_name = "FAILURES", value_parser = clap::value_parser!(u32).range(1..))]
+        ban_after: Option<u32>,
+
+        /// How long addresses are banned for.
+        #[clap(long, value_name = "DURATION", default_value = "10m", value_parser = parse_duration)]
+        ban_duration: Duration,
+
+        /// Address to serve HTTP tunnels on, routed by the subdomain of each request's host.
+        #[clap(long, value_name = "ADDR")]
+        http_addr: Option<SocketAddr>,
+
+        /// Address to pass HTTPS connections through to HTTP tunnels on, routed by server name.
+        #[clap(long, value_name = "ADDR")]
+        https_addr: Option<SocketAddr>,
+
+        /// Address to also accept clients on over WebSocket, like `0.0.0.0:443`.
+        #[clap(long, value_name = "ADDR")]
+        ws_addr: Option<SocketAddr>,
+
+        /// Directory to let clients serve tunnels in as Unix sockets, like `/run/bore`.
+        #[clap(long, value_name = "DIR")]
+        socket_dir: Option<PathBuf>,
+
+        /// Address to serve Prometheus metrics on, at `/metrics`.
+        #[clap(long, value_name = "ADDR")]
+        metrics_addr: Option<SocketAddr>,
+
+        /// Loopback address to serve the admin API on, for `bore admin`.
+        #[clap(long, value_name = "ADDR")]
+        admin_addr: Option<SocketAddr>,
+
+        /// Unix socket to serve the admin API on, for `bore admin`.
+        #[clap(long, value_name = "PATH")]
+        admin_socket: Option<PathBuf>,
+
+        /// Time to wait for handshakes from new connections to complete.
+        #[clap(long, value_name = "DURATION", default_value = "3s", value_parser = parse_duration)]
+        timeout: Duration,
+
+        /// Disconnect clients that send a control message longer than this, like `16KiB`.
+        #[clap(long, value_name = "SIZE", default_value = "4KiB", value_parser = parse_rate)]
+        max_message_size: u64,
+
+        /// Send TCP keepalive probes on control and tunnel connections idle for this long.
+        #[clap(long, value_name = "DURATION", value_parser = parse_interval)]
+        tcp_keepalive: Option<Duration>,
+
+        /// Disable Nagle's algorithm on control and tunnel connections.
+        #[clap(long)]
+        tcp_nodelay: bool,
+
+        /// Interval between heartbeats sent to clients.
+        #[clap(long, value_name = "DURATION", default_value = "500ms", value_parser = parse_interval)]
+        heartbeat_interval: Duration,
+
+        /// Close a client's tunnels if no heartbeat arrives from it for this long.
+        #[clap(long, value_name = "DURATION", default_value = "10s", value_parser = parse_duration)]
+        heartbeat_timeout: Duration,
+
+        /// Time to wait for open connections to finish when shutting down.
+        #[clap(long, value_name = "DURATION", default_value = "30s", value_parser = parse_duration)]
+        #[clap(alias = "shutdown-grace")]
+        drain_timeout: Duration,
+
+        /// Time to keep each forwarded connection for the client to accept.
+        #[clap(long, value_name = "DURATION", default_value = "10s", value_parser = parse_duration)]
+        #[clap(alias = "accept-timeout")]
+        connection_grace: Duration,
+    },
+
+    /// Lists the tunnels open on a remote server.
+    Status {
+        #[clap(flatten)]
+        server: ServerArgs,
+
+        /// Print the tunnels as a JSON array instead of a table.
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Checks that a remote server is reachable and accepts the client's credentials.
+    ///
+    /// Exits with 3 if the server can't be reached, 4 if authentication fails, 5 on
+    /// other protocol errors, and 6 if no port could be allocated.
+    Ping {
+        #[clap(flatten)]
+        server: ServerArgs,
+
+        /// Number of pings to time on the control connection.
+        #[clap(short, long, default_value_t = 3)]
+        count: u32,
+
+        /// Also ask the server for a port, and release it right away.
+        #[clap(long)]
+        port_test: bool,
+    },
+
+    /// Inspects and manages the tunnels of a running server.
+    Admin {
+        /// Address of the server's admin API.
+        #[clap(
+            long,
+            value_name = "ADDR",
+            required_unless_present = "admin_socket",
+            conflicts_with = "admin_socket"
+        )]
+        admin_addr: Option<SocketAddr>,
+
+        /// Unix socket of the server's admin API.
+        #[clap(long, value_name = "PATH")]
+        admin_socket: Option<PathBuf>,
+
+        #[clap(subcommand)]
+        command: AdminCommand,
+    },
+}
+
+/// Ways of passing the secret for authentication, of which only one may be used.
+#[derive(clap::Args, Debug)]
+#[group(id = "secret_source", multiple = false)]
+struct SecretArgs {
+    /// Optional secret for authentication.
+    #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
+    secret: Option<String>,
+
+    /// Read the secret from this file, without surrounding whitespace.
+    #[clap(long, value_name = "FILE")]
+    secret_file: Option<PathBuf>,
+
+    /// Read the secret from the first line of standard input.
+    #[clap(long)]
+    secret_stdin: bool,
+}
+
+impl SecretArgs {
+    /// Read the secret from its file or from standard input, if it was passed that way.
+    fn load(&mut self) -> Result<()> {
+        let (text, source) = if let Some(path) = &self.secret_file {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("could not read secret file {}", path.display()))?;
+            (text, format!("secret file {}", path.display()))
+        } else if self.secret_stdin {
+            let mut line = String::new();
+            io::stdin()
+                .read_line(&mut line)
+                .context("could not read secret from standard input")?;
+            (line, "standard input".into())
+        } else {
+            return Ok(());
+        };
+        let secret = text.trim();
+        if secret.is_empty() {
+            bail!("no secret in {source}");
+        }
+        self.secret = Some(secret.into());
+        Ok(())
+    }
+
+    /// Warn if the secret file can be read by any user on the machine.
+    fn check_permissions(&self) {
+        #[cfg(unix)]
+        if let Some(path) = &self.secret_file {
+            use std::os::unix::fs::PermissionsExt;
+            if fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o004 != 0) {
+                warn!(path = %path.display(), "secret file is readable by all users");
+            }
+        }
+    }
+}
+
+/// Options for connecting to a remote server, for the commands that query it.
+#[derive(clap::Args, Debug)]
+struct ServerArgs {
+    /// Address of the remote server.
+    #[clap(short, long, env = "BORE_SERVER")]
+    to: String,
+
+    #[clap(flatten)]
+    secret: SecretArgs,
+
+    /// Signed token for authentication, for servers started with `--auth-mode token`.
+    /// It is sent as is, so use it with `--tls`.
+    #[clap(
+        long,
+        env = "BORE_TOKEN",
+        hide_env_values = true,
+        conflicts_with = "secret_source"
+    )]
+    token: Option<String>,
+
+    /// Connect to the server over TLS.
+    #[clap(long)]
+    tls: bool,
+
+    /// Verify the server's certificate against a CA certificate file (PEM).
+    #[clap(long, value_name = "FILE", requires = "tls")]
+    tls_ca: Option<PathBuf>,
+
+    /// Skip verification of the server's certificate.
+    #[clap(long, requires = "tls", conflicts_with = "tls_ca")]
+    tls_insecure: bool,
+
+    /// Connect to the server through an HTTP or SOCKS5 proxy [default: $BORE_SOCKS5, $HTTPS_PROXY or $ALL_PROXY].
+    #[clap(long, value_name = "URL", value_parser = Proxy::parse)]
+    proxy: Option<Proxy>,
+
+    /// Connect to the server through a SOCKS5 proxy, as `[USER:PASS@]HOST:PORT`.
+    #[clap(long, value_name = "ADDR", value_parser = Proxy::socks5, conflicts_with = "proxy")]
+    socks5: Option<Proxy>,
+
+    /// Carry connections to the server over plain TCP or WebSocket.
+    #[clap(long, value_name = "TRANSPORT", value_enum, default_value_t)]
+    transport: Transport,
+
+    /// Port of the server's WebSocket address [default: 443 with TLS, 80 without].
+    #[clap(long, value_name = "PORT")]
+    ws_port: Option<u16>,
+
+    /// Time to wait for connections and handshakes to complete.
+    #[clap(long, value_name = "DURATION", default_value = "3s", value_parser = parse_duration)]
+    timeout: Duration,
+}
+
+impl ServerArgs {
+    /// Start building a client that connects to the server with these options.
+    fn to_builder(&self) -> Result<ClientBuilder> {
+        let mut builder = ClientBuilder::new(&self.to).timeout(self.timeout);
+        if let Some(secret) = &self.secret.secret {
+            builder = builder.secret(secret);
+        }
+        if let Some(token) = &self.token {
+            builder = builder.token(token);
+        }
+        let proxy = match self.proxy.as_ref().or(self.socks5.as_ref()) {
+            Some(proxy) => Some(proxy.clone()),
+            None => Proxy::from_env()?,
+        };
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(proxy);
+        }
+        if let Transport::Ws = self.transport {
+            let default_port = if self.tls { 443 } else { 80 };
+            let port = self.ws_port.unwrap_or(default_port);
+            builder = builder.transport(TransportKind::WebSocket(port));
+        }
+        match (self.tls, &self.tls_ca) {
+            (false, _) => {}
+            (true, Some(ca)) => builder = builder.tls(ClientTls::with_ca_file(ca)?),
+            (true, None) if self.tls_insecure => builder = builder.tls(ClientTls::insecure()),
+            (true, None) => builder = builder.tls(ClientTls::new()),
+        }
+        Ok(builder)
+    }
+}
+
+impl Command {
+    /// The options for passing the secret, for the commands that use one.
+    fn secret_args(&mut self) -> Option<&mut SecretArgs> {
+        match self {
+            Command::Local { secret, .. } | Command::Server { secret, .. } => Some(secret),
+            Command::Status { server, .. } | Command::Ping { server, .. } => {
+                Some(&mut server.secret)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Requests to the admin API of a running server.
+#[derive(Subcommand, Debug)]
+enum AdminCommand {
+    /// Lists the open tunnels.
+    List,
+
+    /// Closes the tunnels on a port, along with the other tunnels of their client.
+    Kick {
+        /// The port whose tunnels to close.
+        port: u16,
     },
+
+    /// Lists the clients with tunnels open, and how many each has.
+    Clients,
+
+    /// Lists the bytes proxied for each client, along with their quotas.
+    Quotas,
+
+    /// Resets the bytes counted against the quotas of all clients.
+    QuotaReset,
+}
+
+/// Parse a port mapping of the form `[HOST:]LOCAL_PORT:REMOTE_PORT[@BIND_ADDR]`.
+fn parse_mapping(value: &str) -> Result<(Option<String>, u16, u16, Option<IpAddr>)> {
+    let (value, bind_addr) = match value.rsplit_once('@') {
+        Some((value, addr)) => (value, Some(addr.parse().context("invalid bind address")?)),
+        None => (value, None),
+    };
+    let mut parts = value.rsplitn(3, ':');
+    let remote_port = parts.next().unwrap_or_default();
+    let local_port = parts.next().context("expected LOCAL_PORT:REMOTE_PORT")?;
+    let host = parts
+        .next()
+        .map(|host| host.trim_matches(&['[', ']'][..]).to_string());
+    let local_port = local_port.parse().context("invalid local port")?;
+    let remote_port = remote_port.parse().context("invalid remote port")?;
+    Ok((host, local_port, remote_port, bind_addr))
+}
+
+/// Parse a local backend of the form `[HOST:]PORT`.
+fn parse_backend(value: &str) -> Result<(Option<String>, u16)> {
+    let (host, port) = match value.rsplit_once(':') {
+        Some((host, port)) => (Some(host.trim_matches(&['[', ']'][..]).to_string()), port),
+        None => (None, value),
+    };
+    Ok((host, port.parse().context("invalid port")?))
+}
+
+/// Parse a route of TLS connections by server name, of the form `NAME=PORT`.
+fn parse_sni_route(value: &str) -> Result<(String, u16)> {
+    let (name, port) = value.split_once('=').context("expected NAME=PORT")?;
+    if name.is_empty() {
+        bail!("expected a server name");
+    }
+    Ok((name.into(), port.parse().context("invalid port")?))
+}
+
+/// Parse a port range of the form `START-END`.
+fn parse_port_range(value: &str) -> Result<RangeInclusive<u16>> {
+    let (start, end) = value.split_once('-').context("expected START-END")?;
+    let start: u16 = start.parse().context("invalid start port")?;
+    let end: u16 = end.parse().context("invalid end port")?;
+    if start > end {
+        bail!("start port must not be greater than end port");
+    }
+    Ok(start..=end)
+}
+
+/// Parse a port reservation of the form `LABEL=PORT` or `LABEL=START-END`.
+fn parse_reservation(value: &str) -> Result<(String, RangeInclusive<u16>)> {
+    let (label, ports) = value.split_once('=').context("expected LABEL=PORTS")?;
+    Ok((label.to_string(), parse_ports(ports)?))
+}
+
+/// Parse a byte quota of the form `LABEL=SIZE`, with the units of a rate.
+fn parse_quota(value: &str) -> Result<(String, u64)> {
+    let (label, size) = value.split_once('=').context("expected LABEL=SIZE")?;
+    Ok((label.to_string(), parse_rate(size)?))
+}
+
+/// Parse a tunnel limit of the form `LABEL=TUNNELS`.
+fn parse_tunnel_limit(value: &str) -> Result<(String, usize)> {
+    let (label, max) = value.split_once('=').context("expected LABEL=TUNNELS")?;
+    Ok((label.to_string(), max.parse()?))
+}
+
+/// Parse a single port, or a range of the form `START-END`.
+fn parse_ports(value: &str) -> Result<RangeInclusive<u16>> {
+    match value.parse::<u16>() {
+        Ok(port) => Ok(port..=port),
+        Err(_) => parse_port_range(value),
+    }
+}
+
+/// Parse a rate in bytes per second, with an optional unit such as `KB`, `MB` or `MiB`.
+fn parse_rate(value: &str) -> Result<u64> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().context("expected a number")?;
+    let multiplier: u64 = match unit.trim_start() {
+        "" | "B" => 1,
+        "K" | "KB" => 1_000,
+        "M" | "MB" => 1_000_000,
+        "G" | "GB" => 1_000_000_000,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        unit => bail!("unknown unit {unit:?}, expected one of B, KB, MB, GB, KiB, MiB, GiB"),
+    };
+    match number.checked_mul(multiplier) {
+        Some(0) => bail!("rate must be positive"),
+        Some(rate) => Ok(rate),
+        None => bail!("rate is too large"),
+    }
+}
+
+/// Parse a PROXY protocol version, either `v1` or `v2`.
+fn parse_proxy_protocol(value: &str) -> Result<ProxyProtocol> {
+    match value {
+        "v1" => Ok(ProxyProtocol::V1),
+        "v2" => Ok(ProxyProtocol::V2),
+        _ => bail!("expected v1 or v2"),
+    }
+}
+
+/// Parse a network in CIDR notation, or a single IP address.
+fn parse_cidr(value: &str) -> Result<IpNet> {
+    match value.parse::<IpAddr>() {
+        Ok(ip) => Ok(ip.into()),
+        Err(_) => value
+            .parse()
+            .context("expected an IP address or CIDR network"),
+    }
+}
+
+/// Parse a duration such as `500ms`, `30s`, `10m`, `1h` or `30d`, defaulting to seconds.
+fn parse_duration(value: &str) -> Result<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().context("expected a number")?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        "h" => Ok(Duration::from_secs(number * 3600)),
+        "d" => Ok(Duration::from_secs(number * 86400)),
+        _ => bail!("unknown unit {unit:?}, expected one of ms, s, m, h, d"),
+    }
+}
+
+/// Parse a duration that must be greater than zero.
+fn parse_interval(value: &str) -> Result<Duration> {
+    let interval = parse_duration(value)?;
+    if interval.is_zero() {
+        bail!("must be greater than zero");
+    }
+    Ok(interval)
 }
 
 #[tokio::main]
@@ -55,32 +1079,1379 @@ async fn run(command: Command) -> Result<()> {
         Command::Local {
             local_host,
             local_port,
+            local_socket,
             to,
             port,
-            secret,
+            port_range,
+            map,
+            port_block,
+            backend,
+            sni_route,
+            sni_default,
+            bind_addr,
+            remote_bind,
+            strict,
+            udp,
+            proxy_protocol,
+            max_rate,
+            stats_interval,
+            compress,
+            encrypt_data,
+            subdomain,
+            subdomain_prefix,
+            remote_socket,
+            sticky,
+            sticky_key,
+            reclaim,
+            name,
+            allow_cidr,
+            deny_cidr,
+            reconnect,
+            strict_port,
+            max_retries,
+            secret: SecretArgs { secret, .. },
+            token,
+            tls,
+            tls_ca,
+            tls_insecure,
+            proxy,
+            socks5,
+            transport,
+            ws_port,
+            timeout,
+            connect_timeout,
+            local_timeout,
+            accept_timeout,
+            local_retry,
+            local_retry_attempts,
+            local_retry_delay,
+            wait_local,
+            tcp_keepalive,
+            tcp_nodelay,
+            source_addr,
+            bind_interface,
+            local_bind_addr,
+            heartbeat_interval,
+            heartbeat_timeout,
+            ping_interval,
+            ping_timeout,
+            drain_timeout,
+            json,
+            events,
+            on_connect,
+            on_disconnect,
+            ..
         } => {
-            let client = Client::new(&local_host, local_port, &to, port, secret.as_deref()).await?;
-            client.listen().await?;
+            let to = to.expect("server address is required unless stopping");
+            #[cfg(not(unix))]
+            if local_socket.is_some() {
+                Args::command()
+                    .error(
+                        ErrorKind::InvalidValue,
+                        "Unix sockets are not supported on this platform",
+                    )
+                    .exit();
+            }
+            let mapping = if udp { Mapping::udp } else { Mapping::new };
+            let local = match (local_socket, local_port) {
+                (Some(path), _) => Some(Mapping::with_target(LocalTarget::Unix(path), port)),
+                (None, Some(local_port)) => Some(mapping(&local_host, local_port, port)),
+                (None, None) if !sni_route.is_empty() => Some(Mapping {
+                    sni_routes: sni_route,
+                    sni_default,
+                    ..Mapping::new(&local_host, 0, port)
+                }),
+                (None, None) => None,
+            };
+            let (subdomain, subdomain_prefix) = match (subdomain, subdomain_prefix) {
+                (Some(name), Some(_)) if !name.is_empty() => {
+                    Args::command()
+                        .error(
+                            ErrorKind::ArgumentConflict,
+                            "--subdomain-prefix cannot be used with a subdomain name",
+                        )
+                        .exit();
+                }
+                (Some(name), None) if !name.is_empty() => (Some(name), None),
+                (None, None) => (None, None),
+                (_, prefix) => (None, Some(prefix.unwrap_or_default())),
+            };
+            let mut mappings = Vec::new();
+            if let Some(local) = local {
+                mappings.push(Mapping {
+                    remote_range: port_range,
+                    bind_addr,
+                    subdomain,
+                    subdomain_prefix,
+                    remote_socket: remote_socket.map(PathBuf::from),
+                    name,
+                    backends: backend
+                        .into_iter()
+                        .map(|(host, port)| (host.unwrap_or_else(|| local_host.clone()), port))
+                        .collect(),
+                    ..local
+                });
+            }
+            for (host, local_port, remote_port, addr) in map {
+                let host = host.as_deref().unwrap_or(&local_host);
+                mappings.push(Mapping {
+                    bind_addr: addr.or(bind_addr),
+                    ..mapping(host, local_port, remote_port)
+                });
+            }
+            for ports in port_block {
+                for block in Mapping::block(&local_host, ports) {
+                    mappings.push(Mapping {
+                        protocol: if udp { Protocol::Udp } else { Protocol::Tcp },
+                        bind_addr,
+                        ..block
+                    });
+                }
+            }
+            let ip_filter = IpFilter::new(allow_cidr, deny_cidr);
+            for mapping in &mut mappings {
+                mapping.proxy_protocol = proxy_protocol;
+                mapping.max_rate = max_rate;
+                mapping.stats_interval = stats_interval;
+                mapping.compress = compress;
+                mapping.encrypt = encrypt_data;
+                mapping.reclaim = reclaim;
+                mapping.family = remote_bind.map(|family| match family {
+                    RemoteBind::V4only => IpFamily::V4,
+                    RemoteBind::V6only => IpFamily::V6,
+                });
+                if sticky {
+                    mapping.sticky = Some(sticky_key.clone().unwrap_or_default());
+                }
+                mapping.ip_filter = ip_filter.clone();
+            }
+            let tls = match (tls, tls_ca) {
+                (false, _) => None,
+                (true, Some(ca)) => Some(ClientTls::with_ca_file(ca)?),
+                (true, None) if tls_insecure => Some(ClientTls::insecure()),
+                (true, None) => Some(ClientTls::new()),
+            };
+            let mut builder = ClientBuilder::new(&to)
+                .strict(strict)
+                .wait_local(wait_local)
+                .local_retry_delay(local_retry_delay)
+                .timeout(timeout)
+                .data_channel_timeout(accept_timeout)
+                .tcp_options(TcpOptions {
+                    keepalive: tcp_keepalive,
+                    nodelay: tcp_nodelay,
+                });
+            if let Some(timeout) = connect_timeout {
+                builder = builder.connect_timeout(timeout);
+            }
+            if let Some(timeout) = local_timeout {
+                builder = builder.local_connect_timeout(timeout);
+            }
+            if let Some(window) = local_retry {
+                builder = builder.local_retry(window);
+            }
+            if let Some(attempts) = local_retry_attempts {
+                builder = builder.local_retry_attempts(attempts);
+            }
+            if let Some(addr) = source_addr {
+                builder = builder.source_addr(addr);
+            }
+            if let Some(interface) = &bind_interface {
+                builder = builder.bind_interface(interface);
+            }
+            if let Some(addr) = local_bind_addr {
+                builder = builder.local_bind_addr(addr);
+            }
+            for mapping in mappings {
+                builder = builder.mapping(mapping);
+            }
+            if let Some(secret) = &secret {
+                builder = builder.secret(secret);
+            }
+            if let Some(token) = &token {
+                builder = builder.token(token);
+            }
+            let proxy = match proxy.or(socks5) {
+                Some(proxy) => Some(proxy),
+                None => Proxy::from_env()?,
+            };
+            if let Some(proxy) = proxy {
+                info!(%proxy, "connecting through proxy");
+                builder = builder.proxy(proxy);
+            }
+            if let Transport::Ws = transport {
+                let default_port = if tls.is_some() { 443 } else { 80 };
+                let port = ws_port.unwrap_or(default_port);
+                builder = builder.transport(TransportKind::WebSocket(port));
+            }
+            if let Some(tls) = tls {
+                builder = builder.tls(tls);
+            }
+            let print_events = matches!(events, Some(EventFormat::Json));
+            if json || print_events || on_connect.is_some() || on_disconnect.is_some() {
+                let mut printed = HashMap::new();
+                builder = builder.on_event(move |event| {
+                    if print_events {
+                        print_event(&event);
+                    }
+                    if let (true, ClientEvent::Connected { to, mapping }) = (json, &event) {
+                        // Print tunnels again after reconnecting only if their port changed.
+                        let key = (
+                            mapping.local_host.clone(),
+                            mapping.local_port,
+                            mapping.protocol,
+                        );
+                        if printed.insert(key, mapping.remote_port) != Some(mapping.remote_port) {
+                            println!("{}", tunnel_json(to, mapping));
+                        }
+                    }
+                    let (command, to, mapping) = match &event {
+                        ClientEvent::Connected { to, mapping } => (&on_connect, to, mapping),
+                        ClientEvent::Disconnected { to, mapping, .. } => {
+                            (&on_disconnect, to, mapping)
+                        }
+                        _ => return,
+                    };
+                    if let Some(command) = command {
+                        run_hook(command, to, mapping);
+                    }
+                });
+            }
+            if reconnect {
+                let backoff = Backoff {
+                    max_attempts: max_retries,
+                    ..Default::default()
+                };
+                builder = builder.reconnect(backoff).strict_port(strict_port);
+            }
+            let result = async {
+                let mut client = builder.build().await?;
+                let shutdown = CancellationToken::new();
+                client.set_shutdown(shutdown.clone());
+                client.set_drain_timeout(drain_timeout);
+                client.set_heartbeat_interval(heartbeat_interval);
+                client.set_heartbeat_timeout(heartbeat_timeout);
+                if let Some(interval) = ping_interval {
+                    client.set_ping_interval(interval, ping_timeout);
+                }
+                tokio::spawn(async move {
+                    match shutdown_signal().await {
+                        Ok(()) => shutdown.cancel(),
+                        Err(err) => warn!(%err, "could not listen for shutdown signals"),
+                    }
+                });
+                client.listen().await
+            }
+            .await;
+            if let (true, Err(err)) = (print_events, &result) {
+                let event = serde_json::json!({ "type": "error", "error": format!("{err:#}") });
+                println!("{event}");
+            }
+            result?;
         }
         Command::Server {
             min_port,
             max_port,
-            secret,
+            open_port_range,
+            secret: SecretArgs { secret, .. },
+            secrets_file,
+            secret_overlap,
+            auth_mode,
+            token_key,
+            udp,
+            compress,
+            encrypt_data,
+            udp_idle_timeout,
+            tls_cert,
+            tls_key,
+            rate_limit,
+            rate_limit_up,
+            rate_limit_down,
+            port_strategy,
+            ephemeral,
+            port_cooldown,
+            port_retention,
+            reserve,
+            quota,
+            tunnel_limit,
+            quota_period,
+            quota_file,
+            access_log,
+            access_log_max_size,
+            control_addr,
+            tunnels_addr,
+            v6only,
+            allowed_tunnel_addrs,
+            allow,
+            deny,
+            geoip_db,
+            geoip_allow,
+            geoip_deny,
+            geoip_deny_unknown,
+            max_tunnel_lifetime,
+            idle_timeout,
+            tunnel_idle_timeout,
+            max_conns_per_tunnel,
+            max_tunnels_per_client,
+            max_tunnels,
+            pending_conns_limit,
+            handshake_rate_limit,
+            ban_after,
+            ban_duration,
+            http_addr,
+            https_addr,
+            ws_addr,
+            socket_dir,
+            metrics_addr,
+            admin_addr,
+            admin_socket,
+            timeout,
+            max_message_size,
+            tcp_keepalive,
+            tcp_nodelay,
+            heartbeat_interval,
+            heartbeat_timeout,
+            drain_timeout,
+            connection_grace,
         } => {
+            // A range of only port 0 leaves every choice of port to the system.
+            let ephemeral = ephemeral || (min_port, max_port) == (0, 0);
             let port_range = min_port..=max_port;
             if port_range.is_empty() {
                 Args::command()
                     .error(ErrorKind::InvalidValue, "port range is empty")
                     .exit();
             }
-            Server::new(port_range, secret.as_deref()).listen().await?;
+            if auth_mode != AuthMode::Token && token_key.is_some() {
+                Args::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "--token-key can only be used with --auth-mode token",
+                    )
+                    .exit();
+            }
+            let mut server = Server::new(port_range, secret.as_deref());
+            if let Some(range) = open_port_range {
+                server.set_open_port_range(range);
+            }
+            if let Some(key) = token_key {
+                if tls_cert.is_none() {
+                    warn!("tokens are sent in the clear without TLS, and anyone who sees one can reuse it until it expires");
+                }
+                server.set_authenticator(key);
+            }
+            if let Some(overlap) = secret_overlap {
+                server.set_secret_overlap(overlap);
+            }
+            if let Some(path) = secrets_file {
+                server.set_secrets(load_secrets(&path, secret.as_deref())?);
+                #[cfg(unix)]
+                reload_secrets_on_hangup(server.clone(), path, secret)?;
+            }
+            server.set_udp(udp);
+            server.set_compress(compress);
+            server.set_encrypt_data(encrypt_data);
+            server.set_udp_idle_timeout(udp_idle_timeout);
+            if let (Some(cert), Some(key)) = (tls_cert, tls_key) {
+                server.set_tls(ServerTls::from_pem_files(cert, key)?);
+            }
+            server.set_rate_limit(rate_limit_up.or(rate_limit), rate_limit_down.or(rate_limit));
+            server.set_port_strategy(match port_strategy {
+                PortStrategy::Random => server::PortStrategy::Random,
+                PortStrategy::Sequential => server::PortStrategy::Sequential,
+                PortStrategy::Lru => server::PortStrategy::Lru,
+            });
+            server.set_ephemeral_ports(ephemeral);
+            if let Some(cooldown) = port_cooldown {
+                server.set_port_cooldown(cooldown);
+            }
+            if let Some(retention) = port_retention {
+                server.set_port_retention(retention);
+            }
+            server.set_reservations(reserve);
+            server.set_tunnel_limits(tunnel_limit);
+            server.set_quotas(quota);
+            if let Some(period) = quota_period {
+                server.set_quota_period(period);
+            }
+            if let Some(path) = quota_file {
+                server.set_quota_file(path);
+            }
+            if let Some(path) = access_log {
+                server.set_access_log(path);
+                if let Some(max_size) = access_log_max_size {
+                    server.set_access_log_max_size(max_size);
+                }
+                #[cfg(unix)]
+                reopen_access_log_on_usr1(server.clone())?;
+            }
+            if !control_addr.is_empty() {
+                server.set_control_addrs(control_addr);
+            }
+            if !tunnels_addr.is_empty() {
+                server.set_tunnel_addrs(tunnels_addr);
+            }
+            if let Some(v6only) = v6only {
+                server.set_v6only(v6only);
+            }
+            server.set_allowed_tunnel_addrs(allowed_tunnel_addrs);
+            server.set_ip_filter(IpFilter::new(allow, deny));
+            if let Some(path) = geoip_db {
+                let mut filter = GeoIpFilter::new(open_geoip_db(&path)?, geoip_allow, geoip_deny);
+                filter.set_deny_unknown(geoip_deny_unknown);
+                server.set_geoip_filter(filter);
+            }
+            if let Some(lifetime) = max_tunnel_lifetime {
+                server.set_max_tunnel_lifetime(lifetime);
+            }
+            if let Some(timeout) = idle_timeout {
+                server.set_idle_timeout(timeout);
+            }
+            if let Some(timeout) = tunnel_idle_timeout {
+                server.set_tunnel_idle_timeout(timeout);
+            }
+            if let Some(max) = max_conns_per_tunnel {
+                server.set_max_conns_per_tunnel(max);
+            }
+            if let Some(max) = max_tunnels_per_client {
+                server.set_max_tunnels_per_client(max);
+            }
+            if let Some(max) = max_tunnels {
+                server.set_max_tunnels(max);
+            }
+            server.set_pending_conns_limit(pending_conns_limit as usize);
+            if let Some(per_sec) = handshake_rate_limit {
+                server.set_handshake_rate_limit(per_sec);
+            }
+            if let Some(failures) = ban_after {
+                server.set_ban(failures, ban_duration);
+            }
+            if let Some(addr) = http_addr {
+                server.set_http_addr(addr);
+            }
+            if let Some(addr) = https_addr {
+                server.set_https_addr(addr);
+            }
+            if let Some(addr) = ws_addr {
+                server.set_ws_addr(addr);
+            }
+            #[cfg(not(unix))]
+            if socket_dir.is_some() {
+                Args::command()
+                    .error(
+                        ErrorKind::InvalidValue,
+                        "Unix sockets are not supported on this platform",
+                    )
+                    .exit();
+            }
+            if let Some(dir) = socket_dir {
+                server.set_socket_dir(dir);
+            }
+            if let Some(addr) = metrics_addr {
+                server.set_metrics_addr(addr);
+            }
+            if let Some(addr) = admin_addr {
+                if !addr.ip().is_loopback() {
+                    Args::command()
+                        .error(
+                            ErrorKind::InvalidValue,
+                            "--admin-addr must be a loopback address",
+                        )
+                        .exit();
+                }
+                server.set_admin_addr(addr);
+            }
+            #[cfg(not(unix))]
+            if admin_socket.is_some() {
+                Args::command()
+                    .error(
+                        ErrorKind::InvalidValue,
+                        "Unix sockets are not supported on this platform",
+                    )
+                    .exit();
+            }
+            if let Some(path) = admin_socket {
+                server.set_admin_socket(path);
+            }
+            server.set_network_timeout(timeout);
+            server.set_max_frame_length(max_message_size.try_into().unwrap_or(usize::MAX));
+            server.set_tcp_options(TcpOptions {
+                keepalive: tcp_keepalive,
+                nodelay: tcp_nodelay,
+            });
+            server.set_heartbeat_interval(heartbeat_interval);
+            server.set_heartbeat_timeout(heartbeat_timeout);
+            server.set_drain_timeout(drain_timeout);
+            server.set_connection_grace(connection_grace);
+            tokio::select! {
+                result = server.listen() => result?,
+                result = shutdown_signal() => {
+                    result?;
+                    tokio::select! {
+                        _ = server.shutdown() => (),
+                        result = shutdown_signal() => {
+                            result?;
+                            warn!("signaled again, exiting without waiting for connections");
+                        }
+                    }
+                }
+            }
+        }
+        Command::Status { server, json } => {
+            let tunnels = server.to_builder()?.status().await?;
+            if json {
+                let tunnels: Vec<_> = tunnels
+                    .iter()
+                    .map(|tunnel| {
+                        serde_json::json!({
+                            "port": tunnel.port,
+                            "protocol": protocol_name(tunnel.protocol),
+                            "connections": tunnel.connections,
+                            "uptime_secs": tunnel.uptime_secs,
+                        })
+                    })
+                    .collect();
+                println!("{}", serde_json::Value::from(tunnels));
+            } else {
+                print_status(&tunnels);
+            }
+        }
+        Command::Ping {
+            server,
+            count,
+            port_test,
+        } => std::process::exit(ping(server, count, port_test).await),
+        Command::Admin {
+            admin_addr,
+            admin_socket,
+            command,
+        } => {
+            let addr = match (admin_addr, admin_socket) {
+                (Some(addr), _) => AdminAddr::Tcp(addr),
+                (None, Some(path)) => AdminAddr::Unix(path),
+                (None, None) => unreachable!("clap requires an admin address"),
+            };
+            match command {
+                AdminCommand::List => print_tunnels(&admin::list(&addr).await?),
+                AdminCommand::Kick { port } => {
+                    admin::close(&addr, port).await?;
+                    println!("closed tunnels on port {port}");
+                }
+                AdminCommand::Clients => print_clients(&admin::clients(&addr).await?),
+                AdminCommand::Quotas => print_quotas(&admin::quotas(&addr).await?),
+                AdminCommand::QuotaReset => {
+                    admin::reset_quotas(&addr).await?;
+                    println!("reset quotas");
+                }
+            }
         }
     }
 
     Ok(())
 }
 
+/// Print the tunnels listed by the admin API as a table.
+fn print_tunnels(tunnels: &[TunnelInfo]) {
+    println!(
+        "{:<7} {:<8} {:<16} {:>10} {:>6} {:>14} {:>14}",
+        "PORT", "PROTOCOL", "IDENTITY", "UPTIME", "CONNS", "BYTES UP", "BYTES DOWN"
+    );
+    for tunnel in tunnels {
+        println!(
+            "{:<7} {:<8} {:<16} {:>10} {:>6} {:>14} {:>14}",
+            tunnel.port,
+            protocol_name(tunnel.protocol),
+            tunnel.identity.as_deref().unwrap_or("-"),
+            format!("{}s", tunnel.uptime_secs),
+            tunnel.connections,
+            tunnel.bytes_up,
+            tunnel.bytes_down,
+        );
+    }
+}
+
+/// Probe a server for `bore ping`, printing a summary and returning the exit code.
+async fn ping(server: ServerArgs, count: u32, port_test: bool) -> i32 {
+    let credentials = server.secret.secret.is_some() || server.token.is_some();
+    let failed = |err: anyhow::Error| {
+        let (reachable, auth, code) = match err.downcast_ref::<ClientError>() {
+            Some(ClientError::Unreachable) => ("no", "-", 3),
+            Some(ClientError::AuthFailed) => ("yes", "failed", 4),
+            _ => ("yes", "-", 5),
+        };
+        println!("reachable: {reachable}");
+        println!("auth:      {auth}");
+        println!("error:     {err:#}");
+        code
+    };
+    let result = match server.to_builder() {
+        Ok(builder) => builder.ping(count).await,
+        Err(err) => Err(err),
+    };
+    let rtts = match result {
+        Ok(rtts) => rtts,
+        Err(err) => return failed(err),
+    };
+    println!("reachable: yes");
+    println!("auth:      {}", if credentials { "ok" } else { "not used" });
+    if let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) {
+        let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+        println!("rtt:       min {min:.1?} / avg {avg:.1?} / max {max:.1?}");
+    }
+    if !port_test {
+        return 0;
+    }
+    let result = match server.to_builder() {
+        Ok(builder) => builder.local_port(0).build().await,
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(client) => {
+            println!("port:      {} allocated and released", client.remote_port());
+            0
+        }
+        Err(err) => {
+            println!("port:      failed ({err:#})");
+            6
+        }
+    }
+}
+
+/// Print the tunnels listed by a server's status reply as a table.
+fn print_status(tunnels: &[TunnelStatus]) {
+    println!(
+        "{:<7} {:<8} {:>6} {:>10} {:<5}",
+        "PORT", "PROTOCOL", "CONNS", "UPTIME", "OWNED"
+    );
+    for tunnel in tunnels {
+        println!(
+            "{:<7} {:<8} {:>6} {:>10} {:<5}",
+            tunnel.port,
+            protocol_name(tunnel.protocol),
+            tunnel.connections,
+            format!("{}s", tunnel.uptime_secs),
+            if tunnel.owned { "yes" } else { "no" },
+        );
+    }
+}
+
+/// Print the clients listed by the admin API as a table.
+fn print_clients(clients: &[ClientInfo]) {
+    println!("{:<40} {:>7}", "CLIENT", "TUNNELS");
+    for client in clients {
+        println!("{:<40} {:>7}", client.client, client.tunnels);
+    }
+}
+
+/// Print the quotas listed by the admin API as a table.
+fn print_quotas(quotas: &[QuotaInfo]) {
+    println!("{:<16} {:>14} {:>14}", "IDENTITY", "BYTES USED", "QUOTA");
+    for quota in quotas {
+        let limit = quota.bytes_quota.map(|limit| limit.to_string());
+        println!(
+            "{:<16} {:>14} {:>14}",
+            quota.identity,
+            quota.bytes_used,
+            limit.as_deref().unwrap_or("-"),
+        );
+    }
+}
+
+/// Read the secrets file, along with the secret given on the command line, if any.
+///
+/// Each line holds a `LABEL = SECRET` pair, or just a secret without `=`, which is then
+/// identified by its fingerprint.
+fn load_secrets(path: &Path, secret: Option<&str>) -> Result<Keyring> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("could not read secrets file {}", path.display()))?;
+    let mut keyring = Keyring::new(secret);
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let context = || format!("invalid line {} of secrets file", number + 1);
+        match line.split_once('=') {
+            Some((name, secret)) => {
+                let mut name = name.split_whitespace();
+                let (label, ports, secret) = (name.next(), name.next(), secret.trim());
+                let (Some(label), None, false) = (label, name.next(), secret.is_empty()) else {
+                    return Err(anyhow!("expected LABEL [PORTS] = SECRET")).with_context(context);
+                };
+                keyring.insert(label, secret).with_context(context)?;
+                if let Some(ports) = ports {
+                    let ports = parse_ports(ports).with_context(context)?;
+                    keyring.restrict_ports(label, ports);
+                }
+            }
+            None => keyring
+                .insert(&fingerprint(line), line)
+                .with_context(context)?,
+        }
+    }
+    if keyring.is_empty() {
+        warn!("no secrets configured, all clients will be rejected");
+    }
+    Ok(keyring)
+}
+
+/// Reload the secrets file whenever the process receives SIGHUP.
+#[cfg(unix)]
+fn reload_secrets_on_hangup(server: Server, path: PathBuf, secret: Option<String>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match load_secrets(&path, secret.as_deref()) {
+                Ok(keyring) => {
+                    let secrets = keyring.len();
+                    info!(secrets, "reloaded {secrets} secrets");
+                    server.rotate_secrets(keyring);
+                }
+                Err(err) => warn!(%err, "could not reload secrets file"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Reopen the access log whenever the process receives SIGUSR1, for logrotate.
+#[cfg(unix)]
+fn reopen_access_log_on_usr1(server: Server) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while usr1.recv().await.is_some() {
+            info!("reopening access log");
+            server.reopen_access_log();
+        }
+    });
+    Ok(())
+}
+
+/// Name of a protocol in lowercase, for scripts.
+fn protocol_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Tcp => "tcp",
+        Protocol::Udp => "udp",
+    }
+}
+
+/// Describe the address of a tunnel as a JSON object.
+fn tunnel_json(to: &str, mapping: &Mapping) -> serde_json::Value {
+    let mut tunnel = serde_json::json!({
+        "remote_host": to,
+        "remote_port": mapping.remote_port,
+        "local_host": mapping.local_host,
+        "local_port": mapping.local_port,
+        "protocol": protocol_name(mapping.protocol),
+    });
+    if let Some(path) = &mapping.local_socket {
+        tunnel["local_socket"] = path.display().to_string().into();
+    }
+    if let Some(path) = &mapping.remote_socket {
+        tunnel["remote_socket"] = path.display().to_string().into();
+    }
+    if let Some(subdomain) = &mapping.subdomain {
+        tunnel["url"] = format!("http://{subdomain}.{to}:{}", mapping.remote_port).into();
+    }
+    tunnel
+}
+
+/// Print a client event as a line of JSON on stdout, with its kind under `type`.
+fn print_event(event: &ClientEvent) {
+    let event = match event {
+        ClientEvent::Connected { to, mapping } => {
+            let mut event = tunnel_json(to, mapping);
+            event["type"] = "connected".into();
+            event
+        }
+        ClientEvent::Disconnected {
+            to,
+            mapping,
+            reason,
+        } => {
+            let mut event = tunnel_json(to, mapping);
+            event["type"] = "disconnected".into();
+            event["reason"] = reason.clone().into();
+            event
+        }
+        ClientEvent::ConnectionOpened { id, peer } => serde_json::json!({
+            "type": "connection_opened",
+            "id": id,
+            "peer": peer.map(|peer| peer.to_string()),
+        }),
+        ClientEvent::ConnectionClosed {
+            id,
+            bytes_in,
+            bytes_out,
+        } => serde_json::json!({
+            "type": "connection_closed",
+            "id": id,
+            "bytes_in": bytes_in,
+            "bytes_out": bytes_out,
+        }),
+        ClientEvent::Reconnecting { to, attempt, delay } => serde_json::json!({
+            "type": "reconnecting",
+            "remote_host": to,
+            "attempt": attempt,
+            "delay_ms": delay.as_millis() as u64,
+        }),
+        ClientEvent::Stats(stats) => serde_json::json!({
+            "type": "stats",
+            "tunnel": stats.tunnel,
+            "protocol": protocol_name(stats.protocol),
+            "active_connections": stats.active_connections,
+            "total_connections": stats.total_connections,
+            "bytes_in": stats.bytes_in,
+            "bytes_out": stats.bytes_out,
+        }),
+    };
+    println!("{event}");
+}
+
+/// Run a hook command for a tunnel event in the background, logging any failure.
+///
+/// The command is run by the shell, with the tunnel's details in environment variables.
+fn run_hook(command: &str, to: &str, mapping: &Mapping) {
+    let mut shell = if cfg!(windows) {
+        let mut shell = tokio::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = tokio::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell
+        .arg(command)
+        .env("BORE_REMOTE_HOST", to)
+        .env("BORE_REMOTE_PORT", mapping.remote_port.to_string())
+        .env("BORE_LOCAL_HOST", &mapping.local_host)
+        .env("BORE_LOCAL_PORT", mapping.local_port.to_string())
+        .env("BORE_PROTOCOL", protocol_name(mapping.protocol))
+        .envs(
+            mapping
+                .local_socket
+                .iter()
+                .map(|path| ("BORE_LOCAL_SOCKET", path)),
+        )
+        .envs(
+            mapping
+                .remote_socket
+                .iter()
+                .map(|path| ("BORE_REMOTE_SOCKET", path)),
+        )
+        .stdin(Stdio::null());
+    let mut child = match shell.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            warn!(%err, command, "could not run hook command");
+            return;
+        }
+    };
+    let command = command.to_string();
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!(%status, command, "hook command failed"),
+            Err(err) => warn!(%err, command, "could not wait for hook command"),
+        }
+    });
+}
+
+/// Open a GeoIP database, mapped into memory on Unix rather than read.
+#[cfg(unix)]
+fn open_geoip_db(path: &Path) -> Result<GeoIpDb> {
+    let map = mmap::Mmap::open(path)
+        .with_context(|| format!("could not read GeoIP database {}", path.display()))?;
+    GeoIpDb::from_source(map).with_context(|| format!("invalid GeoIP database {}", path.display()))
+}
+
+#[cfg(not(unix))]
+fn open_geoip_db(path: &Path) -> Result<GeoIpDb> {
+    GeoIpDb::open(path)
+}
+
+/// Wait for a signal asking the process to shut down, such as Ctrl-C or SIGTERM, or
+/// for the service control manager to stop the service on Windows.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = daemon::service_stopped() => {}
+    }
+    Ok(())
+}
+
+/// Parse the command-line arguments, filling in options from the config file if given.
+///
+/// Options from the file are passed to clap as if they had been given as flags after
+/// the command line, so they are validated the same way and take precedence over
+/// environment variables. Options that are already on the command line, or that cannot
+/// be used along with those that are, are skipped.
+fn parse_args() -> Result<Args> {
+    let argv = with_config(env::args_os().collect())?;
+    Ok(Args::parse_from(argv))
+}
+
+/// Append the options in the config file to command-line arguments, if one is given.
+fn with_config(mut argv: Vec<OsString>) -> Result<Vec<OsString>> {
+    let cmd = Args::command().ignore_errors(true);
+    let matches = cmd
+        .try_get_matches_from(&argv)
+        .unwrap_or_else(|err| err.exit());
+    let (Some(path), Some((name, sub_matches))) =
+        (matches.get_one::<PathBuf>("config"), matches.subcommand())
+    else {
+        return Ok(argv);
+    };
+
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("could not read config file {}", path.display()))?;
+    let table: toml::Table = text
+        .parse()
+        .with_context(|| format!("could not parse config file {}", path.display()))?;
+    let mut cmd = Args::command();
+    cmd.build();
+    let sub = cmd.find_subcommand(name).expect("subcommand was parsed");
+    argv.extend(config_args(sub, sub_matches, table)?);
+    Ok(argv)
+}
+
+/// Convert the options in a config file into arguments for a subcommand.
+fn config_args(
+    sub: &clap::Command,
+    matches: &ArgMatches,
+    table: toml::Table,
+) -> Result<Vec<OsString>> {
+    let on_command_line = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let given: Vec<&clap::Arg> = sub
+        .get_arguments()
+        .filter(|arg| on_command_line(arg.get_id().as_str()))
+        .collect();
+    // Groups of which only one argument may be given, like the ways to pass the secret.
+    let exclusive: Vec<Vec<&clap::Id>> = sub
+        .get_groups()
+        .filter(|group| !(*group).clone().is_multiple())
+        .map(|group| group.get_args().collect())
+        .collect();
+    let overridden = |arg: &clap::Arg| {
+        let id = arg.get_id();
+        given.iter().any(|other| {
+            let conflicts = |a: &clap::Arg, b: &clap::Arg| {
+                sub.get_arg_conflicts_with(a)
+                    .iter()
+                    .any(|c| c.get_id() == b.get_id())
+            };
+            other.get_id() == id
+                || conflicts(arg, other)
+                || conflicts(other, arg)
+                || exclusive
+                    .iter()
+                    .any(|group| group.contains(&id) && group.contains(&other.get_id()))
+        })
+    };
+    let mut args = Vec::new();
+    for (key, value) in table {
+        if key == "tunnels" && sub.get_name() == "local" {
+            if !on_command_line("map") {
+                args.extend(tunnel_args(value)?);
+            }
+            continue;
+        }
+        // Keys may be spelled like the flags or like the fields they fill in.
+        let flag = key.replace('_', "-");
+        let arg = sub
+            .get_arguments()
+            .find(|arg| match arg.get_long() {
+                Some(long) => long == flag && !matches!(long, "config" | "help"),
+                None => arg.is_positional() && arg.get_id() == key.replace('-', "_").as_str(),
+            })
+            .with_context(|| format!("unknown key `{key}` in config file"))?;
+        if overridden(arg) {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Boolean(value) if !arg.get_action().takes_values() => {
+                    if value {
+                        args.push(format!("--{flag}").into());
+                    }
+                    continue;
+                }
+                toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => {
+                    value.to_string()
+                }
+                _ => bail!("unsupported value for key `{key}` in config file"),
+            };
+            if !arg.get_action().takes_values() {
+                bail!("expected true or false for key `{key}` in config file");
+            }
+            match arg.get_long() {
+                Some(_) => args.push(format!("--{flag}={value}").into()),
+                None => args.push(value.into()),
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Convert the named tunnels of a client config file into `--map` arguments.
+fn tunnel_args(tunnels: toml::Value) -> Result<Vec<OsString>> {
+    let toml::Value::Table(tunnels) = tunnels else {
+        bail!("expected a table of named tunnels for key `tunnels` in config file");
+    };
+    let mut args = Vec::new();
+    for (name, tunnel) in tunnels {
+        let toml::Value::Table(tunnel) = tunnel else {
+            bail!("expected a table for tunnel `{name}` in config file");
+        };
+        let (mut host, mut local_port, mut remote_port, mut bind_addr) = (None, None, 0, None);
+        for (key, value) in tunnel {
+            let port = |value: &toml::Value| {
+                value
+                    .as_integer()
+                    .and_then(|port| u16::try_from(port).ok())
+                    .with_context(|| format!("invalid port for key `tunnels.{name}.{key}`"))
+            };
+            let string = |value: &toml::Value| {
+                value
+                    .as_str()
+                    .map(String::from)
+                    .with_context(|| format!("expected a string for key `tunnels.{name}.{key}`"))
+            };
+            match key.replace('_', "-").as_str() {
+                "local-host" => host = Some(string(&value)?),
+                "bind-addr" => bind_addr = Some(string(&value)?),
+                "local-port" => local_port = Some(port(&value)?),
+                "remote-port" => remote_port = port(&value)?,
+                _ => bail!("unknown key `tunnels.{name}.{key}` in config file"),
+            }
+        }
+        let local_port = local_port
+            .with_context(|| format!("missing key `tunnels.{name}.local-port` in config file"))?;
+        let mut mapping = match host {
+            Some(host) => format!("[{host}]:{local_port}:{remote_port}"),
+            None => format!("{local_port}:{remote_port}"),
+        };
+        if let Some(addr) = bind_addr {
+            mapping = format!("{mapping}@{addr}");
+        }
+        args.push(format!("--map={mapping}").into());
+    }
+    Ok(args)
+}
+
 fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    run(Args::parse().command)
+    let mut args = parse_args()?;
+    // Read the secret while standard input is still there, before detaching.
+    if let Some(secret) = args.command.secret_args() {
+        secret.load()?;
+    }
+    let mut log_file = None;
+    let mut pid_file = None;
+    let mut detached = false;
+    let mut service_name = None;
+    if let Command::Local {
+        daemon,
+        pid_file: pid_path,
+        log_file: log_path,
+        stop,
+        drain_timeout,
+        install_service,
+        uninstall_service,
+        service,
+        ..
+    } = &args.command
+    {
+        if let (true, Some(path)) = (stop, pid_path) {
+            // Give the client time to drain its connections before giving up on it.
+            return daemon::stop(path, *drain_timeout + Duration::from_secs(5));
+        }
+        if let Some(name) = install_service {
+            let exe = env::current_exe().context("could not find the bore executable")?;
+            let command = daemon::service_command(&exe, env::args_os().skip(1), name)?;
+            daemon::install_service(name, &command)?;
+            println!("installed service {name}, running {command}");
+            return Ok(());
+        }
+        if let Some(name) = uninstall_service {
+            daemon::uninstall_service(name)?;
+            println!("removed service {name}");
+            return Ok(());
+        }
+        // Report problems with the files on the terminal, before detaching from it.
+        if let Some(path) = log_path {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("could not open log file {}", path.display()))?;
+            log_file = Some(Arc::new(file));
+        }
+        if let Some(path) = pid_path {
+            PidFile::check(path)?;
+        }
+        if *daemon {
+            daemon::detach()?;
+            detached = true;
+        }
+        if let Some(name) = service {
+            // A service has no terminal to print errors on either.
+            service_name = Some(name.clone());
+            detached = true;
+        }
+        pid_file = pid_path.as_deref().map(PidFile::create).transpose()?;
+    }
+    let ansi = log_file.is_none();
+    // Keep stdout clean for the tunnel addresses when they are printed as JSON.
+    let writer = match (log_file, &args.command) {
+        (Some(file), _) => BoxMakeWriter::new(file),
+        (None, Command::Local { json: true, .. })
+        | (
+            None,
+            Command::Local {
+                events: Some(_), ..
+            },
+        ) => BoxMakeWriter::new(io::stderr),
+        _ => BoxMakeWriter::new(io::stdout),
+    };
+    let logs = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match args.log_format {
+        LogFormat::Text => logs.init(),
+        LogFormat::Json => logs.json().flatten_event(true).init(),
+        LogFormat::Pretty => logs.pretty().init(),
+        LogFormat::Compact => logs.compact().init(),
+    }
+    if let Some(secret) = args.command.secret_args() {
+        secret.check_permissions();
+    }
+    let result = match service_name {
+        Some(name) => daemon::run_service(&name, move || run(args.command)),
+        None => run(args.command),
+    };
+    if let (true, Err(err)) = (detached, &result) {
+        // Errors printed on exit go nowhere once detached, so log them as well.
+        error!("{err:#}");
+    }
+    drop(pid_file);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    use super::*;
+
+    /// Clap reads defaults from the environment, so tests that parse, or change the
+    /// environment, must not run in parallel.
+    static SERIAL_GUARD: Mutex<()> = Mutex::new(());
+
+    fn serial() -> MutexGuard<'static, ()> {
+        SERIAL_GUARD.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Parse a command line for `bore local`, along with a config file holding `config`.
+    fn parse(args: &[&str], config: &str) -> Result<Command> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let n = FILES.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("bore-config-{}-{n}.toml", std::process::id()));
+        fs::write(&path, config)?;
+        let mut argv: Vec<OsString> = vec!["bore".into(), "--config".into(), path.clone().into()];
+        argv.push("local".into());
+        argv.extend(args.iter().map(OsString::from));
+        let result = with_config(argv).and_then(|argv| Ok(Args::try_parse_from(argv)?));
+        fs::remove_file(&path)?;
+        Ok(result?.command)
+    }
+
+    fn secret(command: &Command) -> Option<&str> {
+        match command {
+            Command::Local { secret, .. } => secret.secret.as_deref(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn accept_timeout_alias() -> Result<()> {
+        let _guard = serial();
+        let args = Args::try_parse_from(["bore", "server", "--accept-timeout", "2s"])?;
+        let Command::Server {
+            connection_grace, ..
+        } = args.command
+        else {
+            unreachable!();
+        };
+        assert_eq!(connection_grace, Duration::from_secs(2));
+        Ok(())
+    }
+
+    #[test]
+    fn service_flags() -> Result<()> {
+        let _guard = serial();
+        // Removing a service needs neither a local port nor a server.
+        let args = Args::try_parse_from(["bore", "local", "--uninstall-service", "bore"])?;
+        let Command::Local {
+            uninstall_service, ..
+        } = args.command
+        else {
+            unreachable!();
+        };
+        assert_eq!(uninstall_service.as_deref(), Some("bore"));
+        let local = ["bore", "local", "8000", "--to", "bore.pub"];
+        for flags in [
+            ["--install-service", "bore", "--daemon"],
+            ["--install-service", "bore", "--service=bore"],
+            ["--uninstall-service", "bore", "--install-service=bore"],
+        ] {
+            assert!(Args::try_parse_from(local.iter().chain(&flags)).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn config_unknown_keys() {
+        let _guard = serial();
+        let err = parse(&["8000", "--to", "x"], "colour = 1").unwrap_err();
+        assert_eq!(err.to_string(), "unknown key `colour` in config file");
+        let err = parse(&["8000", "--to", "x"], "config = \"other.toml\"").unwrap_err();
+        assert_eq!(err.to_string(), "unknown key `config` in config file");
+        let config = "[tunnels.web]\nlocal_port = 80\ncolour = 1";
+        let err = parse(&["--to", "x"], config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown key `tunnels.web.colour` in config file"
+        );
+        let err = parse(&["--to", "x"], "[tunnels.web]\nremote_port = 80").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "missing key `tunnels.web.local-port` in config file"
+        );
+    }
+
+    #[test]
+    fn config_precedence() -> Result<()> {
+        let _guard = serial();
+        // Options on the command line override the file, along with those they conflict
+        // with or share an exclusive group with.
+        let command = parse(
+            &["8000", "--to", "x", "--secret", "cli"],
+            "secret = \"file\"",
+        )?;
+        assert_eq!(secret(&command), Some("cli"));
+        let args = ["8000", "--to", "x", "--secret-file", "/dev/null"];
+        let Command::Local { secret: args, .. } = parse(&args, "secret = \"file\"")? else {
+            unreachable!();
+        };
+        assert_eq!(args.secret, None);
+        assert!(args.secret_file.is_some());
+        let config = "port_range = \"9000-9010\"";
+        let Command::Local {
+            port, port_range, ..
+        } = parse(&["8000", "--to", "x", "--port", "5"], config)?
+        else {
+            unreachable!();
+        };
+        assert_eq!((port, port_range), (5, None));
+
+        // The file overrides the environment.
+        env::set_var("BORE_SECRET", "env");
+        let from_env = parse(&["8000", "--to", "x"], "");
+        let from_file = parse(&["8000", "--to", "x"], "secret = \"file\"");
+        env::remove_var("BORE_SECRET");
+        assert_eq!(secret(&from_env?), Some("env"));
+        assert_eq!(secret(&from_file?), Some("file"));
+        Ok(())
+    }
+
+    #[test]
+    fn config_values() -> Result<()> {
+        let _guard = serial();
+        let config = "to = \"x\"\nlocal_port = 8000\ncompress = true\nreconnect = false\n\
+                      allow_cidr = [\"10.0.0.0/8\", \"192.168.0.0/16\"]\nport = 9000";
+        let Command::Local {
+            to,
+            local_port,
+            compress,
+            reconnect,
+            allow_cidr,
+            port,
+            ..
+        } = parse(&[], config)?
+        else {
+            unreachable!();
+        };
+        assert_eq!(to.as_deref(), Some("x"));
+        assert_eq!(local_port, Some(8000));
+        assert!(compress && !reconnect);
+        assert_eq!(
+            allow_cidr,
+            ["10.0.0.0/8".parse()?, "192.168.0.0/16".parse()?]
+        );
+        assert_eq!(port, 9000);
+
+        let err = parse(&["8000"], "to = \"x\"\ncompress = \"yes\"").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected true or false for key `compress` in config file"
+        );
+        let err = parse(&["8000"], "to = \"x\"\nport = { number = 1 }").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unsupported value for key `port` in config file"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn config_tunnels() -> Result<()> {
+        let _guard = serial();
+        let config = "to = \"x\"\n\
+                      [tunnels.web]\nlocal_port = 80\nremote_port = 8080\n\
+                      [tunnels.db]\nlocal-host = \"db.internal\"\nlocal_port = 5432\n\
+                      bind_addr = \"10.0.0.5\"";
+        let Command::Local { map, .. } = parse(&[], config)? else {
+            unreachable!();
+        };
+        assert_eq!(map.len(), 2);
+        assert!(map.contains(&(None, 80, 8080, None)));
+        assert!(map.contains(&(
+            Some("db.internal".into()),
+            5432,
+            0,
+            Some("10.0.0.5".parse()?)
+        )));
+
+        // Mappings on the command line replace the tunnels of the file.
+        let Command::Local { map, .. } = parse(&["--map", "3000:0"], config)? else {
+            unreachable!();
+        };
+        assert_eq!(map, [(None, 3000, 0, None)]);
+
+        let err = parse(&[], "to = \"x\"\ntunnels = 1").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected a table of named tunnels for key `tunnels` in config file"
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,214 @@
+//! Server metrics, served over HTTP in the Prometheus text format.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use dashmap::DashMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::shared::{Protocol, NETWORK_TIMEOUT};
+
+/// Maximum byte length of an HTTP request to the metrics endpoint.
+const MAX_REQUEST_LENGTH: usize = 8192;
+
+/// Counters and gauges describing the activity of a server.
+#[derive(Default)]
+pub struct Metrics {
+    /// Byte counters of the tunnels that are currently open.
+    tunnels: DashMap<(u16, Protocol), TunnelBytes>,
+
+    /// Number of connections and UDP flows currently being proxied.
+    connections: AtomicU64,
+
+    /// Handshakes on the control port that were accepted.
+    handshakes_accepted: AtomicU64,
+
+    /// Handshakes on the control port that were rejected.
+    handshakes_rejected: AtomicU64,
+
+    /// Connections and UDP flows rejected for the country of their address.
+    geoip_rejected: AtomicU64,
+}
+
+/// Byte counters for one tunnel, shared by all of its connections.
+#[derive(Clone, Default)]
+pub struct TunnelBytes {
+    /// Bytes sent from the client out to the public port.
+    pub up: Arc<AtomicU64>,
+
+    /// Bytes received on the public port and sent to the client.
+    pub down: Arc<AtomicU64>,
+}
+
+impl Metrics {
+    /// Register a newly opened tunnel, which is removed when the guard is dropped.
+    pub fn open_tunnel(&self, port: u16, protocol: Protocol) -> TunnelGuard<'_> {
+        let bytes = TunnelBytes::default();
+        self.tunnels.insert((port, protocol), bytes.clone());
+        TunnelGuard {
+            metrics: self,
+            key: (port, protocol),
+            bytes,
+        }
+    }
+
+    /// Count a connection being proxied, until the guard is dropped.
+    pub fn open_connection(&self) -> ConnectionGuard<'_> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self)
+    }
+
+    /// Record the result of a handshake on the control port.
+    pub fn handshake(&self, accepted: bool) {
+        let counter = if accepted {
+            &self.handshakes_accepted
+        } else {
+            &self.handshakes_rejected
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection or UDP flow rejected for the country of its address.
+    pub fn geoip_rejected(&self) {
+        self.geoip_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = "bore_active_tunnels";
+        header(f, name, "gauge", "Number of ports currently forwarded.")?;
+        writeln!(f, "{name} {}", self.tunnels.len())?;
+
+        let name = "bore_active_connections";
+        header(f, name, "gauge", "Number of connections currently proxied.")?;
+        writeln!(f, "{name} {}", self.connections.load(Ordering::Relaxed))?;
+
+        let name = "bore_handshakes_total";
+        header(
+            f,
+            name,
+            "counter",
+            "Handshakes on the control port, by result.",
+        )?;
+        for (result, counter) in [
+            ("accepted", &self.handshakes_accepted),
+            ("rejected", &self.handshakes_rejected),
+        ] {
+            let value = counter.load(Ordering::Relaxed);
+            writeln!(f, "{name}{{result=\"{result}\"}} {value}")?;
+        }
+
+        let name = "bore_geoip_rejected_total";
+        header(
+            f,
+            name,
+            "counter",
+            "Connections rejected for the country of their address.",
+        )?;
+        writeln!(f, "{name} {}", self.geoip_rejected.load(Ordering::Relaxed))?;
+
+        let name = "bore_tunnel_bytes_total";
+        header(f, name, "counter", "Bytes forwarded through each tunnel.")?;
+        for entry in self.tunnels.iter() {
+            let ((port, protocol), bytes) = entry.pair();
+            let protocol = match protocol {
+                Protocol::Tcp => "tcp",
+                Protocol::Udp => "udp",
+            };
+            for (direction, counter) in [("up", &bytes.up), ("down", &bytes.down)] {
+                let labels =
+                    format!("port=\"{port}\",protocol=\"{protocol}\",direction=\"{direction}\"");
+                let value = counter.load(Ordering::Relaxed);
+                writeln!(f, "{name}{{{labels}}} {value}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write the help and type comments that introduce a metric.
+fn header(f: &mut fmt::Formatter<'_>, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(f, "# HELP {name} {help}")?;
+    writeln!(f, "# TYPE {name} {kind}")
+}
+
+/// Keeps a tunnel registered in the metrics while it is open.
+pub struct TunnelGuard<'a> {
+    metrics: &'a Metrics,
+    key: (u16, Protocol),
+    bytes: TunnelBytes,
+}
+
+impl TunnelGuard<'_> {
+    /// Byte counters of this tunnel.
+    pub fn bytes(&self) -> &TunnelBytes {
+        &self.bytes
+    }
+}
+
+impl Drop for TunnelGuard<'_> {
+    fn drop(&mut self) {
+        self.metrics.tunnels.remove(&self.key);
+    }
+}
+
+/// Keeps a connection counted in the metrics while it is proxied.
+pub struct ConnectionGuard<'a>(&'a Metrics);
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Serve the metrics at `/metrics` over HTTP on a listener.
+pub async fn serve(metrics: Arc<Metrics>, listener: TcpListener) -> Result<()> {
+    info!(addr = ?listener.local_addr()?, "metrics listening");
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let metrics = Arc::clone(&metrics);
+        tokio::spawn(async move {
+            if let Err(err) = respond(&metrics, stream).await {
+                warn!(%err, ?addr, "metrics request failed");
+            }
+        });
+    }
+}
+
+/// Answer a single HTTP request, then close the connection.
+async fn respond(metrics: &Metrics, mut stream: TcpStream) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = timeout(NETWORK_TIMEOUT, stream.read(&mut buf)).await??;
+        if n == 0 || request.len() + n > MAX_REQUEST_LENGTH {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics.to_string();
+            format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{body}",
+                body.len(),
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,103 @@
+//! Read-only memory maps of files, for large files that are read in place, such as GeoIP
+//! databases.
+
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::ptr;
+
+/// A private, read-only mapping of a whole file, which is unmapped when dropped.
+///
+/// Writing to the file in place shows through the mapping, so files should be updated
+/// by replacing them, as by renaming a new one over them.
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// SAFETY: the mapping is only ever read, and unmapped once it is no longer borrowed.
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Map a file into memory.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = fs::File::open(path)?;
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "file is too large"))?;
+        if len == 0 {
+            // Empty mappings are not allowed, and there is nothing to map anyway.
+            let ptr = ptr::null_mut();
+            return Ok(Self { ptr, len });
+        }
+        // SAFETY: this asks for a new mapping of an open file, which may be closed once
+        // the call returns.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: the mapping is `len` bytes long, and readable until it is dropped.
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: the mapping is no longer borrowed, and only unmapped here.
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    #[test]
+    fn map_files() -> Result<()> {
+        let path =
+            |name| std::env::temp_dir().join(format!("bore-test-{}-{name}", std::process::id()));
+        let (full, empty) = (path("full.mmap"), path("empty.mmap"));
+        fs::write(&full, b"MaxMind.com")?;
+        fs::write(&empty, b"")?;
+        let map = Mmap::open(&full)?;
+        // The mapping outlives the name of its file.
+        fs::remove_file(&full)?;
+        assert_eq!(&*map, b"MaxMind.com");
+        assert!(Mmap::open(&empty)?.is_empty());
+        fs::remove_file(&empty)?;
+        assert!(Mmap::open(&full).is_err());
+        Ok(())
+    }
+}
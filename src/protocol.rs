@@ -0,0 +1,16 @@
+//! Messages of the control protocol, for tools that talk to a server or client directly.
+//!
+//! Every stream to the [`CONTROL_PORT`] carries JSON messages, each terminated by a null
+//! byte and at most [`MAX_FRAME_LENGTH`] bytes long, which [`Delimited`] reads and
+//! writes. A client opens its tunnels by sending [`ClientMessage::Hello`] with a
+//! [`PortRequest`] for each of them, after answering a [`ServerMessage::Challenge`] if
+//! the server requires authentication. The server then announces incoming connections
+//! on the control stream, and the client accepts each of them on a new stream with
+//! [`ClientMessage::Accept`], after which that stream carries the connection's data.
+//! A client may instead send [`ClientMessage::Status`] to list the open tunnels.
+
+pub use crate::shared::{
+    ClientMessage, Compression, Delimited, Endpoint, IpFamily, PortRequest, Protocol,
+    ServerMessage, TunnelStatus, COMPRESSION_METHODS, CONTROL_PORT, LEGACY_PROTOCOL_VERSION,
+    MAX_FRAME_LENGTH, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
@@ -0,0 +1,200 @@
+//! Byte quotas for the clients of a server, counted by the label of their secret.
+//!
+//! Every connection proxied for an authenticated client adds the bytes sent both ways
+//! to the running total of its identity. Once a total exceeds the identity's quota,
+//! the server refuses new tunnels and connections for it until the totals are reset,
+//! either through the admin API or when the quota period elapses. The totals can be
+//! saved to a JSON file, so that they survive restarts.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Interval between saves of the running totals, when they have changed.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Running byte totals of the identities of a server, checked against their quotas.
+#[derive(Default)]
+pub struct Quotas(Mutex<State>);
+
+#[derive(Default)]
+struct State {
+    limits: HashMap<String, u64>,
+    period: Option<Duration>,
+    path: Option<PathBuf>,
+    totals: Totals,
+    dirty: bool,
+}
+
+/// Contents of the file that the running totals are saved to.
+#[derive(Default, Serialize, Deserialize)]
+struct Totals {
+    /// Seconds since the Unix epoch at which the totals were last reset.
+    since: u64,
+
+    /// Bytes proxied for each identity since then.
+    usage: BTreeMap<String, u64>,
+}
+
+/// Bytes proxied for an identity, as listed by the admin API.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaInfo {
+    /// Name of the client, from the label of its secret.
+    pub identity: String,
+
+    /// Bytes proxied for the client since the totals were last reset.
+    pub bytes_used: u64,
+
+    /// The client's quota in bytes, if it has one.
+    pub bytes_quota: Option<u64>,
+}
+
+impl Quotas {
+    /// Set the quotas of identities and how often they are reset, loading the running
+    /// totals from a file, if it is given and exists.
+    pub fn configure(
+        &self,
+        limits: Vec<(String, u64)>,
+        period: Option<Duration>,
+        path: Option<PathBuf>,
+    ) -> Result<()> {
+        let totals = match &path {
+            Some(path) if path.exists() => load(path)?,
+            _ => Totals {
+                since: now(),
+                ..Default::default()
+            },
+        };
+        let mut state = self.0.lock().unwrap();
+        *state = State {
+            limits: limits.into_iter().collect(),
+            period,
+            path,
+            totals,
+            dirty: false,
+        };
+        state.roll_over();
+        Ok(())
+    }
+
+    /// Add the bytes of a finished connection to an identity's total.
+    pub fn add(&self, identity: &str, bytes: u64) {
+        let mut state = self.0.lock().unwrap();
+        let limit = state.limits.get(identity).copied();
+        let total = state.totals.usage.entry(identity.into()).or_default();
+        let before = *total;
+        *total += bytes;
+        if let Some(limit) = limit.filter(|&limit| before <= limit && *total > limit) {
+            warn!(
+                event = "quota_exceeded",
+                identity,
+                bytes_used = *total,
+                bytes_quota = limit,
+                "client exceeded its quota",
+            );
+        }
+        state.dirty |= bytes > 0;
+    }
+
+    /// Whether an identity has used up its quota.
+    pub fn exceeded(&self, identity: &str) -> bool {
+        let mut state = self.0.lock().unwrap();
+        state.roll_over();
+        let used = state.totals.usage.get(identity).copied().unwrap_or(0);
+        state
+            .limits
+            .get(identity)
+            .is_some_and(|&limit| used > limit)
+    }
+
+    /// List the totals of identities that have used bytes or have a quota, by name.
+    pub fn list(&self) -> Vec<QuotaInfo> {
+        let mut state = self.0.lock().unwrap();
+        state.roll_over();
+        let mut names: Vec<&String> = state.totals.usage.keys().collect();
+        names.extend(state.limits.keys());
+        names.sort();
+        names.dedup();
+        names
+            .into_iter()
+            .map(|name| QuotaInfo {
+                identity: name.clone(),
+                bytes_used: state.totals.usage.get(name).copied().unwrap_or(0),
+                bytes_quota: state.limits.get(name).copied(),
+            })
+            .collect()
+    }
+
+    /// Reset the totals of all identities, starting a new quota period.
+    pub fn reset(&self) {
+        self.0.lock().unwrap().reset();
+    }
+
+    /// Save the running totals to the file, if they have changed since the last save,
+    /// and log the usage of the identities with quotas.
+    pub fn save(&self) -> Result<()> {
+        let mut state = self.0.lock().unwrap();
+        state.roll_over();
+        if !state.dirty {
+            return Ok(());
+        }
+        for (identity, &limit) in &state.limits {
+            let used = state.totals.usage.get(identity).copied().unwrap_or(0);
+            info!(
+                event = "quota_usage",
+                identity,
+                bytes_used = used,
+                bytes_quota = limit,
+                "quota usage",
+            );
+        }
+        if let Some(path) = &state.path {
+            let context = || format!("could not save quota totals to {}", path.display());
+            let temp = path.with_extension("tmp");
+            let json = serde_json::to_vec_pretty(&state.totals)?;
+            std::fs::write(&temp, json).with_context(context)?;
+            std::fs::rename(&temp, path).with_context(context)?;
+        }
+        state.dirty = false;
+        Ok(())
+    }
+}
+
+impl State {
+    /// Reset the totals if the quota period has elapsed since they were last reset.
+    fn roll_over(&mut self) {
+        let elapsed = Duration::from_secs(now().saturating_sub(self.totals.since));
+        if self.period.is_some_and(|period| elapsed >= period) {
+            self.reset();
+        }
+    }
+
+    fn reset(&mut self) {
+        info!(event = "quota_reset", "resetting quota totals");
+        self.totals = Totals {
+            since: now(),
+            usage: BTreeMap::new(),
+        };
+        self.dirty = true;
+    }
+}
+
+/// Read the running totals from a file.
+fn load(path: &Path) -> Result<Totals> {
+    let context = || format!("could not read quota totals from {}", path.display());
+    let json = std::fs::read(path).with_context(context)?;
+    serde_json::from_slice(&json).with_context(context)
+}
+
+/// Seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
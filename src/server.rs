@@ -1,168 +1,3917 @@
 //! Server implementation for the `bore` service.
 
-use std::{io, net::SocketAddr, ops::RangeInclusive, sync::Arc, time::Duration};
-
-use anyhow::Result;
-use dashmap::DashMap;
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::time::{sleep, timeout};
-use tracing::{info, info_span, warn, Instrument};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use std::{fmt, ops::RangeInclusive};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures_util::future::{self, select_all};
+use futures_util::stream;
+use futures_util::{SinkExt, Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use socket2::{Domain, Socket, Type};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{interval, interval_at, sleep, timeout, Instant};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
 use uuid::Uuid;
 
-use crate::auth::Authenticator;
-use crate::shared::{proxy, ClientMessage, Delimited, ServerMessage, CONTROL_PORT};
+use crate::access_log::AccessLog;
+use crate::admin::{self, TunnelLimit, Tunnels};
+use crate::auth::{
+    server_challenge, AuthCallback, AuthDecision, Authenticator, Authorizer, ClientAuth, Identity,
+    Keyring,
+};
+use crate::crypto::{DataKey, Encrypted, Side};
+use crate::geoip::GeoIpFilter;
+use crate::metrics::{self, Metrics, TunnelBytes};
+use crate::quota::{self, Quotas};
+use crate::shared::{
+    proxy_with, tls_record_complete, tls_server_name, ClientMessage, Compressed, Delimited,
+    Direction, Drain, Endpoint, Events, IpFamily, IpFilter, PortRequest, Protocol, RateLimiter,
+    ServerMessage, TcpOptions, Transport, TunnelStats, TunnelStatus, WebSocket,
+    COMPRESSION_METHODS, CONNECTION_GRACE, CONTROL_PORT, DRAIN_TIMEOUT, HEARTBEAT_INTERVAL,
+    HEARTBEAT_TIMEOUT, LEGACY_PROTOCOL_VERSION, MAX_DATAGRAM_LENGTH, MAX_FRAME_LENGTH,
+    MAX_TLS_RECORD_LENGTH, MIN_PROTOCOL_VERSION, NETWORK_TIMEOUT, PROTOCOL_VERSION,
+    UDP_IDLE_TIMEOUT,
+};
+use crate::systemd;
+use crate::tls::ServerTls;
+
+/// Time to wait for a TLS handshake to start before assuming a plaintext client.
+const TLS_DETECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Maximum byte length of the head of an HTTP request, read to find its Host header.
+const MAX_HTTP_HEAD_LENGTH: usize = 8192;
+
+/// Response to HTTP requests for a host without a tunnel.
+const HTTP_NOT_FOUND: &[u8] =
+    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// Maximum byte length of the prefix of a random subdomain, leaving room in the 63 bytes
+/// of a DNS label for the words after it.
+const MAX_SUBDOMAIN_PREFIX_LENGTH: usize = 40;
+
+/// Adjectives that random subdomains start with.
+const SUBDOMAIN_ADJECTIVES: &[&str] = &[
+    "amber", "bold", "brave", "bright", "calm", "clever", "cosmic", "crisp", "daring", "eager",
+    "fancy", "gentle", "giant", "golden", "happy", "jolly", "kind", "lively", "lucky", "mellow",
+    "misty", "noble", "proud", "quick", "quiet", "rapid", "silent", "sunny", "swift", "tidy",
+    "vivid", "witty",
+];
+
+/// Nouns that random subdomains end with, before a number.
+const SUBDOMAIN_NOUNS: &[&str] = &[
+    "badger", "bear", "beaver", "bison", "cedar", "comet", "coral", "crane", "delta", "eagle",
+    "falcon", "fern", "fox", "harbor", "heron", "island", "lynx", "maple", "meadow", "moose",
+    "otter", "owl", "panda", "pine", "raven", "river", "robin", "salmon", "summit", "tiger",
+    "valley", "walrus",
+];
+
+/// Fatal TLS alert for connections to a server name without a tunnel.
+const TLS_UNRECOGNIZED_NAME: &[u8] = &[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x70];
+
+/// Interval between log summaries of connections dropped on the control port.
+const REJECTED_SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Error for a requested port that is already bound, which clients are also told about
+/// with [`ServerMessage::PortInUse`].
+const PORT_IN_USE: &str = "port already in use";
+
+/// Number of times to let the system choose a port for a tunnel, before giving up.
+const EPHEMERAL_ATTEMPTS: usize = 16;
+
+/// Number of ports in a range to try binding for a tunnel, before giving up.
+const PORT_ATTEMPTS: usize = 150;
+
+/// Maximum time to wait for a stale tunnel to release a port that a client reclaims.
+const RECLAIM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Interval between attempts to bind a port that a client reclaims.
+const RECLAIM_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Interval between checks of whether the connections of a tunnel have finished while
+/// the server is shutting down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Minimum interval between warnings about a tunnel rejecting connections at its limit.
+const LIMIT_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default number of connections that each tunnel holds for its client to accept.
+const PENDING_CONNS_LIMIT: usize = 128;
+
+/// Number of tunnels in each batch of a status reply, which keeps its frame short.
+const STATUS_BATCH_SIZE: usize = 32;
+
+/// Maximum number of named tunnels whose ports are remembered, open or released.
+const MAX_RETAINED_NAMES: usize = 4096;
+
+/// Maximum byte length of the name of a tunnel.
+const MAX_TUNNEL_NAME_LENGTH: usize = 64;
+
+/// Number of connections that may wait for the stream of [`Server::incoming`] before new
+/// ones are dropped.
+const INCOMING_BUFFER: usize = 128;
+
+/// Shortest interval that the statistics of tunnels are sent to clients on.
+const MIN_STATS_INTERVAL: Duration = Duration::from_secs(1);
 
 /// State structure for the server.
+///
+/// Clones share the same connections, metrics and shutdown state.
+#[derive(Clone)]
 pub struct Server {
     /// Range of TCP ports that can be forwarded.
     port_range: RangeInclusive<u16>,
 
-    /// Optional secret used to authenticate clients.
-    auth: Option<Authenticator>,
+    /// Range of ports that clients may be given without authenticating, if they may
+    /// connect without credentials at all.
+    open_ports: Option<RangeInclusive<u16>>,
+
+    /// Optional authenticator for clients, which can be replaced at runtime.
+    auth: Arc<RwLock<AuthState>>,
+
+    /// Time for which the secrets replaced by [`Server::rotate_secrets`] are still
+    /// accepted.
+    secret_overlap: Duration,
+
+    /// Whether clients may forward UDP ports.
+    udp: bool,
+
+    /// Whether clients may ask for the data of their TCP connections to be compressed.
+    compress: bool,
+
+    /// Whether clients with a secret may ask for the data of their TCP connections to be
+    /// encrypted.
+    encrypt_data: bool,
+
+    /// Time without datagrams in either direction after which a UDP flow is dropped.
+    udp_idle_timeout: Duration,
+
+    /// Optional TLS settings for the control port.
+    tls: Option<ServerTls>,
+
+    /// Optional limits in bytes per second on each tunnel, sent and received.
+    rate_limit: (Option<u64>, Option<u64>),
+
+    /// Optional maximum time that a client may keep its tunnels open.
+    max_tunnel_lifetime: Option<Duration>,
+
+    /// Optional time without incoming connections after which tunnels are closed.
+    idle_timeout: Option<Duration>,
+
+    /// Optional time without any open connections after which tunnels are closed.
+    tunnel_idle_timeout: Option<Duration>,
+
+    /// Optional limit on the connections that each tunnel may have open at once.
+    max_conns_per_tunnel: Option<usize>,
+
+    /// Optional limit on the tunnels that each client may have open at once.
+    max_tunnels_per_client: Option<usize>,
+
+    /// Optional limit on the tunnels that all clients together may have open at once.
+    max_tunnels: Option<usize>,
+
+    /// Number of connections that each tunnel holds until the client accepts them.
+    pending_conns_limit: usize,
+
+    /// Addresses that may connect to forwarded ports.
+    ip_filter: IpFilter,
+
+    /// Optional countries that may connect to forwarded ports.
+    geoip_filter: Option<Arc<GeoIpFilter>>,
+
+    /// Addresses that tunnels are bound on, unless a client asks for another.
+    tunnel_addrs: Vec<IpAddr>,
+
+    /// Addresses of server interfaces that clients may bind their tunnels to.
+    allowed_tunnel_addrs: Vec<IpAddr>,
+
+    /// Addresses to listen for control connections on, on the control port.
+    control_addrs: Vec<IpAddr>,
+
+    /// Whether IPv6 sockets only accept IPv6 connections, instead of only when IPv4
+    /// addresses are bound alongside them.
+    v6only: Option<bool>,
+
+    /// How ports are chosen for tunnels that don't ask for a specific one.
+    port_strategy: PortStrategy,
+
+    /// Whether the system chooses the ports of tunnels that don't ask for one, instead
+    /// of the port strategy.
+    ephemeral_ports: bool,
+
+    /// Time after a port is released during which it is not chosen for a new tunnel.
+    port_cooldown: Duration,
+
+    /// Time after a named tunnel closes during which its port is kept for its name.
+    port_retention: Duration,
+
+    /// Ports released by closed tunnels, and the next port of a sequential strategy.
+    ports: Arc<Ports>,
+
+    /// Ports that only clients with a given identity may forward.
+    reservations: Vec<(String, RangeInclusive<u16>)>,
+
+    /// Limits on the tunnels that the clients with some identities may have open at once.
+    tunnel_limits: Vec<(String, usize)>,
+
+    /// Byte quotas of clients with a given identity.
+    quota_limits: Vec<(String, u64)>,
+
+    /// Optional time after which the byte totals of all clients are reset.
+    quota_period: Option<Duration>,
+
+    /// Optional file to save the byte totals of clients in, across restarts.
+    quota_file: Option<PathBuf>,
+
+    /// Byte totals of clients, checked against their quotas.
+    quotas: Arc<Quotas>,
+
+    /// Optional file to append a line to for each proxied connection.
+    access_log_file: Option<PathBuf>,
+
+    /// Size in bytes at which the access log is rotated, if any.
+    access_log_max_size: Option<u64>,
+
+    /// Writer of the access log, once it is open.
+    access_log: Arc<AccessLog>,
+
+    /// Optional limit on new control connections per second from each address.
+    handshake_rate_limit: Option<u32>,
+
+    /// Optional number of consecutive authentication failures after which an address
+    /// is banned, and for how long.
+    ban: Option<(u32, Duration)>,
+
+    /// Rate limits and bans of the addresses connecting to the control port.
+    peers: Arc<Peers>,
+
+    /// Optional address to serve HTTP tunnels on, routed by their subdomain.
+    http_addr: Option<SocketAddr>,
+
+    /// Optional address to pass HTTPS connections through to HTTP tunnels on, routed by
+    /// the server name of their TLS handshake.
+    https_addr: Option<SocketAddr>,
+
+    /// Optional address to accept clients on over WebSocket, as well as the control port.
+    ws_addr: Option<SocketAddr>,
+
+    /// Optional directory that clients may serve their tunnels in, as Unix sockets.
+    socket_dir: Option<PathBuf>,
+
+    /// Channels to the control connections of HTTP tunnels, by subdomain.
+    routes: Arc<DashMap<String, mpsc::Sender<RoutedConnection>>>,
+
+    /// Optional address to serve metrics on.
+    metrics_addr: Option<SocketAddr>,
+
+    /// Metrics about the tunnels and connections on this server.
+    metrics: Arc<Metrics>,
+
+    /// Optional loopback address to serve the admin API on.
+    admin_addr: Option<SocketAddr>,
+
+    /// Optional Unix socket to serve the admin API on.
+    admin_socket: Option<PathBuf>,
+
+    /// Tunnels that are open, for the admin API to list and close.
+    tunnels: Arc<Tunnels>,
+
+    /// Time to wait for handshakes and initial messages from new connections.
+    network_timeout: Duration,
+
+    /// Maximum byte length of a message from a client.
+    max_frame_length: usize,
+
+    /// Socket options for connections accepted on tunnel ports.
+    tcp_options: TcpOptions,
+
+    /// Interval between heartbeats sent to clients.
+    heartbeat_interval: Duration,
+
+    /// Time without a heartbeat from a client after which its tunnels are closed.
+    heartbeat_timeout: Duration,
+
+    /// Time to wait for proxied connections to finish when shutting down.
+    drain_timeout: Duration,
+
+    /// Time to keep a forwarded connection until the client accepts it.
+    connection_grace: Duration,
+
+    /// Signal to stop accepting connections, triggered by [`Server::shutdown`].
+    shutdown: CancellationToken,
+
+    /// Tasks for connections that are waited for when shutting down.
+    drain: Drain,
+
+    /// Concurrent map of IDs to incoming connections, with the time they arrived.
+    conns: Arc<DashMap<Uuid, (SystemTime, PendingConnection, OwnedSemaphorePermit)>>,
+
+    /// Optional callback for each proxied connection once it closes.
+    on_connection: Option<Arc<dyn Fn(ConnectionRecord) + Send + Sync>>,
+
+    /// Streams of changes in the state of the tunnels, from [`Server::events`].
+    events: Events<ServerEvent>,
+
+    /// Optional channel to the stream of [`Server::incoming`], which takes the TCP
+    /// connections to tunnels instead of their clients while it is open.
+    incoming: Arc<Mutex<Option<mpsc::Sender<IncomingConnection>>>>,
+}
+
+/// A change in the state of a tunnel or one of its connections, yielded by
+/// [`Server::events`].
+#[derive(Clone, Debug)]
+pub enum ServerEvent {
+    /// A client opened a tunnel.
+    Connected {
+        /// The tunnel's public port, or its Unix socket.
+        tunnel: Endpoint,
+        /// Name of the client owning the tunnel, if it authenticated.
+        owner: Option<String>,
+    },
+
+    /// A connection, or UDP flow, arrived on a tunnel for its client to accept.
+    ConnectionOpened {
+        /// ID of the connection, which its [`ServerEvent::ConnectionClosed`] shares.
+        id: Uuid,
+        /// The tunnel that the connection arrived on.
+        tunnel: Endpoint,
+        /// Address of the remote peer, unless it connected on a Unix socket.
+        peer: Option<SocketAddr>,
+    },
+
+    /// A connection closed, whether it was proxied or never accepted by the client.
+    ConnectionClosed {
+        /// ID of the connection.
+        id: Uuid,
+        /// Bytes received from the remote peer, or 0 if the connection failed.
+        bytes_in: u64,
+        /// Bytes sent back to the remote peer, or 0 if the connection failed.
+        bytes_out: u64,
+    },
+
+    /// A tunnel closed along with the control connection of its client.
+    Disconnected {
+        /// The tunnel's public port, or its Unix socket.
+        tunnel: Endpoint,
+        /// The error that closed the control connection, if there was one.
+        reason: Option<String>,
+    },
+}
+
+/// How a server chooses ports for tunnels that don't ask for a specific one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PortStrategy {
+    /// Ports chosen uniformly at random from the range.
+    #[default]
+    Random,
+
+    /// Increasing ports, starting after the last one chosen and wrapping around.
+    Sequential,
+
+    /// The port released least recently, preferring ports that were never used.
+    Lru,
+}
+
+/// Builder for a [`Server`], as an alternative to calling its setters one by one.
+///
+/// Each method does the same as the [`Server`] setter with its name and a `set_`
+/// prefix. Secrets and other authentication can also be changed on a running server
+/// through those setters.
+pub struct ServerBuilder(Server);
+
+impl ServerBuilder {
+    /// Start building a server that forwards ports in a range, without authentication.
+    pub fn new(port_range: RangeInclusive<u16>) -> Self {
+        ServerBuilder(Server::new(port_range, None))
+    }
+
+    /// Require clients to authenticate with a secret.
+    pub fn secret(self, secret: &str) -> Self {
+        self.secrets(Keyring::new([secret]))
+    }
+
+    /// See [`Server::set_secrets`].
+    pub fn secrets(self, secrets: Keyring) -> Self {
+        self.0.set_secrets(secrets);
+        self
+    }
+
+    /// See [`Server::set_authenticator`].
+    pub fn authenticator(self, auth: impl Authenticator + 'static) -> Self {
+        self.0.set_authenticator(auth);
+        self
+    }
+
+    /// See [`Server::set_authorizer`].
+    pub fn authorizer(self, auth: impl Authorizer + 'static) -> Self {
+        self.0.set_authorizer(auth);
+        self
+    }
+
+    /// See [`Server::set_secret_overlap`].
+    pub fn secret_overlap(mut self, overlap: Duration) -> Self {
+        self.0.set_secret_overlap(overlap);
+        self
+    }
+
+    /// See [`Server::set_auth_callback`].
+    pub fn auth_callback<F, Fut>(self, callback: F) -> Self
+    where
+        F: Fn(ClientAuth) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<AuthDecision>> + Send + 'static,
+    {
+        self.0.set_auth_callback(callback);
+        self
+    }
+
+    /// See [`Server::set_udp`].
+    pub fn udp(mut self, udp: bool) -> Self {
+        self.0.set_udp(udp);
+        self
+    }
+
+    /// See [`Server::set_compress`].
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.0.set_compress(compress);
+        self
+    }
+
+    /// See [`Server::set_encrypt_data`].
+    pub fn encrypt_data(mut self, encrypt_data: bool) -> Self {
+        self.0.set_encrypt_data(encrypt_data);
+        self
+    }
+
+    /// See [`Server::set_udp_idle_timeout`].
+    pub fn udp_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.0.set_udp_idle_timeout(timeout);
+        self
+    }
+
+    /// See [`Server::set_tls`].
+    pub fn tls(mut self, tls: ServerTls) -> Self {
+        self.0.set_tls(tls);
+        self
+    }
+
+    /// See [`Server::set_rate_limit`].
+    pub fn rate_limit(mut self, up: Option<u64>, down: Option<u64>) -> Self {
+        self.0.set_rate_limit(up, down);
+        self
+    }
+
+    /// See [`Server::set_max_tunnel_lifetime`].
+    pub fn max_tunnel_lifetime(mut self, lifetime: Duration) -> Self {
+        self.0.set_max_tunnel_lifetime(lifetime);
+        self
+    }
+
+    /// See [`Server::set_idle_timeout`].
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.0.set_idle_timeout(timeout);
+        self
+    }
+
+    /// See [`Server::set_tunnel_idle_timeout`].
+    pub fn tunnel_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.0.set_tunnel_idle_timeout(timeout);
+        self
+    }
+
+    /// See [`Server::set_max_conns_per_tunnel`].
+    pub fn max_conns_per_tunnel(mut self, max: usize) -> Self {
+        self.0.set_max_conns_per_tunnel(max);
+        self
+    }
+
+    /// See [`Server::set_max_tunnels_per_client`].
+    pub fn max_tunnels_per_client(mut self, max: usize) -> Self {
+        self.0.set_max_tunnels_per_client(max);
+        self
+    }
+
+    /// See [`Server::set_max_tunnels`].
+    pub fn max_tunnels(mut self, max: usize) -> Self {
+        self.0.set_max_tunnels(max);
+        self
+    }
+
+    /// See [`Server::set_pending_conns_limit`].
+    pub fn pending_conns_limit(mut self, limit: usize) -> Self {
+        self.0.set_pending_conns_limit(limit);
+        self
+    }
+
+    /// See [`Server::set_ip_filter`].
+    pub fn ip_filter(mut self, filter: IpFilter) -> Self {
+        self.0.set_ip_filter(filter);
+        self
+    }
+
+    /// See [`Server::set_geoip_filter`].
+    pub fn geoip_filter(mut self, filter: GeoIpFilter) -> Self {
+        self.0.set_geoip_filter(filter);
+        self
+    }
+
+    /// See [`Server::set_control_addrs`].
+    pub fn control_addrs(mut self, addrs: Vec<IpAddr>) -> Self {
+        self.0.set_control_addrs(addrs);
+        self
+    }
+
+    /// See [`Server::set_v6only`].
+    pub fn v6only(mut self, v6only: bool) -> Self {
+        self.0.set_v6only(v6only);
+        self
+    }
+
+    /// See [`Server::set_tunnel_addrs`].
+    pub fn tunnel_addrs(mut self, addrs: Vec<IpAddr>) -> Self {
+        self.0.set_tunnel_addrs(addrs);
+        self
+    }
+
+    /// See [`Server::set_allowed_tunnel_addrs`].
+    pub fn allowed_tunnel_addrs(mut self, addrs: Vec<IpAddr>) -> Self {
+        self.0.set_allowed_tunnel_addrs(addrs);
+        self
+    }
+
+    /// See [`Server::set_open_port_range`].
+    pub fn open_port_range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.0.set_open_port_range(range);
+        self
+    }
+
+    /// See [`Server::set_port_strategy`].
+    pub fn port_strategy(mut self, strategy: PortStrategy) -> Self {
+        self.0.set_port_strategy(strategy);
+        self
+    }
+
+    /// See [`Server::set_ephemeral_ports`].
+    pub fn ephemeral_ports(mut self, ephemeral: bool) -> Self {
+        self.0.set_ephemeral_ports(ephemeral);
+        self
+    }
+
+    /// See [`Server::set_port_cooldown`].
+    pub fn port_cooldown(mut self, cooldown: Duration) -> Self {
+        self.0.set_port_cooldown(cooldown);
+        self
+    }
+
+    /// See [`Server::set_port_retention`].
+    pub fn port_retention(mut self, retention: Duration) -> Self {
+        self.0.set_port_retention(retention);
+        self
+    }
+
+    /// See [`Server::set_reservations`].
+    pub fn reservations(mut self, reservations: Vec<(String, RangeInclusive<u16>)>) -> Self {
+        self.0.set_reservations(reservations);
+        self
+    }
+
+    /// See [`Server::set_quotas`].
+    pub fn quotas(mut self, quotas: Vec<(String, u64)>) -> Self {
+        self.0.set_quotas(quotas);
+        self
+    }
+
+    /// See [`Server::set_tunnel_limits`].
+    pub fn tunnel_limits(mut self, limits: Vec<(String, usize)>) -> Self {
+        self.0.set_tunnel_limits(limits);
+        self
+    }
+
+    /// See [`Server::set_quota_period`].
+    pub fn quota_period(mut self, period: Duration) -> Self {
+        self.0.set_quota_period(period);
+        self
+    }
+
+    /// See [`Server::set_quota_file`].
+    pub fn quota_file(mut self, path: PathBuf) -> Self {
+        self.0.set_quota_file(path);
+        self
+    }
+
+    /// See [`Server::set_access_log`].
+    pub fn access_log(mut self, path: PathBuf) -> Self {
+        self.0.set_access_log(path);
+        self
+    }
+
+    /// See [`Server::set_access_log_max_size`].
+    pub fn access_log_max_size(mut self, max_size: u64) -> Self {
+        self.0.set_access_log_max_size(max_size);
+        self
+    }
+
+    /// See [`Server::set_handshake_rate_limit`].
+    pub fn handshake_rate_limit(mut self, per_sec: u32) -> Self {
+        self.0.set_handshake_rate_limit(per_sec);
+        self
+    }
+
+    /// See [`Server::set_ban`].
+    pub fn ban(mut self, failures: u32, duration: Duration) -> Self {
+        self.0.set_ban(failures, duration);
+        self
+    }
+
+    /// See [`Server::set_http_addr`].
+    pub fn http_addr(mut self, addr: SocketAddr) -> Self {
+        self.0.set_http_addr(addr);
+        self
+    }
+
+    /// See [`Server::set_https_addr`].
+    pub fn https_addr(mut self, addr: SocketAddr) -> Self {
+        self.0.set_https_addr(addr);
+        self
+    }
+
+    /// See [`Server::set_ws_addr`].
+    pub fn ws_addr(mut self, addr: SocketAddr) -> Self {
+        self.0.set_ws_addr(addr);
+        self
+    }
+
+    /// See [`Server::set_socket_dir`].
+    pub fn socket_dir(mut self, dir: PathBuf) -> Self {
+        self.0.set_socket_dir(dir);
+        self
+    }
+
+    /// See [`Server::set_metrics_addr`].
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.0.set_metrics_addr(addr);
+        self
+    }
+
+    /// See [`Server::set_admin_addr`].
+    pub fn admin_addr(mut self, addr: SocketAddr) -> Self {
+        self.0.set_admin_addr(addr);
+        self
+    }
+
+    /// See [`Server::set_admin_socket`].
+    pub fn admin_socket(mut self, path: PathBuf) -> Self {
+        self.0.set_admin_socket(path);
+        self
+    }
+
+    /// See [`Server::set_network_timeout`].
+    pub fn network_timeout(mut self, timeout: Duration) -> Self {
+        self.0.set_network_timeout(timeout);
+        self
+    }
+
+    /// See [`Server::set_max_frame_length`].
+    pub fn max_frame_length(mut self, max_length: usize) -> Self {
+        self.0.set_max_frame_length(max_length);
+        self
+    }
+
+    /// See [`Server::set_tcp_options`].
+    pub fn tcp_options(mut self, options: TcpOptions) -> Self {
+        self.0.set_tcp_options(options);
+        self
+    }
+
+    /// See [`Server::set_heartbeat_interval`].
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.0.set_heartbeat_interval(interval);
+        self
+    }
+
+    /// See [`Server::set_heartbeat_timeout`].
+    pub fn heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.0.set_heartbeat_timeout(timeout);
+        self
+    }
+
+    /// See [`Server::set_drain_timeout`].
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.0.set_drain_timeout(timeout);
+        self
+    }
+
+    /// See [`Server::set_connection_grace`].
+    pub fn connection_grace(mut self, grace: Duration) -> Self {
+        self.0.set_connection_grace(grace);
+        self
+    }
+
+    /// See [`Server::set_on_connection`].
+    pub fn on_connection(
+        mut self,
+        on_connection: impl Fn(ConnectionRecord) + Send + Sync + 'static,
+    ) -> Self {
+        self.0.set_on_connection(on_connection);
+        self
+    }
+
+    /// Finish building the server, which can then be started with [`Server::listen`].
+    pub fn build(self) -> Server {
+        self.0
+    }
+}
+
+/// A proxied connection that has closed, reported to [`Server::set_on_connection`].
+#[derive(Clone, Debug)]
+pub struct ConnectionRecord {
+    /// ID of the connection, which the client was sent in [`ServerMessage::Connection`]
+    /// and which its [`ServerEvent`]s share.
+    pub id: Uuid,
+
+    /// The tunnel's public port, or its Unix socket, that the connection arrived on.
+    pub tunnel: Endpoint,
+
+    /// Protocol of the tunnel, where UDP connections are flows from one peer.
+    pub protocol: Protocol,
+
+    /// Address of the remote peer, unless it connected on a Unix socket.
+    pub peer: Option<SocketAddr>,
+
+    /// Name of the client owning the tunnel, if it authenticated.
+    pub owner: Option<String>,
+
+    /// When the connection arrived at the server.
+    pub opened: SystemTime,
+
+    /// How long the connection was open, including the time until the client accepted it.
+    pub duration: Duration,
+
+    /// Bytes sent from the client out to the peer.
+    pub bytes_up: u64,
+
+    /// Bytes received from the peer and sent to the client.
+    pub bytes_down: u64,
+
+    /// Why the connection failed, or `None` if it closed normally.
+    pub reason: Option<String>,
+}
+
+/// An incoming connection waiting to be accepted by the client.
+enum PendingConnection {
+    /// A TCP connection from a remote peer, accepted on a tunnel's listener, with any
+    /// bytes that were already read from it.
+    Tcp(TcpStream, SocketAddr, Vec<u8>, Tunnel, ConnectionSlot),
+
+    /// A UDP flow from a new remote peer, with a queue of its datagrams.
+    Udp(
+        Arc<UdpSocket>,
+        SocketAddr,
+        mpsc::Receiver<Vec<u8>>,
+        Tunnel,
+        ConnectionSlot,
+    ),
+
+    /// A connection accepted on the Unix socket of a tunnel, at the given path.
+    Socket(SocketStream, PathBuf, Tunnel, ConnectionSlot),
+}
+
+impl PendingConnection {
+    /// The tunnel that the connection arrived on.
+    fn tunnel(&self) -> &Tunnel {
+        match self {
+            PendingConnection::Tcp(_, _, _, tunnel, _) => tunnel,
+            PendingConnection::Udp(_, _, _, tunnel, _) => tunnel,
+            PendingConnection::Socket(_, _, tunnel, _) => tunnel,
+        }
+    }
+
+    /// The tunnel's public port, or its Unix socket, with the address of the remote peer
+    /// unless it connected on the socket.
+    fn endpoint(&self) -> (Endpoint, Option<SocketAddr>) {
+        match self {
+            PendingConnection::Tcp(_, peer, _, tunnel, _)
+            | PendingConnection::Udp(_, peer, _, tunnel, _) => {
+                (Endpoint::Port(tunnel.port), Some(*peer))
+            }
+            PendingConnection::Socket(_, path, _, _) => (Endpoint::Socket(path.clone()), None),
+        }
+    }
+
+    /// A span for proxying the connection, identifying it and where it came from.
+    fn span(&self, id: Uuid) -> Span {
+        match self {
+            PendingConnection::Tcp(_, peer, _, tunnel, _)
+            | PendingConnection::Udp(_, peer, _, tunnel, _) => {
+                info_span!("connection", %id, tunnel_port = tunnel.port, peer_addr = %peer)
+            }
+            PendingConnection::Socket(_, path, _, _) => {
+                info_span!("connection", %id, tunnel_socket = %path.display())
+            }
+        }
+    }
+}
+
+/// A connection to the HTTP port, with the head of its request that was already read.
+type RoutedConnection = (TcpStream, SocketAddr, Vec<u8>);
+
+/// Keeps a subdomain routed to a client's control connection until it is dropped.
+struct Route<'a> {
+    routes: &'a DashMap<String, mpsc::Sender<RoutedConnection>>,
+    subdomain: String,
+}
+
+impl Drop for Route<'_> {
+    fn drop(&mut self) {
+        self.routes.remove(&self.subdomain);
+    }
+}
+
+/// Rate limits and byte counters shared by all connections on one forwarded port.
+#[derive(Clone)]
+struct Tunnel {
+    /// The forwarded port.
+    port: u16,
+
+    /// Name of the client owning the tunnel, if it authenticated, along with the
+    /// authorizer that it was checked by at the time.
+    owner: Option<(String, Arc<dyn Authorizer>)>,
+
+    /// Traffic from the client out to the public port.
+    up: Direction,
+
+    /// Traffic from the public port to the client.
+    down: Direction,
+
+    /// Number of connections and UDP flows open on the port, pending or proxied.
+    conns: Arc<AtomicUsize>,
+
+    /// Number of connections and UDP flows opened on the port so far.
+    opened: Arc<AtomicU64>,
+
+    /// Optional limit on the number of open connections.
+    max_conns: Option<usize>,
+
+    /// Permits for connections waiting for the client to accept them, which stop the
+    /// tunnel from accepting more while they are all taken.
+    pending: Arc<Semaphore>,
+
+    /// Whether data between the server and client is compressed.
+    compress: bool,
+
+    /// Key that data between the server and client is encrypted with, if it is.
+    encrypt: Option<DataKey>,
+}
+
+impl Tunnel {
+    /// Take a slot for a new connection, unless the tunnel is at its limit.
+    fn open_slot(&self) -> Option<ConnectionSlot> {
+        let max = self.max_conns.unwrap_or(usize::MAX);
+        self.conns
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |conns| {
+                (conns < max).then_some(conns + 1)
+            })
+            .ok()?;
+        self.opened.fetch_add(1, Ordering::Relaxed);
+        Some(ConnectionSlot(Arc::clone(&self.conns)))
+    }
+
+    /// Number of connections open on the tunnel.
+    fn conns(&self) -> usize {
+        self.conns.load(Ordering::Relaxed)
+    }
+
+    /// Whether any connections are open on the tunnel.
+    fn is_active(&self) -> bool {
+        self.conns() > 0
+    }
+
+    /// The counters of the tunnel, for the statistics sent to its client.
+    fn stats(&self, tunnel: Endpoint, protocol: Protocol) -> TunnelStats {
+        let bytes = |direction: &Direction| {
+            let bytes = direction.bytes.as_ref();
+            bytes.map_or(0, |bytes| bytes.load(Ordering::Relaxed))
+        };
+        TunnelStats {
+            tunnel,
+            protocol,
+            active_connections: self.conns() as u64,
+            total_connections: self.opened.load(Ordering::Relaxed),
+            bytes_in: bytes(&self.down),
+            bytes_out: bytes(&self.up),
+        }
+    }
+}
+
+/// A stream accepted on the Unix socket of a tunnel.
+#[cfg(unix)]
+type SocketStream = UnixStream;
+
+/// Unix sockets are never bound on other platforms, so this is never constructed.
+#[cfg(not(unix))]
+type SocketStream = TcpStream;
+
+/// A Unix socket that a tunnel is served on, which is removed when dropped.
+struct SocketListener {
+    /// Path of the socket.
+    path: PathBuf,
+
+    #[cfg(unix)]
+    listener: UnixListener,
+
+    /// Whether data between the server and client is compressed.
+    compress: bool,
+
+    /// Whether data between the server and client is encrypted.
+    encrypt: bool,
+}
+
+impl SocketListener {
+    /// Bind a new socket, replacing a stale one that nothing is listening on.
+    #[cfg(unix)]
+    async fn bind(path: PathBuf, compress: bool) -> Result<Self, &'static str> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            if !metadata.file_type().is_socket() || UnixStream::connect(&path).await.is_ok() {
+                return Err("socket already in use");
+            }
+            std::fs::remove_file(&path).map_err(|_| "failed to remove stale socket")?;
+        }
+        let listener = UnixListener::bind(&path).map_err(|err| match err.kind() {
+            io::ErrorKind::PermissionDenied => "permission denied",
+            _ => "failed to bind socket",
+        })?;
+        Ok(Self {
+            path,
+            listener,
+            compress,
+            encrypt: false,
+        })
+    }
+
+    #[cfg(not(unix))]
+    async fn bind(_path: PathBuf, _compress: bool) -> Result<Self, &'static str> {
+        Err("Unix sockets are not supported on this server")
+    }
+
+    #[cfg(unix)]
+    async fn accept(&self) -> io::Result<SocketStream> {
+        let (stream, _) = self.listener.accept().await?;
+        Ok(stream)
+    }
+
+    #[cfg(not(unix))]
+    async fn accept(&self) -> io::Result<SocketStream> {
+        future::pending().await
+    }
+}
+
+impl Drop for SocketListener {
+    fn drop(&mut self) {
+        _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A TCP connection accepted on the public port of a tunnel, which was handed to the
+/// stream of [`Server::incoming`] instead of being proxied to the client.
+pub struct IncomingConnection {
+    /// The tunnel's public port.
+    pub tunnel_port: u16,
+
+    /// Address of the remote peer.
+    pub peer: SocketAddr,
+
+    /// The connection, which is still counted against the tunnel's connection limit
+    /// until this is dropped.
+    pub stream: TcpStream,
+
+    /// Bytes already read from the connection, such as the head of an HTTP request that
+    /// was routed to the tunnel by its subdomain, which are otherwise empty.
+    pub head: Vec<u8>,
+
+    /// Name of the client owning the tunnel, if it authenticated.
+    pub owner: Option<String>,
+
+    /// Slot of the connection in its tunnel's limit.
+    slot: ConnectionSlot,
+}
+
+/// Keeps a connection counted against its tunnel's limit until it is dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Server {
+    /// Create a new server with a specified minimum port number.
+    pub fn new(port_range: RangeInclusive<u16>, secret: Option<&str>) -> Self {
+        assert!(!port_range.is_empty(), "must provide at least one port");
+        Server {
+            port_range,
+            open_ports: None,
+            udp: false,
+            compress: false,
+            encrypt_data: false,
+            udp_idle_timeout: UDP_IDLE_TIMEOUT,
+            tls: None,
+            rate_limit: (None, None),
+            max_tunnel_lifetime: None,
+            idle_timeout: None,
+            tunnel_idle_timeout: None,
+            max_conns_per_tunnel: None,
+            max_tunnels_per_client: None,
+            max_tunnels: None,
+            pending_conns_limit: PENDING_CONNS_LIMIT,
+            ip_filter: IpFilter::default(),
+            geoip_filter: None,
+            tunnel_addrs: vec![Ipv4Addr::UNSPECIFIED.into()],
+            allowed_tunnel_addrs: Vec::new(),
+            control_addrs: vec![Ipv4Addr::UNSPECIFIED.into()],
+            v6only: None,
+            port_strategy: PortStrategy::default(),
+            ephemeral_ports: false,
+            port_cooldown: Duration::ZERO,
+            port_retention: Duration::ZERO,
+            ports: Arc::default(),
+            reservations: Vec::new(),
+            tunnel_limits: Vec::new(),
+            quota_limits: Vec::new(),
+            quota_period: None,
+            quota_file: None,
+            quotas: Arc::default(),
+            access_log_file: None,
+            access_log_max_size: None,
+            access_log: Arc::default(),
+            handshake_rate_limit: None,
+            ban: None,
+            peers: Arc::default(),
+            http_addr: None,
+            https_addr: None,
+            ws_addr: None,
+            socket_dir: None,
+            routes: Arc::default(),
+            metrics_addr: None,
+            metrics: Arc::default(),
+            admin_addr: None,
+            admin_socket: None,
+            tunnels: Arc::default(),
+            network_timeout: NETWORK_TIMEOUT,
+            max_frame_length: MAX_FRAME_LENGTH,
+            tcp_options: TcpOptions::default(),
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            heartbeat_timeout: HEARTBEAT_TIMEOUT,
+            drain_timeout: DRAIN_TIMEOUT,
+            connection_grace: CONNECTION_GRACE,
+            shutdown: CancellationToken::new(),
+            drain: Drain::default(),
+            conns: Arc::new(DashMap::new()),
+            on_connection: None,
+            events: Events::default(),
+            incoming: Arc::default(),
+            auth: Arc::new(RwLock::new(AuthState {
+                current: secret
+                    .map(|secret| Arc::new(Keyring::new([secret])) as Arc<dyn Authorizer>),
+                generation: 1,
+                previous: None,
+            })),
+            secret_overlap: Duration::ZERO,
+        }
+    }
+
+    /// Require clients to authenticate with one of these secrets.
+    ///
+    /// This can be called on a clone of a running server to add or revoke secrets.
+    /// Revoked secrets can no longer open tunnels, but tunnels that are already open
+    /// keep forwarding connections.
+    pub fn set_secrets(&self, secrets: Keyring) {
+        self.set_authenticator(secrets);
+    }
+
+    /// Require clients to authenticate in some other way than with a shared secret,
+    /// such as with a [`TokenKey`](crate::auth::TokenKey).
+    ///
+    /// Like [`Server::set_secrets`], this can be called on a running server.
+    pub fn set_authenticator(&self, auth: impl Authenticator + 'static) {
+        self.set_authorizer(auth);
+    }
+
+    /// Decide on clients with an [`Authorizer`], which may check them asynchronously.
+    ///
+    /// Like [`Server::set_secrets`], this can be called on a running server.
+    pub fn set_authorizer(&self, auth: impl Authorizer + 'static) {
+        let mut state = self.auth.write().unwrap();
+        state.current = Some(Arc::new(auth));
+        state.generation += 1;
+        state.previous = None;
+    }
+
+    /// Replace the secrets that clients authenticate with, like [`Server::set_secrets`],
+    /// but keep accepting the previous ones for new connections for the overlap set with
+    /// [`Server::set_secret_overlap`], so that clients can move to the new secrets
+    /// without a gap.
+    ///
+    /// Each replacement starts a new generation of secrets, which the server logs for
+    /// each client that authenticates, as `secret_generation`.
+    pub fn rotate_secrets(&self, secrets: Keyring) {
+        let mut state = self.auth.write().unwrap();
+        let previous = state.current.replace(Arc::new(secrets));
+        let until = Instant::now() + self.secret_overlap;
+        state.previous = previous
+            .filter(|_| !self.secret_overlap.is_zero())
+            .map(|auth| (auth, state.generation, until));
+        state.generation += 1;
+    }
+
+    /// Set how long the secrets replaced by [`Server::rotate_secrets`] are still
+    /// accepted, which is not at all by default.
+    pub fn set_secret_overlap(&mut self, overlap: Duration) {
+        self.secret_overlap = overlap;
+    }
+
+    /// Decide on clients by calling an async function with what they sent to
+    /// authenticate, such as to validate a bearer token with an external service.
+    ///
+    /// The function is also called for each proxied connection, and has up to the
+    /// network timeout to decide.
+    pub fn set_auth_callback<F, Fut>(&self, callback: F)
+    where
+        F: Fn(ClientAuth) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<AuthDecision>> + Send + 'static,
+    {
+        self.set_authorizer(AuthCallback(callback));
+    }
+
+    /// Set whether clients may forward UDP ports, which is disabled by default.
+    pub fn set_udp(&mut self, udp: bool) {
+        self.udp = udp;
+    }
+
+    /// Set whether clients may ask for their connections to be compressed, which is
+    /// disabled by default.
+    ///
+    /// Data between the server and client is then compressed for the tunnels that
+    /// asked for it, with the first method in [`COMPRESSION_METHODS`] that the client
+    /// offered. Clients that ask a server which does not allow it fall back to sending
+    /// data uncompressed.
+    pub fn set_compress(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    /// Set whether clients that authenticate with a secret may ask for their connections
+    /// to be encrypted, which is disabled by default.
+    ///
+    /// Data between the server and client is then encrypted with keys derived from the
+    /// secret, as described in [`crate::crypto`], for the tunnels that asked for it.
+    /// Clients that ask a server which does not allow it, or that authenticate with a
+    /// token, fall back to sending data unencrypted.
+    pub fn set_encrypt_data(&mut self, encrypt_data: bool) {
+        self.encrypt_data = encrypt_data;
+    }
+
+    /// Set how long a UDP flow may go without traffic before it is dropped.
+    pub fn set_udp_idle_timeout(&mut self, timeout: Duration) {
+        self.udp_idle_timeout = timeout;
+    }
+
+    /// Require TLS on the control port, for both control and data connections.
+    ///
+    /// Connections to the forwarded ports themselves are not affected.
+    pub fn set_tls(&mut self, tls: ServerTls) {
+        self.tls = Some(tls);
+    }
+
+    /// Limit the bytes per second that each tunnel can send and receive.
+    ///
+    /// Sending is traffic from the client out to the public port, and receiving is
+    /// traffic from the public port back to the client.
+    pub fn set_rate_limit(&mut self, up: Option<u64>, down: Option<u64>) {
+        self.rate_limit = (up, down);
+    }
+
+    /// Close a client's tunnels once they have been open for this long.
+    pub fn set_max_tunnel_lifetime(&mut self, lifetime: Duration) {
+        self.max_tunnel_lifetime = Some(lifetime);
+    }
+
+    /// Close a client's tunnels if none of them receive a new connection for this long.
+    ///
+    /// Connections and UDP flows that are already being proxied are not affected.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = Some(timeout);
+    }
+
+    /// Close a client's tunnels once none of them have had a connection open for this long.
+    ///
+    /// Unlike [`Server::set_idle_timeout`], long-lived connections keep the tunnels open.
+    /// Tunnels are checked on every heartbeat, so they may stay open for up to one
+    /// heartbeat interval longer.
+    pub fn set_tunnel_idle_timeout(&mut self, timeout: Duration) {
+        self.tunnel_idle_timeout = Some(timeout);
+    }
+
+    /// Limit how many connections each tunnel may have open at once, counting those
+    /// waiting for the client to accept them.
+    ///
+    /// Connections beyond the limit are closed right away, and new UDP flows are dropped.
+    pub fn set_max_conns_per_tunnel(&mut self, max: usize) {
+        self.max_conns_per_tunnel = Some(max);
+    }
+
+    /// Limit how many tunnels each client may have open at once, across all of its
+    /// control connections.
+    ///
+    /// Clients are told apart by their identity if they authenticate, or by their
+    /// address otherwise. A handshake that would take a client over the limit is
+    /// refused with a "tunnel quota exceeded" error.
+    pub fn set_max_tunnels_per_client(&mut self, max: usize) {
+        self.max_tunnels_per_client = Some(max);
+    }
+
+    /// Limit how many tunnels all clients together may have open at once.
+    ///
+    /// A handshake that would take the server over the limit is refused with a "server
+    /// tunnel limit reached" error, so that one client can't use up the port range for
+    /// everyone else. Clients may also be limited by the secret they authenticate with,
+    /// see [`Server::set_tunnel_limits`].
+    pub fn set_max_tunnels(&mut self, max: usize) {
+        self.max_tunnels = Some(max);
+    }
+
+    /// Limit how many connections each tunnel holds while waiting for the client to
+    /// accept them, which is 128 by default.
+    ///
+    /// A tunnel at this limit stops accepting on its listeners until the client catches
+    /// up, leaving further connections in the kernel's backlog, and drops new UDP flows.
+    pub fn set_pending_conns_limit(&mut self, limit: usize) {
+        assert!(limit > 0, "pending connection limit must be positive");
+        self.pending_conns_limit = limit;
+    }
+
+    /// Restrict which remote addresses may connect to forwarded ports.
+    pub fn set_ip_filter(&mut self, filter: IpFilter) {
+        self.ip_filter = filter;
+    }
+
+    /// Restrict which countries may connect to forwarded ports, as looked up in a GeoIP
+    /// database. Connections from other countries are closed as soon as they are
+    /// accepted, before the client hears of them.
+    pub fn set_geoip_filter(&mut self, filter: GeoIpFilter) {
+        self.geoip_filter = Some(Arc::new(filter));
+    }
+
+    /// Listen for control connections on all of these addresses, instead of just
+    /// `0.0.0.0`.
+    ///
+    /// The server fails to start if any of them cannot be bound, rather than serving on
+    /// the others. A listening socket passed by systemd takes precedence over them.
+    pub fn set_control_addrs(&mut self, addrs: Vec<IpAddr>) {
+        assert!(!addrs.is_empty(), "control addresses must not be empty");
+        self.control_addrs = addrs;
+    }
+
+    /// Set whether IPv6 control and tunnel sockets are restricted to IPv6 connections,
+    /// with `IPV6_V6ONLY`, instead of accepting IPv4 ones as mapped addresses too.
+    ///
+    /// By default, they are restricted only if IPv4 addresses are bound alongside them,
+    /// so that `::` on its own is dual-stack on every platform. A client asking for
+    /// IPv6 tunnels only always gets ports restricted to IPv6.
+    pub fn set_v6only(&mut self, v6only: bool) {
+        self.v6only = Some(v6only);
+    }
+
+    /// Bind each tunnel port on all of these addresses, instead of just `0.0.0.0`.
+    ///
+    /// Pass both `0.0.0.0` and `::` to accept connections over IPv4 and IPv6 on every
+    /// port. Clients may ask for only the addresses of one family.
+    pub fn set_tunnel_addrs(&mut self, addrs: Vec<IpAddr>) {
+        assert!(!addrs.is_empty(), "tunnel addresses must not be empty");
+        self.tunnel_addrs = addrs;
+    }
+
+    /// Allow clients to bind tunnels to specific addresses of the server's interfaces.
+    ///
+    /// Tunnels are bound to all interfaces unless the client asks for one of these.
+    pub fn set_allowed_tunnel_addrs(&mut self, addrs: Vec<IpAddr>) {
+        self.allowed_tunnel_addrs = addrs;
+    }
+
+    /// Let clients without credentials open tunnels on random ports in a range, while
+    /// clients that authenticate may still ask for any port.
+    ///
+    /// Unauthenticated clients that ask for a specific port, or a range of ports that is
+    /// not within this one, are refused with an "authentication required for requested
+    /// port" error. Without any secret configured, this applies to all clients.
+    pub fn set_open_port_range(&mut self, range: RangeInclusive<u16>) {
+        self.open_ports = Some(range);
+    }
+
+    /// Set how ports are chosen for tunnels that don't ask for a specific one, which is
+    /// at random by default.
+    pub fn set_port_strategy(&mut self, strategy: PortStrategy) {
+        self.port_strategy = strategy;
+    }
+
+    /// Set whether to let the system choose any free ephemeral port for tunnels that
+    /// don't ask for a specific one, even outside the port range, which is disabled by
+    /// default. This takes one bind instead of probing the range for a free port.
+    ///
+    /// Tunnels that ask for a port or a range of ports still get one in the port range,
+    /// and so do clients whose credentials only allow a narrower range.
+    pub fn set_ephemeral_ports(&mut self, ephemeral: bool) {
+        self.ephemeral_ports = ephemeral;
+    }
+
+    /// Avoid choosing a port for a new tunnel until this long after it was released,
+    /// such as to let its connections in TIME_WAIT expire.
+    ///
+    /// Clients may still ask for a released port by number, such as when reconnecting.
+    pub fn set_port_cooldown(&mut self, cooldown: Duration) {
+        self.port_cooldown = cooldown;
+    }
+
+    /// Give a tunnel that asks for any port the port of the last tunnel with the same
+    /// name and identity, if it closed less than this long ago and the port is free.
+    ///
+    /// A tunnel whose port was taken in the meantime gets a new one, and its client is
+    /// told with [`ServerMessage::PortReassigned`]. At most a few thousand names are
+    /// remembered at a time, forgetting the longest released ones first. This is off
+    /// by default.
+    pub fn set_port_retention(&mut self, retention: Duration) {
+        self.port_retention = retention;
+    }
+
+    /// Reserve ranges of ports for the clients that authenticate with an identity.
+    ///
+    /// Other clients cannot request a reserved port, and are never assigned one.
+    pub fn set_reservations(&mut self, reservations: Vec<(String, RangeInclusive<u16>)>) {
+        self.reservations = reservations;
+    }
+
+    /// Limit how many tunnels the clients that authenticate with an identity may have
+    /// open at once, below [`Server::set_max_tunnels_per_client`] if that is also set.
+    ///
+    /// A handshake that would take a client over its limit is refused with a "tunnel
+    /// quota exceeded" error.
+    pub fn set_tunnel_limits(&mut self, limits: Vec<(String, usize)>) {
+        self.tunnel_limits = limits;
+    }
+
+    /// Limit the bytes proxied for the clients that authenticate with an identity.
+    ///
+    /// Once a client's total exceeds its quota, its new tunnels and connections are
+    /// refused until the totals are reset. Connections that are already open finish.
+    pub fn set_quotas(&mut self, quotas: Vec<(String, u64)>) {
+        self.quota_limits = quotas;
+    }
+
+    /// Reset the byte totals of all clients after each period of this length.
+    pub fn set_quota_period(&mut self, period: Duration) {
+        assert!(!period.is_zero(), "quota period must be positive");
+        self.quota_period = Some(period);
+    }
+
+    /// Save the byte totals of clients to a JSON file, and load them from it on start.
+    pub fn set_quota_file(&mut self, path: PathBuf) {
+        self.quota_file = Some(path);
+    }
+
+    /// Reset the byte totals of all clients, as if a new quota period started.
+    pub fn reset_quotas(&self) {
+        self.quotas.reset();
+    }
+
+    /// Append a line to a file for each proxied connection when it closes, as described
+    /// in [`crate::access_log`].
+    pub fn set_access_log(&mut self, path: PathBuf) {
+        self.access_log_file = Some(path);
+    }
+
+    /// Rotate the access log once it would grow past a size in bytes, keeping a few of
+    /// the older files as described in [`crate::access_log`].
+    pub fn set_access_log_max_size(&mut self, max_size: u64) {
+        self.access_log_max_size = Some(max_size);
+    }
+
+    /// Reopen the access log at its path, such as after logrotate moved it away.
+    pub fn reopen_access_log(&self) {
+        self.access_log.reopen();
+    }
+
+    /// Limit how many connections per second each address may open to the control port.
+    ///
+    /// Every proxied connection opens a new control connection from the client, so the
+    /// limit should leave room for the traffic of busy tunnels.
+    pub fn set_handshake_rate_limit(&mut self, per_sec: u32) {
+        assert!(per_sec > 0, "handshake rate limit must be positive");
+        self.handshake_rate_limit = Some(per_sec);
+    }
+
+    /// Ban addresses from the control port for a while after repeated authentication
+    /// failures in a row.
+    pub fn set_ban(&mut self, failures: u32, duration: Duration) {
+        assert!(failures > 0, "ban threshold must be positive");
+        self.ban = Some((failures, duration));
+    }
+
+    /// Serve HTTP tunnels on one port, routing each request by the subdomain in its
+    /// `Host` header to the client that asked for it.
+    ///
+    /// Clients are told that their tunnel is on this address's port, so it should not
+    /// be 0. Other tunnels are still forwarded on ports of their own.
+    pub fn set_http_addr(&mut self, addr: SocketAddr) {
+        self.http_addr = Some(addr);
+    }
+
+    /// Also serve HTTP tunnels over HTTPS on one port, routing each connection by the
+    /// subdomain of the server name in its TLS handshake.
+    ///
+    /// Connections are passed through still encrypted, so the client's local service
+    /// terminates TLS with a certificate for its own hostname. Clients are told about
+    /// the HTTP port if there is one, and this one otherwise.
+    pub fn set_https_addr(&mut self, addr: SocketAddr) {
+        self.https_addr = Some(addr);
+    }
+
+    /// Also accept clients that connect over WebSocket on the given address, for
+    /// networks that only let HTTP out.
+    ///
+    /// Both control and data connections of such clients arrive here. If TLS is
+    /// enabled, it is required on this address too.
+    pub fn set_ws_addr(&mut self, addr: SocketAddr) {
+        self.ws_addr = Some(addr);
+    }
+
+    /// Allow clients to serve tunnels on Unix sockets in a directory, instead of on
+    /// public ports, for a reverse proxy on the same machine to connect to.
+    ///
+    /// Clients ask for a socket by name, and it is removed when the tunnel closes.
+    /// This is only supported on Unix.
+    pub fn set_socket_dir(&mut self, dir: PathBuf) {
+        self.socket_dir = Some(dir);
+    }
+
+    /// Serve Prometheus metrics over HTTP at `/metrics` on the given address.
+    pub fn set_metrics_addr(&mut self, addr: SocketAddr) {
+        self.metrics_addr = Some(addr);
+    }
+
+    /// Serve the admin API on the given address, to list and close tunnels.
+    ///
+    /// Connections from addresses other than loopback are refused, so the address
+    /// should be a loopback one.
+    pub fn set_admin_addr(&mut self, addr: SocketAddr) {
+        self.admin_addr = Some(addr);
+    }
+
+    /// Serve the admin API on a Unix socket at the given path, which only the user
+    /// running the server may connect to.
+    pub fn set_admin_socket(&mut self, path: PathBuf) {
+        self.admin_socket = Some(path);
+    }
+
+    /// Set how long to wait for handshakes and initial messages from new connections,
+    /// which is 3 seconds by default.
+    pub fn set_network_timeout(&mut self, timeout: Duration) {
+        self.network_timeout = timeout;
+    }
+
+    /// Set the maximum byte length of a message from a client, which is
+    /// [`MAX_FRAME_LENGTH`] by default. A client that sends a longer one is disconnected
+    /// as soon as it goes over, so that it can't make the server buffer much.
+    pub fn set_max_frame_length(&mut self, max_length: usize) {
+        self.max_frame_length = max_length;
+    }
+
+    /// Set socket options, such as TCP keepalives, for connections accepted on tunnel
+    /// ports and on the control port, which includes the clients' data connections.
+    pub fn set_tcp_options(&mut self, options: TcpOptions) {
+        self.tcp_options = options;
+    }
+
+    /// Set how often heartbeats are sent to clients on their control connections.
+    pub fn set_heartbeat_interval(&mut self, interval: Duration) {
+        assert!(!interval.is_zero(), "heartbeat interval must be positive");
+        self.heartbeat_interval = interval;
+    }
+
+    /// Close a client's tunnels and free their ports if no heartbeat arrives for this long.
+    ///
+    /// This only applies to clients that have sent at least one heartbeat, so that older
+    /// clients which never send any keep working.
+    pub fn set_heartbeat_timeout(&mut self, timeout: Duration) {
+        self.heartbeat_timeout = timeout;
+    }
+
+    /// Set how long to wait for proxied connections to finish when shutting down.
+    pub fn set_drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    /// Set how long to keep each forwarded connection for the client to accept, which
+    /// is 10 seconds by default. Connections that the client doesn't accept in time are
+    /// closed as soon as this elapses.
+    pub fn set_connection_grace(&mut self, grace: Duration) {
+        self.connection_grace = grace;
+    }
+
+    /// Call a function with a record of each proxied connection once it closes, such
+    /// as to keep access logs outside of the server's own logging.
+    ///
+    /// It is called from the task of the connection, so it should not block.
+    pub fn set_on_connection(
+        &mut self,
+        on_connection: impl Fn(ConnectionRecord) + Send + Sync + 'static,
+    ) {
+        self.on_connection = Some(Arc::new(on_connection));
+    }
+
+    /// Stream changes in the state of the tunnels from now on.
+    ///
+    /// The stream skips events if it falls far behind.
+    pub fn events(&self) -> impl Stream<Item = ServerEvent> + Send + Unpin + 'static {
+        self.events.subscribe()
+    }
+
+    /// Take the TCP connections to the public ports of tunnels from now on, instead of
+    /// proxying them to the clients, to handle them some other way.
+    ///
+    /// Only the latest stream receives connections, and the server goes back to
+    /// proxying them once it is dropped. Connections still go through the server's IP
+    /// filter, quotas and connection limits first. If the stream falls far behind, new
+    /// connections are dropped with a warning.
+    pub fn incoming(&self) -> impl Stream<Item = IncomingConnection> + Send + Unpin + 'static {
+        let (sender, receiver) = mpsc::channel(INCOMING_BUFFER);
+        *self.incoming.lock().unwrap() = Some(sender);
+        stream::unfold(receiver, |mut receiver| async move {
+            let conn = receiver.recv().await?;
+            Some((conn, receiver))
+        })
+        .boxed()
+    }
+
+    /// Hand a connection to the stream of [`Server::incoming`], giving it back if no
+    /// stream is open.
+    fn hand_off(&self, conn: IncomingConnection) -> Option<IncomingConnection> {
+        let Some(sender) = self.incoming.lock().unwrap().clone() else {
+            return Some(conn);
+        };
+        match sender.try_send(conn) {
+            Ok(()) => None,
+            Err(TrySendError::Full(conn)) => {
+                warn!(
+                    tunnel_port = conn.tunnel_port,
+                    "incoming stream is behind, dropping a connection"
+                );
+                None
+            }
+            Err(TrySendError::Closed(conn)) => Some(conn),
+        }
+    }
+
+    /// Metrics about the tunnels and connections on this server.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Start the server, listening for new connections until it is shut down.
+    ///
+    /// The returned future does not borrow the server, so it can be spawned while
+    /// keeping the server around to call [`Server::shutdown`] later.
+    pub fn listen(&self) -> impl Future<Output = Result<()>> + Send + 'static {
+        let this = Arc::new(self.clone());
+        async move { this.accept_loop().await }
+    }
+
+    /// Stop accepting connections and tunnels, then wait for proxied connections to
+    /// finish, up to the drain timeout.
+    pub async fn shutdown(&self) {
+        info!("shutting down, draining connections");
+        systemd::notify(false);
+        self.shutdown.cancel();
+        if !self.drain.wait(self.drain_timeout).await {
+            warn!(remaining = self.drain.len(), "drain timeout elapsed");
+        }
+        if let Err(err) = self.quotas.save() {
+            warn!(%err, "could not save quota totals");
+        }
+        self.access_log.close();
+    }
+
+    async fn accept_loop(self: Arc<Self>) -> Result<()> {
+        let listeners = match systemd::take_listener()? {
+            Some(listener) => {
+                let addr = listener.local_addr()?;
+                info!(?addr, "server listening on socket passed by systemd");
+                vec![listener]
+            }
+            None => {
+                let v6only = self.v6only(&self.control_addrs);
+                let mut listeners = Vec::new();
+                for &ip in &self.control_addrs {
+                    let addr = SocketAddr::new(ip, CONTROL_PORT);
+                    let listener = bind_tcp(addr, v6only)
+                        .with_context(|| format!("could not bind control address {addr}"))?;
+                    info!(?addr, "server listening");
+                    listeners.push(listener);
+                }
+                listeners
+            }
+        };
+
+        self.quotas.configure(
+            self.quota_limits.clone(),
+            self.quota_period,
+            self.quota_file.clone(),
+        )?;
+        if let Some(path) = &self.access_log_file {
+            self.access_log
+                .open(path.clone(), self.access_log_max_size)?;
+        }
+        let quotas = Arc::clone(&self.quotas);
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            let mut save = interval(quota::SAVE_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = save.tick() => {}
+                    _ = shutdown.cancelled() => return,
+                }
+                if let Err(err) = quotas.save() {
+                    warn!(%err, "could not save quota totals");
+                }
+            }
+        });
+
+        let ws_listener = match self.ws_addr {
+            Some(ws_addr) => {
+                let ws_listener = TcpListener::bind(&ws_addr)
+                    .await
+                    .with_context(|| format!("could not bind websocket address {ws_addr}"))?;
+                info!(addr = ?ws_listener.local_addr()?, "websocket listening");
+                Some(ws_listener)
+            }
+            None => None,
+        };
+
+        for (addr, tls) in [(self.http_addr, false), (self.https_addr, true)] {
+            let Some(addr) = addr else {
+                continue;
+            };
+            let scheme = if tls { "https" } else { "http" };
+            let http_listener = TcpListener::bind(&addr)
+                .await
+                .with_context(|| format!("could not bind {scheme} address {addr}"))?;
+            let serve = Arc::clone(&self).serve_http(http_listener, tls);
+            let shutdown = self.shutdown.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    result = serve => result,
+                    _ = shutdown.cancelled() => Ok(()),
+                }
+            });
+        }
+
+        if let Some(metrics_addr) = self.metrics_addr {
+            let metrics_listener = TcpListener::bind(&metrics_addr)
+                .await
+                .with_context(|| format!("could not bind metrics address {metrics_addr}"))?;
+            let serve = metrics::serve(self.metrics(), metrics_listener);
+            let shutdown = self.shutdown.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    result = serve => result,
+                    _ = shutdown.cancelled() => Ok(()),
+                }
+            });
+        }
+
+        if let Some(admin_addr) = self.admin_addr {
+            let admin_listener = TcpListener::bind(&admin_addr)
+                .await
+                .with_context(|| format!("could not bind admin address {admin_addr}"))?;
+            let serve = admin::serve(
+                Arc::clone(&self.tunnels),
+                Arc::clone(&self.quotas),
+                admin_listener,
+            );
+            let shutdown = self.shutdown.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    result = serve => result,
+                    _ = shutdown.cancelled() => Ok(()),
+                }
+            });
+        }
+
+        if let Some(path) = &self.admin_socket {
+            let context = || format!("could not bind admin socket {}", path.display());
+            let admin_listener = SocketListener::bind(path.clone(), false)
+                .await
+                .map_err(anyhow::Error::msg)
+                .with_context(context)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let permissions = std::fs::Permissions::from_mode(0o600);
+                std::fs::set_permissions(path, permissions).with_context(context)?;
+            }
+            let serve = serve_admin_socket(
+                Arc::clone(&self.tunnels),
+                Arc::clone(&self.quotas),
+                admin_listener,
+            );
+            let shutdown = self.shutdown.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    result = serve => result,
+                    _ = shutdown.cancelled() => Ok(()),
+                }
+            });
+        }
+
+        if self.handshake_rate_limit.is_some() || self.ban.is_some() {
+            let this = Arc::clone(&self);
+            tokio::spawn(async move {
+                let mut summary = interval(REJECTED_SUMMARY_INTERVAL);
+                summary.tick().await;
+                loop {
+                    tokio::select! {
+                        _ = summary.tick() => {}
+                        _ = this.shutdown.cancelled() => return,
+                    }
+                    let rejected = this.peers.expire(this.handshake_rate_limit, this.ban);
+                    if rejected > 0 {
+                        info!(
+                            rejected,
+                            "dropped connections from rate limited or banned addresses"
+                        );
+                    }
+                }
+            });
+        }
+
+        systemd::notify(true);
+        loop {
+            let (stream, addr, websocket) = tokio::select! {
+                result = accept_control(&listeners) => {
+                    let (stream, addr) = result?;
+                    (stream, addr, false)
+                }
+                result = accept_optional(&ws_listener) => {
+                    let (stream, addr) = result?;
+                    (stream, addr, true)
+                }
+                _ = self.shutdown.cancelled() => return Ok(()),
+            };
+            // Drop abusive peers before spending anything on them, including logs.
+            if !self.peers.admit(addr.ip(), self.handshake_rate_limit) {
+                continue;
+            }
+            if let Err(err) = self.tcp_options.apply(&stream) {
+                warn!(%err, "could not set socket options");
+            }
+            let this = Arc::clone(&self);
+            self.drain.spawn(
+                async move {
+                    info!("incoming connection");
+                    let result = match this.accept_transport(stream, websocket).await {
+                        Ok(Some(stream)) => this.handle_connection(stream, addr.ip()).await,
+                        Ok(None) => Ok(()),
+                        Err(err) => Err(err),
+                    };
+                    if let Err(err) = result {
+                        warn!(%err, "connection exited with error");
+                    } else {
+                        info!("connection exited");
+                    }
+                }
+                .instrument(info_span!(
+                    "control",
+                    ?addr,
+                    identity = field::Empty,
+                    secret_generation = field::Empty,
+                    conns = field::Empty,
+                )),
+            );
+        }
+    }
+
+    /// Set up a new connection from a client, with a TLS handshake if enabled, and a
+    /// WebSocket handshake if it arrived on the WebSocket address.
+    ///
+    /// Returns `None` if the client did not use TLS when it was expected or vice
+    /// versa, after letting it know if possible.
+    async fn accept_transport(
+        &self,
+        stream: TcpStream,
+        websocket: bool,
+    ) -> Result<Option<Box<dyn Transport>>> {
+        let Some(stream) = self.accept_tls(stream).await? else {
+            return Ok(None);
+        };
+        if !websocket {
+            return Ok(Some(stream));
+        }
+        let stream = timeout(
+            self.network_timeout,
+            tokio_tungstenite::accept_async(stream),
+        )
+        .await
+        .context("timed out waiting for WebSocket handshake")?
+        .context("WebSocket handshake failed")?;
+        Ok(Some(Box::new(WebSocket::new(stream))))
+    }
+
+    /// Perform a TLS handshake with a new connection if TLS is enabled.
+    async fn accept_tls(&self, stream: TcpStream) -> Result<Option<Box<dyn Transport>>> {
+        let Some(tls) = &self.tls else {
+            return Ok(Some(Box::new(stream)));
+        };
+
+        // TLS clients speak first, starting with a handshake record (0x16). Plaintext
+        // clients either send a JSON message or wait for an authentication challenge.
+        let mut first = [0u8; 1];
+        let is_tls = match timeout(TLS_DETECT_TIMEOUT, stream.peek(&mut first)).await {
+            Ok(result) => result? > 0 && first[0] == 0x16,
+            Err(_) => false,
+        };
+        if !is_tls {
+            warn!(
+                event = "handshake_rejected",
+                "client did not start a TLS handshake"
+            );
+            self.metrics.handshake(false);
+            let mut stream = Delimited::new(stream);
+            stream.set_timeout(self.network_timeout);
+            let message = "server requires TLS, but the client connected without it";
+            stream.send(ServerMessage::Error(message.into())).await?;
+            return Ok(None);
+        }
+
+        let stream = timeout(self.network_timeout, tls.accept(stream))
+            .await
+            .context("timed out waiting for TLS handshake")?
+            .context("TLS handshake failed")?;
+        Ok(Some(Box::new(stream)))
+    }
+
+    /// Bind a port for a tunnel request on each of its addresses, all with the same
+    /// port number.
+    async fn create_listeners(
+        &self,
+        request: &PortRequest,
+        identity: Option<&Identity>,
+    ) -> Result<Vec<TcpListener>, &'static str> {
+        let (ips, v6only) = self.bind_ips(request)?;
+        let bind = |port| future::ready(bind_each(&ips, port, |addr| bind_tcp(addr, v6only)));
+        self.bind_port(request, identity, bind).await
+    }
+
+    async fn create_udp_sockets(
+        &self,
+        request: &PortRequest,
+        identity: Option<&Identity>,
+    ) -> Result<Vec<UdpSocket>, &'static str> {
+        let (ips, v6only) = self.bind_ips(request)?;
+        let bind = |port| future::ready(bind_each(&ips, port, |addr| bind_udp(addr, v6only)));
+        self.bind_port(request, identity, bind).await
+    }
+
+    /// Addresses to bind the port of a tunnel request on, and whether IPv6 sockets
+    /// should leave IPv4 connections to the others.
+    fn bind_ips(&self, request: &PortRequest) -> Result<(Vec<IpAddr>, bool), &'static str> {
+        let ips: Vec<IpAddr> = match request.bind_addr {
+            Some(ip) => vec![ip],
+            None => self.tunnel_addrs.clone(),
+        };
+        let ips: Vec<IpAddr> = match request.family {
+            Some(family) => ips.into_iter().filter(|&ip| family.contains(ip)).collect(),
+            None => ips,
+        };
+        if ips.is_empty() {
+            return Err("server does not bind tunnels on the requested address family");
+        }
+        let v6only = request.family == Some(IpFamily::V6) || self.v6only(&ips);
+        Ok((ips, v6only))
+    }
+
+    /// Whether IPv6 sockets bound alongside these addresses should be restricted to
+    /// IPv6, which by default leaves IPv4 connections to any IPv4 addresses.
+    fn v6only(&self, ips: &[IpAddr]) -> bool {
+        self.v6only
+            .unwrap_or_else(|| ips.iter().any(IpAddr::is_ipv4))
+    }
+
+    /// Bind the Unix socket that a tunnel request asks for, in the socket directory.
+    async fn bind_socket(
+        &self,
+        request: &PortRequest,
+        compress: bool,
+    ) -> Result<SocketListener, &'static str> {
+        let Some(dir) = &self.socket_dir else {
+            return Err("Unix socket tunnels are not enabled");
+        };
+        if request.protocol != Protocol::Tcp {
+            return Err("Unix sockets are only supported for TCP tunnels");
+        }
+        let name = request.socket.as_deref().unwrap_or_default();
+        let valid = name.len() <= 64
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+            && !name.starts_with('.');
+        if name.is_empty() || !valid {
+            return Err("invalid socket name");
+        }
+        SocketListener::bind(dir.join(name), compress).await
+    }
+
+    /// The ports that a client with the given identity may forward, which is the server's
+    /// range narrowed down to the one allowed for its credentials, if any, or to the open
+    /// range for clients without credentials.
+    fn allowed_ports(&self, identity: Option<&Identity>) -> RangeInclusive<u16> {
+        let range = match identity {
+            Some(identity) => identity.ports.as_ref(),
+            None => self.open_ports.as_ref(),
+        };
+        match range {
+            Some(range) => {
+                let start = *range.start().max(self.port_range.start());
+                let end = *range.end().min(self.port_range.end());
+                start..=end
+            }
+            None => self.port_range.clone(),
+        }
+    }
+
+    /// Route HTTP requests for the subdomain of a tunnel request to this client, returning
+    /// the port that the tunnel is reported on.
+    ///
+    /// Each control connection may have one subdomain, and each subdomain one client. If
+    /// the request has no subdomain, a random one is chosen.
+    fn add_route<'a>(
+        &'a self,
+        request: &PortRequest,
+        route: &mut Option<(u16, Route<'a>, mpsc::Receiver<RoutedConnection>)>,
+    ) -> Result<u16, &'static str> {
+        let Some(http_port) = self.http_addr.or(self.https_addr).map(|addr| addr.port()) else {
+            return Err("HTTP tunnels are not enabled");
+        };
+        if request.protocol != Protocol::Tcp {
+            return Err("subdomains are only supported for TCP tunnels");
+        }
+        if route.is_some() {
+            return Err("only one subdomain may be requested");
+        }
+        let (sender, receiver) = mpsc::channel(64);
+        let subdomain = match &request.subdomain {
+            Some(subdomain) => {
+                if !valid_subdomain(subdomain) {
+                    return Err("invalid subdomain");
+                }
+                let subdomain = subdomain.to_ascii_lowercase();
+                match self.routes.entry(subdomain.clone()) {
+                    Entry::Occupied(_) => return Err("subdomain already in use"),
+                    Entry::Vacant(entry) => entry.insert(sender),
+                };
+                subdomain
+            }
+            None => {
+                let prefix = request.subdomain_prefix.as_deref().unwrap_or_default();
+                let valid = prefix.is_empty()
+                    || (prefix.len() <= MAX_SUBDOMAIN_PREFIX_LENGTH && valid_subdomain(prefix));
+                if !valid {
+                    return Err("invalid subdomain prefix");
+                }
+                // Choose again on the rare collision with a subdomain that is in use.
+                let prefix = prefix.to_ascii_lowercase();
+                let mut chosen = None;
+                for _ in 0..16 {
+                    let subdomain = random_subdomain(&prefix);
+                    if let Entry::Vacant(entry) = self.routes.entry(subdomain.clone()) {
+                        entry.insert(sender.clone());
+                        chosen = Some(subdomain);
+                        break;
+                    }
+                }
+                chosen.ok_or("could not choose a free subdomain")?
+            }
+        };
+        let guard = Route {
+            routes: &self.routes,
+            subdomain,
+        };
+        *route = Some((http_port, guard, receiver));
+        Ok(http_port)
+    }
+
+    /// Accept connections on the HTTP or HTTPS port, handing each one to the client whose
+    /// subdomain it is for.
+    async fn serve_http(self: Arc<Self>, listener: TcpListener, tls: bool) -> Result<()> {
+        let scheme = if tls { "https" } else { "http" };
+        info!(addr = ?listener.local_addr()?, "{scheme} listening");
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let this = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(err) = this.route_http(stream, addr, tls).await {
+                    warn!(%err, ?addr, "{scheme} request failed");
+                }
+            });
+        }
+    }
+
+    /// Read the head of an HTTP request, or the TLS ClientHello of an HTTPS one, and pass
+    /// the connection on to its tunnel, or reject it if there is none.
+    async fn route_http(&self, mut stream: TcpStream, addr: SocketAddr, tls: bool) -> Result<()> {
+        let (complete, max_length): (fn(&[u8]) -> bool, _) = match tls {
+            false => (
+                |head| head.windows(4).any(|w| w == b"\r\n\r\n"),
+                MAX_HTTP_HEAD_LENGTH,
+            ),
+            true => (tls_record_complete, MAX_TLS_RECORD_LENGTH),
+        };
+        let mut head = Vec::new();
+        let mut buf = [0; 1024];
+        while !complete(&head) {
+            let n = timeout(self.network_timeout, stream.read(&mut buf)).await??;
+            if n == 0 || head.len() + n > max_length {
+                return Ok(());
+            }
+            head.extend_from_slice(&buf[..n]);
+        }
+        let subdomain = match tls {
+            false => http_subdomain(&head),
+            true => tls_subdomain(&head),
+        };
+        let sender = subdomain
+            .and_then(|subdomain| self.routes.get(&subdomain).map(|sender| sender.clone()));
+        let mut stream = match sender {
+            Some(sender) => match sender.send((stream, addr, head)).await {
+                Ok(()) => return Ok(()),
+                Err(mpsc::error::SendError((stream, _, _))) => stream,
+            },
+            None => stream,
+        };
+        let response = if tls {
+            TLS_UNRECOGNIZED_NAME
+        } else {
+            HTTP_NOT_FOUND
+        };
+        stream.write_all(response).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Whether a client with the given identity may forward a port.
+    fn may_forward(&self, port: u16, identity: Option<&str>) -> bool {
+        let mut owners = self
+            .reservations
+            .iter()
+            .filter(|(_, ports)| ports.contains(&port))
+            .map(|(owner, _)| owner.as_str())
+            .peekable();
+        owners.peek().is_none() || owners.any(|owner| Some(owner) == identity)
+    }
+
+    async fn bind_port<T, F, Fut>(
+        &self,
+        request: &PortRequest,
+        identity: Option<&Identity>,
+        bind: F,
+    ) -> Result<Vec<T>, &'static str>
+    where
+        T: LocalPort,
+        F: Fn(u16) -> Fut,
+        Fut: Future<Output = io::Result<Vec<T>>>,
+    {
+        let try_bind = |port: u16| {
+            let bind = bind(port);
+            async move {
+                bind.await.map_err(|err| match err.kind() {
+                    io::ErrorKind::AddrInUse => PORT_IN_USE,
+                    io::ErrorKind::PermissionDenied => "permission denied",
+                    io::ErrorKind::AddrNotAvailable => "bind address not available on server",
+                    _ => "failed to bind to port",
+                })
+            }
+        };
+        let allowed = self.allowed_ports(identity);
+        let identity = identity.map(|identity| identity.name.as_str());
+        if request.port > 0 {
+            // Client requests a specific port number.
+            if !self.port_range.contains(&request.port) {
+                return Err("client port number not in allowed range");
+            }
+            if !allowed.contains(&request.port) {
+                return Err("client port number not in range allowed for this secret");
+            }
+            if !self.may_forward(request.port, identity) {
+                return Err("port reserved");
+            }
+            let result = try_bind(request.port).await;
+            let reclaimed = match identity {
+                Some(name) if request.reclaim && result.as_ref().err() == Some(&PORT_IN_USE) => {
+                    self.tunnels.reclaim(request.port, name)
+                }
+                _ => false,
+            };
+            if !reclaimed {
+                return result;
+            }
+            // Wait for the stale tunnel to notice and release the port.
+            info!(
+                event = "port_reclaimed",
+                tunnel_port = request.port,
+                "reclaiming port from a stale tunnel",
+            );
+            let deadline = Instant::now() + RECLAIM_TIMEOUT;
+            loop {
+                sleep(RECLAIM_INTERVAL).await;
+                match try_bind(request.port).await {
+                    Err(PORT_IN_USE) if Instant::now() < deadline => continue,
+                    result => return result,
+                }
+            }
+        } else {
+            let range = match &request.range {
+                Some(range) => {
+                    let start = *range.start().max(allowed.start());
+                    let end = *range.end().min(allowed.end());
+                    if start > end {
+                        return Err("client port range not in allowed range");
+                    }
+                    start..=end
+                }
+                None if allowed.is_empty() => {
+                    return Err("no ports in range allowed for this secret");
+                }
+                None => allowed,
+            };
+
+            // Named tunnels get their last port back while it is retained and free.
+            let retained = self
+                .retained_port(request, identity)
+                .filter(|port| range.contains(port) && self.may_forward(*port, identity));
+            if let Some(port) = retained {
+                if let Ok(listener) = try_bind(port).await {
+                    return Ok(listener);
+                }
+            }
+
+            // Let the system choose a port, binding again if it is reserved for others.
+            if self.ephemeral_ports
+                && request.range.is_none()
+                && request.sticky.is_none()
+                && range == self.port_range
+            {
+                for _ in 0..EPHEMERAL_ATTEMPTS {
+                    let bound = match try_bind(0).await {
+                        Ok(bound) => bound,
+                        // Another address of the tunnel has the port the first one got.
+                        Err(PORT_IN_USE) => continue,
+                        Err(err) => return Err(err),
+                    };
+                    let port = bound[0]
+                        .local_port()
+                        .map_err(|_| "failed to bind to port")?;
+                    if self.may_forward(port, identity) {
+                        return Ok(bound);
+                    }
+                }
+                return Err("failed to find an available port");
+            }
+
+            // Client requests any available port in range.
+            //
+            // In this case, we bind to 150 random port numbers. We choose this value because in
+            // order to find a free port with probability at least 1-δ, when ε proportion of the
+            // ports are currently available, it suffices to check approximately -2 ln(δ) / ε
+            // independently and uniformly chosen ports (up to a second-order term in ε).
+            //
+            // Checking 150 times gives us 99.999% success at utilizing 85% of ports under these
+            // conditions, when ε=0.15 and δ=0.00001. Narrower ranges are checked exhaustively.
+            let ports: Vec<u16> = if let Some(key) = &request.sticky {
+                // Probe forward from a port derived from the client's identity, so that
+                // it gets the same port each time it connects, as long as it is free.
+                let len = range.len() as u64;
+                let offset = sticky_offset(identity, key);
+                (0..len.min(PORT_ATTEMPTS as u64))
+                    .map(|i| range.start() + ((offset + i) % len) as u16)
+                    .collect()
+            } else {
+                self.candidate_ports(range)
+            };
+            let ports = ports
+                .into_iter()
+                .filter(|&port| self.may_forward(port, identity));
+            for port in ports {
+                match try_bind(port).await {
+                    Ok(listener) => {
+                        self.ports
+                            .next
+                            .store(port.wrapping_add(1), Ordering::Relaxed);
+                        return Ok(listener);
+                    }
+                    Err(_) => continue,
+                }
+            }
+            Err("failed to find an available port")
+        }
+    }
+
+    /// The port retained for the name of a tunnel request, if it has one.
+    fn retained_port(&self, request: &PortRequest, identity: Option<&str>) -> Option<u16> {
+        let name = request.name.as_ref()?;
+        if self.port_retention.is_zero() {
+            return None;
+        }
+        let key = (identity.map(String::from), name.clone(), request.protocol);
+        self.ports.retained(&key, self.port_retention)
+    }
+
+    /// Remember the port of a new named tunnel, and whether the port retained for its
+    /// name was taken in the meantime.
+    fn retain_name(
+        &self,
+        request: &PortRequest,
+        identity: Option<&str>,
+        port: u16,
+        retained: Option<u16>,
+        reassigned: &mut Vec<String>,
+    ) {
+        let Some(name) = &request.name else {
+            return;
+        };
+        if self.port_retention.is_zero() {
+            return;
+        }
+        if retained.is_some_and(|retained| retained != port) {
+            info!(
+                event = "port_reassigned",
+                tunnel_port = port,
+                name,
+                "retained port was taken",
+            );
+            reassigned.push(name.clone());
+        }
+        let key = (identity.map(String::from), name.clone(), request.protocol);
+        self.ports.assign(key, port, self.port_retention);
+    }
+
+    /// List up to [`PORT_ATTEMPTS`] ports of a range to try for a new tunnel, in order
+    /// of preference under the port strategy, leaving out those that tunnels of this
+    /// server hold and those released too recently.
+    fn candidate_ports(&self, range: RangeInclusive<u16>) -> Vec<u16> {
+        let free = |port: &u16| {
+            let cooled = self.port_cooldown.is_zero()
+                || (self.ports.released.get(port))
+                    .is_none_or(|at| at.elapsed() >= self.port_cooldown);
+            cooled && !self.tunnels.is_open(*port)
+        };
+        match self.port_strategy {
+            PortStrategy::Random => {
+                let mut ports: Vec<u16> = if range.len() <= PORT_ATTEMPTS {
+                    let mut ports: Vec<u16> = range.collect();
+                    fastrand::shuffle(&mut ports);
+                    ports
+                } else {
+                    let sample = (0..PORT_ATTEMPTS).map(|_| fastrand::u16(range.clone()));
+                    sample.collect()
+                };
+                ports.retain(free);
+                ports
+            }
+            PortStrategy::Sequential => {
+                let next = self.ports.next.load(Ordering::Relaxed);
+                let next = if range.contains(&next) {
+                    next
+                } else {
+                    *range.start()
+                };
+                let ports = (next..=*range.end()).chain(*range.start()..next);
+                ports.filter(free).take(PORT_ATTEMPTS).collect()
+            }
+            PortStrategy::Lru => {
+                // Ports of the range that were never released come first, in random
+                // order, and then the released ones, oldest release first.
+                let mut released: Vec<(u16, Instant)> = (self.ports.released.iter())
+                    .filter(|entry| range.contains(entry.key()))
+                    .map(|entry| (*entry.key(), *entry.value()))
+                    .collect();
+                released.sort_by_key(|&(_, at)| at);
+                let fresh = |port: &u16| !self.ports.released.contains_key(port);
+                let mut ports: Vec<u16> = range.filter(fresh).filter(free).collect();
+                // Shuffle only as many as are tried.
+                for i in 0..ports.len().min(PORT_ATTEMPTS) {
+                    let j = fastrand::usize(i..ports.len());
+                    ports.swap(i, j);
+                }
+                ports.truncate(PORT_ATTEMPTS);
+                let released = released.into_iter().map(|(port, _)| port).filter(free);
+                ports.extend(released.take(PORT_ATTEMPTS - ports.len()));
+                ports
+            }
+        }
+    }
+
+    async fn handle_connection(&self, stream: Box<dyn Transport>, ip: IpAddr) -> Result<()> {
+        let mut stream = Delimited::new(stream);
+        stream.set_timeout(self.network_timeout);
+        stream.set_max_length(self.max_frame_length);
+        let auth = self.auth.read().unwrap().clone();
+        let mut owner = None;
+        let mut data_key = None;
+        let mut unknown_answer = None;
+        // The first message of a client that didn't answer the challenge, if allowed.
+        let mut unauthenticated = None;
+        if let Some(current) = auth.current.clone() {
+            let answer = match &self.open_ports {
+                Some(_) => open_challenge(&mut stream).await,
+                None => server_challenge(&mut stream).await.map(Ok),
+            };
+            match answer {
+                Ok(Ok((challenge, reply))) => {
+                    let client = ClientAuth {
+                        challenge,
+                        reply,
+                        addr: ip,
+                    };
+                    match self.authorize_rotated(current, &auth, client.clone()).await {
+                        Ok((identity, auth, generation)) => {
+                            let span = Span::current();
+                            span.record("identity", identity.name.as_str());
+                            span.record("secret_generation", generation);
+                            if self.encrypt_data {
+                                data_key = auth.data_key(&client);
+                            }
+                            owner = Some((identity, auth));
+                        }
+                        Err(err) => unknown_answer = Some((client, err)),
+                    }
+                }
+                Ok(Err(message)) => unauthenticated = Some(message),
+                Err(err) => return self.reject_handshake(stream, ip, err).await,
+            }
+        }
+
+        let message = match unauthenticated.take() {
+            Some(message) => {
+                // Connections for tunnels of authenticated clients need their credentials.
+                let owned = match &message {
+                    Some(ClientMessage::Accept(id)) => self
+                        .conns
+                        .get(id)
+                        .is_some_and(|entry| entry.value().1.tunnel().owner.is_some()),
+                    _ => false,
+                };
+                if owned {
+                    return self.reject_handshake(stream, ip, "invalid secret").await;
+                }
+                message
+            }
+            None => stream.recv_timeout().await?,
+        };
+        if let Some((client, err)) = unknown_answer {
+            // Connections for tunnels that are already open may use the credentials that
+            // opened the tunnel, even if they have been revoked since.
+            let tunnel_owner = match &message {
+                Some(ClientMessage::Accept(id)) => self
+                    .conns
+                    .get(id)
+                    .and_then(|entry| entry.value().1.tunnel().owner.clone()),
+                _ => None,
+            };
+            let owned = match tunnel_owner {
+                Some((name, auth)) => self
+                    .authorize(&*auth, client)
+                    .await
+                    .is_ok_and(|identity| identity.name == name),
+                None => false,
+            };
+            if !owned {
+                return self.reject_handshake(stream, ip, err).await;
+            }
+        }
+
+        self.metrics.handshake(true);
+        self.peers.succeed(ip);
+
+        match message {
+            Some(ClientMessage::Authenticate(_)) => {
+                warn!("unexpected authenticate");
+                Ok(())
+            }
+            Some(ClientMessage::Heartbeat) => {
+                warn!("unexpected heartbeat");
+                Ok(())
+            }
+            Some(ClientMessage::Ping(seq)) => {
+                // Answer pings until the client sends something else or closes.
+                let mut message = Some(ClientMessage::Ping(seq));
+                while let Some(ClientMessage::Ping(seq)) = message {
+                    stream.send(ServerMessage::Pong(seq)).await?;
+                    message = stream.recv_timeout().await?;
+                }
+                Ok(())
+            }
+            Some(ClientMessage::Status) => {
+                info!("status requested");
+                let name = owner.as_ref().map(|(identity, _)| identity.name.as_str());
+                let tunnels: Vec<TunnelStatus> = self
+                    .tunnels
+                    .list()
+                    .into_iter()
+                    .map(|tunnel| TunnelStatus {
+                        port: tunnel.port,
+                        protocol: tunnel.protocol,
+                        connections: tunnel.connections,
+                        uptime_secs: tunnel.uptime_secs,
+                        owned: name.is_some() && tunnel.identity.as_deref() == name,
+                    })
+                    .collect();
+                for batch in tunnels.chunks(STATUS_BATCH_SIZE) {
+                    stream.send(ServerMessage::Status(batch.to_vec())).await?;
+                }
+                stream.send(ServerMessage::Status(Vec::new())).await?;
+                Ok(())
+            }
+            Some(ClientMessage::Hello(requests)) => {
+                info!(
+                    event = "handshake_accepted",
+                    ports = requests.len(),
+                    "handshake accepted"
+                );
+                let mut opened = Vec::new();
+                let result = self
+                    .handle_tunnels(stream, requests, owner, data_key, ip, &mut opened)
+                    .await;
+                for tunnel in opened {
+                    if let Endpoint::Port(port) = &tunnel {
+                        self.ports.released.insert(*port, Instant::now());
+                        self.ports.release_names(*port);
+                    }
+                    let reason = result.as_ref().err().map(|err| format!("{err:#}"));
+                    self.events
+                        .send(ServerEvent::Disconnected { tunnel, reason });
+                }
+                result
+            }
+            Some(ClientMessage::Accept(id)) => {
+                let Some((_, (opened, pending, permit))) = self.conns.remove(&id) else {
+                    warn!(%id, "missing connection");
+                    return Ok(());
+                };
+                // The connection is no longer pending, so the tunnel may accept another.
+                drop(permit);
+                let span = pending.span(id);
+                let result = self
+                    .forward_connection(stream, id, opened, pending)
+                    .instrument(span)
+                    .await;
+                let (bytes_out, bytes_in) = *result.as_ref().unwrap_or(&(0, 0));
+                self.events.send(ServerEvent::ConnectionClosed {
+                    id,
+                    bytes_in,
+                    bytes_out,
+                });
+                result.map(drop)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Decide on a client with the current authorizer, or else with the previous one if
+    /// it is still accepted, returning the authorizer that let it in and its generation.
+    async fn authorize_rotated(
+        &self,
+        current: Arc<dyn Authorizer>,
+        state: &AuthState,
+        client: ClientAuth,
+    ) -> Result<(Identity, Arc<dyn Authorizer>, u64), String> {
+        let err = match self.authorize(&*current, client.clone()).await {
+            Ok(identity) => return Ok((identity, current, state.generation)),
+            Err(err) => err,
+        };
+        match &state.previous {
+            Some((previous, generation, until)) if Instant::now() < *until => {
+                let identity = self.authorize(&**previous, client).await.map_err(|_| err)?;
+                info!(
+                    generation,
+                    "client authenticated with a secret that is being rotated out"
+                );
+                Ok((identity, Arc::clone(previous), *generation))
+            }
+            _ => Err(err),
+        }
+    }
+
+    /// Ask an authorizer about a client, returning its identity or the reason that it
+    /// was rejected.
+    async fn authorize(
+        &self,
+        auth: &dyn Authorizer,
+        client: ClientAuth,
+    ) -> Result<Identity, String> {
+        match timeout(self.network_timeout, auth.authorize(client)).await {
+            Ok(Ok(AuthDecision::Allow(identity))) => Ok(identity),
+            Ok(Ok(AuthDecision::Deny(reason))) => Err(reason),
+            Ok(Err(err)) => {
+                warn!(%err, "could not authorize client");
+                Err("authorization failed".into())
+            }
+            Err(_) => {
+                warn!("timed out authorizing client");
+                Err("authorization failed".into())
+            }
+        }
+    }
+
+    /// Proxy a connection that the client accepted, then report it as closed, returning
+    /// the bytes sent each way.
+    async fn forward_connection(
+        &self,
+        stream: Delimited<Box<dyn Transport>>,
+        id: Uuid,
+        opened: SystemTime,
+        pending: PendingConnection,
+    ) -> Result<(u64, u64)> {
+        info!("forwarding connection");
+        let _guard = self.metrics.open_connection();
+        let owner = pending
+            .tunnel()
+            .owner
+            .as_ref()
+            .map(|(name, _)| name.clone());
+        let (tunnel, protocol, peer, result) = match pending {
+            PendingConnection::Tcp(stream2, peer, head, tunnel, _slot) => {
+                let result = forward_stream(stream, id, stream2, &head, &tunnel).await;
+                (
+                    Endpoint::Port(tunnel.port),
+                    Protocol::Tcp,
+                    Some(peer),
+                    result,
+                )
+            }
+            PendingConnection::Socket(stream2, path, tunnel, _slot) => {
+                let result = forward_stream(stream, id, stream2, &[], &tunnel).await;
+                (Endpoint::Socket(path), Protocol::Tcp, None, result)
+            }
+            PendingConnection::Udp(socket, peer, datagrams, tunnel, _slot) => {
+                let stream = stream.into_datagrams();
+                let port = tunnel.port;
+                let idle_timeout = self.udp_idle_timeout;
+                let result =
+                    proxy_udp_flow(stream, &socket, peer, datagrams, tunnel, idle_timeout).await;
+                (Endpoint::Port(port), Protocol::Udp, Some(peer), result)
+            }
+        };
+        // Failed connections are recorded too, without the bytes they sent.
+        let (bytes_up, bytes_down) = *result.as_ref().unwrap_or(&(0, 0));
+        let record = ConnectionRecord {
+            id,
+            tunnel,
+            protocol,
+            peer,
+            owner,
+            opened,
+            duration: opened.elapsed().unwrap_or_default(),
+            bytes_up,
+            bytes_down,
+            reason: result.as_ref().err().map(|err| format!("{err:#}")),
+        };
+        connection_closed(&record);
+        if let Some(owner) = &record.owner {
+            self.quotas.add(owner, bytes_up + bytes_down);
+        }
+        self.access_log.log(&record);
+        if let Some(on_connection) = &self.on_connection {
+            on_connection(record);
+        }
+        result
+    }
+
+    /// Let a client know that its handshake failed.
+    async fn reject_handshake(
+        &self,
+        mut stream: Delimited<Box<dyn Transport>>,
+        ip: IpAddr,
+        err: impl fmt::Display,
+    ) -> Result<()> {
+        warn!(event = "auth_failed", %err, "server handshake failed");
+        self.metrics.handshake(false);
+        if let Some((failures, duration)) = self.ban {
+            self.peers.fail(ip, failures, duration);
+        }
+        stream.send(ServerMessage::Error(err.to_string())).await?;
+        Ok(())
+    }
+
+    async fn handle_tunnels(
+        &self,
+        mut stream: Delimited<Box<dyn Transport>>,
+        requests: Vec<PortRequest>,
+        owner: Option<(Identity, Arc<dyn Authorizer>)>,
+        data_key: Option<DataKey>,
+        ip: IpAddr,
+        opened: &mut Vec<Endpoint>,
+    ) -> Result<()> {
+        if requests.is_empty() {
+            stream
+                .send(ServerMessage::Error("no ports requested".into()))
+                .await?;
+            return Ok(());
+        }
+
+        let legacy =
+            matches!(&requests[..], [request] if request.version == Some(LEGACY_PROTOCOL_VERSION));
+        if legacy {
+            warn!(
+                event = "legacy_client",
+                "client speaks the original protocol, which the next release will refuse"
+            );
+            stream.set_legacy();
+        }
+        let incompatible = requests
+            .iter()
+            .filter_map(|request| request.version)
+            .find(|version| !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(version))
+            .filter(|_| !legacy);
+        if let Some(version) = incompatible {
+            warn!(version, "client speaks an incompatible protocol version");
+            let message = format!(
+                "client speaks protocol version {version}, but this server speaks version \
+                 {PROTOCOL_VERSION} and accepts versions {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}"
+            );
+            stream.send(ServerMessage::Error(message)).await?;
+            return Ok(());
+        }
+        if let (None, Some(open)) = (&owner, &self.open_ports) {
+            let pinned = requests.iter().any(|request| {
+                let outside = |range: &RangeInclusive<u16>| {
+                    range.start() < open.start() || range.end() > open.end()
+                };
+                request.port > 0 || request.range.as_ref().is_some_and(outside)
+            });
+            if pinned {
+                warn!("unauthenticated client requested a port outside the open range");
+                let message = "authentication required for requested port";
+                stream.send(ServerMessage::Error(message.into())).await?;
+                return Ok(());
+            }
+        }
+
+        let identity = owner.as_ref().map(|(identity, _)| identity);
+        let name = identity.map(|identity| identity.name.as_str());
+        if name.is_some_and(|name| self.quotas.exceeded(name)) {
+            warn!(event = "quota_exceeded", "client has used up its quota");
+            stream
+                .send(ServerMessage::Error("bandwidth quota exceeded".into()))
+                .await?;
+            return Ok(());
+        }
+        // The count is held until the control connection closes, however it ends.
+        let client = name.map_or_else(|| ip.to_string(), String::from);
+        let max = self
+            .tunnel_limits
+            .iter()
+            .filter(|(label, _)| Some(label.as_str()) == name)
+            .map(|&(_, max)| max)
+            .chain(self.max_tunnels_per_client)
+            .min()
+            .unwrap_or(usize::MAX);
+        let total_max = self.max_tunnels.unwrap_or(usize::MAX);
+        let _client_guard = match self.tunnels.reserve(client, requests.len(), max, total_max) {
+            Ok(guard) => guard,
+            Err(TunnelLimit::Client) => {
+                warn!(
+                    event = "quota_exceeded",
+                    max, "client has too many tunnels open"
+                );
+                stream
+                    .send(ServerMessage::Error("tunnel quota exceeded".into()))
+                    .await?;
+                return Ok(());
+            }
+            Err(TunnelLimit::Server) => {
+                warn!(max = total_max, "server has too many tunnels open");
+                stream
+                    .send(ServerMessage::Error("server tunnel limit reached".into()))
+                    .await?;
+                return Ok(());
+            }
+        };
+        let owner = owner
+            .as_ref()
+            .map(|(identity, auth)| (identity.name.clone(), Arc::clone(auth)));
+        let reserved = requests
+            .iter()
+            .find(|request| request.port > 0 && !self.may_forward(request.port, name));
+        if let Some(request) = reserved {
+            warn!(
+                port = request.port,
+                "client requested a port reserved for someone else"
+            );
+            stream
+                .send(ServerMessage::Error("port reserved".into()))
+                .await?;
+            return Ok(());
+        }
 
-    /// Concurrent map of IDs to incoming connections.
-    conns: Arc<DashMap<Uuid, TcpStream>>,
-}
+        let disallowed = requests
+            .iter()
+            .filter_map(|request| request.bind_addr)
+            .find(|addr| !self.allowed_tunnel_addrs.contains(addr));
+        if let Some(addr) = disallowed {
+            warn!(%addr, "client requested a bind address that is not allowed");
+            let message = format!("binding tunnels to {addr} is not allowed");
+            stream.send(ServerMessage::Error(message)).await?;
+            return Ok(());
+        }
 
-impl Server {
-    /// Create a new server with a specified minimum port number.
-    pub fn new(port_range: RangeInclusive<u16>, secret: Option<&str>) -> Self {
-        assert!(!port_range.is_empty(), "must provide at least one port");
-        Server {
-            port_range,
-            conns: Arc::new(DashMap::new()),
-            auth: secret.map(Authenticator::new),
+        // Bind each requested port independently, so that one failure does not
+        // prevent the client from forwarding the others.
+        let mut listeners = Vec::new();
+        let mut sockets = Vec::new();
+        let mut socket_listeners = Vec::new();
+        let mut route = None;
+        let mut results = Vec::new();
+        let mut compressed = HashSet::new();
+        let mut encrypted = HashSet::new();
+        let mut in_use = Vec::new();
+        let mut reassigned = Vec::new();
+        let mut chosen_subdomain = None;
+        let stats_interval = requests
+            .iter()
+            .filter_map(|request| request.stats_interval_ms)
+            .min()
+            .map(|ms| Duration::from_millis(ms).max(MIN_STATS_INTERVAL));
+        let blocks: Vec<_> = requests
+            .iter()
+            .map(|request| (request.block, request.protocol))
+            .collect();
+        // One method compresses every tunnel that offers it: the first one that the
+        // server supports, in the order of preference of the tunnels asking for any.
+        let compression = requests
+            .iter()
+            .flat_map(|request| &request.compression)
+            .find(|method| self.compress && COMPRESSION_METHODS.contains(method))
+            .copied();
+        for request in requests {
+            let compress = compression.is_some_and(|method| request.compression.contains(&method));
+            if request
+                .name
+                .as_ref()
+                .is_some_and(|name| name.len() > MAX_TUNNEL_NAME_LENGTH)
+            {
+                results.push(Err("tunnel name too long".into()));
+                continue;
+            }
+            let retained = self.retained_port(&request, name);
+            let result = match request.protocol {
+                _ if request.socket.is_some() => match self.bind_socket(&request, compress).await {
+                    Ok(mut listener) => {
+                        listener.encrypt = request.encrypt && data_key.is_some();
+                        let path = listener.path.clone();
+                        socket_listeners.push(listener);
+                        Ok(Endpoint::Socket(path))
+                    }
+                    Err(err) => Err(err),
+                },
+                _ if request.subdomain.is_some() || request.subdomain_prefix.is_some() => {
+                    let result = self.add_route(&request, &mut route);
+                    if result.is_ok() && request.subdomain.is_none() {
+                        chosen_subdomain =
+                            route.as_ref().map(|(_, route, _)| route.subdomain.clone());
+                    }
+                    result.map(Endpoint::Port)
+                }
+                Protocol::Tcp => match self.create_listeners(&request, identity).await {
+                    Ok(bound) => {
+                        let port = bound[0].local_addr()?.port();
+                        listeners.extend(bound.into_iter().map(|listener| (port, listener)));
+                        self.retain_name(&request, name, port, retained, &mut reassigned);
+                        Ok(Endpoint::Port(port))
+                    }
+                    Err(err) => Err(err),
+                },
+                Protocol::Udp if !self.udp => Err("UDP forwarding is not enabled"),
+                Protocol::Udp => match self.create_udp_sockets(&request, identity).await {
+                    Ok(bound) => {
+                        let port = bound[0].local_addr()?.port();
+                        sockets.extend(bound.into_iter().map(|socket| (port, Arc::new(socket))));
+                        self.retain_name(&request, name, port, retained, &mut reassigned);
+                        Ok(Endpoint::Port(port))
+                    }
+                    Err(err) => Err(err),
+                },
+            };
+            match &result {
+                Ok(Endpoint::Port(port)) => {
+                    info!(
+                        event = "port_allocated",
+                        tunnel_port = port,
+                        protocol = ?request.protocol,
+                        "new client",
+                    );
+                    if compress && request.protocol == Protocol::Tcp {
+                        compressed.insert(*port);
+                    }
+                    if request.encrypt && data_key.is_some() && request.protocol == Protocol::Tcp {
+                        encrypted.insert(*port);
+                    }
+                }
+                Ok(Endpoint::Socket(path)) => {
+                    info!(
+                        event = "port_allocated",
+                        tunnel_socket = %path.display(),
+                        "new client",
+                    );
+                }
+                Err(err) if request.port > 0 && *err == PORT_IN_USE => in_use.push(request.port),
+                Err(_) => (),
+            }
+            results.push(result.map_err(String::from));
+        }
+        // Blocks of ports are forwarded all or none, so release the ports of any block
+        // that was not bound in full. Until then, the listeners hold them all.
+        let failed: HashSet<_> = blocks
+            .iter()
+            .zip(&results)
+            .filter_map(|((block, _), result)| block.filter(|_| result.is_err()))
+            .collect();
+        for ((block, protocol), result) in blocks.into_iter().zip(&mut results) {
+            let Some(block) = block.filter(|block| failed.contains(block)) else {
+                continue;
+            };
+            if let Ok(Endpoint::Port(port)) = *result {
+                match protocol {
+                    Protocol::Tcp => listeners.retain(|(bound, _)| *bound != port),
+                    Protocol::Udp => sockets.retain(|(bound, _)| *bound != port),
+                }
+                compressed.remove(&port);
+                encrypted.remove(&port);
+                warn!(
+                    port,
+                    block, "releasing port of a block that is not available in full"
+                );
+                *result = Err("another port of the block is not available".into());
+            }
+        }
+        let compress_sockets = socket_listeners.iter().any(|listener| listener.compress);
+        if let Some(method) = compression.filter(|_| !compressed.is_empty() || compress_sockets) {
+            stream.send(ServerMessage::Compress(method)).await?;
+        }
+        let encrypt_sockets = socket_listeners.iter().any(|listener| listener.encrypt);
+        if !encrypted.is_empty() || encrypt_sockets {
+            stream.send(ServerMessage::Encrypt).await?;
+        }
+        for port in in_use {
+            stream.send(ServerMessage::PortInUse(port)).await?;
+        }
+        for name in reassigned {
+            stream.send(ServerMessage::PortReassigned(name)).await?;
+        }
+        if let Some(subdomain) = chosen_subdomain {
+            stream.send(ServerMessage::Subdomain(subdomain)).await?;
+        }
+        stream.send(ServerMessage::Hello(results.clone())).await?;
+        for tunnel in results.into_iter().flatten() {
+            let owner = name.map(String::from);
+            let event = ServerEvent::Connected {
+                tunnel: tunnel.clone(),
+                owner,
+            };
+            self.events.send(event);
+            opened.push(tunnel);
+        }
+        if listeners.is_empty()
+            && sockets.is_empty()
+            && socket_listeners.is_empty()
+            && route.is_none()
+        {
+            return Ok(());
         }
-    }
 
-    /// Start the server, listening for new connections.
-    pub async fn listen(self) -> Result<()> {
-        let this = Arc::new(self);
-        let addr = SocketAddr::from(([0, 0, 0, 0], CONTROL_PORT));
-        let listener = TcpListener::bind(&addr).await?;
-        info!(?addr, "server listening");
+        // Each forwarded port gets its own limiters and counters, shared by all of its
+        // connections. The guards unregister the tunnels from the metrics on return.
+        // Ports bound on several addresses have a listener or socket for each of them.
+        let tcp_ports = listeners.iter().map(|(port, _)| (*port, Protocol::Tcp));
+        let udp_ports = sockets.iter().map(|(port, _)| (*port, Protocol::Udp));
+        let mut ports: Vec<_> = tcp_ports.chain(udp_ports).collect();
+        ports.dedup();
+        // They are also listed in the admin API, which can close them all with `close`,
+        // and a new connection of the same client can take them over with `reclaim`.
+        let close = CancellationToken::new();
+        let reclaim = CancellationToken::new();
+        let name = owner.as_ref().map(|(name, _)| name.clone());
+        let mut guards = Vec::new();
+        let mut admin_guards = Vec::new();
+        let mut tunnels = HashMap::new();
+        for (port, protocol) in ports {
+            let guard = self.metrics.open_tunnel(port, protocol);
+            let tunnel = self.new_tunnel(port, owner.clone(), guard.bytes());
+            admin_guards.push(self.tunnels.open(
+                port,
+                protocol,
+                name.clone(),
+                &tunnel.conns,
+                guard.bytes(),
+                &close,
+                &reclaim,
+            ));
+            tunnels.insert((port, protocol), tunnel);
+            guards.push(guard);
+        }
+        if let Some((port, _, _)) = &route {
+            // The HTTP port is shared by many clients, so it is not counted in the metrics.
+            let tunnel = self.new_tunnel(*port, owner.clone(), &TunnelBytes::default());
+            tunnels.insert((*port, Protocol::Tcp), tunnel);
+        }
+        for port in compressed {
+            if let Some(tunnel) = tunnels.get_mut(&(port, Protocol::Tcp)) {
+                tunnel.compress = true;
+            }
+        }
+        for port in encrypted {
+            if let Some(tunnel) = tunnels.get_mut(&(port, Protocol::Tcp)) {
+                tunnel.encrypt = data_key.clone();
+            }
+        }
+        // Tunnels on Unix sockets have no port to be counted by in the metrics.
+        let socket_tunnels: Vec<Tunnel> = socket_listeners
+            .iter()
+            .map(|listener| Tunnel {
+                compress: listener.compress,
+                encrypt: data_key.clone().filter(|_| listener.encrypt),
+                ..self.new_tunnel(0, owner.clone(), &TunnelBytes::default())
+            })
+            .collect();
+        let mut flows: HashMap<u16, HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>> = HashMap::new();
+        let mut buf = vec![0; MAX_DATAGRAM_LENGTH];
+        let mut heartbeat = interval(self.heartbeat_interval);
+        let stats_period = stats_interval.unwrap_or(self.heartbeat_interval);
+        let mut stats = interval_at(Instant::now() + stats_period, stats_period);
+        let liveness = sleep(self.heartbeat_timeout);
+        let mut client_heartbeats = false;
+        let lifetime = sleep(self.max_tunnel_lifetime.unwrap_or(Duration::MAX));
+        let idle = sleep(self.idle_timeout.unwrap_or(Duration::MAX));
+        tokio::pin!(liveness, lifetime, idle);
+        let mut last_active = Instant::now();
+        let mut limit_warnings = HashMap::new();
 
         loop {
-            let (stream, addr) = listener.accept().await?;
-            let this = Arc::clone(&this);
-            tokio::spawn(
-                async move {
-                    info!("incoming connection");
-                    if let Err(err) = this.handle_connection(stream).await {
-                        warn!(%err, "connection exited with error");
-                    } else {
-                        info!("connection exited");
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = close.cancelled() => {
+                    info!(event = "tunnel_closed", "tunnel closed through the admin API");
+                    let message = "tunnel closed by the server operator";
+                    stream.send(ServerMessage::Error(message.into())).await?;
+                    return Ok(());
+                }
+                _ = reclaim.cancelled() => {
+                    info!(event = "tunnel_reclaimed", "tunnel reclaimed by a new connection");
+                    let message = "tunnel reclaimed by a new connection with the same secret";
+                    stream.send(ServerMessage::Error(message.into())).await?;
+                    return Ok(());
+                }
+                _ = &mut lifetime, if self.max_tunnel_lifetime.is_some() => {
+                    info!(event = "tunnel_expired", "tunnel lifetime exceeded, closing");
+                    stream.send(ServerMessage::Error("tunnel expired".into())).await?;
+                    return Ok(());
+                }
+                _ = &mut idle, if self.idle_timeout.is_some() => {
+                    info!(event = "tunnel_expired", "tunnel idle, closing");
+                    stream.send(ServerMessage::Error("tunnel expired".into())).await?;
+                    return Ok(());
+                }
+                message = stream.recv() => match message? {
+                    Some(ClientMessage::Heartbeat) => {
+                        client_heartbeats = true;
+                        liveness.as_mut().reset(Instant::now() + self.heartbeat_timeout);
+                    }
+                    Some(ClientMessage::Ping(nonce)) => {
+                        stream.send(ServerMessage::Pong(nonce)).await?;
+                    }
+                    Some(_) => warn!("unexpected message on control connection"),
+                    None => return Ok(()),
+                },
+                _ = &mut liveness, if client_heartbeats => {
+                    warn!(event = "heartbeat_timeout", "no heartbeat from client, closing");
+                    return Ok(());
+                }
+                _ = heartbeat.tick() => {
+                    if stream.send(ServerMessage::Heartbeat).await.is_err() {
+                        // Assume that the TCP connection has been dropped.
+                        return Ok(());
+                    }
+                    Span::current().record("conns", open_conns(&tunnels, &socket_tunnels));
+                    // Forget flows that have ended, so that their peers start new ones.
+                    for peers in flows.values_mut() {
+                        peers.retain(|_, sender| !sender.is_closed());
+                    }
+                    if let Some(timeout) = self.tunnel_idle_timeout {
+                        let mut all = tunnels.values().chain(&socket_tunnels);
+                        if all.any(Tunnel::is_active) {
+                            last_active = Instant::now();
+                        } else if last_active.elapsed() >= timeout {
+                            info!(event = "tunnel_expired", "tunnel has no connections, closing");
+                            stream.send(ServerMessage::Error("tunnel expired".into())).await?;
+                            return Ok(());
+                        }
                     }
                 }
-                .instrument(info_span!("control", ?addr)),
+                _ = stats.tick(), if stats_interval.is_some() => {
+                    let ports = tunnels.iter().map(|(&(port, protocol), tunnel)| {
+                        tunnel.stats(Endpoint::Port(port), protocol)
+                    });
+                    let sockets = socket_listeners.iter().zip(&socket_tunnels).map(
+                        |(listener, tunnel)| {
+                            tunnel.stats(Endpoint::Socket(listener.path.clone()), Protocol::Tcp)
+                        },
+                    );
+                    for tunnel in ports.chain(sockets).collect::<Vec<_>>() {
+                        stream.send(ServerMessage::Stats(tunnel)).await?;
+                    }
+                }
+                result = accept_tcp(&listeners, &tunnels, &mut route) => {
+                    let (port, stream2, addr, head, permit) = result?;
+                    if !self.ip_filter.allows(addr.ip()) {
+                        // Dropping the stream closes the connection immediately.
+                        warn!(
+                            event = "connection_rejected",
+                            tunnel_port = port,
+                            peer_addr = %addr,
+                            "rejected connection from filtered address",
+                        );
+                        continue;
+                    }
+                    if !self.geoip_allows(port, addr) {
+                        continue;
+                    }
+                    if let Err(err) = self.tcp_options.apply(&stream2) {
+                        warn!(%err, tunnel_port = port, "could not set socket options");
+                    }
+                    let tunnel = tunnels[&(port, Protocol::Tcp)].clone();
+                    if self.over_quota(&tunnel) {
+                        continue;
+                    }
+                    let Some(slot) = tunnel.open_slot() else {
+                        reject_at_limit(&mut limit_warnings, port, addr);
+                        continue;
+                    };
+                    Span::current().record("conns", open_conns(&tunnels, &socket_tunnels));
+                    info!(
+                        event = "connection_opened",
+                        tunnel_port = port,
+                        peer_addr = %addr,
+                        conns = tunnel.conns(),
+                        "new connection",
+                    );
+                    if let Some(timeout) = self.idle_timeout {
+                        idle.as_mut().reset(Instant::now() + timeout);
+                    }
+                    let conn = IncomingConnection {
+                        tunnel_port: port,
+                        peer: addr,
+                        stream: stream2,
+                        head,
+                        owner: tunnel.owner.as_ref().map(|(name, _)| name.clone()),
+                        slot,
+                    };
+                    let Some(conn) = self.hand_off(conn) else {
+                        continue;
+                    };
+                    let (stream2, head, slot) = (conn.stream, conn.head, conn.slot);
+                    let pending = PendingConnection::Tcp(stream2, addr, head, tunnel, slot);
+                    let id = self.insert_pending(pending, permit);
+                    stream.send(ServerMessage::Connection(id, port, addr)).await?;
+                }
+                result = accept_socket(&socket_listeners, &socket_tunnels) => {
+                    let (index, stream2, permit) = result?;
+                    let path = &socket_listeners[index].path;
+                    let tunnel = socket_tunnels[index].clone();
+                    if self.over_quota(&tunnel) {
+                        continue;
+                    }
+                    let Some(slot) = tunnel.open_slot() else {
+                        debug!(
+                            event = "connection_rejected",
+                            tunnel_socket = %path.display(),
+                            "rejected connection, tunnel is at its connection limit",
+                        );
+                        continue;
+                    };
+                    Span::current().record("conns", open_conns(&tunnels, &socket_tunnels));
+                    info!(
+                        event = "connection_opened",
+                        tunnel_socket = %path.display(),
+                        conns = tunnel.conns(),
+                        "new connection",
+                    );
+                    if let Some(timeout) = self.idle_timeout {
+                        idle.as_mut().reset(Instant::now() + timeout);
+                    }
+                    let pending = PendingConnection::Socket(stream2, path.clone(), tunnel, slot);
+                    let id = self.insert_pending(pending, permit);
+                    stream.send(ServerMessage::SocketConnection(id, path.clone())).await?;
+                }
+                result = recv_any(&sockets, &mut buf) => {
+                    let (index, len, addr) = result?;
+                    if !self.ip_filter.allows(addr.ip()) {
+                        continue;
+                    }
+                    let (port, socket) = &sockets[index];
+                    let peers = flows.entry(*port).or_default();
+                    if let Some(sender) = peers.get(&addr) {
+                        // Datagrams may be dropped if the flow is not keeping up.
+                        match sender.try_send(buf[..len].to_vec()) {
+                            Err(TrySendError::Closed(_)) => drop(peers.remove(&addr)),
+                            _ => continue,
+                        }
+                    }
+                    if !self.geoip_allows(*port, addr) {
+                        continue;
+                    }
+                    let tunnel = tunnels[&(*port, Protocol::Udp)].clone();
+                    if self.over_quota(&tunnel) {
+                        continue;
+                    }
+                    // Datagrams can't wait in a backlog, so new flows are dropped instead.
+                    let Ok(permit) = Arc::clone(&tunnel.pending).try_acquire_owned() else {
+                        continue;
+                    };
+                    let Some(slot) = tunnel.open_slot() else {
+                        reject_at_limit(&mut limit_warnings, *port, addr);
+                        continue;
+                    };
+                    Span::current().record("conns", open_conns(&tunnels, &socket_tunnels));
+                    info!(
+                        event = "connection_opened",
+                        tunnel_port = *port,
+                        peer_addr = %addr,
+                        "new udp flow",
+                    );
+                    if let Some(timeout) = self.idle_timeout {
+                        idle.as_mut().reset(Instant::now() + timeout);
+                    }
+                    let (sender, receiver) = mpsc::channel(64);
+                    sender.try_send(buf[..len].to_vec()).expect("new channel has capacity");
+                    peers.insert(addr, sender);
+                    let socket = Arc::clone(socket);
+                    let pending = PendingConnection::Udp(socket, addr, receiver, tunnel, slot);
+                    let id = self.insert_pending(pending, permit);
+                    stream.send(ServerMessage::UdpConnection(id, *port, addr)).await?;
+                }
+            }
+        }
+
+        // Stop taking new connections, but keep the control connection open while the
+        // open ones finish, so that the client waits before it reconnects.
+        drop((listeners, sockets, socket_listeners, route));
+        let grace_secs = self.drain_timeout.as_secs();
+        stream
+            .send(ServerMessage::ShuttingDown { grace_secs })
+            .await?;
+        let drained = async {
+            while open_conns(&tunnels, &socket_tunnels) > 0 {
+                sleep(SHUTDOWN_POLL_INTERVAL).await;
+            }
+        };
+        _ = timeout(self.drain_timeout, drained).await;
+        Ok(())
+    }
+
+    /// Whether the country of a peer may connect to forwarded ports, counting and logging
+    /// the connections and UDP flows that are rejected.
+    fn geoip_allows(&self, port: u16, peer: SocketAddr) -> bool {
+        let Some(filter) = &self.geoip_filter else {
+            return true;
+        };
+        let allowed = filter.allows(peer.ip());
+        if !allowed {
+            self.metrics.geoip_rejected();
+            debug!(
+                event = "connection_rejected",
+                tunnel_port = port,
+                peer_addr = %peer,
+                "rejected connection from filtered country",
             );
         }
+        allowed
     }
 
-    async fn create_listener(&self, port: u16) -> Result<TcpListener, &'static str> {
-        let try_bind = |port: u16| async move {
-            TcpListener::bind(("0.0.0.0", port))
-                .await
-                .map_err(|err| match err.kind() {
-                    io::ErrorKind::AddrInUse => "port already in use",
-                    io::ErrorKind::PermissionDenied => "permission denied",
-                    _ => "failed to bind to port",
-                })
+    /// Whether the client owning a tunnel has used up its quota, in which case new
+    /// connections to the tunnel are dropped.
+    fn over_quota(&self, tunnel: &Tunnel) -> bool {
+        let Some((name, _)) = &tunnel.owner else {
+            return false;
         };
-        if port > 0 {
-            // Client requests a specific port number.
-            if !self.port_range.contains(&port) {
-                return Err("client port number not in allowed range");
+        let exceeded = self.quotas.exceeded(name);
+        if exceeded {
+            debug!(
+                event = "connection_rejected",
+                tunnel_port = tunnel.port,
+                "rejected connection, client has used up its quota",
+            );
+        }
+        exceeded
+    }
+
+    /// Set up a tunnel with new rate limiters, if enabled, and the given counters.
+    fn new_tunnel(
+        &self,
+        port: u16,
+        owner: Option<(String, Arc<dyn Authorizer>)>,
+        bytes: &TunnelBytes,
+    ) -> Tunnel {
+        let (up, down) = self.rate_limit;
+        Tunnel {
+            port,
+            owner,
+            up: Direction {
+                limit: up.map(RateLimiter::new),
+                bytes: Some(Arc::clone(&bytes.up)),
+            },
+            down: Direction {
+                limit: down.map(RateLimiter::new),
+                bytes: Some(Arc::clone(&bytes.down)),
+            },
+            conns: Arc::default(),
+            opened: Arc::default(),
+            max_conns: self.max_conns_per_tunnel,
+            pending: Arc::new(Semaphore::new(self.pending_conns_limit)),
+            compress: false,
+            encrypt: None,
+        }
+    }
+
+    /// Store an incoming connection until the client accepts it, returning its ID.
+    ///
+    /// The permit is held against the tunnel's pending limit until then.
+    fn insert_pending(&self, pending: PendingConnection, permit: OwnedSemaphorePermit) -> Uuid {
+        let id = Uuid::new_v4();
+        let conns = Arc::clone(&self.conns);
+        let events = self.events.clone();
+        let grace = self.connection_grace;
+
+        let (tunnel, peer) = pending.endpoint();
+        let span = pending.span(id);
+        events.send(ServerEvent::ConnectionOpened { id, tunnel, peer });
+        conns.insert(id, (SystemTime::now(), pending, permit));
+        tokio::spawn(async move {
+            // Remove stale entries to avoid memory leaks.
+            sleep(grace).await;
+            if conns.remove(&id).is_some() {
+                span.in_scope(|| {
+                    warn!(
+                        event = "connection_expired",
+                        grace_ms = grace.as_millis() as u64,
+                        "client did not accept the connection in time, closed it",
+                    );
+                });
+                events.send(ServerEvent::ConnectionClosed {
+                    id,
+                    bytes_in: 0,
+                    bytes_out: 0,
+                });
             }
-            try_bind(port).await
-        } else {
-            // Client requests any available port in range.
-            //
-            // In this case, we bind to 150 random port numbers. We choose this value because in
-            // order to find a free port with probability at least 1-δ, when ε proportion of the
-            // ports are currently available, it suffices to check approximately -2 ln(δ) / ε
-            // independently and uniformly chosen ports (up to a second-order term in ε).
-            //
-            // Checking 150 times gives us 99.999% success at utilizing 85% of ports under these
-            // conditions, when ε=0.15 and δ=0.00001.
-            for _ in 0..150 {
-                let port = fastrand::u16(self.port_range.clone());
-                match try_bind(port).await {
-                    Ok(listener) => return Ok(listener),
-                    Err(_) => continue,
+        });
+        id
+    }
+}
+
+/// Accept a connection on any of the given listeners, or one routed to the client's HTTP
+/// tunnel, along with any bytes already read from it and its tunnel's pending permit.
+async fn accept_tcp(
+    listeners: &[(u16, TcpListener)],
+    tunnels: &HashMap<(u16, Protocol), Tunnel>,
+    route: &mut Option<(u16, Route<'_>, mpsc::Receiver<RoutedConnection>)>,
+) -> io::Result<(u16, TcpStream, SocketAddr, Vec<u8>, OwnedSemaphorePermit)> {
+    let routed = async {
+        match route {
+            Some((port, _, receiver)) => {
+                let permit = pending_permit(&tunnels[&(*port, Protocol::Tcp)]).await;
+                match receiver.recv().await {
+                    Some((stream, addr, head)) => (*port, stream, addr, head, permit),
+                    None => future::pending().await,
                 }
             }
-            Err("failed to find an available port")
+            None => future::pending().await,
         }
+    };
+    tokio::select! {
+        result = accept_any(listeners, tunnels) => {
+            let (port, stream, addr, permit) = result?;
+            Ok((port, stream, addr, Vec::new(), permit))
+        }
+        connection = routed => Ok(connection),
+    }
+}
+
+/// Accept a connection on any of the given Unix sockets whose tunnel is below its
+/// pending limit, or wait forever if there are none.
+async fn accept_socket(
+    listeners: &[SocketListener],
+    tunnels: &[Tunnel],
+) -> io::Result<(usize, SocketStream, OwnedSemaphorePermit)> {
+    if listeners.is_empty() {
+        return future::pending().await;
     }
+    let futures = listeners.iter().zip(tunnels).map(|(listener, tunnel)| {
+        Box::pin(async move {
+            let permit = pending_permit(tunnel).await;
+            Ok::<_, io::Error>((listener.accept().await?, permit))
+        })
+    });
+    let (result, index, _) = select_all(futures).await;
+    let (stream, permit) = result?;
+    Ok((index, stream, permit))
+}
 
-    async fn handle_connection(&self, stream: TcpStream) -> Result<()> {
-        let mut stream = Delimited::new(stream);
-        if let Some(auth) = &self.auth {
-            if let Err(err) = auth.server_handshake(&mut stream).await {
-                warn!(%err, "server handshake failed");
-                stream.send(ServerMessage::Error(err.to_string())).await?;
-                return Ok(());
+/// Serve the admin API on a Unix socket, which is removed when this returns.
+async fn serve_admin_socket(
+    tunnels: Arc<Tunnels>,
+    quotas: Arc<Quotas>,
+    listener: SocketListener,
+) -> Result<()> {
+    info!(path = %listener.path.display(), "admin listening");
+    loop {
+        let stream = listener.accept().await?;
+        let tunnels = Arc::clone(&tunnels);
+        let quotas = Arc::clone(&quotas);
+        tokio::spawn(async move {
+            if let Err(err) = admin::respond(&tunnels, &quotas, stream).await {
+                warn!(%err, "admin request failed");
             }
+        });
+    }
+}
+
+/// Log the end of a proxied connection, with its duration and the bytes sent each way.
+fn connection_closed(record: &ConnectionRecord) {
+    let ConnectionRecord {
+        bytes_up,
+        bytes_down,
+        ..
+    } = *record;
+    let duration_ms = record.duration.as_millis() as u64;
+    match &record.tunnel {
+        Endpoint::Port(port) => info!(
+            event = "connection_closed",
+            tunnel_port = port,
+            peer_addr = record.peer.map(field::display),
+            duration_ms,
+            bytes = bytes_up + bytes_down,
+            bytes_up,
+            bytes_down,
+            "connection closed",
+        ),
+        Endpoint::Socket(path) => info!(
+            event = "connection_closed",
+            tunnel_socket = %path.display(),
+            duration_ms,
+            bytes = bytes_up + bytes_down,
+            bytes_up,
+            bytes_down,
+            "connection closed",
+        ),
+    }
+}
+
+/// Proxy a forwarded connection over the stream that the client accepted it on, after
+/// sending the bytes already read from it, returning the bytes sent each way.
+async fn forward_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: Delimited<Box<dyn Transport>>,
+    id: Uuid,
+    mut stream2: S,
+    head: &[u8],
+    tunnel: &Tunnel,
+) -> Result<(u64, u64)> {
+    let mut parts = stream.into_parts();
+    debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
+    if let Some(key) = &tunnel.encrypt {
+        // Data is compressed before it is encrypted, since sealed chunks look random.
+        let buffered = parts.read_buf.to_vec();
+        let client = Encrypted::new(parts.io, buffered, key, id, Side::Server);
+        if tunnel.compress {
+            let client = Compressed::new(client, Vec::new());
+            forward_wrapped(client, stream2, head, tunnel).await
+        } else {
+            forward_wrapped(client, stream2, head, tunnel).await
         }
+    } else if tunnel.compress {
+        let buffered = parts.read_buf.to_vec();
+        let client = Compressed::new(parts.io, buffered);
+        forward_wrapped(client, stream2, head, tunnel).await
+    } else {
+        let buffered = parts.read_buf.len();
+        tunnel.up.consume(buffered).await;
+        stream2.write_all(&parts.read_buf).await?;
+        tunnel.down.consume(head.len()).await;
+        parts.io.write_all(head).await?;
+        let (up, down) = proxy_with(parts.io, stream2, &tunnel.up, &tunnel.down).await?;
+        Ok((up + buffered as u64, down + head.len() as u64))
+    }
+}
 
-        match stream.recv_timeout().await? {
-            Some(ClientMessage::Authenticate(_)) => {
-                warn!("unexpected authenticate");
-                Ok(())
-            }
-            Some(ClientMessage::Hello(port)) => {
-                let listener = match self.create_listener(port).await {
-                    Ok(listener) => listener,
-                    Err(err) => {
-                        stream.send(ServerMessage::Error(err.into())).await?;
-                        return Ok(());
-                    }
-                };
-                let port = listener.local_addr()?.port();
-                info!(?port, "new client");
-                stream.send(ServerMessage::Hello(port)).await?;
+/// Proxy a forwarded connection over a compressed or encrypted stream to the client,
+/// which holds back data until it is flushed.
+async fn forward_wrapped<C, S>(
+    mut client: C,
+    stream2: S,
+    head: &[u8],
+    tunnel: &Tunnel,
+) -> Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tunnel.down.consume(head.len()).await;
+    client.write_all(head).await?;
+    client.flush().await?;
+    let (up, down) = proxy_with(client, stream2, &tunnel.up, &tunnel.down).await?;
+    Ok((up, down + head.len() as u64))
+}
 
-                loop {
-                    if stream.send(ServerMessage::Heartbeat).await.is_err() {
-                        // Assume that the TCP connection has been dropped.
-                        return Ok(());
-                    }
-                    const TIMEOUT: Duration = Duration::from_millis(500);
-                    if let Ok(result) = timeout(TIMEOUT, listener.accept()).await {
-                        let (stream2, addr) = result?;
-                        info!(?addr, ?port, "new connection");
+/// Whether a subdomain is a valid DNS label, of letters, digits and inner hyphens.
+fn valid_subdomain(subdomain: &str) -> bool {
+    !subdomain.is_empty()
+        && subdomain.len() <= 63
+        && subdomain
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !subdomain.starts_with('-')
+        && !subdomain.ends_with('-')
+}
 
-                        let id = Uuid::new_v4();
-                        let conns = Arc::clone(&self.conns);
+/// Choose a random, readable subdomain like `brave-otter-41`, after a prefix if given.
+fn random_subdomain(prefix: &str) -> String {
+    let adjective = SUBDOMAIN_ADJECTIVES[fastrand::usize(..SUBDOMAIN_ADJECTIVES.len())];
+    let noun = SUBDOMAIN_NOUNS[fastrand::usize(..SUBDOMAIN_NOUNS.len())];
+    let number = fastrand::u8(10..100);
+    match prefix {
+        "" => format!("{adjective}-{noun}-{number}"),
+        prefix => format!("{prefix}-{adjective}-{noun}-{number}"),
+    }
+}
 
-                        conns.insert(id, stream2);
-                        tokio::spawn(async move {
-                            // Remove stale entries to avoid memory leaks.
-                            sleep(Duration::from_secs(10)).await;
-                            if conns.remove(&id).is_some() {
-                                warn!(%id, "removed stale connection");
-                            }
-                        });
-                        stream.send(ServerMessage::Connection(id)).await?;
-                    }
+/// Find the subdomain that a TLS connection is for, from the first label of the server
+/// name in its ClientHello.
+fn tls_subdomain(record: &[u8]) -> Option<String> {
+    let host = tls_server_name(record)?;
+    Some(host.split('.').next()?.to_ascii_lowercase())
+}
+
+/// Find the subdomain that an HTTP request is for, from the first label of its Host header.
+fn http_subdomain(head: &[u8]) -> Option<String> {
+    let end = head.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = String::from_utf8_lossy(&head[..end]);
+    let host = head.lines().skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("host")
+            .then_some(value.trim())
+    })?;
+    let subdomain = host.split(['.', ':']).next()?;
+    Some(subdomain.to_ascii_lowercase())
+}
+
+/// Open a socket for a tunnel on an address, restricted to IPv6 if `v6only` is set and
+/// it is an IPv6 address, so that it can share its port with an IPv4 socket.
+fn open_socket(addr: SocketAddr, kind: Type, v6only: bool) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), kind, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6only)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Bind a TCP listener for a tunnel or the control port, with the same options as
+/// [`TcpListener::bind`], whose `SO_REUSEADDR` lets a restarted server bind its ports
+/// while connections from before are still in `TIME_WAIT`.
+fn bind_tcp(addr: SocketAddr, v6only: bool) -> io::Result<TcpListener> {
+    let socket = open_socket(addr, Type::STREAM, v6only)?;
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Bind a UDP socket for a tunnel.
+fn bind_udp(addr: SocketAddr, v6only: bool) -> io::Result<UdpSocket> {
+    let socket = open_socket(addr, Type::DGRAM, v6only)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// A socket bound for a tunnel, whose port can be read back.
+trait LocalPort {
+    /// The port that the socket is bound on.
+    fn local_port(&self) -> io::Result<u16>;
+}
+
+impl LocalPort for TcpListener {
+    fn local_port(&self) -> io::Result<u16> {
+        Ok(self.local_addr()?.port())
+    }
+}
+
+impl LocalPort for UdpSocket {
+    fn local_port(&self) -> io::Result<u16> {
+        Ok(self.local_addr()?.port())
+    }
+}
+
+/// Bind a socket for a tunnel on each of its addresses, all on the same port, which is
+/// the one that the system chose for the first address if `port` is 0.
+fn bind_each<T: LocalPort>(
+    ips: &[IpAddr],
+    mut port: u16,
+    bind: impl Fn(SocketAddr) -> io::Result<T>,
+) -> io::Result<Vec<T>> {
+    let mut bound = Vec::with_capacity(ips.len());
+    for &ip in ips {
+        let socket = bind((ip, port).into())?;
+        port = socket.local_port()?;
+        bound.push(socket);
+    }
+    Ok(bound)
+}
+
+/// Accept a connection on any of the given listeners whose tunnel is below its pending
+/// limit, or wait forever if there are none.
+async fn accept_any(
+    listeners: &[(u16, TcpListener)],
+    tunnels: &HashMap<(u16, Protocol), Tunnel>,
+) -> io::Result<(u16, TcpStream, SocketAddr, OwnedSemaphorePermit)> {
+    if listeners.is_empty() {
+        return future::pending().await;
+    }
+    let accepts = listeners.iter().map(|(port, listener)| {
+        Box::pin(async move {
+            // Only accept once the tunnel can hold another pending connection.
+            let permit = pending_permit(&tunnels[&(*port, Protocol::Tcp)]).await;
+            Ok::<_, io::Error>((listener.accept().await?, permit))
+        })
+    });
+    let (result, index, _) = select_all(accepts).await;
+    let ((stream, addr), permit) = result?;
+    Ok((listeners[index].0, stream, addr, permit))
+}
+
+/// Wait until a tunnel can hold another connection for its client to accept.
+async fn pending_permit(tunnel: &Tunnel) -> OwnedSemaphorePermit {
+    Arc::clone(&tunnel.pending)
+        .acquire_owned()
+        .await
+        .expect("pending semaphore is never closed")
+}
+
+/// Total number of connections open on a client's tunnels.
+fn open_conns(tunnels: &HashMap<(u16, Protocol), Tunnel>, socket_tunnels: &[Tunnel]) -> usize {
+    tunnels
+        .values()
+        .chain(socket_tunnels)
+        .map(Tunnel::conns)
+        .sum()
+}
+
+/// Count a connection that a tunnel rejected at its limit, warning about it at most once
+/// per [`LIMIT_WARNING_INTERVAL`] for each port.
+///
+/// The map holds, for each port, when the last warning was logged and how many
+/// connections were rejected since.
+fn reject_at_limit(
+    warnings: &mut HashMap<u16, (Option<Instant>, u64)>,
+    port: u16,
+    peer: SocketAddr,
+) {
+    let (warned, rejected) = warnings.entry(port).or_default();
+    *rejected += 1;
+    let now = Instant::now();
+    if warned.is_some_and(|warned| now - warned < LIMIT_WARNING_INTERVAL) {
+        return;
+    }
+    warn!(
+        event = "connection_rejected",
+        tunnel_port = port,
+        peer_addr = %peer,
+        rejected = *rejected,
+        "rejected connections, tunnel is at its connection limit",
+    );
+    *warned = Some(now);
+    *rejected = 0;
+}
+
+/// Accept a connection on any of the control listeners.
+async fn accept_control(listeners: &[TcpListener]) -> io::Result<(TcpStream, SocketAddr)> {
+    let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+    select_all(accepts).await.0
+}
+
+/// Accept a connection on a listener, or wait forever if there is none.
+async fn accept_optional(listener: &Option<TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => future::pending().await,
+    }
+}
+
+/// Like [`server_challenge`], but for a server that also accepts clients without
+/// credentials, which send their first message right away instead of an answer. That
+/// message is returned instead.
+async fn open_challenge(
+    stream: &mut Delimited<Box<dyn Transport>>,
+) -> Result<Result<(Uuid, String), Option<ClientMessage>>> {
+    let challenge = Uuid::new_v4();
+    stream.send(ServerMessage::Challenge(challenge)).await?;
+    match stream.recv_timeout().await? {
+        Some(ClientMessage::Authenticate(tag)) => Ok(Ok((challenge, tag))),
+        message => Ok(Err(message)),
+    }
+}
+
+/// Hash a client's identity and sticky key into an offset for choosing a port.
+fn sticky_offset(identity: Option<&str>, key: &str) -> u64 {
+    let hash = Sha256::new()
+        .chain_update(identity.unwrap_or_default())
+        .chain_update([0])
+        .chain_update(key)
+        .finalize();
+    u64::from_be_bytes(hash[..8].try_into().unwrap())
+}
+
+/// Receive a datagram on any of the given sockets, or wait forever if there are none.
+async fn recv_any(
+    sockets: &[(u16, Arc<UdpSocket>)],
+    buf: &mut [u8],
+) -> io::Result<(usize, usize, SocketAddr)> {
+    if sockets.is_empty() {
+        return future::pending().await;
+    }
+    // Poll each socket for readiness, then read from whichever one is ready, waiting
+    // again if the readiness was spurious.
+    loop {
+        let readable = sockets
+            .iter()
+            .map(|(_, socket)| Box::pin(socket.readable()));
+        let (result, index, _) = select_all(readable).await;
+        result?;
+        match sockets[index].1.try_recv_from(buf) {
+            Ok((len, addr)) => return Ok((index, len, addr)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Relay datagrams between a remote peer and the client's data stream for a UDP flow.
+///
+/// Returns the number of bytes sent to the peer and received from it.
+async fn proxy_udp_flow(
+    mut stream: Framed<Box<dyn Transport>, LengthDelimitedCodec>,
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+    tunnel: Tunnel,
+    idle_timeout: Duration,
+) -> Result<(u64, u64)> {
+    let (mut up, mut down) = (0, 0);
+    loop {
+        tokio::select! {
+            datagram = datagrams.recv() => match datagram {
+                Some(datagram) => {
+                    tunnel.down.consume(datagram.len()).await;
+                    down += datagram.len() as u64;
+                    stream.send(Bytes::from(datagram)).await?
+                }
+                None => return Ok((up, down)),
+            },
+            datagram = stream.next() => match datagram {
+                Some(datagram) => {
+                    let datagram = datagram?;
+                    tunnel.up.consume(datagram.len()).await;
+                    up += datagram.len() as u64;
+                    socket.send_to(&datagram, peer).await?;
                 }
+                None => return Ok((up, down)),
+            },
+            _ = sleep(idle_timeout) => {
+                info!(?peer, "udp flow idle, closing");
+                return Ok((up, down));
             }
-            Some(ClientMessage::Accept(id)) => {
-                info!(%id, "forwarding connection");
-                match self.conns.remove(&id) {
-                    Some((_, mut stream2)) => {
-                        let parts = stream.into_parts();
-                        debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
-                        stream2.write_all(&parts.read_buf).await?;
-                        proxy(parts.io, stream2).await?
-                    }
-                    None => warn!(%id, "missing connection"),
+        }
+    }
+}
+
+/// Ports that tunnels have freed, for choosing the ports of new tunnels.
+#[derive(Default)]
+struct Ports {
+    /// When each port was last released by a closed tunnel.
+    released: DashMap<u16, Instant>,
+
+    /// Port after the one last chosen for a tunnel, where a sequential strategy resumes.
+    next: AtomicU16,
+
+    /// Last port of each named tunnel, and when it was released if it is closed.
+    names: Mutex<HashMap<TunnelName, (u16, Option<Instant>)>>,
+}
+
+/// Identity of the client, name and protocol of a named tunnel.
+type TunnelName = (Option<String>, String, Protocol);
+
+impl Ports {
+    /// The port last assigned to a named tunnel, if it is still open or was released
+    /// less than `retention` ago.
+    fn retained(&self, name: &TunnelName, retention: Duration) -> Option<u16> {
+        let names = self.names.lock().unwrap();
+        let (port, released) = names.get(name)?;
+        match released {
+            Some(at) if at.elapsed() >= retention => None,
+            _ => Some(*port),
+        }
+    }
+
+    /// Remember the port of a named tunnel while it is open, making room if needed by
+    /// forgetting expired names and then the longest released one.
+    fn assign(&self, name: TunnelName, port: u16, retention: Duration) {
+        let mut names = self.names.lock().unwrap();
+        if names.len() >= MAX_RETAINED_NAMES && !names.contains_key(&name) {
+            names.retain(|_, (_, released)| released.is_none_or(|at| at.elapsed() < retention));
+            let oldest = names
+                .iter()
+                .filter_map(|(name, (_, released))| released.map(|at| (at, name)))
+                .min_by_key(|(at, _)| *at)
+                .map(|(_, name)| name.clone());
+            if names.len() >= MAX_RETAINED_NAMES {
+                match oldest {
+                    Some(oldest) => names.remove(&oldest),
+                    None => return,
+                };
+            }
+        }
+        names.insert(name, (port, None));
+    }
+
+    /// Start the retention of the names of the tunnels on a port that closed.
+    fn release_names(&self, port: u16) {
+        let now = Instant::now();
+        for (assigned, released) in self.names.lock().unwrap().values_mut() {
+            if *assigned == port && released.is_none() {
+                *released = Some(now);
+            }
+        }
+    }
+}
+
+/// The authorizer of a server, along with the one it replaced while clients may still
+/// authenticate with that one.
+#[derive(Clone)]
+struct AuthState {
+    /// Optional authorizer that clients are checked with first.
+    current: Option<Arc<dyn Authorizer>>,
+
+    /// Number of the current authorizer, counting those that the server had before.
+    generation: u64,
+
+    /// The previous authorizer and its generation, with when it stops being accepted.
+    previous: Option<(Arc<dyn Authorizer>, u64, Instant)>,
+}
+
+/// Handshake rate limits and bans on the control port, for each address.
+#[derive(Default)]
+struct Peers {
+    /// State of the addresses that connected recently.
+    peers: DashMap<IpAddr, Peer>,
+
+    /// Connections dropped since the last summary was logged.
+    rejected: AtomicU64,
+}
+
+/// Rate limit and authentication state of one address.
+struct Peer {
+    /// Connections this address may still open right away.
+    tokens: f64,
+
+    /// When the tokens were last refilled.
+    updated: Instant,
+
+    /// Authentication failures in a row since the last success.
+    failures: u32,
+
+    /// When authentication last failed.
+    failed_at: Instant,
+
+    /// Optional time until which connections from this address are dropped.
+    banned_until: Option<Instant>,
+}
+
+impl Peers {
+    /// Decide whether to accept a new connection, taking a token if rate limited.
+    fn admit(&self, ip: IpAddr, rate: Option<u32>) -> bool {
+        let now = Instant::now();
+        let admitted = match rate {
+            Some(rate) => {
+                let rate = f64::from(rate);
+                let mut peer = self.peers.entry(ip).or_insert_with(|| Peer::new(rate, now));
+                let elapsed = now.duration_since(peer.updated).as_secs_f64();
+                peer.tokens = (peer.tokens + elapsed * rate).min(rate);
+                peer.updated = now;
+                let admitted = !peer.is_banned(now) && peer.tokens >= 1.0;
+                if admitted {
+                    peer.tokens -= 1.0;
                 }
-                Ok(())
+                admitted
             }
-            None => Ok(()),
+            None => !self.peers.get(&ip).is_some_and(|peer| peer.is_banned(now)),
+        };
+        if !admitted {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
         }
+        admitted
+    }
+
+    /// Count an authentication failure, banning the address if there were too many.
+    fn fail(&self, ip: IpAddr, failures: u32, duration: Duration) {
+        let now = Instant::now();
+        // A new entry starts with any tokens, capped at the rate on the next connection.
+        let mut peer = self
+            .peers
+            .entry(ip)
+            .or_insert_with(|| Peer::new(f64::INFINITY, now));
+        // Failures that are older than a ban would last are forgotten.
+        if now.duration_since(peer.failed_at) > duration {
+            peer.failures = 0;
+        }
+        peer.failures += 1;
+        peer.failed_at = now;
+        if peer.failures >= failures {
+            peer.failures = 0;
+            peer.banned_until = Some(now + duration);
+            warn!(
+                event = "peer_banned",
+                peer_addr = %ip,
+                ?duration,
+                "banned address after repeated authentication failures",
+            );
+        }
+    }
+
+    /// Reset the authentication failures of an address after it authenticated.
+    fn succeed(&self, ip: IpAddr) {
+        if let Some(mut peer) = self.peers.get_mut(&ip) {
+            peer.failures = 0;
+        }
+    }
+
+    /// Forget addresses with nothing left to track, returning the connections dropped
+    /// since the last call.
+    fn expire(&self, rate: Option<u32>, ban: Option<(u32, Duration)>) -> u64 {
+        let now = Instant::now();
+        let rate = rate.map_or(0.0, f64::from);
+        let ban_duration = ban.map_or(Duration::ZERO, |(_, duration)| duration);
+        self.peers.retain(|_, peer| {
+            let refilled = peer.tokens + now.duration_since(peer.updated).as_secs_f64() * rate;
+            let failed = peer.failures > 0 && now.duration_since(peer.failed_at) <= ban_duration;
+            peer.is_banned(now) || failed || refilled < rate
+        });
+        self.rejected.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl Peer {
+    fn new(tokens: f64, now: Instant) -> Self {
+        Peer {
+            tokens,
+            updated: now,
+            failures: 0,
+            failed_at: now,
+            banned_until: None,
+        }
+    }
+
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| now < until)
     }
 }
@@ -1,36 +1,274 @@
 //! Shared data structures, utilities, and protocol definitions.
 
+use std::future::Future;
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
 use std::time::Duration;
 
-use anyhow::{Context, Result};
-use futures_util::{SinkExt, StreamExt};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::io::{self, AsyncRead, AsyncWrite};
-use tokio::time::timeout;
-use tokio_util::codec::{AnyDelimiterCodec, Framed, FramedParts};
+use anyhow::{bail, Context as _, Result};
+use async_compression::tokio::{bufread::GzipDecoder, write::GzipEncoder};
+use bytes::Bytes;
+use futures_util::{stream, SinkExt, Stream, StreamExt};
+use ipnet::IpNet;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::io::{Chain, ReadHalf, WriteHalf};
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Instant};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio_util::codec::{
+    AnyDelimiterCodec, AnyDelimiterCodecError, Framed, FramedParts, LengthDelimitedCodec,
+};
+use tokio_util::task::TaskTracker;
 use tracing::trace;
 use uuid::Uuid;
 
 /// TCP port used for control connections with the server.
 pub const CONTROL_PORT: u16 = 7835;
 
-/// Maximum byte length for a JSON frame in the stream.
-pub const MAX_FRAME_LENGTH: usize = 256;
+/// Default maximum byte length for a JSON frame in the stream.
+pub const MAX_FRAME_LENGTH: usize = 4096;
 
-/// Timeout for network connections and initial protocol messages.
+/// Version of the control protocol spoken by this crate, sent in each [`PortRequest`].
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest version of the control protocol that the server accepts from clients.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Version of the original control protocol, spoken by clients from before versioning
+/// that send a bare port in [`ClientMessage::Hello`]. The server still serves them in
+/// that wire format for this release, and will refuse them in the next one.
+pub const LEGACY_PROTOCOL_VERSION: u32 = 0;
+
+/// Default timeout for network connections and initial protocol messages.
 pub const NETWORK_TIMEOUT: Duration = Duration::from_secs(3);
 
+/// Maximum byte length of a forwarded UDP datagram.
+pub const MAX_DATAGRAM_LENGTH: usize = 65535;
+
+/// Maximum byte length of a TLS record, read to find the server name of a ClientHello.
+pub const MAX_TLS_RECORD_LENGTH: usize = 5 + (1 << 14);
+
+/// Default idle timeout after which the server drops a forwarded UDP flow.
+pub const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default interval between heartbeats sent on a control connection.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Default time without a heartbeat after which a control connection is considered dead.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default time to wait for proxied connections to finish when shutting down.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default time for a client to open a connection back to the server to accept a
+/// forwarded connection, including its handshake.
+pub const DATA_CHANNEL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default time that the server keeps a forwarded connection for the client to accept.
+pub const CONNECTION_GRACE: Duration = Duration::from_secs(10);
+
+/// Transport protocol of a forwarded port.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Protocol {
+    /// Forward TCP connections.
+    #[default]
+    Tcp,
+
+    /// Forward UDP datagrams, as one flow per remote peer.
+    Udp,
+}
+
+/// Method of compressing the data of TCP connections between client and server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Streaming gzip in both directions.
+    Gzip,
+}
+
+/// Compression methods that this crate supports, in order of preference.
+pub const COMPRESSION_METHODS: &[Compression] = &[Compression::Gzip];
+
+/// Address family of the server addresses that a forwarded port is bound on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IpFamily {
+    /// Bind the port on IPv4 addresses only.
+    V4,
+
+    /// Bind the port on IPv6 addresses only.
+    V6,
+}
+
+impl IpFamily {
+    /// Whether an address is of this family.
+    pub fn contains(self, addr: IpAddr) -> bool {
+        match self {
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// A request from the client to forward a single port on the server.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortRequest {
+    /// Port to forward, or 0 to let the server choose one.
+    pub port: u16,
+
+    /// Transport protocol to forward on the port.
+    #[serde(default)]
+    pub protocol: Protocol,
+
+    /// Optional range of ports to choose from, if no specific port is requested.
+    #[serde(default)]
+    pub range: Option<RangeInclusive<u16>>,
+
+    /// Optional address of a server interface to bind the port on, instead of all of them.
+    #[serde(default)]
+    pub bind_addr: Option<IpAddr>,
+
+    /// Optional address family to bind the port on, out of the server's tunnel
+    /// addresses, instead of all of them.
+    #[serde(default)]
+    pub family: Option<IpFamily>,
+
+    /// Optional key to derive the port from, along with the client's identity, if no
+    /// specific port is requested.
+    #[serde(default)]
+    pub sticky: Option<String>,
+
+    /// Optional subdomain to receive HTTP requests for on the server's HTTP port,
+    /// instead of forwarding a port of its own.
+    #[serde(default)]
+    pub subdomain: Option<String>,
+
+    /// Optional prefix of a random subdomain for the server to choose, if `subdomain`
+    /// is not set, which may be empty.
+    #[serde(default)]
+    pub subdomain_prefix: Option<String>,
+
+    /// Methods that the client can compress the data of TCP connections with, in order
+    /// of preference, of which the server picks one with [`ServerMessage::Compress`] or
+    /// none. Methods that the server does not know are left out when it reads them.
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "deserialize_compression"
+    )]
+    pub compression: Vec<Compression>,
+
+    /// Whether to encrypt the data of TCP connections between client and server with a
+    /// key derived from the client's secret, if the server agrees with
+    /// [`ServerMessage::Encrypt`].
+    #[serde(default)]
+    pub encrypt: bool,
+
+    /// Optional name of a Unix socket to serve the tunnel on, in the server's socket
+    /// directory, instead of a public port.
+    #[serde(default)]
+    pub socket: Option<String>,
+
+    /// Whether to take over the requested port from a stale tunnel of a client with the
+    /// same identity, such as one that crashed before the server noticed.
+    #[serde(default)]
+    pub reclaim: bool,
+
+    /// Optional stable name of the tunnel, under which the server may keep its port for
+    /// a while after it closes, to give it back to the same client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// Optional first port of a block of consecutive ports that this request is part
+    /// of, which the server forwards all or none of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<u16>,
+
+    /// Optional interval in milliseconds for the server to send the statistics of the
+    /// tunnel on, as [`ServerMessage::Stats`], which older servers never do.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats_interval_ms: Option<u64>,
+
+    /// Version of the control protocol that the client speaks, which is
+    /// [`LEGACY_PROTOCOL_VERSION`] for the bare port of clients from before versioning.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+}
+
+/// Where the server serves a tunnel that it established.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Endpoint {
+    /// A public port, which is sent as a bare number like in older versions.
+    Port(u16),
+
+    /// The path of a Unix socket on the server.
+    Socket(PathBuf),
+}
+
+/// Read the ports of [`ClientMessage::Hello`], taking the bare port that clients from
+/// before port requests send as a request for [`LEGACY_PROTOCOL_VERSION`].
+fn deserialize_hello<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<PortRequest>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Hello {
+        Requests(Vec<PortRequest>),
+        Legacy(u16),
+    }
+    Ok(match Hello::deserialize(deserializer)? {
+        Hello::Requests(requests) => requests,
+        Hello::Legacy(port) => vec![PortRequest {
+            port,
+            version: Some(LEGACY_PROTOCOL_VERSION),
+            ..Default::default()
+        }],
+    })
+}
+
+/// Read the compression methods of a [`PortRequest`], leaving out unknown ones, so that
+/// clients may offer methods that only newer servers support.
+fn deserialize_compression<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Compression>, D::Error> {
+    let methods = Vec::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(methods
+        .into_iter()
+        .filter_map(|method| serde_json::from_value(method).ok())
+        .collect())
+}
+
 /// A message from the client on the control connection.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
     /// Response to an authentication challenge from the server.
     Authenticate(String),
 
-    /// Initial client message specifying a port to forward.
-    Hello(u16),
+    /// Initial client message specifying the ports to forward.
+    Hello(#[serde(deserialize_with = "deserialize_hello")] Vec<PortRequest>),
 
     /// Accepts an incoming TCP connection, using this stream as a proxy.
     Accept(Uuid),
+
+    /// No-op sent periodically to show that the client is still alive.
+    Heartbeat,
+
+    /// Asks for the tunnels that are open on the server, without opening one.
+    Status,
+
+    /// Asks the server to reply with [`ServerMessage::Pong`], to measure the round-trip
+    /// time. Further pings may follow on the same connection.
+    Ping(u32),
 }
 
 /// A message from the server on the control connection.
@@ -39,34 +277,267 @@ pub enum ServerMessage {
     /// Authentication challenge, sent as the first message, if enabled.
     Challenge(Uuid),
 
-    /// Response to a client's initial message, with actual public port.
-    Hello(u16),
+    /// The method that the server picked to compress the data of the tunnels that
+    /// offered it, sent just before [`ServerMessage::Hello`]. Without it, no tunnel is
+    /// compressed.
+    Compress(Compression),
+
+    /// Agreement to encrypt the data of the tunnels that asked for it, sent just before
+    /// [`ServerMessage::Hello`].
+    Encrypt,
+
+    /// Notice that a specific port the client asked for is already in use on the
+    /// server, sent before [`ServerMessage::Hello`] along with the error for that port.
+    PortInUse(u16),
+
+    /// Notice that the port that the server kept for a named tunnel was taken in the
+    /// meantime, so that it got a new one, sent before [`ServerMessage::Hello`].
+    PortReassigned(String),
+
+    /// The subdomain that the server chose for a tunnel that asked for a random one,
+    /// sent before [`ServerMessage::Hello`].
+    Subdomain(String),
+
+    /// Response to a client's initial message, with the actual public port or socket,
+    /// or an error, for each requested port in the same order.
+    Hello(Vec<Result<Endpoint, String>>),
 
     /// No-op used to test if the client is still reachable.
     Heartbeat,
 
-    /// Asks the client to accept a forwarded TCP connection.
-    Connection(Uuid),
+    /// Asks the client to accept a forwarded TCP connection on a public port, from
+    /// the given remote peer.
+    Connection(Uuid, u16, SocketAddr),
+
+    /// Asks the client to accept a new UDP flow from a remote peer on a public port.
+    UdpConnection(Uuid, u16, SocketAddr),
+
+    /// Asks the client to accept a forwarded connection on the Unix socket of a tunnel.
+    SocketConnection(Uuid, PathBuf),
+
+    /// Some of the tunnels open on the server, in reply to [`ClientMessage::Status`].
+    ///
+    /// The tunnels are sent in batches that each fit in a frame, and an empty batch
+    /// ends the list.
+    Status(Vec<TunnelStatus>),
+
+    /// Reply to [`ClientMessage::Ping`], with the same number.
+    Pong(u32),
+
+    /// Notice that the server is shutting down, and stopped accepting new connections
+    /// to the client's tunnels. It closes the control connection once their open
+    /// connections finish, or after the grace period in seconds, without sending
+    /// heartbeats in the meantime.
+    ShuttingDown {
+        /// Seconds that the server waits for open connections to finish.
+        grace_secs: u64,
+    },
+
+    /// Statistics of one of the client's tunnels, sent on the interval that it asked for
+    /// in its [`PortRequest`].
+    Stats(TunnelStats),
 
     /// Indicates a server error that terminates the connection.
     Error(String),
 }
 
+/// A message from the server in the original wire format, for legacy clients that
+/// forward a single TCP port.
+#[derive(Serialize)]
+enum LegacyServerMessage {
+    Challenge(Uuid),
+    Hello(u16),
+    Heartbeat,
+    Connection(Uuid),
+    Error(String),
+}
+
+impl LegacyServerMessage {
+    /// The equivalent of a server message for legacy clients, if they have one.
+    fn new(message: ServerMessage) -> Option<Self> {
+        Some(match message {
+            ServerMessage::Challenge(challenge) => Self::Challenge(challenge),
+            ServerMessage::Hello(mut results) => match results.pop()? {
+                Ok(Endpoint::Port(port)) => Self::Hello(port),
+                Ok(Endpoint::Socket(_)) => return None,
+                Err(message) => Self::Error(message),
+            },
+            ServerMessage::Heartbeat => Self::Heartbeat,
+            ServerMessage::Connection(id, _, _) => Self::Connection(id),
+            ServerMessage::Error(message) => Self::Error(message),
+            _ => return None,
+        })
+    }
+}
+
+/// A tunnel open on the server, as reported by [`ServerMessage::Status`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelStatus {
+    /// The forwarded port.
+    pub port: u16,
+
+    /// Protocol of the forwarded port.
+    pub protocol: Protocol,
+
+    /// Number of connections and UDP flows open on the port.
+    pub connections: usize,
+
+    /// Seconds since the tunnel was opened.
+    pub uptime_secs: u64,
+
+    /// Whether the tunnel was opened by a client with the same identity as the one that
+    /// asked for the status.
+    #[serde(default)]
+    pub owned: bool,
+}
+
+/// Counters of a tunnel, as reported by [`ServerMessage::Stats`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelStats {
+    /// Where the server serves the tunnel.
+    pub tunnel: Endpoint,
+
+    /// Protocol of the tunnel.
+    pub protocol: Protocol,
+
+    /// Number of connections and UDP flows open on the tunnel.
+    pub active_connections: u64,
+
+    /// Number of connections and UDP flows opened on the tunnel so far.
+    pub total_connections: u64,
+
+    /// Bytes received from remote peers so far.
+    pub bytes_in: u64,
+
+    /// Bytes sent back to remote peers so far.
+    pub bytes_out: u64,
+}
+
+/// A byte stream between the client and the server, such as TCP, TLS over TCP, or a
+/// [`WebSocket`] over either.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Transport for T {}
+
+/// A byte stream carried in the binary messages of a WebSocket connection.
+///
+/// Each write is sent as one message, so that the frames of a [`Delimited`] stream go
+/// out as soon as they are flushed.
+pub struct WebSocket<S> {
+    stream: WebSocketStream<S>,
+
+    /// Rest of the last message received, which has not been read yet.
+    buffer: Bytes,
+}
+
+impl<S> WebSocket<S> {
+    /// Wrap a WebSocket connection after its handshake.
+    pub fn new(stream: WebSocketStream<S>) -> Self {
+        Self {
+            stream,
+            buffer: Bytes::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocket<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.buffer.is_empty() {
+            match ready!(self.stream.poll_next_unpin(cx)) {
+                Some(Ok(Message::Binary(data))) => self.buffer = data.into(),
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // Pings are answered by the WebSocket stream itself.
+                Some(Ok(_)) => (),
+                Some(Err(err)) => return Poll::Ready(Err(io::Error::other(err))),
+            }
+        }
+        let n = buf.remaining().min(self.buffer.len());
+        buf.put_slice(&self.buffer.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocket<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.stream.poll_ready_unpin(cx)).map_err(io::Error::other)?;
+        self.stream
+            .start_send_unpin(Message::Binary(buf.to_vec()))
+            .map_err(io::Error::other)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.stream.poll_flush_unpin(cx).map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.stream.poll_close_unpin(cx).map_err(io::Error::other)
+    }
+}
+
 /// Transport stream with JSON frames delimited by null characters.
-pub struct Delimited<U>(Framed<U, AnyDelimiterCodec>);
+pub struct Delimited<U> {
+    framed: Framed<U, AnyDelimiterCodec>,
+
+    /// Time to wait for a message in [`Delimited::recv_timeout`].
+    timeout: Duration,
+
+    /// Whether server messages are sent in the original wire format.
+    legacy: bool,
+}
 
 impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
     /// Construct a new delimited stream.
     pub fn new(stream: U) -> Self {
         let codec = AnyDelimiterCodec::new_with_max_length(vec![0], vec![0], MAX_FRAME_LENGTH);
-        Self(Framed::new(stream, codec))
+        Self {
+            framed: Framed::new(stream, codec),
+            timeout: NETWORK_TIMEOUT,
+            legacy: false,
+        }
+    }
+
+    /// Set how long [`Delimited::recv_timeout`] waits for a message, which is
+    /// [`NETWORK_TIMEOUT`] by default.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Set the maximum byte length of a message, which is [`MAX_FRAME_LENGTH`] by default,
+    /// before any message is received. Longer messages fail to be received, without being
+    /// buffered in full.
+    pub fn set_max_length(&mut self, max_length: usize) {
+        let codec = AnyDelimiterCodec::new_with_max_length(vec![0], vec![0], max_length);
+        *self.framed.codec_mut() = codec;
+    }
+
+    /// Send server messages in the wire format of clients that speak
+    /// [`LEGACY_PROTOCOL_VERSION`], leaving out those that the clients have no
+    /// equivalent for.
+    pub fn set_legacy(&mut self) {
+        self.legacy = true;
     }
 
     /// Read the next null-delimited JSON instruction from a stream.
     pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
         trace!("waiting to receive json message");
-        if let Some(next_message) = self.0.next().await {
-            let byte_message = next_message.context("frame error, invalid byte length")?;
+        if let Some(next_message) = self.framed.next().await {
+            let byte_message = match next_message {
+                Ok(byte_message) => byte_message,
+                Err(AnyDelimiterCodecError::MaxChunkLengthExceeded) => {
+                    let max_length = self.framed.codec().max_length();
+                    bail!("message is longer than the maximum of {max_length} bytes");
+                }
+                Err(err) => return Err(err).context("frame error, invalid byte length"),
+            };
             let serialized_obj =
                 serde_json::from_slice(&byte_message).context("unable to parse message")?;
             Ok(serialized_obj)
@@ -75,40 +546,467 @@ impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
         }
     }
 
-    /// Read the next null-delimited JSON instruction, with a default timeout.
+    /// Read the next null-delimited JSON instruction, with a timeout.
     ///
     /// This is useful for parsing the initial message of a stream for handshake or
     /// other protocol purposes, where we do not want to wait indefinitely.
     pub async fn recv_timeout<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
-        timeout(NETWORK_TIMEOUT, self.recv())
+        let duration = self.timeout;
+        timeout(duration, self.recv())
             .await
-            .context("timed out waiting for initial message")?
+            .with_context(|| format!("timed out waiting for handshake after {duration:?}"))?
     }
 
     /// Send a null-terminated JSON instruction on a stream.
     pub async fn send<T: Serialize>(&mut self, msg: T) -> Result<()> {
         trace!("sending json message");
-        self.0.send(serde_json::to_string(&msg)?).await?;
+        let text = if self.legacy {
+            let message = serde_json::from_value(serde_json::to_value(&msg)?)?;
+            match LegacyServerMessage::new(message) {
+                Some(message) => serde_json::to_string(&message)?,
+                None => return Ok(()),
+            }
+        } else {
+            serde_json::to_string(&msg)?
+        };
+        self.framed.send(text).await?;
         Ok(())
     }
 
     /// Consume this object, returning current buffers and the inner transport.
     pub fn into_parts(self) -> FramedParts<U, AnyDelimiterCodec> {
-        self.0.into_parts()
+        self.framed.into_parts()
+    }
+
+    /// Consume this object, continuing the stream as a sequence of UDP datagrams.
+    ///
+    /// Each datagram is prefixed by its length as a 16-bit integer. Any bytes that were
+    /// already buffered are kept as the start of the first datagram.
+    pub fn into_datagrams(self) -> Framed<U, LengthDelimitedCodec> {
+        let parts = self.into_parts();
+        let codec = LengthDelimitedCodec::builder()
+            .length_field_length(2)
+            .max_frame_length(MAX_DATAGRAM_LENGTH)
+            .new_codec();
+        let mut datagram_parts = FramedParts::new::<Bytes>(parts.io, codec);
+        datagram_parts.read_buf = parts.read_buf;
+        datagram_parts.write_buf = parts.write_buf;
+        Framed::from_parts(datagram_parts)
+    }
+}
+
+/// Rules for which remote addresses may connect, as allowed and denied networks.
+///
+/// Denied networks take precedence. If there are no allowed networks, any address
+/// that is not denied is allowed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// Create a new filter from lists of allowed and denied networks.
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// Check whether an address may connect.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Socket options for TCP connections, such as keepalives for tunnels that sit idle
+/// behind a NAT.
+///
+/// The default leaves sockets configured as the operating system opens them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// Send keepalive probes once a connection has been idle for this long.
+    pub keepalive: Option<Duration>,
+
+    /// Disable Nagle's algorithm, so that small writes are sent right away.
+    pub nodelay: bool,
+}
+
+impl TcpOptions {
+    /// Apply the options to a newly opened connection.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        Ok(())
+    }
+}
+
+/// Local address and network interface to open outbound TCP connections from, such as
+/// on a host with several uplinks.
+///
+/// The default lets the operating system pick both from its routing table.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceAddr {
+    /// Address to bind connections to, with a port picked by the operating system.
+    pub addr: Option<IpAddr>,
+
+    /// Network interface to send from, with `SO_BINDTODEVICE`. Only supported on Linux.
+    pub interface: Option<String>,
+}
+
+impl SourceAddr {
+    /// Open a TCP connection to `host` and `port` from this source.
+    ///
+    /// Each resolved address of the same family as the source address is tried in turn.
+    /// Failing to bind the source is an error right away, since no other address of the
+    /// host would fare better.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut last_err = None;
+        for addr in lookup_host((host, port)).await? {
+            if self
+                .addr
+                .is_some_and(|source| source.is_ipv4() != addr.is_ipv4())
+            {
+                continue;
+            }
+            let socket = self.bind(addr)?;
+            match socket.connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        match (last_err, self.addr) {
+            (Some(err), _) => Err(err.into()),
+            (None, Some(source)) => {
+                bail!("{host} has no address of the same family as source address {source}")
+            }
+            (None, None) => bail!("could not resolve {host}"),
+        }
+    }
+
+    /// Open a socket for a connection to `addr`, bound to this source.
+    fn bind(&self, addr: SocketAddr) -> Result<TcpSocket> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_nonblocking(true)?;
+        if let Some(interface) = &self.interface {
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            socket
+                .bind_device(Some(interface.as_bytes()))
+                .with_context(|| format!("could not bind to interface {interface}"))?;
+            #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+            bail!("could not bind to interface {interface}, this is only supported on Linux");
+        }
+        if let Some(source) = self.addr {
+            socket
+                .bind(&SocketAddr::new(source, 0).into())
+                .with_context(|| format!("could not bind source address {source}"))?;
+        }
+        Ok(TcpSocket::from_std_stream(socket.into()))
+    }
+}
+
+/// Tracks proxied connections, so that they can be drained on shutdown.
+///
+/// Clones share the same set of connections.
+#[derive(Clone, Default)]
+pub struct Drain(TaskTracker);
+
+impl Drain {
+    /// Spawn a task for a connection that should be waited for on shutdown.
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.0.spawn(task)
+    }
+
+    /// Wait up to a timeout for all tracked connections to finish.
+    ///
+    /// Returns `false` if some connections were still open when the timeout elapsed.
+    pub async fn wait(&self, drain_timeout: Duration) -> bool {
+        self.0.close();
+        let finished = timeout(drain_timeout, self.0.wait()).await.is_ok();
+        self.0.reopen();
+        finished
+    }
+
+    /// Number of connections that are still open.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether there are no open connections.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Number of events that a stream from [`Events::subscribe`] may fall behind by before
+/// it skips the oldest ones.
+const EVENT_BUFFER: usize = 256;
+
+/// Broadcasts events to any number of streams that subscribed to them.
+///
+/// Clones share the same subscribers. The streams end once every clone is dropped.
+pub struct Events<T>(broadcast::Sender<T>);
+
+impl<T: Clone + Send + 'static> Events<T> {
+    /// Send an event to the current subscribers, if there are any.
+    pub fn send(&self, event: T) {
+        // Sending only fails when nobody is subscribed.
+        let _ = self.0.send(event);
+    }
+
+    /// Subscribe to the events sent from now on.
+    pub fn subscribe(&self) -> impl Stream<Item = T> + Send + Unpin + 'static {
+        stream::unfold(self.0.subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+impl<T> Clone for Events<T> {
+    fn clone(&self) -> Self {
+        Events(self.0.clone())
+    }
+}
+
+impl<T: Clone> Default for Events<T> {
+    fn default() -> Self {
+        Events(broadcast::channel(EVENT_BUFFER).0)
+    }
+}
+
+/// Token bucket that limits throughput to a number of bytes per second.
+///
+/// Clones share the same bucket, so one limiter can bound the total throughput of
+/// several connections. Bursts of up to one second's worth of bytes are allowed.
+#[derive(Clone)]
+pub struct RateLimiter(Arc<Mutex<Bucket>>);
+
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Create a new limiter allowing the given number of bytes per second.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "rate limit must be positive");
+        let rate = bytes_per_sec as f64;
+        Self(Arc::new(Mutex::new(Bucket {
+            rate,
+            tokens: rate,
+            updated: Instant::now(),
+        })))
+    }
+
+    /// Take tokens for sending some bytes, waiting until they are available.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.0.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(bucket.rate);
+            bucket.updated = now;
+            // Go into debt rather than splitting the write, so later callers wait longer.
+            bucket.tokens -= bytes as f64;
+            (-bucket.tokens / bucket.rate).max(0.0)
+        };
+        if wait > 0.0 {
+            sleep(Duration::from_secs_f64(wait)).await;
+        }
+    }
+}
+
+/// Rate limit and byte counter applied to data copied in one direction.
+#[derive(Clone, Default)]
+pub struct Direction {
+    /// Optional limit on the throughput in this direction.
+    pub limit: Option<RateLimiter>,
+
+    /// Optional counter of all bytes copied in this direction.
+    pub bytes: Option<Arc<AtomicU64>>,
+}
+
+impl Direction {
+    /// Account for bytes about to be copied, waiting for the rate limit if needed.
+    pub async fn consume(&self, bytes: usize) {
+        if let Some(limit) = &self.limit {
+            limit.acquire(bytes).await;
+        }
+        if let Some(counter) = &self.bytes {
+            counter.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Compressed data read from a stream, after some that was already buffered.
+type CompressedReader<S> = BufReader<Chain<Cursor<Vec<u8>>, ReadHalf<S>>>;
+
+/// A stream whose data is compressed with gzip in both directions.
+pub struct Compressed<S> {
+    reader: GzipDecoder<CompressedReader<S>>,
+    writer: GzipEncoder<WriteHalf<S>>,
+}
+
+impl<S: AsyncRead + AsyncWrite> Compressed<S> {
+    /// Wrap a stream, after some compressed data that was already read from it.
+    pub fn new(stream: S, buffered: Vec<u8>) -> Self {
+        let (reader, writer) = io::split(stream);
+        let reader = BufReader::new(Cursor::new(buffered).chain(reader));
+        Self {
+            reader: GzipDecoder::new(reader),
+            writer: GzipEncoder::new(writer),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for Compressed<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for Compressed<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
     }
 }
 
 /// Copy data mutually between two read/write streams.
-pub async fn proxy<S1, S2>(stream1: S1, stream2: S2) -> io::Result<()>
+///
+/// Each direction is shut down on its own once its reader reaches the end, so a peer
+/// that half-closes its stream keeps receiving until the other side is done too.
+/// Returns the number of bytes copied from the first stream to the second, and back.
+pub async fn proxy<S1, S2>(stream1: S1, stream2: S2) -> io::Result<(u64, u64)>
+where
+    S1: AsyncRead + AsyncWrite + Unpin,
+    S2: AsyncRead + AsyncWrite + Unpin,
+{
+    let none = Direction::default();
+    proxy_with(stream1, stream2, &none, &none).await
+}
+
+/// Copy data mutually between two streams, with options for the data read from each.
+pub async fn proxy_with<S1, S2>(
+    stream1: S1,
+    stream2: S2,
+    direction1: &Direction,
+    direction2: &Direction,
+) -> io::Result<(u64, u64)>
 where
     S1: AsyncRead + AsyncWrite + Unpin,
     S2: AsyncRead + AsyncWrite + Unpin,
 {
     let (mut s1_read, mut s1_write) = io::split(stream1);
     let (mut s2_read, mut s2_write) = io::split(stream2);
-    tokio::select! {
-        res = io::copy(&mut s1_read, &mut s2_write) => res,
-        res = io::copy(&mut s2_read, &mut s1_write) => res,
-    }?;
-    Ok(())
+    tokio::try_join!(
+        copy(&mut s1_read, &mut s2_write, direction1),
+        copy(&mut s2_read, &mut s1_write, direction2),
+    )
+}
+
+/// Copy all data from a reader to a writer, applying the options of a direction, then
+/// shut down the writer. Returns the number of bytes copied.
+async fn copy<R, W>(reader: &mut R, writer: &mut W, direction: &Direction) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; 8192];
+    let mut total = 0;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(total);
+        }
+        direction.consume(n).await;
+        writer.write_all(&buf[..n]).await?;
+        // Compressed streams hold data back until they are flushed.
+        writer.flush().await?;
+        total += n as u64;
+    }
+}
+
+/// Whether a TLS record has been read in full, from the length in its header.
+pub fn tls_record_complete(record: &[u8]) -> bool {
+    match record.get(3..5) {
+        Some(len) => record.len() >= 5 + u16::from_be_bytes([len[0], len[1]]) as usize,
+        None => false,
+    }
+}
+
+/// Find the server name that a TLS connection is for, from the ClientHello in its
+/// first record, without terminating TLS.
+pub fn tls_server_name(record: &[u8]) -> Option<&str> {
+    /// Split off a field prefixed with its length, in `width` big-endian bytes.
+    fn field(data: &[u8], width: usize) -> Option<(&[u8], &[u8])> {
+        let len = data.get(..width)?;
+        let len = len.iter().fold(0, |len, &b| len << 8 | b as usize);
+        let rest = &data[width..];
+        (rest.len() >= len).then(|| rest.split_at(len))
+    }
+
+    // A handshake record, holding a handshake message that must be a ClientHello.
+    if record.first() != Some(&0x16) {
+        return None;
+    }
+    let (hello, _) = field(record.get(3..)?, 2)?;
+    if hello.first() != Some(&0x01) {
+        return None;
+    }
+    let (hello, _) = field(&hello[1..], 3)?;
+
+    // Skip the version and random, then the session ID, cipher suites and compression
+    // methods, to get to the extensions.
+    let mut rest = hello.get(34..)?;
+    for width in [1, 2, 1] {
+        rest = field(rest, width)?.1;
+    }
+    let (mut extensions, _) = field(rest, 2)?;
+    while !extensions.is_empty() {
+        let kind = extensions.get(..2)?;
+        let (data, rest) = field(&extensions[2..], 2)?;
+        if kind == [0, 0] {
+            // The server name extension, a list whose first entry is a host name.
+            let (names, _) = field(data, 2)?;
+            let (name, _) = field(names.get(1..)?, 2)?;
+            return std::str::from_utf8(name).ok();
+        }
+        extensions = rest;
+    }
+    None
 }
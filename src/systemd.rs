@@ -0,0 +1,48 @@
+//! Socket activation and readiness notification for servers run by systemd.
+//!
+//! Without the `systemd` feature, these do nothing, so the server always binds its
+//! own control port.
+
+use anyhow::Result;
+use tokio::net::TcpListener;
+
+/// Take the control port listener passed by systemd socket activation, if any.
+#[cfg(feature = "systemd")]
+pub fn take_listener() -> Result<Option<TcpListener>> {
+    use anyhow::Context;
+
+    let mut fds = listenfd::ListenFd::from_env();
+    let Some(listener) = fds
+        .take_tcp_listener(0)
+        .context("socket passed by systemd is not a TCP listener")?
+    else {
+        return Ok(None);
+    };
+    listener.set_nonblocking(true)?;
+    Ok(Some(TcpListener::from_std(listener)?))
+}
+
+#[cfg(not(feature = "systemd"))]
+pub fn take_listener() -> Result<Option<TcpListener>> {
+    Ok(None)
+}
+
+/// Tell systemd that the server is ready, or that it is stopping.
+///
+/// This does nothing if the server was not started by systemd with `Type=notify`.
+#[cfg(feature = "systemd")]
+pub fn notify(ready: bool) {
+    use sd_notify::NotifyState;
+
+    let state = if ready {
+        NotifyState::Ready
+    } else {
+        NotifyState::Stopping
+    };
+    if let Err(err) = sd_notify::notify(&[state]) {
+        tracing::warn!(%err, "could not notify systemd");
+    }
+}
+
+#[cfg(not(feature = "systemd"))]
+pub fn notify(_: bool) {}
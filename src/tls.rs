@@ -0,0 +1,150 @@
+//! TLS encryption for connections between the client and the server.
+
+use std::{path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+/// TLS settings used by the client to connect to a server.
+#[derive(Clone)]
+pub struct ClientTls(TlsConnector);
+
+impl ClientTls {
+    /// Verify the server's certificate against the bundled web PKI roots.
+    pub fn new() -> Self {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        Self::with_roots(roots)
+    }
+
+    /// Verify the server's certificate against the certificates in a PEM file.
+    pub fn with_ca_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(path)? {
+            roots
+                .add(cert)
+                .with_context(|| format!("invalid CA certificate in {}", path.display()))?;
+        }
+        Ok(Self::with_roots(roots))
+    }
+
+    /// Accept any certificate from the server, without verification.
+    ///
+    /// This still encrypts traffic, but does not protect against an active attacker
+    /// impersonating the server. It is mostly useful for self-signed certificates.
+    pub fn insecure() -> Self {
+        let algorithms = rustls::crypto::ring::default_provider().signature_verification_algorithms;
+        let config = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(algorithms)))
+            .with_no_client_auth();
+        ClientTls(TlsConnector::from(Arc::new(config)))
+    }
+
+    fn with_roots(roots: RootCertStore) -> Self {
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        ClientTls(TlsConnector::from(Arc::new(config)))
+    }
+
+    /// Perform a TLS handshake with the server at the given host.
+    pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        host: &str,
+        stream: S,
+    ) -> Result<client::TlsStream<S>> {
+        let name = ServerName::try_from(host.to_string())
+            .with_context(|| format!("invalid server name {host}"))?;
+        let stream = self.0.connect(name, stream).await?;
+        Ok(stream)
+    }
+}
+
+impl Default for ClientTls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// TLS settings used by the server to accept connections from clients.
+#[derive(Clone)]
+pub struct ServerTls(TlsAcceptor);
+
+impl ServerTls {
+    /// Load a certificate chain and private key from PEM files.
+    pub fn from_pem_files(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        let certs = read_certs(cert.as_ref())?;
+        let key = key.as_ref();
+        let key = PrivateKeyDer::from_pem_file(key)
+            .with_context(|| format!("could not read private key from {}", key.display()))?;
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .context("invalid TLS certificate or key")?;
+        Ok(ServerTls(TlsAcceptor::from(Arc::new(config))))
+    }
+
+    /// Perform a TLS handshake with a client.
+    pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+    ) -> Result<server::TlsStream<S>> {
+        Ok(self.0.accept(stream).await?)
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("could not read certificates from {}", path.display()))?;
+    anyhow::ensure!(!certs.is_empty(), "no certificates in {}", path.display());
+    Ok(certs)
+}
+
+/// Certificate verifier that accepts any server, while still checking signatures.
+#[derive(Debug)]
+struct NoVerification(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
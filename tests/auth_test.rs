@@ -1,10 +1,14 @@
 use anyhow::Result;
-use bore_cli::{auth::Authenticator, shared::Delimited};
+use bore_cli::{
+    auth::{fingerprint, HmacAuthenticator, Keyring},
+    shared::Delimited,
+};
 use tokio::io::{self};
+use uuid::Uuid;
 
 #[tokio::test]
 async fn auth_handshake() -> Result<()> {
-    let auth = Authenticator::new("some secret string");
+    let auth = HmacAuthenticator::new("some secret string");
 
     let (client, server) = io::duplex(8); // Ensure correctness with limited capacity.
     let mut client = Delimited::new(client);
@@ -20,8 +24,8 @@ async fn auth_handshake() -> Result<()> {
 
 #[tokio::test]
 async fn auth_handshake_fail() {
-    let auth = Authenticator::new("client secret");
-    let auth2 = Authenticator::new("different server secret");
+    let auth = HmacAuthenticator::new("client secret");
+    let auth2 = HmacAuthenticator::new("different server secret");
 
     let (client, server) = io::duplex(8); // Ensure correctness with limited capacity.
     let mut client = Delimited::new(client);
@@ -33,3 +37,55 @@ async fn auth_handshake_fail() {
     );
     assert!(result.is_err());
 }
+
+#[tokio::test]
+async fn keyring_handshake() -> Result<()> {
+    let keyring = Keyring::new(["first secret", "second secret"]);
+
+    for (secret, expected) in [("second secret", true), ("third secret", false)] {
+        let auth = HmacAuthenticator::new(secret);
+        let (client, server) = io::duplex(8); // Ensure correctness with limited capacity.
+        let mut client = Delimited::new(client);
+        let mut server = Delimited::new(server);
+
+        let (client_result, server_result) = tokio::join!(
+            auth.client_handshake(&mut client),
+            keyring.server_handshake(&mut server),
+        );
+        client_result?;
+        match server_result {
+            Ok(identity) => {
+                assert!(expected);
+                assert_eq!(identity, fingerprint(secret));
+            }
+            Err(_) => assert!(!expected),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn keyring_labels() -> Result<()> {
+    let mut keyring = Keyring::new(["unlabeled secret"]);
+    keyring.insert("alice", "alice's secret")?;
+    keyring.insert("bob", "bob's secret")?;
+    assert!(keyring.insert("alice", "another secret").is_err());
+    assert_eq!(keyring.len(), 3);
+
+    let challenge = Uuid::new_v4();
+    let identify = |secret| {
+        let tag = HmacAuthenticator::new(secret).answer(&challenge);
+        keyring
+            .identify(&challenge, &tag)
+            .map(|(identity, _)| identity)
+    };
+    assert_eq!(identify("bob's secret"), Some("bob"));
+    assert_eq!(
+        identify("unlabeled secret"),
+        Some(fingerprint("unlabeled secret").as_str())
+    );
+    assert_eq!(identify("revoked secret"), None);
+
+    Ok(())
+}
@@ -1,16 +1,41 @@
 #![allow(clippy::items_after_test_module)]
 
-use std::net::SocketAddr;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{anyhow, Result};
-use bore_cli::{client::Client, server::Server, shared::CONTROL_PORT};
+use bore_cli::{
+    admin::{self, AdminAddr},
+    auth::{fingerprint, AuthDecision, Claims, HmacAuthenticator, Identity, Keyring, TokenKey},
+    client::{
+        Backoff, Client, ClientBuilder, ClientError, ClientEvent, LocalTarget, Mapping,
+        ProxyProtocol, TransportKind,
+    },
+    crypto::{DataKey, Encrypted, Side},
+    geoip::{GeoIpDb, GeoIpFilter},
+    metrics::Metrics,
+    proxy::Proxy,
+    server::{ConnectionRecord, PortStrategy, Server, ServerBuilder, ServerEvent},
+    shared::{
+        ClientMessage, Compression, Delimited, Endpoint, IpFamily, IpFilter, PortRequest, Protocol,
+        ServerMessage, TcpOptions, TunnelStats, CONTROL_PORT, MIN_PROTOCOL_VERSION,
+        PROTOCOL_VERSION,
+    },
+    tls::{ClientTls, ServerTls},
+};
+use futures_util::StreamExt;
 use lazy_static::lazy_static;
 use rstest::*;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
 use tokio::sync::Mutex;
 use tokio::time;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 
 lazy_static! {
     /// Guard to make sure that tests are run serially, not concurrently.
@@ -18,27 +43,75 @@ lazy_static! {
 }
 
 /// Spawn the server, giving some time for the control port TcpListener to start.
-async fn spawn_server(secret: Option<&str>) {
-    tokio::spawn(Server::new(1024..=65535, secret).listen());
+async fn spawn_server(secret: Option<&str>) -> Arc<Metrics> {
+    let server = Server::new(1024..=65535, secret);
+    let metrics = server.metrics();
+    tokio::spawn(server.listen());
     time::sleep(Duration::from_millis(50)).await;
+    metrics
+}
+
+/// Start building a client for the test server that forwards these mappings.
+fn with_mappings(mappings: impl IntoIterator<Item = Mapping>) -> ClientBuilder {
+    mappings
+        .into_iter()
+        .fold(ClientBuilder::new("localhost"), ClientBuilder::mapping)
 }
 
 /// Spawns a client with randomly assigned ports, returning the listener and remote address.
 async fn spawn_client(secret: Option<&str>) -> Result<(TcpListener, SocketAddr)> {
     let listener = TcpListener::bind("localhost:0").await?;
     let local_port = listener.local_addr()?.port();
-    let client = Client::new("localhost", local_port, "localhost", 0, secret).await?;
+    let mut builder = ClientBuilder::new("localhost").local_port(local_port);
+    if let Some(secret) = secret {
+        builder = builder.secret(secret);
+    }
+    let client = builder.build().await?;
     let remote_addr = ([127, 0, 0, 1], client.remote_port()).into();
     tokio::spawn(client.listen());
     Ok((listener, remote_addr))
 }
 
+/// The positional constructors that predate [`ClientBuilder`] still work.
+#[tokio::test]
+#[allow(deprecated)]
+async fn deprecated_constructors() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(Some("abc")).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+
+    let client = Client::new("localhost", local_port, "localhost", 0, Some("abc")).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+    let mappings = vec![Mapping::new("localhost", local_port, 0)];
+    let client = Client::with_mappings(mappings, "localhost", Some("abc"), None, true).await?;
+    assert_ne!(client.remote_port(), port);
+    let ports = [port, client.remote_port()];
+    tokio::spawn(client.listen());
+
+    for port in ports {
+        let mut stream = TcpStream::connect(("localhost", port)).await?;
+        let (mut local, _) = listener.accept().await?;
+        stream.write_all(b"hello").await?;
+        let mut buf = [0u8; 5];
+        local.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+    }
+
+    let result = Client::new("localhost", local_port, "localhost", 0, None).await;
+    assert!(result.is_err());
+
+    Ok(())
+}
+
 #[rstest]
 #[tokio::test]
 async fn basic_proxy(#[values(None, Some(""), Some("abc"))] secret: Option<&str>) -> Result<()> {
     let _guard = SERIAL_GUARD.lock().await;
 
-    spawn_server(secret).await;
+    let metrics = spawn_server(secret).await;
     let (listener, addr) = spawn_client(secret).await?;
 
     tokio::spawn(async move {
@@ -61,6 +134,14 @@ async fn basic_proxy(#[values(None, Some(""), Some("abc"))] secret: Option<&str>
     // Ensure that the client end of the stream is closed now.
     assert_eq!(stream.read(&mut buf).await?, 0);
 
+    let metrics = metrics.to_string();
+    let port = addr.port();
+    assert!(metrics.contains("bore_active_tunnels 1\n"));
+    assert!(metrics.contains("bore_handshakes_total{result=\"accepted\"} 2\n"));
+    let bytes = format!("bore_tunnel_bytes_total{{port=\"{port}\",protocol=\"tcp\"");
+    assert!(metrics.contains(&format!("{bytes},direction=\"up\"}} 25\n")));
+    assert!(metrics.contains(&format!("{bytes},direction=\"down\"}} 11\n")));
+
     // Also ensure that additional connections do not produce any data.
     let mut stream = TcpStream::connect(addr).await?;
     assert_eq!(stream.read(&mut buf).await?, 0);
@@ -68,6 +149,33 @@ async fn basic_proxy(#[values(None, Some(""), Some("abc"))] secret: Option<&str>
     Ok(())
 }
 
+#[tokio::test]
+async fn half_close() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    // The local service only answers once the request is complete.
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await?;
+        assert_eq!(request, b"request");
+        stream.write_all(b"response").await?;
+        anyhow::Ok(())
+    });
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"request").await?;
+    stream.shutdown().await?;
+    let mut response = Vec::new();
+    time::timeout(Duration::from_secs(1), stream.read_to_end(&mut response)).await??;
+    assert_eq!(response, b"response");
+
+    Ok(())
+}
+
 #[rstest]
 #[case(None, Some("my secret"))]
 #[case(Some("my secret"), None)]
@@ -82,48 +190,3823 @@ async fn mismatched_secret(
     assert!(spawn_client(client_secret).await.is_err());
 }
 
+#[rstest]
 #[tokio::test]
-async fn invalid_address() -> Result<()> {
-    // We don't need the serial guard for this test because it doesn't create a server.
-    async fn check_address(to: &str, use_secret: bool) -> Result<()> {
-        match Client::new("localhost", 5000, to, 0, use_secret.then_some("a secret")).await {
-            Ok(_) => Err(anyhow!("expected error for {to}, use_secret={use_secret}")),
-            Err(_) => Ok(()),
+async fn open_port_range(#[values(None, Some("my secret"))] secret: Option<&str>) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, secret);
+    server.set_open_port_range(40000..=40009);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Clients without a secret get a random port in the open range, and connections
+    // to it are forwarded as usual.
+    let (listener, addr) = spawn_client(None).await?;
+    assert!((40000..=40009).contains(&addr.port()));
+    let mut stream = TcpStream::connect(addr).await?;
+    let (mut local, _) = listener.accept().await?;
+    stream.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    for port in [40001, 50000] {
+        let result = ClientBuilder::new("localhost")
+            .local_port(5000)
+            .remote_port(port)
+            .build()
+            .await;
+        let err = result
+            .err()
+            .expect("unauthenticated client should not pick a port");
+        assert!(err
+            .to_string()
+            .contains("authentication required for requested port"));
+    }
+    let outside = Mapping {
+        remote_range: Some(30000..=40009),
+        ..Mapping::new("localhost", 5000, 0)
+    };
+    let result = ClientBuilder::new("localhost")
+        .mapping(outside)
+        .build()
+        .await;
+    assert!(
+        result.is_err(),
+        "range outside the open one should be refused"
+    );
+
+    // Clients with the secret may still ask for any port.
+    if let Some(secret) = secret {
+        let client = ClientBuilder::new("localhost")
+            .local_port(5000)
+            .remote_port(50000)
+            .secret(secret)
+            .build()
+            .await?;
+        assert_eq!(client.remote_port(), 50000);
+        assert!(ClientBuilder::new("localhost")
+            .local_port(5000)
+            .remote_port(50001)
+            .secret("wrong")
+            .build()
+            .await
+            .is_err());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn multiple_mappings() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener1 = TcpListener::bind("localhost:0").await?;
+    let listener2 = TcpListener::bind("localhost:0").await?;
+    let mappings = vec![
+        Mapping::new("localhost", listener1.local_addr()?.port(), 0),
+        Mapping::new("localhost", listener2.local_addr()?.port(), 0),
+    ];
+    let client = with_mappings(mappings).build().await?;
+    let ports: Vec<u16> = client.mappings().iter().map(|m| m.remote_port).collect();
+    assert_eq!(ports.len(), 2);
+    tokio::spawn(client.listen());
+
+    for (listener, port) in [(listener1, ports[0]), (listener2, ports[1])] {
+        let mut stream = TcpStream::connect(("localhost", port)).await?;
+        let (mut local, _) = listener.accept().await?;
+        stream.write_all(&port.to_be_bytes()).await?;
+        let mut buf = [0u8; 2];
+        local.read_exact(&mut buf).await?;
+        assert_eq!(u16::from_be_bytes(buf), port);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_backends() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let first = TcpListener::bind("localhost:0").await?;
+    let second = TcpListener::bind("localhost:0").await?;
+    let dead = TcpListener::bind("localhost:0").await?.local_addr()?.port();
+    let mapping = Mapping {
+        backends: vec![
+            ("localhost".into(), dead),
+            ("localhost".into(), second.local_addr()?.port()),
+        ],
+        ..Mapping::new("localhost", first.local_addr()?.port(), 0)
+    };
+    let client = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .build()
+        .await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    // The dead backend is skipped, first for the next one in turn, and then for a while.
+    let mut chosen = Vec::new();
+    for _ in 0..4 {
+        let _stream = TcpStream::connect(("localhost", port)).await?;
+        chosen.push(tokio::select! {
+            Ok(_) = first.accept() => 1,
+            Ok(_) = second.accept() => 2,
+        });
+    }
+    assert_eq!(chosen, [1, 2, 2, 1]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sni_routes() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    // Each routed service terminates TLS itself, and answers with its own name.
+    let mut ports = Vec::new();
+    for name in ["api", "web"] {
+        let listener = TcpListener::bind("localhost:0").await?;
+        ports.push(listener.local_addr()?.port());
+        tokio::spawn(async move {
+            let tls = ServerTls::from_pem_files("tests/certs/cert.pem", "tests/certs/key.pem")?;
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = tls.accept(stream).await?;
+                stream.write_all(name.as_bytes()).await?;
+                stream.shutdown().await?;
+            }
+            anyhow::Ok(())
+        });
+    }
+    let fallback = TcpListener::bind("localhost:0").await?;
+    let routes = vec![
+        ("api.bore.test".to_string(), ports[0]),
+        ("WEB.bore.test".to_string(), ports[1]),
+    ];
+    let mappings = vec![
+        Mapping {
+            sni_routes: routes.clone(),
+            sni_default: Some(fallback.local_addr()?.port()),
+            ..Mapping::new("localhost", 0, 0)
+        },
+        Mapping {
+            sni_routes: routes,
+            ..Mapping::new("localhost", 0, 0)
+        },
+    ];
+    let client = with_mappings(mappings).build().await?;
+    let (port, strict_port) = (
+        client.mappings()[0].remote_port,
+        client.mappings()[1].remote_port,
+    );
+    tokio::spawn(client.listen());
+
+    let connect = |host: &'static str, port: u16| async move {
+        let stream = TcpStream::connect(("localhost", port)).await?;
+        let mut stream = ClientTls::insecure().connect(host, stream).await?;
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await?;
+        anyhow::Ok(String::from_utf8(buf)?)
+    };
+    assert_eq!(connect("api.bore.test", port).await?, "api");
+    assert_eq!(connect("web.bore.test", port).await?, "web");
+
+    // Data that is not TLS goes to the default port, intact.
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = fallback.accept().await?;
+    let mut buf = [0; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    // Without a default port, unknown names are closed.
+    assert!(connect("other.bore.test", strict_port).await.is_err());
+    assert_eq!(connect("api.bore.test", strict_port).await?, "api");
+
+    Ok(())
+}
+
+#[rstest]
+#[case(false)]
+#[case(true)]
+#[tokio::test]
+async fn partial_mapping_failure(#[case] strict: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let taken = TcpListener::bind("0.0.0.0:0").await?;
+    let port = taken.local_addr()?.port();
+    let mappings = vec![
+        Mapping::new("localhost", 5000, 0),
+        Mapping::new("localhost", 5001, port),
+    ];
+    let result = with_mappings(mappings).strict(strict).build().await;
+    if strict {
+        let err = result.err().expect("client should not connect");
+        assert_eq!(err.downcast_ref(), Some(&ClientError::PortInUse(port)));
+        assert_eq!(
+            err.to_string(),
+            format!("port {port} is already in use on the server")
+        );
+    } else {
+        let client = result?;
+        assert_eq!(client.mappings().len(), 1);
+        assert_eq!(client.mappings()[0].local_port, 5000);
+        assert_eq!(client.failures().len(), 1);
+        assert_eq!(client.failures()[0].0.local_port, 5001);
+        assert_eq!(client.failures()[0].1, "port already in use");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn ephemeral_ports() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // The only port of the range is taken, so tunnels can only get one from the system.
+    let taken = TcpListener::bind("127.0.0.1:0").await?;
+    let range_port = taken.local_addr()?.port();
+    let server = ServerBuilder::new(range_port..=range_port)
+        .tunnel_addrs(vec!["127.0.0.1".parse()?, "127.0.0.2".parse()?])
+        .ephemeral_ports(true)
+        .build();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let mapping = Mapping::new("localhost", listener.local_addr()?.port(), 0);
+    let client = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .build()
+        .await?;
+    let port = client.remote_port();
+    assert_ne!(port, range_port);
+    tokio::spawn(client.listen());
+
+    // Both addresses of the tunnel are bound on the port that the system chose.
+    for ip in ["127.0.0.1", "127.0.0.2"] {
+        let mut conn = TcpStream::connect((ip, port)).await?;
+        let (mut local, _) = listener.accept().await?;
+        conn.write_all(b"hello").await?;
+        let mut buf = [0; 5];
+        local.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+    }
+
+    // Specific ports must still be in the range.
+    let mapping = Mapping::new("localhost", 5000, range_port ^ 1);
+    let result = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .build()
+        .await;
+    let err = result
+        .err()
+        .expect("port outside the range should be refused");
+    assert!(err.to_string().contains("not in allowed range"), "{err:#}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn port_block() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // Tunnels are bound on one loopback address and local services on another, so that
+    // they can share port numbers.
+    let server = ServerBuilder::new(1024..=65535)
+        .tunnel_addrs(vec!["127.0.0.1".parse()?])
+        .build();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let free = |port| std::net::TcpListener::bind(("127.0.0.1", port)).is_ok();
+    let mut locals = Vec::new();
+    let mut start = 40000;
+    while locals.len() < 3 {
+        let port = start + locals.len() as u16;
+        match TcpListener::bind(("127.0.0.2", port)).await {
+            Ok(listener) if free(port) => locals.push(listener),
+            _ => {
+                locals.clear();
+                start = port + 1;
+            }
         }
     }
-    tokio::try_join!(
-        check_address("google.com", false),
-        check_address("google.com", true),
-        check_address("nonexistent.domain.for.demonstration", false),
-        check_address("nonexistent.domain.for.demonstration", true),
-        check_address("malformed !$uri$%", false),
-        check_address("malformed !$uri$%", true),
-    )?;
+    let ports = start..=start + 2;
+
+    // None of the block is forwarded while one of its ports is taken.
+    let taken = TcpListener::bind(("127.0.0.1", start + 1)).await?;
+    let mappings = Mapping::block("127.0.0.2", ports.clone());
+    let result = with_mappings(mappings.clone()).strict(false).build().await;
+    assert!(result.is_err());
+    assert!(free(start) && free(start + 2));
+
+    drop(taken);
+    let client = with_mappings(mappings).strict(false).build().await?;
+    let remote: Vec<_> = client.mappings().iter().map(|m| m.remote_port).collect();
+    assert_eq!(remote, ports.collect::<Vec<_>>());
+    tokio::spawn(client.listen());
+    for (listener, port) in locals.into_iter().zip(remote) {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        let (mut local, _) = listener.accept().await?;
+        stream.write_all(&port.to_be_bytes()).await?;
+        let mut buf = [0u8; 2];
+        local.read_exact(&mut buf).await?;
+        assert_eq!(u16::from_be_bytes(buf), port);
+    }
+
     Ok(())
 }
 
 #[tokio::test]
-async fn very_long_frame() -> Result<()> {
+async fn udp_proxy() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_udp(true);
+    server.set_udp_idle_timeout(Duration::from_millis(200));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A local service that echoes datagrams back in uppercase.
+    let local = UdpSocket::bind("127.0.0.1:0").await?;
+    let local_port = local.local_addr()?.port();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 64];
+        loop {
+            let (len, addr) = local.recv_from(&mut buf).await?;
+            local
+                .send_to(&buf[..len].to_ascii_uppercase(), addr)
+                .await?;
+        }
+        #[allow(unreachable_code)]
+        anyhow::Ok(())
+    });
+
+    let mapping = Mapping::udp("127.0.0.1", local_port, 0);
+    let client = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .build()
+        .await?;
+    let remote_port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    for peer in 0..2 {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        socket.connect(("127.0.0.1", remote_port)).await?;
+        for message in ["hello", "world"] {
+            socket.send(format!("{message} {peer}").as_bytes()).await?;
+            let mut buf = [0u8; 64];
+            let len = time::timeout(Duration::from_secs(1), socket.recv(&mut buf)).await??;
+            assert_eq!(
+                &buf[..len],
+                format!("{message} {peer}").to_uppercase().as_bytes()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[rstest]
+#[case(&[], &[], true)]
+#[case(&["127.0.0.1/32"], &[], true)]
+#[case(&["10.0.0.0/8"], &[], false)]
+#[case(&[], &["127.0.0.0/8"], false)]
+#[case(&["127.0.0.0/8"], &["127.0.0.1/32"], false)]
+#[case(&["::/0", "0.0.0.0/0"], &["10.0.0.0/8"], true)]
+#[tokio::test]
+async fn ip_filter(
+    #[case] allow: &[&str],
+    #[case] deny: &[&str],
+    #[case] accepted: bool,
+) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let parse = |nets: &[&str]| nets.iter().map(|net| net.parse().unwrap()).collect();
+    let mut server = Server::new(1024..=65535, None);
+    server.set_ip_filter(IpFilter::new(parse(allow), parse(deny)));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        stream.write_all(b"hello").await?;
+        anyhow::Ok(())
+    });
+
+    let mut conn = TcpStream::connect(addr).await?;
+    let mut buf = Vec::new();
+    let _ = conn.read_to_end(&mut buf).await;
+    if accepted {
+        assert_eq!(buf, b"hello");
+    } else {
+        assert!(buf.is_empty());
+    }
+
+    Ok(())
+}
+
+#[rstest]
+#[case("127.0.0.2", &["de"], &[], false, true)]
+#[case("127.0.0.3", &["DE"], &[], false, false)]
+#[case("127.0.0.2", &[], &["DE"], false, false)]
+#[case("127.0.0.1", &["DE"], &[], false, true)]
+#[case("127.0.0.1", &[], &[], true, false)]
+#[tokio::test]
+async fn geoip_filter(
+    #[case] peer: &str,
+    #[case] allow: &[&str],
+    #[case] deny: &[&str],
+    #[case] deny_unknown: bool,
+    #[case] accepted: bool,
+) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    assert!(GeoIpDb::from_bytes(b"not a database".to_vec()).is_err());
+    let db = GeoIpDb::from_bytes(geoip_db(&[("127.0.0.2", "DE"), ("127.0.0.3", "US")]))?;
+    assert_eq!(db.country("127.0.0.3".parse()?), Some("US"));
+    let codes = |codes: &[&str]| codes.iter().map(|code| code.to_string()).collect();
+    let mut filter = GeoIpFilter::new(db, codes(allow), codes(deny));
+    filter.set_deny_unknown(deny_unknown);
+    let mut server = Server::new(1024..=65535, None);
+    server.set_geoip_filter(filter);
+    let metrics = server.metrics();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        stream.write_all(b"hello").await?;
+        anyhow::Ok(())
+    });
+
+    let socket = TcpSocket::new_v4()?;
+    socket.bind((peer.parse::<IpAddr>()?, 0).into())?;
+    let mut conn = socket.connect(addr).await?;
+    let mut buf = Vec::new();
+    let _ = conn.read_to_end(&mut buf).await;
+    let rejected = format!("bore_geoip_rejected_total {}\n", u8::from(!accepted));
+    assert!(metrics.to_string().contains(&rejected));
+    if accepted {
+        assert_eq!(buf, b"hello");
+    } else {
+        assert!(buf.is_empty());
+    }
+
+    Ok(())
+}
+
+/// Build a GeoIP database in the MaxMind DB format, with the countries of single IPv4
+/// addresses.
+fn geoip_db(entries: &[(&str, &str)]) -> Vec<u8> {
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+    let mut nodes = vec![[Record::Empty; 2]];
+    let mut data = Vec::new();
+    for (ip, country) in entries {
+        let addr = u32::from(ip.parse::<std::net::Ipv4Addr>().unwrap());
+        let offset = data.len();
+        // A map of the country to a map of its ISO code.
+        data.extend(b"\xe1\x47country\xe1\x48iso_code");
+        data.push(0x40 | country.len() as u8);
+        data.extend(country.as_bytes());
+        let mut node = 0;
+        for bit in (1..32).rev() {
+            let side = (addr >> bit & 1) as usize;
+            node = match nodes[node][side] {
+                Record::Node(next) => next,
+                _ => {
+                    nodes.push([Record::Empty; 2]);
+                    nodes[node][side] = Record::Node(nodes.len() - 1);
+                    nodes.len() - 1
+                }
+            };
+        }
+        nodes[node][(addr & 1) as usize] = Record::Data(offset);
+    }
+    let count = nodes.len();
+    let mut db = Vec::new();
+    for record in nodes.iter().flatten() {
+        let value = match *record {
+            Record::Empty => count,
+            Record::Node(node) => node,
+            Record::Data(offset) => count + 16 + offset,
+        };
+        db.extend(&(value as u32).to_be_bytes()[1..]);
+    }
+    db.extend([0; 16]);
+    db.extend(data);
+    db.extend(b"\xab\xcd\xefMaxMind.com\xe3\x4anode_count\xc4");
+    db.extend((count as u32).to_be_bytes());
+    db.extend(b"\x4brecord_size\xa2\x00\x18\x4aip_version\xa2\x00\x04");
+    db
+}
+
+#[rstest]
+#[case(&["127.0.0.1/32"], &[], true)]
+#[case(&["10.0.0.0/8"], &[], false)]
+#[case(&[], &["127.0.0.0/8"], false)]
+#[tokio::test]
+async fn client_ip_filter(
+    #[case] allow: &[&str],
+    #[case] deny: &[&str],
+    #[case] accepted: bool,
+) -> Result<()> {
     let _guard = SERIAL_GUARD.lock().await;
 
     spawn_server(None).await;
-    let mut attacker = TcpStream::connect(("localhost", CONTROL_PORT)).await?;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let parse = |nets: &[&str]| nets.iter().map(|net| net.parse().unwrap()).collect();
+    let mapping = Mapping {
+        ip_filter: IpFilter::new(parse(allow), parse(deny)),
+        ..Mapping::new("localhost", listener.local_addr()?.port(), 0)
+    };
+    let client = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .build()
+        .await?;
+    let remote_port = client.remote_port();
+    tokio::spawn(client.listen());
 
-    // Slowly send a very long frame.
-    for _ in 0..10 {
-        let result = attacker.write_all(&[42u8; 100000]).await;
-        if result.is_err() {
-            return Ok(());
+    let mut conn = TcpStream::connect(("127.0.0.1", remote_port)).await?;
+    let local = time::timeout(Duration::from_millis(500), listener.accept()).await;
+    assert_eq!(local.is_ok(), accepted);
+    if !accepted {
+        // The connection is closed without reaching the local service.
+        assert_eq!(conn.read(&mut [0u8; 1]).await?, 0);
+    }
+
+    Ok(())
+}
+
+/// Parse a PROXY protocol header from a stream, like a local service would.
+async fn read_proxy_header(stream: &mut TcpStream) -> Result<(SocketAddr, SocketAddr)> {
+    let mut signature = [0u8; 12];
+    stream.read_exact(&mut signature[..6]).await?;
+    if &signature[..6] == b"PROXY " {
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            line.push(stream.read_u8().await?);
         }
-        time::sleep(Duration::from_millis(10)).await;
+        let line = String::from_utf8(line)?;
+        let fields: Vec<&str> = line.trim_end().split(' ').collect();
+        let [_, src, dst, src_port, dst_port] = fields[..] else {
+            return Err(anyhow!("invalid v1 header: {line:?}"));
+        };
+        let source = SocketAddr::new(src.parse()?, src_port.parse()?);
+        let destination = SocketAddr::new(dst.parse()?, dst_port.parse()?);
+        return Ok((source, destination));
     }
-    panic!("did not exit after a 1 MB frame");
+
+    stream.read_exact(&mut signature[6..]).await?;
+    assert_eq!(&signature, b"\r\n\r\n\0\r\nQUIT\n");
+    assert_eq!(stream.read_u8().await?, 0x21);
+    assert_eq!(stream.read_u8().await?, 0x11, "expected TCP over IPv4");
+    assert_eq!(stream.read_u16().await?, 12);
+    let mut addresses = [0u8; 12];
+    stream.read_exact(&mut addresses).await?;
+    let ip = |i: usize| <[u8; 4]>::try_from(&addresses[i..i + 4]).unwrap();
+    let port = |i: usize| u16::from_be_bytes([addresses[i], addresses[i + 1]]);
+    Ok(((ip(0), port(8)).into(), (ip(4), port(10)).into()))
 }
 
-#[test]
-#[should_panic]
-fn empty_port_range() {
-    let min_port = 5000;
-    let max_port = 3000;
-    let _ = Server::new(min_port..=max_port, None);
+#[rstest]
+#[case(ProxyProtocol::V1)]
+#[case(ProxyProtocol::V2)]
+#[tokio::test]
+async fn proxy_protocol(#[case] version: ProxyProtocol) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let local_addr = listener.local_addr()?;
+    let mapping = Mapping {
+        proxy_protocol: Some(version),
+        ..Mapping::new("127.0.0.1", local_addr.port(), 0)
+    };
+    let client = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .build()
+        .await?;
+    let remote_port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut conn = TcpStream::connect(("127.0.0.1", remote_port)).await?;
+    conn.write_all(b"hello").await?;
+
+    let (mut stream, _) = listener.accept().await?;
+    let (source, destination) = read_proxy_header(&mut stream).await?;
+    assert_eq!(source, conn.local_addr()?);
+    assert_eq!(destination, local_addr);
+
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[rstest]
+#[case(1024..=65535, 40000..=40010, true)]
+#[case(1024..=65535, 40000..=40000, true)]
+#[case(2000..=40005, 40000..=40010, true)]
+#[case(2000..=3000, 40000..=40010, false)]
+#[tokio::test]
+async fn requested_port_range(
+    #[case] allowed: RangeInclusive<u16>,
+    #[case] requested: RangeInclusive<u16>,
+    #[case] ok: bool,
+) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    tokio::spawn(Server::new(allowed.clone(), None).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let mapping = Mapping {
+        remote_range: Some(requested.clone()),
+        ..Mapping::new("localhost", 5000, 0)
+    };
+    let result = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .build()
+        .await;
+    match result {
+        Ok(client) => {
+            assert!(ok, "client should not connect");
+            let port = client.remote_port();
+            assert!(allowed.contains(&port) && requested.contains(&port));
+        }
+        Err(err) => {
+            assert!(!ok, "unexpected error: {err:#}");
+            assert!(err.to_string().contains("port range not in allowed range"));
+        }
+    }
+
+    Ok(())
+}
+
+#[rstest]
+#[case(false)]
+#[case(true)]
+#[tokio::test]
+async fn tunnel_expiry(#[case] idle: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    if idle {
+        server.set_idle_timeout(Duration::from_millis(800));
+    } else {
+        server.set_max_tunnel_lifetime(Duration::from_millis(800));
+    }
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let start = time::Instant::now();
+    let mut conn = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    conn.send(ClientMessage::Hello(vec![PortRequest::default()]))
+        .await?;
+    let port = match conn.recv().await? {
+        Some(ServerMessage::Hello(results)) => match &results[0] {
+            Ok(Endpoint::Port(port)) => *port,
+            result => panic!("unexpected result {result:?}"),
+        },
+        message => panic!("unexpected message {message:?}"),
+    };
+
+    // A new connection resets the idle timer, but not the lifetime.
+    time::sleep(Duration::from_millis(400)).await;
+    let _stream = TcpStream::connect(("localhost", port)).await?;
+
+    loop {
+        match conn.recv().await? {
+            Some(ServerMessage::Heartbeat | ServerMessage::Connection(..)) => {}
+            Some(ServerMessage::Error(message)) => {
+                assert_eq!(message, "tunnel expired");
+                break;
+            }
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+    assert_eq!(start.elapsed() >= Duration::from_millis(1200), idle);
+    assert!(conn.recv::<ServerMessage>().await?.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn udp_disabled() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let mapping = Mapping::udp("127.0.0.1", 5000, 0);
+    let result = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .build()
+        .await;
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[rstest]
+#[case(Some(ClientTls::with_ca_file("tests/certs/ca.pem").unwrap()), None)]
+#[case(Some(ClientTls::insecure()), None)]
+#[case(Some(ClientTls::new()), Some("TLS handshake"))]
+#[case(None, Some("server requires TLS"))]
+#[tokio::test]
+async fn tls_proxy(#[case] tls: Option<ClientTls>, #[case] error: Option<&str>) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_tls(ServerTls::from_pem_files(
+        "tests/certs/cert.pem",
+        "tests/certs/key.pem",
+    )?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let mapping = Mapping::new("localhost", listener.local_addr()?.port(), 0);
+    let mut builder = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .secret("secret");
+    if let Some(tls) = tls {
+        builder = builder.tls(tls);
+    }
+    let result = builder.build().await;
+    let client = match (result, error) {
+        (Ok(client), None) => client,
+        (Err(err), Some(error)) => {
+            assert!(
+                format!("{err:#}").contains(error),
+                "unexpected error: {err:#}"
+            );
+            return Ok(());
+        }
+        (Ok(_), Some(_)) => panic!("client should not connect"),
+        (Err(err), None) => return Err(err),
+    };
+    let remote_port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", remote_port)).await?;
+    let (mut local, _) = listener.accept().await?;
+    stream.write_all(b"over tls").await?;
+    let mut buf = [0u8; 8];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"over tls");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn rate_limit() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_rate_limit(Some(10_000), None);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        stream.write_all(&[42; 20_000]).await?;
+        anyhow::Ok(())
+    });
+
+    // The first second's worth of bytes is a burst, then the rest is throttled.
+    let start = time::Instant::now();
+    let mut conn = TcpStream::connect(addr).await?;
+    let mut buf = vec![0; 20_000];
+    conn.read_exact(&mut buf).await?;
+    assert!(start.elapsed() >= Duration::from_millis(900));
+    assert!(buf.iter().all(|&b| b == 42));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn metrics_endpoint() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_metrics_addr(([127, 0, 0, 1], 9835).into());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    assert!(spawn_client(Some("wrong")).await.is_err());
+    let (_listener, _addr) = spawn_client(Some("secret")).await?;
+
+    let scrape = |path: &'static str| async move {
+        let mut stream = TcpStream::connect("127.0.0.1:9835").await?;
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        anyhow::Ok(response)
+    };
+
+    let response = scrape("/metrics").await?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("bore_active_tunnels 1\n"));
+    assert!(response.contains("bore_active_connections 0\n"));
+    assert!(response.contains("bore_handshakes_total{result=\"accepted\"} 1\n"));
+    assert!(response.contains("bore_handshakes_total{result=\"rejected\"} 1\n"));
+
+    let response = scrape("/").await?;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    Ok(())
+}
+
+#[rstest]
+#[case(false)]
+#[case(true)]
+#[tokio::test]
+async fn graceful_shutdown(#[case] drain_timeout_elapses: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_drain_timeout(Duration::from_millis(500));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    let mut conn = TcpStream::connect(addr).await?;
+    let (mut local, _) = listener.accept().await?;
+    let shutdown = tokio::spawn(async move { server.shutdown().await });
+    time::sleep(Duration::from_millis(100)).await;
+
+    // New control connections and connections to the tunnel are refused, but existing
+    // streams keep working.
+    assert!(TcpStream::connect(("localhost", CONTROL_PORT))
+        .await
+        .is_err());
+    assert!(TcpStream::connect(addr).await.is_err());
+    conn.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    assert!(!shutdown.is_finished());
+
+    if !drain_timeout_elapses {
+        drop(conn);
+        drop(local);
+    }
+    time::timeout(Duration::from_secs(1), shutdown).await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn connection_records() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (sender, mut records) = tokio::sync::mpsc::unbounded_channel::<ConnectionRecord>();
+    let mut server = Server::new(1024..=65535, None);
+    server.set_on_connection(move |record| drop(sender.send(record)));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(None).await?;
+    let mut stream = TcpStream::connect(addr).await?;
+    let peer = stream.local_addr()?;
+    let (mut local, _) = listener.accept().await?;
+    stream.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    local.write_all(b"goodbye").await?;
+    drop(local);
+    assert_eq!(stream.read_to_end(&mut Vec::new()).await?, 7);
+    drop(stream);
+
+    let record = time::timeout(Duration::from_secs(1), records.recv()).await?;
+    let record = record.expect("server should report the connection");
+    assert_eq!(record.tunnel, Endpoint::Port(addr.port()));
+    assert_eq!(record.peer, Some(peer));
+    assert_eq!((record.bytes_up, record.bytes_down), (7, 5));
+    assert!(record.opened.elapsed()? >= record.duration);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_api() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let addr = AdminAddr::Tcp(([127, 0, 0, 1], 7836).into());
+    let mut server = Server::new(1024..=65535, None);
+    server.set_admin_addr(([127, 0, 0, 1], 7836).into());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let client = ClientBuilder::new("localhost")
+        .local_port(listener.local_addr()?.port())
+        .build()
+        .await?;
+    let port = client.remote_port();
+    let listen = tokio::spawn(client.listen());
+    let _stream = TcpStream::connect(("localhost", port)).await?;
+    let _local = listener.accept().await?;
+
+    let tunnels = admin::list(&addr).await?;
+    assert_eq!(tunnels.len(), 1);
+    assert_eq!((tunnels[0].port, tunnels[0].connections), (port, 1));
+    assert_eq!(tunnels[0].identity, None);
+
+    // Closing the tunnel disconnects its client and frees the port.
+    admin::close(&addr, port).await?;
+    time::timeout(Duration::from_secs(1), listen).await??.ok();
+    assert!(admin::list(&addr).await?.is_empty());
+    assert!(TcpListener::bind(("0.0.0.0", port)).await.is_ok());
+    let err = admin::close(&addr, port).await.unwrap_err();
+    assert_eq!(err.to_string(), format!("no tunnel on port {port}"));
+
+    // Requests from web pages, which carry an origin or a foreign host name, are refused.
+    for headers in [
+        "Host: localhost:7836\r\nOrigin: https://example.com",
+        "Host: attacker.example:7836",
+        "Host: [::1]:7836\r\nOrigin: null",
+        "",
+    ] {
+        let mut stream = TcpStream::connect("127.0.0.1:7836").await?;
+        let request = format!("POST /quotas/reset HTTP/1.1\r\n{headers}\r\n\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 403 "), "{response}");
+    }
+    let mut stream = TcpStream::connect("127.0.0.1:7836").await?;
+    stream
+        .write_all(b"POST /quotas/reset HTTP/1.1\r\nhost: [::1]:7836\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 200 "), "{response}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_shutdown() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mut client = ClientBuilder::new("localhost")
+        .local_port(local_port)
+        .build()
+        .await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    let shutdown = CancellationToken::new();
+    client.set_shutdown(shutdown.clone());
+    let listen = tokio::spawn(client.listen());
+
+    let mut conn = TcpStream::connect(addr).await?;
+    let (mut local, _) = listener.accept().await?;
+    shutdown.cancel();
+    time::sleep(Duration::from_millis(100)).await;
+
+    // The open connection is still forwarded while the client drains it.
+    conn.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    assert!(!listen.is_finished());
+
+    drop(conn);
+    drop(local);
+    time::timeout(Duration::from_secs(1), listen).await???;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn tls_to_plain_server() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let mapping = Mapping::new("localhost", 5000, 0);
+    let result = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .tls(ClientTls::insecure())
+        .build()
+        .await;
+    let err = result.err().expect("client should not connect");
+    assert!(format!("{err:#}").contains("is TLS enabled on the server?"));
+
+    Ok(())
+}
+
+#[rstest]
+#[case(false)]
+#[case(true)]
+#[tokio::test]
+async fn reconnect_same_port(#[case] strict_port: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // A scripted server that drops the control connection right after each handshake.
+    let control = TcpListener::bind(("0.0.0.0", CONTROL_PORT)).await?;
+    let handshake = |reply: Result<u16, String>| {
+        let control = &control;
+        async move {
+            let mut stream = Delimited::new(control.accept().await?.0);
+            let requests = match stream.recv().await? {
+                Some(ClientMessage::Hello(requests)) => requests,
+                _ => return Err(anyhow!("expected hello")),
+            };
+            stream
+                .send(ServerMessage::Hello(vec![reply.map(Endpoint::Port)]))
+                .await?;
+            Ok(requests.iter().map(|r| r.port).collect::<Vec<_>>())
+        }
+    };
+
+    let (ports, client) = tokio::join!(
+        handshake(Ok(12345)),
+        ClientBuilder::new("localhost").local_port(5000).build(),
+    );
+    assert_eq!(ports?, vec![0]);
+    let backoff = Backoff {
+        initial_delay: Duration::from_millis(10),
+        ..Default::default()
+    };
+    let client = tokio::spawn(client?.listen_with_retry(backoff, strict_port));
+
+    // The client asks for its old port back, but it was taken in the meantime.
+    assert_eq!(
+        handshake(Err("port already in use".into())).await?,
+        vec![12345]
+    );
+    if strict_port {
+        assert!(client.await?.is_err());
+    } else {
+        assert_eq!(handshake(Ok(23456)).await?, vec![0]);
+        client.abort();
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnect_max_attempts() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let control = TcpListener::bind(("0.0.0.0", CONTROL_PORT)).await?;
+    let (stream, client) = tokio::join!(
+        async {
+            let mut stream = Delimited::new(control.accept().await?.0);
+            let _: Option<ClientMessage> = stream.recv().await?;
+            stream
+                .send(ServerMessage::Hello(vec![Ok(Endpoint::Port(12345))]))
+                .await?;
+            anyhow::Ok(stream)
+        },
+        ClientBuilder::new("localhost").local_port(5000).build(),
+    );
+
+    // The server goes away entirely, so every reconnection attempt is refused.
+    drop((control, stream?));
+    let backoff = Backoff {
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(20),
+        max_attempts: Some(3),
+    };
+    let result = time::timeout(
+        Duration::from_secs(5),
+        client?.listen_with_retry(backoff, false),
+    )
+    .await?;
+    assert!(result.unwrap_err().to_string().contains("after 3 attempts"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn ping_interval() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // A server that answers pings keeps the connection up.
+    let server = Server::new(1024..=65535, None);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let client = ClientBuilder::new("localhost")
+        .local_port(5000)
+        .ping_interval(Duration::from_millis(20), Duration::from_millis(200))
+        .build()
+        .await?;
+    let result = time::timeout(Duration::from_millis(500), client.listen()).await;
+    assert!(result.is_err(), "client should still be listening");
+    server.shutdown().await;
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A server that never answers is treated as gone, even while the link is open.
+    let control = TcpListener::bind(("0.0.0.0", CONTROL_PORT)).await?;
+    let (stream, client) = tokio::join!(
+        async {
+            let mut stream = Delimited::new(control.accept().await?.0);
+            let _: Option<ClientMessage> = stream.recv().await?;
+            stream
+                .send(ServerMessage::Hello(vec![Ok(Endpoint::Port(12345))]))
+                .await?;
+            anyhow::Ok(stream)
+        },
+        ClientBuilder::new("localhost")
+            .local_port(5000)
+            .ping_interval(Duration::from_millis(20), Duration::from_millis(200))
+            .build(),
+    );
+    let _stream = stream?;
+    let result = time::timeout(Duration::from_secs(5), client?.listen()).await?;
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("no pong from server"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_builder_reconnect() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let control = TcpListener::bind(("0.0.0.0", CONTROL_PORT)).await?;
+    let builder = ClientBuilder::new("localhost")
+        .local_host("127.0.0.1")
+        .local_port(5000)
+        .remote_port(12345)
+        .reconnect(Backoff {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+            max_attempts: Some(2),
+        });
+    let (stream, client) = tokio::join!(
+        async {
+            let mut stream = Delimited::new(control.accept().await?.0);
+            let requests = match stream.recv().await? {
+                Some(ClientMessage::Hello(requests)) => requests,
+                _ => return Err(anyhow!("expected hello")),
+            };
+            assert_eq!(requests.iter().map(|r| r.port).collect::<Vec<_>>(), [12345]);
+            stream
+                .send(ServerMessage::Hello(vec![Ok(Endpoint::Port(12345))]))
+                .await?;
+            anyhow::Ok(stream)
+        },
+        builder.build(),
+    );
+    let client = client?;
+    assert_eq!(client.mappings()[0].local_host, "127.0.0.1");
+    assert_eq!(client.mappings()[0].local_port, 5000);
+    let events = client.events();
+
+    // Listening retries on its own, since the client was built to reconnect.
+    drop((control, stream?));
+    let result = time::timeout(Duration::from_secs(5), client.listen()).await?;
+    assert!(result.unwrap_err().to_string().contains("after 2 attempts"));
+
+    // Each attempt is reported, after the lost connection.
+    let attempts: Vec<_> = events
+        .filter_map(|event| async move {
+            match event {
+                ClientEvent::Reconnecting { to, attempt, .. } => Some((to, attempt)),
+                _ => None,
+            }
+        })
+        .collect()
+        .await;
+    assert_eq!(attempts, [("localhost".into(), 1), ("localhost".into(), 2)]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_address() -> Result<()> {
+    // We don't need the serial guard for this test because it doesn't create a server.
+    async fn check_address(to: &str, use_secret: bool) -> Result<()> {
+        let mut builder = ClientBuilder::new(to).local_port(5000);
+        if use_secret {
+            builder = builder.secret("a secret");
+        }
+        match builder.build().await {
+            Ok(_) => Err(anyhow!("expected error for {to}, use_secret={use_secret}")),
+            Err(_) => Ok(()),
+        }
+    }
+    tokio::try_join!(
+        check_address("google.com", false),
+        check_address("google.com", true),
+        check_address("nonexistent.domain.for.demonstration", false),
+        check_address("nonexistent.domain.for.demonstration", true),
+        check_address("malformed !$uri$%", false),
+        check_address("malformed !$uri$%", true),
+    )?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn very_long_frame() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let mut attacker = TcpStream::connect(("localhost", CONTROL_PORT)).await?;
+
+    // Slowly send a very long frame.
+    for _ in 0..10 {
+        let result = attacker.write_all(&[42u8; 100000]).await;
+        if result.is_err() {
+            return Ok(());
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("did not exit after a 1 MB frame");
+}
+
+#[tokio::test]
+async fn max_frame_length() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_max_frame_length(512);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A message over the limit is dropped at once, well before the handshake timeout.
+    let mut conn = TcpStream::connect(("localhost", CONTROL_PORT)).await?;
+    conn.write_all(&[b' '; 600]).await?;
+    let mut buf = Vec::new();
+    let closed = time::timeout(Duration::from_secs(1), conn.read_to_end(&mut buf)).await;
+    assert!(closed.is_ok(), "connection should be closed");
+
+    // Clients still fit their messages under it.
+    let (listener, addr) = spawn_client(None).await?;
+    let mut conn = TcpStream::connect(addr).await?;
+    let (mut local, _) = listener.accept().await?;
+    conn.write_all(b"hello").await?;
+    let mut buf = [0; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[test]
+#[should_panic]
+fn empty_port_range() {
+    let min_port = 5000;
+    let max_port = 3000;
+    let _ = Server::new(min_port..=max_port, None);
+}
+
+#[tokio::test]
+async fn server_heartbeat_timeout() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_heartbeat_timeout(Duration::from_millis(300));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A client that completes the handshake and sends one heartbeat, then goes quiet.
+    let mut stream = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    let request = PortRequest::default();
+    stream.send(ClientMessage::Hello(vec![request])).await?;
+    let port = match stream.recv_timeout().await? {
+        Some(ServerMessage::Hello(results)) => match &results[0] {
+            Ok(Endpoint::Port(port)) => *port,
+            result => panic!("unexpected result {result:?}"),
+        },
+        _ => return Err(anyhow!("expected hello")),
+    };
+
+    // Older clients never send heartbeats, so they are not expected until the first.
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(TcpListener::bind(("0.0.0.0", port)).await.is_err());
+    stream.send(ClientMessage::Heartbeat).await?;
+
+    let closed = time::timeout(Duration::from_secs(1), async {
+        while let Ok(Some(ServerMessage::Heartbeat)) = stream.recv().await {}
+    });
+    closed.await?;
+    assert!(TcpListener::bind(("0.0.0.0", port)).await.is_ok());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_heartbeat_timeout() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // A scripted server that never sends a heartbeat, but expects them from the client.
+    let control = TcpListener::bind(("0.0.0.0", CONTROL_PORT)).await?;
+    let (stream, client) = tokio::join!(
+        async {
+            let mut stream = Delimited::new(control.accept().await?.0);
+            let _: Option<ClientMessage> = stream.recv().await?;
+            stream
+                .send(ServerMessage::Hello(vec![Ok(Endpoint::Port(12345))]))
+                .await?;
+            anyhow::Ok(stream)
+        },
+        ClientBuilder::new("localhost").local_port(5000).build(),
+    );
+    let (mut stream, mut client) = (stream?, client?);
+    client.set_heartbeat_interval(Duration::from_millis(50));
+    client.set_heartbeat_timeout(Duration::from_millis(300));
+    let listen = tokio::spawn(client.listen());
+
+    let message = time::timeout(Duration::from_millis(200), stream.recv()).await??;
+    assert!(matches!(message, Some(ClientMessage::Heartbeat)));
+    let result = time::timeout(Duration::from_secs(1), listen).await??;
+    let err = result.expect_err("client should give up on the server");
+    assert!(err.to_string().contains("no heartbeat from server"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn network_timeout() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // A server that accepts connections but never answers the client's hello.
+    let control = TcpListener::bind(("0.0.0.0", CONTROL_PORT)).await?;
+    let build = || {
+        ClientBuilder::new("localhost")
+            .local_port(5000)
+            .timeout(Duration::from_millis(200))
+            .build()
+    };
+    let result = time::timeout(Duration::from_secs(1), build()).await?;
+    let err = result.err().expect("client should time out");
+    assert!(format!("{err:#}").contains("timed out waiting for handshake after 200ms"));
+
+    // Nothing listening is reported as a refused connection instead.
+    drop(control);
+    let err = build().await.err().expect("connection should be refused");
+    assert!(format!("{err:#}").contains("could not connect to localhost:7835"));
+    assert!(!format!("{err:#}").contains("timed out"));
+
+    // The server closes connections that send nothing once its own timeout elapses.
+    let mut server = Server::new(1024..=65535, None);
+    server.set_network_timeout(Duration::from_millis(200));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let mut silent = TcpStream::connect(("localhost", CONTROL_PORT)).await?;
+    let mut buf = Vec::new();
+    time::timeout(Duration::from_secs(1), silent.read_to_end(&mut buf)).await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn tcp_options() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let options = TcpOptions {
+        keepalive: Some(Duration::from_secs(30)),
+        nodelay: true,
+    };
+    let mut server = Server::new(1024..=65535, None);
+    server.set_tcp_options(options);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let client = ClientBuilder::new("localhost")
+        .local_port(listener.local_addr()?.port())
+        .connect_timeout(Duration::from_secs(1))
+        .tcp_options(options)
+        .build()
+        .await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+
+    // Connections still work with the options set at each hop.
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        stream.write_all(b"hello").await?;
+        anyhow::Ok(())
+    });
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    // The options are set on the socket, and the defaults leave it untouched.
+    TcpOptions::default().apply(&stream)?;
+    assert!(!stream.nodelay()?);
+    assert!(!socket2::SockRef::from(&stream).keepalive()?);
+    options.apply(&stream)?;
+    assert!(stream.nodelay()?);
+    assert!(socket2::SockRef::from(&stream).keepalive()?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn source_addr() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    // Connections to the server and the local target leave from the given addresses.
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let client = ClientBuilder::new("127.0.0.1")
+        .local_host("127.0.0.1")
+        .local_port(listener.local_addr()?.port())
+        .source_addr([127, 0, 0, 2].into())
+        .local_bind_addr([127, 0, 0, 3].into())
+        .build()
+        .await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+
+    let _stream = TcpStream::connect(addr).await?;
+    let (_, peer) = listener.accept().await?;
+    assert_eq!(peer.ip(), IpAddr::from([127, 0, 0, 3]));
+
+    // An address that is not on this host fails right away, naming the address.
+    let build = |addr: IpAddr| {
+        ClientBuilder::new("127.0.0.1")
+            .local_port(5000)
+            .source_addr(addr)
+            .build()
+    };
+    let err = build([192, 0, 2, 1].into())
+        .await
+        .err()
+        .expect("bind should fail");
+    assert!(format!("{err:#}").contains("could not bind source address 192.0.2.1"));
+    let err = build("::1".parse()?)
+        .await
+        .err()
+        .expect("family should not match");
+    assert!(format!("{err:#}").contains("same family as source address ::1"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_retry() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    // Nothing listens on the local port until after the tunnel is asked for.
+    let local_port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+    let build = ClientBuilder::new("localhost")
+        .local_target(LocalTarget::Tcp("127.0.0.1".into(), local_port))
+        .wait_local(true)
+        .local_retry(Duration::from_secs(2))
+        .build();
+    let build = tokio::spawn(build);
+    time::sleep(Duration::from_millis(300)).await;
+    assert!(!build.is_finished());
+
+    let listener = TcpListener::bind(("127.0.0.1", local_port)).await?;
+    let client = build.await??;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+    drop(listener);
+
+    // A connection arriving while the local service restarts waits for it to come back.
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    time::sleep(Duration::from_millis(300)).await;
+    let listener = TcpListener::bind(("127.0.0.1", local_port)).await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_retry_attempts() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let local_port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+    let client = ClientBuilder::new("localhost")
+        .local_target(LocalTarget::Tcp("127.0.0.1".into(), local_port))
+        .local_retry_attempts(2)
+        .local_retry_delay(Duration::from_millis(100))
+        .build()
+        .await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+
+    // Retries wait for at least 50ms and then 100ms, before the connection is dropped.
+    let start = time::Instant::now();
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut buf = [0u8; 1];
+    let read = time::timeout(Duration::from_secs(2), stream.read(&mut buf)).await?;
+    assert!(matches!(read, Ok(0) | Err(_)));
+    assert!(start.elapsed() >= Duration::from_millis(150));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn tunnel_bind_addr() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (allowed, other): (IpAddr, IpAddr) = ([127, 0, 0, 2].into(), [127, 0, 0, 3].into());
+    let mut server = Server::new(1024..=65535, None);
+    server.set_allowed_tunnel_addrs(vec![allowed]);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mapping = Mapping {
+        bind_addr: Some(allowed),
+        ..Mapping::new("localhost", local_port, 0)
+    };
+    let client = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .build()
+        .await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    // The tunnel only listens on the requested interface.
+    let mut conn = TcpStream::connect((allowed, port)).await?;
+    let (mut local, _) = listener.accept().await?;
+    conn.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+
+    let mapping = Mapping {
+        bind_addr: Some(other),
+        ..Mapping::new("localhost", local_port, 0)
+    };
+    let result = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .build()
+        .await;
+    let err = result.err().expect("client should be rejected");
+    assert!(err
+        .to_string()
+        .contains("binding tunnels to 127.0.0.3 is not allowed"));
+
+    Ok(())
+}
+
+#[rstest]
+#[case(None, true)]
+#[case(Some(true), false)]
+#[case(Some(false), true)]
+#[tokio::test]
+async fn v6only_tunnels(#[case] v6only: Option<bool>, #[case] accepts_v4: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_tunnel_addrs(vec![Ipv6Addr::UNSPECIFIED.into()]);
+    if let Some(v6only) = v6only {
+        server.set_v6only(v6only);
+    }
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    TcpStream::connect(("::1", addr.port())).await?;
+    listener.accept().await?;
+    let result = TcpStream::connect(("127.0.0.1", addr.port())).await;
+    assert_eq!(result.is_ok(), accepts_v4);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn dual_stack_tunnels() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (v4, v6): (IpAddr, IpAddr) = ([127, 0, 0, 1].into(), "::1".parse()?);
+    let mut server = Server::new(1024..=65535, None);
+    server.set_tunnel_addrs(vec![v4, v6]);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let connect = |family: Option<IpFamily>| {
+        let mapping = Mapping {
+            family,
+            ..Mapping::new("localhost", local_port, 0)
+        };
+        ClientBuilder::new("localhost").mapping(mapping).build()
+    };
+
+    // Each port accepts connections on both addresses.
+    let client = connect(None).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+    for ip in [v4, v6] {
+        let mut conn = TcpStream::connect((ip, port)).await?;
+        let (mut local, _) = listener.accept().await?;
+        conn.write_all(b"hello").await?;
+        let mut buf = [0u8; 5];
+        local.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+    }
+
+    // Clients can ask for the addresses of one family only.
+    let client = connect(Some(IpFamily::V6)).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+    TcpStream::connect((v6, port)).await?;
+    listener.accept().await?;
+    assert!(TcpStream::connect((v4, port)).await.is_err());
+
+    // A port is only allocated if it is free on all addresses, and released otherwise.
+    let taken = TcpListener::bind((v6, 0)).await?;
+    let port = taken.local_addr()?.port();
+    let mapping = Mapping::new("localhost", local_port, port);
+    let result = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .build()
+        .await;
+    let err = result.err().expect("port should be in use");
+    assert!(err
+        .to_string()
+        .contains(&format!("port {port} is already in use")));
+    TcpListener::bind((v4, port)).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn tunnel_stats() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let mapping = Mapping {
+        stats_interval: Some(Duration::from_millis(100)),
+        ..Mapping::new("localhost", listener.local_addr()?.port(), 0)
+    };
+    let client = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .build()
+        .await?;
+    let port = client.remote_port();
+    let mut events = client.events();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    let (mut local, _) = listener.accept().await?;
+    stream.write_all(b"hi!").await?;
+    local.read_exact(&mut [0; 3]).await?;
+    local.write_all(b"hello").await?;
+    stream.read_exact(&mut [0; 5]).await?;
+
+    // Servers send statistics at most once a second, however often they are asked for.
+    let stats = time::timeout(Duration::from_millis(1500), async {
+        while let Some(event) = events.next().await {
+            if let ClientEvent::Stats(stats) = event {
+                return Some(stats);
+            }
+        }
+        None
+    })
+    .await?;
+    let expected = TunnelStats {
+        tunnel: Endpoint::Port(port),
+        protocol: Protocol::Tcp,
+        active_connections: 1,
+        total_connections: 1,
+        bytes_in: 3,
+        bytes_out: 5,
+    };
+    assert_eq!(stats, Some(expected));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn incoming_connections() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = Server::new(1024..=65535, None);
+    let mut incoming = server.incoming();
+    tokio::spawn(server.clone().listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    // While the stream is open, it takes the connections instead of the client.
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hi").await?;
+    let mut conn = time::timeout(Duration::from_secs(1), incoming.next())
+        .await?
+        .expect("connection should be handed to the stream");
+    assert_eq!(conn.tunnel_port, addr.port());
+    assert_eq!(conn.peer, stream.local_addr()?);
+    let mut buf = [0u8; 2];
+    conn.stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hi");
+    conn.stream.write_all(b"yo").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"yo");
+    assert!(time::timeout(Duration::from_millis(100), listener.accept())
+        .await
+        .is_err());
+
+    // Once it is dropped, connections are proxied to the client again.
+    drop(incoming);
+    let _stream = TcpStream::connect(addr).await?;
+    time::timeout(Duration::from_secs(1), listener.accept()).await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn revoke_secret() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = Server::new(1024..=65535, None);
+    server.set_secrets(Keyring::new(["alice", "bob"]));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let (listener, addr) = spawn_client(Some("bob")).await?;
+    let mut conn = TcpStream::connect(addr).await?;
+    let (mut local, _) = listener.accept().await?;
+
+    server.set_secrets(Keyring::new(["alice"]));
+    assert!(spawn_client(Some("bob")).await.is_err());
+    assert!(spawn_client(Some("alice")).await.is_ok());
+
+    // The tunnel that was opened with the revoked secret keeps working, for existing
+    // connections as well as new ones.
+    conn.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    let mut conn = TcpStream::connect(addr).await?;
+    let (mut local, _) = listener.accept().await?;
+    conn.write_all(b"world").await?;
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"world");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn rotate_secrets() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_secret_overlap(Duration::from_millis(500));
+    server.set_secrets(Keyring::new(["old"]));
+    tokio::spawn(server.clone().listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Both secrets are accepted during the overlap, and only the new one after it.
+    server.rotate_secrets(Keyring::new(["new"]));
+    assert!(spawn_client(Some("old")).await.is_ok());
+    assert!(spawn_client(Some("new")).await.is_ok());
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(spawn_client(Some("old")).await.is_err());
+    assert!(spawn_client(Some("new")).await.is_ok());
+
+    // Without an overlap, rotating secrets replaces them right away.
+    server.set_secret_overlap(Duration::ZERO);
+    server.rotate_secrets(Keyring::new(["newer"]));
+    assert!(spawn_client(Some("new")).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reserved_ports() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(40000..=40002, None);
+    server.set_reservations(vec![(fingerprint("alice"), 40000..=40001)]);
+    server.set_secrets(Keyring::new(["alice", "bob"]));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let result = ClientBuilder::new("localhost")
+        .local_port(5000)
+        .remote_port(40000)
+        .secret("bob")
+        .build()
+        .await;
+    let err = result.err().expect("reserved port should be refused");
+    assert!(err.to_string().contains("port reserved"));
+
+    // Only the unreserved port is ever handed out at random to someone else.
+    let bob = ClientBuilder::new("localhost")
+        .local_port(5000)
+        .secret("bob")
+        .build()
+        .await?;
+    assert_eq!(bob.remote_port(), 40002);
+    let alice = ClientBuilder::new("localhost")
+        .local_port(5000)
+        .remote_port(40000)
+        .secret("alice")
+        .build()
+        .await?;
+    assert_eq!(alice.remote_port(), 40000);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn handshake_rate_limit() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_handshake_rate_limit(2);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    assert!(spawn_client(None).await.is_ok());
+    assert!(spawn_client(None).await.is_ok());
+    assert!(spawn_client(None).await.is_err());
+
+    time::sleep(Duration::from_millis(600)).await;
+    assert!(spawn_client(None).await.is_ok());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn ban_after_auth_failures() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("abc"));
+    server.set_ban(2, Duration::from_millis(500));
+    let metrics = server.metrics();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    assert!(spawn_client(Some("wrong")).await.is_err());
+    assert!(spawn_client(Some("abc")).await.is_ok());
+    assert!(spawn_client(Some("wrong")).await.is_err());
+    assert!(spawn_client(Some("wrong")).await.is_err());
+
+    // Banned addresses are dropped before the handshake, even with the right secret.
+    assert!(spawn_client(Some("abc")).await.is_err());
+    let metrics_text = metrics.to_string();
+    assert!(metrics_text.contains("bore_handshakes_total{result=\"rejected\"} 3\n"));
+
+    time::sleep(Duration::from_millis(600)).await;
+    assert!(spawn_client(Some("abc")).await.is_ok());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_events() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let (events_tx, mut events) = tokio::sync::mpsc::unbounded_channel();
+    let mut client = ClientBuilder::new("localhost")
+        .mapping(Mapping::new("localhost", 5000, 0))
+        .on_event(move |event| events_tx.send(event).unwrap())
+        .build()
+        .await?;
+    let port = client.remote_port();
+    let shutdown = CancellationToken::new();
+    client.set_shutdown(shutdown.clone());
+    let handle = tokio::spawn(client.listen());
+
+    match events.recv().await {
+        Some(ClientEvent::Connected { to, mapping }) => {
+            assert_eq!(to, "localhost");
+            assert_eq!((mapping.local_port, mapping.remote_port), (5000, port));
+        }
+        event => panic!("expected a connected event, got {event:?}"),
+    }
+
+    shutdown.cancel();
+    handle.await??;
+    match events.recv().await {
+        Some(ClientEvent::Disconnected { mapping, .. }) => assert_eq!(mapping.remote_port, port),
+        event => panic!("expected a disconnected event, got {event:?}"),
+    }
+    assert!(events.recv().await.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn events() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = ServerBuilder::new(1024..=65535).secret("secret").build();
+    let mut server_events = server.events();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let client = ClientBuilder::new("localhost")
+        .local_port(listener.local_addr()?.port())
+        .secret("secret")
+        .heartbeat_interval(Duration::from_millis(100))
+        .build()
+        .await?;
+    let port = client.remote_port();
+    let mut client_events = client.events();
+    let shutdown = CancellationToken::new();
+    let mut client = client;
+    client.set_shutdown(shutdown.clone());
+    let handle = tokio::spawn(client.listen());
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = [0u8; 3];
+        stream.read_exact(&mut buf).await?;
+        stream.write_all(b"hello").await?;
+        anyhow::Ok(())
+    });
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    stream.write_all(b"hi!").await?;
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await?;
+    drop(stream);
+
+    match server_events.next().await {
+        Some(ServerEvent::Connected { tunnel, owner }) => {
+            assert_eq!(tunnel, Endpoint::Port(port));
+            assert!(owner.is_some());
+        }
+        event => panic!("expected a connected event, got {event:?}"),
+    }
+    let Some(ServerEvent::ConnectionOpened { id, tunnel, peer }) = server_events.next().await
+    else {
+        panic!("expected a connection to open");
+    };
+    assert_eq!(tunnel, Endpoint::Port(port));
+    assert!(peer.is_some());
+    match server_events.next().await {
+        Some(ServerEvent::ConnectionClosed {
+            id: closed,
+            bytes_in,
+            bytes_out,
+        }) => assert_eq!((closed, bytes_in, bytes_out), (id, 3, 5)),
+        event => panic!("expected the connection to close, got {event:?}"),
+    }
+
+    assert!(matches!(
+        client_events.next().await,
+        Some(ClientEvent::Connected { .. })
+    ));
+    match client_events.next().await {
+        Some(ClientEvent::ConnectionOpened { id: opened, .. }) => assert_eq!(opened, id),
+        event => panic!("expected a connection to open, got {event:?}"),
+    }
+    match client_events.next().await {
+        Some(ClientEvent::ConnectionClosed {
+            bytes_in,
+            bytes_out,
+            ..
+        }) => assert_eq!((bytes_in, bytes_out), (3, 5)),
+        event => panic!("expected the connection to close, got {event:?}"),
+    }
+
+    // Shutting down closes the tunnel on both sides, and the client's stream ends.
+    shutdown.cancel();
+    handle.await??;
+    match client_events.next().await {
+        Some(ClientEvent::Disconnected { reason, .. }) => assert_eq!(reason, None),
+        event => panic!("expected a disconnected event, got {event:?}"),
+    }
+    assert!(client_events.next().await.is_none());
+    assert!(matches!(
+        server_events.next().await,
+        Some(ServerEvent::Disconnected { .. })
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_conns_per_tunnel() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_max_conns_per_tunnel(1);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    let mut first = TcpStream::connect(addr).await?;
+    let (mut local, _) = listener.accept().await?;
+    first.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+
+    // The tunnel is full, so the next connection is closed without reaching the client.
+    let mut second = TcpStream::connect(addr).await?;
+    assert_eq!(second.read(&mut buf).await?, 0);
+
+    // Closing the first connection frees its slot.
+    drop((first, local));
+    time::sleep(Duration::from_millis(100)).await;
+    let mut third = TcpStream::connect(addr).await?;
+    let (mut local, _) = listener.accept().await?;
+    third.write_all(b"world").await?;
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"world");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn pending_conns_limit() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_pending_conns_limit(2);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let mut conn = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    conn.send(ClientMessage::Hello(vec![PortRequest::default()]))
+        .await?;
+    let port = match conn.recv().await? {
+        Some(ServerMessage::Hello(results)) => match &results[0] {
+            Ok(Endpoint::Port(port)) => *port,
+            result => panic!("unexpected result {result:?}"),
+        },
+        message => panic!("unexpected message {message:?}"),
+    };
+
+    // The third connection waits in the backlog, since nothing has been accepted yet.
+    let mut streams = Vec::new();
+    for _ in 0..3 {
+        streams.push(TcpStream::connect(("localhost", port)).await?);
+    }
+    let mut ids = Vec::new();
+    let deadline = time::Instant::now() + Duration::from_millis(500);
+    while let Ok(message) = time::timeout_at(deadline, conn.recv()).await {
+        match message? {
+            Some(ServerMessage::Heartbeat) => {}
+            Some(ServerMessage::Connection(id, ..)) => ids.push(id),
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+    assert_eq!(ids.len(), 2);
+
+    // Accepting a connection lets the server take the next one.
+    let mut accept = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    accept.send(ClientMessage::Accept(ids[0])).await?;
+    loop {
+        match conn.recv().await? {
+            Some(ServerMessage::Heartbeat) => {}
+            Some(ServerMessage::Connection(..)) => break,
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_max_rate() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mapping = Mapping {
+        max_rate: Some(10_000),
+        ..Mapping::new("localhost", local_port, 0)
+    };
+    let client = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .build()
+        .await?;
+    let addr = SocketAddr::from(([127, 0, 0, 1], client.remote_port()));
+    tokio::spawn(client.listen());
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move { stream.write_all(&[42; 10_000]).await });
+        }
+    });
+
+    // The limit is shared by both connections, so together they take over a second.
+    let start = time::Instant::now();
+    let read = || async move {
+        let mut conn = TcpStream::connect(addr).await?;
+        let mut buf = vec![0; 10_000];
+        conn.read_exact(&mut buf).await?;
+        anyhow::Ok(buf)
+    };
+    let (first, second) = tokio::try_join!(read(), read())?;
+    assert!(start.elapsed() >= Duration::from_millis(900));
+    assert!(first.iter().chain(&second).all(|&b| b == 42));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn tunnel_idle_timeout() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_tunnel_idle_timeout(Duration::from_millis(800));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let client = ClientBuilder::new("localhost")
+        .local_port(local_port)
+        .build()
+        .await?;
+    let addr = SocketAddr::from(([127, 0, 0, 1], client.remote_port()));
+    let handle = tokio::spawn(client.listen());
+
+    // An open connection keeps the tunnel alive, however long it lasts.
+    let conn = TcpStream::connect(addr).await?;
+    let (local, _) = listener.accept().await?;
+    time::sleep(Duration::from_millis(1500)).await;
+    assert!(!handle.is_finished());
+
+    // Once it closes, the tunnel expires and the client's control connection ends, either
+    // cleanly or with a reset if a heartbeat was still in flight.
+    drop((conn, local));
+    let _result = time::timeout(Duration::from_secs(2), handle).await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sticky_ports() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = Server::new(40000..=49999, None);
+    server.set_secrets(Keyring::new(["alice", "bob"]));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let connect = |secret: &'static str, key: &str| {
+        let mapping = Mapping {
+            sticky: Some(key.into()),
+            ..Mapping::new("localhost", 5000, 0)
+        };
+        ClientBuilder::new("localhost")
+            .mapping(mapping)
+            .secret(secret)
+            .build()
+    };
+
+    let first = connect("alice", "").await?;
+    let port = first.remote_port();
+    // While the derived port is taken, the next one up is chosen instead.
+    let second = connect("alice", "").await?;
+    assert_eq!(
+        second.remote_port(),
+        if port == 49999 { 40000 } else { port + 1 }
+    );
+    drop((first, second));
+    time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(connect("alice", "").await?.remote_port(), port);
+    assert_ne!(connect("alice", "web").await?.remote_port(), port);
+    assert_ne!(connect("bob", "").await?.remote_port(), port);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn secret_port_ranges() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut keyring = Keyring::default();
+    keyring.insert("team-a", "alice")?;
+    keyring.insert("team-b", "bob")?;
+    keyring.restrict_ports("team-a", 40000..=40009);
+    keyring.restrict_ports("team-b", 40010..=40019);
+    let server = Server::new(30000..=49999, None);
+    server.set_secrets(keyring);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    for _ in 0..5 {
+        let client = ClientBuilder::new("localhost")
+            .local_port(5000)
+            .secret("alice")
+            .build()
+            .await?;
+        assert!((40000..=40009).contains(&client.remote_port()));
+    }
+    let client = ClientBuilder::new("localhost")
+        .local_port(5000)
+        .remote_port(40015)
+        .secret("bob")
+        .build()
+        .await?;
+    assert_eq!(client.remote_port(), 40015);
+
+    let result = ClientBuilder::new("localhost")
+        .local_port(5000)
+        .remote_port(40015)
+        .secret("alice")
+        .build()
+        .await;
+    let err = result
+        .err()
+        .expect("port outside the secret's range should be refused");
+    assert!(err
+        .to_string()
+        .contains("not in range allowed for this secret"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn token_auth() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let private_key = [42; 32];
+    let server = Server::new(30000..=49999, None);
+    server.set_authenticator(TokenKey::from_private_key(&private_key));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let connect = |token: String| async move {
+        ClientBuilder::new("localhost")
+            .local_port(5000)
+            .token(&token)
+            .build()
+            .await
+    };
+    let claims = Claims {
+        identity: "alice".into(),
+        expires: u64::MAX,
+        ports: Some(40000..=40009),
+    };
+    let client = connect(claims.sign(&private_key)).await?;
+    assert!((40000..=40009).contains(&client.remote_port()));
+
+    let expired = Claims {
+        expires: 1,
+        ..claims.clone()
+    };
+    let wrong_key = claims.sign(&[7; 32]);
+    for (token, message) in [
+        (expired.sign(&private_key), "server error: token expired"),
+        (wrong_key, "server error: invalid token signature"),
+        ("not a token".into(), "server error: malformed token"),
+    ] {
+        let err = connect(token).await.err().expect("token should be refused");
+        assert_eq!(err.to_string(), message);
+    }
+
+    let result = ClientBuilder::new("localhost")
+        .local_port(5000)
+        .secret("secret")
+        .build()
+        .await;
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn auth_callback() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = Server::new(30000..=49999, None);
+    server.set_auth_callback(|auth| async move {
+        // Stand in for a request to an external auth service.
+        time::sleep(Duration::from_millis(10)).await;
+        Ok(match auth.reply.as_str() {
+            "bearer alice" => AuthDecision::Allow(Identity {
+                name: "alice".into(),
+                ports: Some(40000..=40009),
+            }),
+            "bearer broken" => anyhow::bail!("auth service unavailable"),
+            _ => AuthDecision::Deny("unknown bearer token".into()),
+        })
+    });
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let connect = |token: &'static str| {
+        ClientBuilder::new("localhost")
+            .local_port(5000)
+            .token(token)
+            .build()
+    };
+    let client = connect("bearer alice").await?;
+    assert!((40000..=40009).contains(&client.remote_port()));
+
+    for (token, message) in [
+        ("bearer mallory", "server error: unknown bearer token"),
+        ("bearer broken", "server error: authorization failed"),
+    ] {
+        let err = connect(token)
+            .await
+            .err()
+            .expect("client should be refused");
+        assert_eq!(err.to_string(), message);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_subdomains() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_http_addr(([127, 0, 0, 1], 9836).into());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let mapping = Mapping {
+        subdomain: Some("MyApp".into()),
+        ..Mapping::new("localhost", listener.local_addr()?.port(), 0)
+    };
+    let client = ClientBuilder::new("localhost")
+        .mapping(mapping.clone())
+        .build()
+        .await?;
+    assert_eq!(client.remote_port(), 9836);
+    tokio::spawn(client.listen());
+
+    // Another client cannot take the same subdomain, in any case.
+    let duplicate = Mapping {
+        subdomain: Some("myapp".into()),
+        ..mapping
+    };
+    let result = ClientBuilder::new("localhost")
+        .mapping(duplicate)
+        .build()
+        .await;
+    let err = result.err().expect("duplicate subdomain should be refused");
+    assert!(err.to_string().contains("subdomain already in use"));
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = [0u8; 41];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"GET / HTTP/1.1\r\nHost: myapp.bore.test\r\n\r\n");
+        stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+        anyhow::Ok(())
+    });
+
+    let request = |host: &'static str| async move {
+        let mut stream = TcpStream::connect("127.0.0.1:9836").await?;
+        let request = format!("GET / HTTP/1.1\r\nHost: {host}\r\n\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        anyhow::Ok(response)
+    };
+    assert!(request("myapp.bore.test")
+        .await?
+        .starts_with("HTTP/1.1 204 No Content"));
+    assert!(request("other.bore.test:80")
+        .await?
+        .starts_with("HTTP/1.1 404 Not Found"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn random_subdomains() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_http_addr(([127, 0, 0, 1], 9836).into());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let connect = |prefix: &str| {
+        let mapping = Mapping {
+            subdomain_prefix: Some(prefix.into()),
+            ..Mapping::new("localhost", listener.local_addr().unwrap().port(), 0)
+        };
+        ClientBuilder::new("localhost").mapping(mapping).build()
+    };
+    let client = connect("team").await?;
+    let subdomain = client.mappings()[0]
+        .subdomain
+        .clone()
+        .expect("chosen subdomain");
+    let words: Vec<&str> = subdomain.split('-').collect();
+    assert_eq!(words.len(), 4, "unexpected subdomain {subdomain}");
+    assert_eq!(words[0], "team");
+    assert!(words[3].parse::<u8>().is_ok_and(|n| (10..100).contains(&n)));
+    tokio::spawn(client.listen());
+
+    // Clients without a prefix get a readable name too, which is never one in use.
+    let other = connect("").await?;
+    let other_subdomain = other.mappings()[0]
+        .subdomain
+        .clone()
+        .expect("chosen subdomain");
+    assert_eq!(other_subdomain.split('-').count(), 3);
+    assert_ne!(other_subdomain, subdomain);
+
+    let mut stream = TcpStream::connect("127.0.0.1:9836").await?;
+    let request = format!("GET / HTTP/1.1\r\nHost: {subdomain}.bore.test\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = vec![0; request.len()];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(buf, request.as_bytes());
+
+    let result = connect("-bad").await;
+    let err = result.err().expect("invalid prefix should be refused");
+    assert!(err.to_string().contains("invalid subdomain prefix"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn https_subdomains() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_https_addr(([127, 0, 0, 1], 9838).into());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let mapping = Mapping {
+        subdomain: Some("myapp".into()),
+        ..Mapping::new("localhost", listener.local_addr()?.port(), 0)
+    };
+    let client = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .build()
+        .await?;
+    assert_eq!(client.remote_port(), 9838);
+    tokio::spawn(client.listen());
+
+    // The local service terminates TLS itself.
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let tls = ServerTls::from_pem_files("tests/certs/cert.pem", "tests/certs/key.pem")?;
+        let mut stream = tls.accept(stream).await?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        stream.write_all(b"pong").await?;
+        stream.shutdown().await?;
+        anyhow::Ok(())
+    });
+
+    let connect = |host: &'static str| async move {
+        let stream = TcpStream::connect("127.0.0.1:9838").await?;
+        ClientTls::insecure().connect(host, stream).await
+    };
+    let mut stream = connect("myapp.bore.test").await?;
+    stream.write_all(b"ping").await?;
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"pong");
+
+    let err = connect("other.bore.test").await.expect_err("unknown name");
+    assert!(format!("{err:#}").contains("UnrecognisedName"), "{err:#}");
+
+    Ok(())
+}
+
+#[rstest]
+#[case(true)]
+#[case(false)]
+#[tokio::test]
+async fn compression(#[case] server_compress: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_compress(server_compress);
+    let metrics = server.metrics();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Connections go through a proxy, to count the bytes sent between client and server.
+    let (proxy_addr, _, wire_bytes) = spawn_proxy().await?;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mapping = Mapping {
+        compress: true,
+        ..Mapping::new("localhost", local_port, 0)
+    };
+    let client = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .proxy(Proxy::parse(&format!("http://user:pass@{proxy_addr}"))?)
+        .build()
+        .await?;
+    assert_eq!(client.mappings()[0].compress, server_compress);
+    let addr = SocketAddr::from(([127, 0, 0, 1], client.remote_port()));
+    tokio::spawn(client.listen());
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = vec![0; 100_000];
+        stream.read_exact(&mut buf).await?;
+        assert!(buf.iter().all(|&b| b == b'a'));
+        stream.write_all(b"I can send a message too!").await?;
+        anyhow::Ok(())
+    });
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(&[b'a'; 100_000]).await?;
+    let mut buf = [0u8; 25];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"I can send a message too!");
+    assert_eq!(stream.read(&mut buf).await?, 0);
+    // The data connection stays open until both sides are done sending.
+    drop(stream);
+
+    // Byte counts are of the data before it was compressed.
+    let metrics = metrics.to_string();
+    let bytes = format!(
+        "bore_tunnel_bytes_total{{port=\"{}\",protocol=\"tcp\"",
+        addr.port()
+    );
+    assert!(metrics.contains(&format!("{bytes},direction=\"up\"}} 25\n")));
+    assert!(metrics.contains(&format!("{bytes},direction=\"down\"}} 100000\n")));
+
+    // On the wire, the compressed data connection is a fraction of the size.
+    let wire_bytes = async {
+        loop {
+            match wire_bytes.load(Ordering::SeqCst) {
+                0 => time::sleep(Duration::from_millis(10)).await,
+                bytes => return bytes,
+            }
+        }
+    };
+    let wire_bytes = time::timeout(Duration::from_secs(1), wire_bytes).await?;
+    assert_eq!(wire_bytes < 10_000, server_compress, "{wire_bytes} bytes");
+
+    Ok(())
+}
+
+#[rstest]
+#[case(true, r#"["zstd", "gzip"]"#, Some(Compression::Gzip))]
+#[case(true, r#"["zstd"]"#, None)]
+#[case(true, "[]", None)]
+#[case(false, r#"["gzip"]"#, None)]
+#[tokio::test]
+async fn compression_negotiation(
+    #[case] server_compress: bool,
+    #[case] offered: &str,
+    #[case] picked: Option<Compression>,
+) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = ServerBuilder::new(1024..=65535)
+        .compress(server_compress)
+        .build();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Offer methods that the server may not know, like a newer client could.
+    let mut conn = TcpStream::connect(("localhost", CONTROL_PORT)).await?;
+    let hello = format!(r#"{{"Hello":[{{"port":0,"compression":{offered},"version":1}}]}}"#);
+    conn.write_all(format!("{hello}\0").as_bytes()).await?;
+    let mut conn = Delimited::new(conn);
+    let mut message = conn.recv().await?;
+    let mut agreed = None;
+    if let Some(ServerMessage::Compress(method)) = message {
+        agreed = Some(method);
+        message = conn.recv().await?;
+    }
+    assert_eq!(agreed, picked);
+    assert!(matches!(
+        message,
+        Some(ServerMessage::Hello(results)) if results[0].is_ok()
+    ));
+
+    Ok(())
+}
+
+#[rstest]
+#[case(true)]
+#[case(false)]
+#[tokio::test]
+async fn encrypt_data(#[case] server_encrypt: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = ServerBuilder::new(1024..=65535)
+        .secret("secret")
+        .compress(true)
+        .encrypt_data(server_encrypt)
+        .build();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mapping = Mapping {
+        compress: true,
+        encrypt: true,
+        ..Mapping::new("localhost", local_port, 0)
+    };
+    let client = ClientBuilder::new("localhost")
+        .secret("secret")
+        .mapping(mapping)
+        .build()
+        .await?;
+    // Clients fall back to unencrypted data if the server does not allow it.
+    assert_eq!(client.mappings()[0].encrypt, server_encrypt);
+    let addr = SocketAddr::from(([127, 0, 0, 1], client.remote_port()));
+    tokio::spawn(client.listen());
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = vec![0; 100_000];
+        stream.read_exact(&mut buf).await?;
+        assert!(buf.iter().all(|&b| b == b'a'));
+        stream.write_all(b"I can send a message too!").await?;
+        anyhow::Ok(())
+    });
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(&[b'a'; 100_000]).await?;
+    let mut buf = [0u8; 25];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"I can send a message too!");
+    assert_eq!(stream.read(&mut buf).await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn encrypted_stream() -> Result<()> {
+    let key = DataKey::new("secret");
+    let id = Uuid::new_v4();
+    let (client, mut wire) = tokio::io::duplex(1 << 16);
+    let mut client = Encrypted::new(client, Vec::new(), &key, id, Side::Client);
+    client.write_all(b"hello, world").await?;
+    client.shutdown().await?;
+    let mut sealed = Vec::new();
+    time::timeout(Duration::from_secs(1), wire.read_buf(&mut sealed)).await??;
+    assert!(!sealed.windows(5).any(|window| window == b"hello"));
+
+    let open = |sealed: Vec<u8>, secret: &'static str, id| async move {
+        let (server, mut wire) = tokio::io::duplex(1 << 16);
+        wire.write_all(&sealed).await?;
+        drop(wire);
+        let key = DataKey::new(secret);
+        let mut server = Encrypted::new(server, Vec::new(), &key, id, Side::Server);
+        let mut data = Vec::new();
+        server.read_to_end(&mut data).await.map(|_| data)
+    };
+    assert_eq!(open(sealed.clone(), "secret", id).await?, b"hello, world");
+
+    // Tampered chunks, the wrong key or connection ID, and truncated streams all fail.
+    let mut tampered = sealed.clone();
+    tampered[5] ^= 1;
+    let err = open(tampered, "secret", id).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let err = open(sealed.clone(), "wrong", id).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let err = open(sealed.clone(), "secret", Uuid::new_v4())
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let truncated = sealed[..sealed.len() - 18].to_vec();
+    let err = open(truncated, "secret", id).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+    Ok(())
+}
+
+#[rstest]
+#[case(None)]
+#[case(Some(ClientTls::insecure()))]
+#[tokio::test]
+async fn websocket_transport(#[case] tls: Option<ClientTls>) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_ws_addr(SocketAddr::from(([127, 0, 0, 1], 9837)));
+    if tls.is_some() {
+        server.set_tls(ServerTls::from_pem_files(
+            "tests/certs/cert.pem",
+            "tests/certs/key.pem",
+        )?);
+    }
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let mapping = Mapping::new("localhost", listener.local_addr()?.port(), 0);
+    let mut builder = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .secret("secret")
+        .transport(TransportKind::WebSocket(9837));
+    if let Some(tls) = tls {
+        builder = builder.tls(tls);
+    }
+    let client = builder.build().await?;
+    let remote_port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    // Data connections are carried over WebSocket too, so connections still work.
+    let mut stream = TcpStream::connect(("localhost", remote_port)).await?;
+    let (mut local, _) = listener.accept().await?;
+    stream.write_all(b"over websocket").await?;
+    let mut buf = [0u8; 14];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"over websocket");
+    local.write_all(b"and back again").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"and back again");
+    drop(local);
+    assert_eq!(stream.read(&mut buf).await?, 0);
+
+    Ok(())
+}
+
+/// Run a proxy that only lets `user:pass` through, speaking SOCKS5 or HTTP depending on
+/// the first byte from the client, and count the connections made through it and the
+/// bytes relayed on those that finished.
+async fn spawn_proxy() -> Result<(SocketAddr, Arc<AtomicUsize>, Arc<AtomicUsize>)> {
+    async fn handshake(stream: &mut TcpStream) -> Result<Option<String>> {
+        let first = stream.read_u8().await?;
+        if first == 0x05 {
+            let mut methods = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut methods).await?;
+            assert!(methods.contains(&0x02));
+            stream.write_all(&[0x05, 0x02]).await?;
+            assert_eq!(stream.read_u8().await?, 0x01);
+            let mut user = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut user).await?;
+            let mut pass = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut pass).await?;
+            if (&user[..], &pass[..]) != (b"user", b"pass") {
+                stream.write_all(&[0x01, 0x01]).await?;
+                return Ok(None);
+            }
+            stream.write_all(&[0x01, 0x00]).await?;
+            let mut request = [0; 4];
+            stream.read_exact(&mut request).await?;
+            assert_eq!(request, [0x05, 0x01, 0x00, 0x03]);
+            let mut host = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut host).await?;
+            let port = stream.read_u16().await?;
+            stream
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await?;
+            Ok(Some(format!("{}:{port}", String::from_utf8(host)?)))
+        } else {
+            let mut head = vec![first];
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await?);
+            }
+            let head = String::from_utf8(head)?;
+            if !head.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n") {
+                stream
+                    .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                    .await?;
+                return Ok(None);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await?;
+            let target = head
+                .strip_prefix("CONNECT ")
+                .and_then(|rest| rest.split(' ').next());
+            Ok(target.map(String::from))
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let count = Arc::new(AtomicUsize::new(0));
+    let bytes = Arc::new(AtomicUsize::new(0));
+    let (counter, relayed) = (Arc::clone(&count), Arc::clone(&bytes));
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let (counter, relayed) = (Arc::clone(&counter), Arc::clone(&relayed));
+            tokio::spawn(async move {
+                if let Some(target) = handshake(&mut stream).await? {
+                    let mut server = TcpStream::connect(target).await?;
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let (up, down) =
+                        tokio::io::copy_bidirectional(&mut stream, &mut server).await?;
+                    relayed.fetch_add((up + down) as usize, Ordering::SeqCst);
+                }
+                anyhow::Ok(())
+            });
+        }
+    });
+    Ok((addr, count, bytes))
+}
+
+#[rstest]
+#[case("http://user:pass@", None)]
+#[case("socks5://user:pass@", None)]
+#[case("user:pass@", None)]
+#[case("http://user:wrong@", Some("407 Proxy Authentication Required"))]
+#[case("socks5://user:wrong@", Some("rejected the credentials"))]
+#[tokio::test]
+async fn connect_through_proxy(#[case] url: &str, #[case] error: Option<&str>) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(Some("secret")).await;
+    let (proxy_addr, count, _) = spawn_proxy().await?;
+
+    // Addresses without a scheme are those of SOCKS5 proxies, as for `--socks5`.
+    let proxy = match url.contains("://") {
+        true => Proxy::parse(&format!("{url}{proxy_addr}"))?,
+        false => Proxy::socks5(&format!("{url}{proxy_addr}"))?,
+    };
+    let listener = TcpListener::bind("localhost:0").await?;
+    let result = ClientBuilder::new("localhost")
+        .local_port(listener.local_addr()?.port())
+        .secret("secret")
+        .proxy(proxy)
+        .build()
+        .await;
+    let client = match (result, error) {
+        (Ok(client), None) => client,
+        (Err(err), Some(error)) => {
+            let err = format!("{err:#}");
+            assert!(
+                err.contains("proxy") && err.contains(error),
+                "unexpected error: {err}"
+            );
+            return Ok(());
+        }
+        (Ok(_), Some(_)) => panic!("client should not connect"),
+        (Err(err), None) => return Err(err),
+    };
+    let remote_port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", remote_port)).await?;
+    let (mut local, _) = listener.accept().await?;
+    stream.write_all(b"over proxy").await?;
+    let mut buf = [0u8; 10];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"over proxy");
+
+    // Both the control connection and the data connection went through the proxy.
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket_target() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let path = std::env::temp_dir().join(format!("bore-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = tokio::net::UnixListener::bind(&path)?;
+    let client = ClientBuilder::new("localhost")
+        .local_target(LocalTarget::Unix(path.clone()))
+        .build()
+        .await?;
+    let remote_port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", remote_port)).await?;
+    let (mut local, _) = listener.accept().await?;
+    stream.write_all(b"over unix").await?;
+    let mut buf = [0u8; 9];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"over unix");
+    local.write_all(b"and back!").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"and back!");
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket_tunnel() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let dir = std::env::temp_dir().join(format!("bore-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let mut server = Server::new(1024..=65535, None);
+    server.set_socket_dir(dir.clone());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let mapping = Mapping {
+        remote_socket: Some("app.sock".into()),
+        ..Mapping::new("localhost", listener.local_addr()?.port(), 0)
+    };
+    let invalid = Mapping {
+        remote_socket: Some("..".into()),
+        ..mapping.clone()
+    };
+    let mut client = ClientBuilder::new("localhost")
+        .mapping(mapping)
+        .mapping(invalid)
+        .strict(false)
+        .build()
+        .await?;
+    let path = dir.join("app.sock");
+    assert_eq!(client.mappings()[0].remote_socket, Some(path.clone()));
+    assert_eq!(client.failures()[0].1, "invalid socket name");
+    let shutdown = CancellationToken::new();
+    client.set_shutdown(shutdown.clone());
+    let task = tokio::spawn(client.listen());
+
+    let mut stream = tokio::net::UnixStream::connect(&path).await?;
+    let (mut local, _) = listener.accept().await?;
+    stream.write_all(b"over unix").await?;
+    let mut buf = [0u8; 9];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"over unix");
+    local.write_all(b"and back!").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"and back!");
+    drop((stream, local));
+
+    // The socket is removed once the tunnel closes.
+    shutdown.cancel();
+    task.await??;
+    time::sleep(Duration::from_millis(100)).await;
+    assert!(!path.exists());
+
+    std::fs::remove_dir(&dir)?;
+    Ok(())
+}
+
+#[cfg(feature = "systemd")]
+#[tokio::test]
+async fn systemd_activation() -> Result<()> {
+    use std::os::fd::IntoRawFd;
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // Pass a bound control socket and a notification socket, like systemd would.
+    let dir = std::env::temp_dir().join(format!("bore-systemd-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let notify = tokio::net::UnixDatagram::bind(dir.join("notify"))?;
+    let control = std::net::TcpListener::bind(("0.0.0.0", CONTROL_PORT))?;
+    std::env::set_var("LISTEN_FDS", "1");
+    std::env::set_var("LISTEN_FDS_FIRST_FD", control.into_raw_fd().to_string());
+    std::env::set_var("NOTIFY_SOCKET", dir.join("notify"));
+
+    let server = Server::new(1024..=65535, None);
+    let listen = tokio::spawn(server.listen());
+    let mut buf = [0u8; 64];
+    let n = time::timeout(Duration::from_secs(1), notify.recv(&mut buf)).await??;
+    assert_eq!(&buf[..n], b"READY=1\n");
+
+    // The server was handed the control port rather than binding it again.
+    let (listener, addr) = spawn_client(None).await?;
+    let mut stream = TcpStream::connect(addr).await?;
+    let (mut local, _) = listener.accept().await?;
+    stream.write_all(b"activated").await?;
+    local.read_exact(&mut buf[..9]).await?;
+    assert_eq!(&buf[..9], b"activated");
+    drop((stream, local));
+
+    server.shutdown().await;
+    let n = time::timeout(Duration::from_secs(1), notify.recv(&mut buf)).await??;
+    assert_eq!(&buf[..n], b"STOPPING=1\n");
+    listen.await??;
+
+    std::env::remove_var("LISTEN_FDS_FIRST_FD");
+    std::env::remove_var("NOTIFY_SOCKET");
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[rstest]
+#[case(PROTOCOL_VERSION + 1)]
+#[case(u32::MAX)]
+#[tokio::test]
+async fn protocol_version(#[case] version: u32) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    tokio::spawn(Server::new(1024..=65535, None).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The client goes through a proxy that mangles the version in its hello.
+    let proxy = TcpListener::bind("localhost:0").await?;
+    let proxy_addr = proxy.local_addr()?;
+    tokio::spawn(async move {
+        let (mut client, _) = proxy.accept().await?;
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await?);
+        }
+        client
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+        let server = TcpStream::connect(("localhost", CONTROL_PORT)).await?;
+        let (mut client_read, mut client_write) = client.into_split();
+        let (mut server_read, mut server_write) = server.into_split();
+        tokio::spawn(async move { tokio::io::copy(&mut server_read, &mut client_write).await });
+        let mut buf = vec![0; 4096];
+        loop {
+            let n = client_read.read(&mut buf).await?;
+            if n == 0 {
+                return anyhow::Ok(());
+            }
+            let message = String::from_utf8_lossy(&buf[..n]).replace(
+                &format!("\"version\":{PROTOCOL_VERSION}"),
+                &format!("\"version\":{version}"),
+            );
+            server_write.write_all(message.as_bytes()).await?;
+        }
+    });
+
+    let result = ClientBuilder::new("localhost")
+        .local_port(5000)
+        .proxy(Proxy::parse(&format!("http://{proxy_addr}"))?)
+        .build()
+        .await;
+    let err = result.err().expect("client should be refused");
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "server error: client speaks protocol version {version}, but this server speaks \
+             version {PROTOCOL_VERSION} and accepts versions {MIN_PROTOCOL_VERSION} to \
+             {PROTOCOL_VERSION}"
+        )
+    );
+
+    // Clients that send port requests without a version are still accepted.
+    let mut conn = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    conn.send(ClientMessage::Hello(vec![PortRequest::default()]))
+        .await?;
+    assert!(matches!(
+        conn.recv().await?,
+        Some(ServerMessage::Hello(results)) if results[0].is_ok()
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn legacy_client() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(Some("legacy secret")).await;
+    let auth = HmacAuthenticator::new("legacy secret");
+
+    /// Read a frame, without its null byte.
+    async fn read_frame(conn: &mut BufReader<TcpStream>) -> Result<String> {
+        let mut frame = Vec::new();
+        conn.read_until(0, &mut frame).await?;
+        assert_eq!(frame.pop(), Some(0));
+        Ok(String::from_utf8(frame)?)
+    }
+    // Open a control connection and answer the challenge, in the original wire format.
+    let connect = || async {
+        let mut conn = BufReader::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+        let challenge = read_frame(&mut conn).await?;
+        let challenge = challenge
+            .strip_prefix(r#"{"Challenge":""#)
+            .and_then(|rest| rest.strip_suffix(r#""}"#))
+            .expect("challenge should come first");
+        let reply = auth.answer(&challenge.parse()?);
+        let message = format!("{{\"Authenticate\":\"{reply}\"}}\0");
+        conn.get_mut().write_all(message.as_bytes()).await?;
+        anyhow::Ok(conn)
+    };
+
+    // The bytes that the clients from before protocol versioning send and expect.
+    let mut control = connect().await?;
+    control.get_mut().write_all(b"{\"Hello\":0}\0").await?;
+    let hello = read_frame(&mut control).await?;
+    let port: u16 = hello
+        .strip_prefix(r#"{"Hello":"#)
+        .and_then(|rest| rest.strip_suffix('}'))
+        .expect("hello should name the port")
+        .parse()?;
+
+    let mut outside = TcpStream::connect(("localhost", port)).await?;
+    let id = loop {
+        let message = read_frame(&mut control).await?;
+        if message == r#""Heartbeat""# {
+            continue;
+        }
+        let id = message
+            .strip_prefix(r#"{"Connection":""#)
+            .and_then(|rest| rest.strip_suffix(r#""}"#))
+            .unwrap_or_else(|| panic!("unexpected message {message}"));
+        break Uuid::parse_str(id)?;
+    };
+    let mut data = connect().await?;
+    let message = format!("{{\"Accept\":\"{id}\"}}\0");
+    data.get_mut().write_all(message.as_bytes()).await?;
+    let mut data = data.into_inner();
+
+    outside.write_all(b"hello").await?;
+    let mut buf = [0; 5];
+    data.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    data.write_all(b"world").await?;
+    outside.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"world");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn bandwidth_quota() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let path = std::env::temp_dir().join(format!("bore-quota-{}.json", std::process::id()));
+    _ = std::fs::remove_file(&path);
+    let addr = AdminAddr::Tcp(([127, 0, 0, 1], 7836).into());
+    let start = || {
+        let mut keyring = Keyring::default();
+        keyring.insert("team-a", "alice").unwrap();
+        let server = ServerBuilder::new(1024..=65535)
+            .quotas(vec![("team-a".into(), 10)])
+            .quota_file(path.clone())
+            .admin_addr(([127, 0, 0, 1], 7836).into())
+            .build();
+        server.set_secrets(keyring);
+        tokio::spawn(server.listen());
+        server
+    };
+    let server = start();
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let client = ClientBuilder::new("localhost")
+        .local_port(local_port)
+        .secret("alice")
+        .build()
+        .await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    let (mut local, _) = listener.accept().await?;
+    stream.write_all(b"hello").await?;
+    local.read_exact(&mut [0u8; 5]).await?;
+    local.write_all(b"goodbye").await?;
+    drop(local);
+    assert_eq!(stream.read_to_end(&mut Vec::new()).await?, 7);
+    drop(stream);
+    time::sleep(Duration::from_millis(100)).await;
+    let quotas = admin::quotas(&addr).await?;
+    assert_eq!(quotas.len(), 1);
+    assert_eq!(quotas[0].identity, "team-a");
+    assert_eq!(
+        (quotas[0].bytes_used, quotas[0].bytes_quota),
+        (12, Some(10))
+    );
+
+    // New connections to the open tunnel are refused.
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    assert!(!matches!(stream.read(&mut [0u8; 1]).await, Ok(1)));
+    assert!(time::timeout(Duration::from_millis(100), listener.accept())
+        .await
+        .is_err());
+
+    // The totals survive a restart, and only a reset lets the client in again.
+    server.shutdown().await;
+    time::sleep(Duration::from_millis(50)).await;
+    let _server = start();
+    time::sleep(Duration::from_millis(50)).await;
+    let result = ClientBuilder::new("localhost")
+        .local_port(local_port)
+        .secret("alice")
+        .build()
+        .await;
+    let err = result
+        .err()
+        .expect("client over its quota should be refused");
+    assert!(err.to_string().contains("bandwidth quota exceeded"));
+    admin::reset_quotas(&addr).await?;
+    assert_eq!(admin::quotas(&addr).await?[0].bytes_used, 0);
+    ClientBuilder::new("localhost")
+        .local_port(local_port)
+        .secret("alice")
+        .build()
+        .await?;
+
+    _ = std::fs::remove_file(&path);
+    Ok(())
+}
+
+#[tokio::test]
+async fn access_log() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let path = std::env::temp_dir().join(format!("bore-access-{}.log", std::process::id()));
+    let rotated = path.with_extension("log.1");
+    _ = std::fs::remove_file(&path);
+    let mut keyring = Keyring::default();
+    keyring.insert("team-a", "alice").unwrap();
+    let server = ServerBuilder::new(1024..=65535)
+        .access_log(path.clone())
+        .build();
+    server.set_secrets(keyring);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let client = ClientBuilder::new("localhost")
+        .local_port(local_port)
+        .secret("alice")
+        .build()
+        .await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+    tokio::spawn(async move {
+        while let Ok((mut local, _)) = listener.accept().await {
+            local.read_exact(&mut [0u8; 5]).await?;
+            local.write_all(b"goodbye").await?;
+        }
+        anyhow::Ok(())
+    });
+    let connect = || async {
+        let mut stream = TcpStream::connect(("localhost", port)).await?;
+        stream.write_all(b"hello").await?;
+        stream.read_to_end(&mut Vec::new()).await?;
+        anyhow::Ok(stream.local_addr()?)
+    };
+    let read_log = |path| async move {
+        time::sleep(Duration::from_millis(100)).await;
+        anyhow::Ok(std::fs::read_to_string(path)?)
+    };
+
+    let peer = connect().await?;
+    let log = read_log(path.clone()).await?;
+    let line = log.lines().next().expect("no line in access log");
+    assert!(line.starts_with(&format!("{peer} team-a [")), "{line}");
+    assert!(line.contains(&format!("\"TCP {port}\"")), "{line}");
+    let (line, id) = line.rsplit_once(' ').unwrap();
+    assert!(line.ends_with(" 5 7 \"closed\""), "{line}");
+    assert!(id.parse::<Uuid>().is_ok(), "{id}");
+
+    // After the log is moved away and reopened, lines go to a new file.
+    std::fs::rename(&path, &rotated)?;
+    server.reopen_access_log();
+    connect().await?;
+    assert_eq!(read_log(path.clone()).await?.lines().count(), 1);
+    assert_eq!(read_log(rotated.clone()).await?.lines().count(), 1);
+
+    _ = std::fs::remove_file(&path);
+    _ = std::fs::remove_file(&rotated);
+    Ok(())
+}
+
+#[tokio::test]
+async fn connection_grace() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = ServerBuilder::new(1024..=65535)
+        .connection_grace(Duration::from_millis(200))
+        .build();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let mut conn = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    conn.send(ClientMessage::Hello(vec![PortRequest::default()]))
+        .await?;
+    let port = match conn.recv().await? {
+        Some(ServerMessage::Hello(results)) => match &results[0] {
+            Ok(Endpoint::Port(port)) => *port,
+            result => panic!("unexpected result {result:?}"),
+        },
+        message => panic!("unexpected message {message:?}"),
+    };
+
+    // A connection that the client never accepts is closed once the grace elapses.
+    let start = time::Instant::now();
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    let read = time::timeout(Duration::from_secs(1), stream.read(&mut [0; 1])).await?;
+    assert_eq!(read?, 0);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn connection_expired() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // The test runtime has a single thread, so the subscriber also sees the server's logs.
+    let logs = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let writer = {
+        let logs = Arc::clone(&logs);
+        move || LogWriter(Arc::clone(&logs))
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(writer)
+        .finish();
+    let _subscriber = tracing::subscriber::set_default(subscriber);
+
+    let server = ServerBuilder::new(1024..=65535)
+        .connection_grace(Duration::from_millis(200))
+        .build();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A client that never accepts the connections to its tunnel.
+    let mut conn = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    conn.send(ClientMessage::Hello(vec![PortRequest::default()]))
+        .await?;
+    let port = match conn.recv().await? {
+        Some(ServerMessage::Hello(results)) => match &results[0] {
+            Ok(Endpoint::Port(port)) => *port,
+            result => panic!("unexpected result {result:?}"),
+        },
+        message => panic!("unexpected message {message:?}"),
+    };
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    time::sleep(Duration::from_millis(100)).await;
+    let expired = || {
+        let logs = String::from_utf8_lossy(&logs.lock().unwrap()).into_owned();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("connection_expired"));
+        line.map(String::from)
+    };
+    assert_eq!(expired(), None);
+    let read = time::timeout(Duration::from_secs(1), stream.read(&mut [0; 1])).await?;
+    assert_eq!(read?, 0);
+    let line = expired().expect("expired connection should be logged");
+    assert!(
+        line.contains("WARN") && line.contains("grace_ms=200"),
+        "{line}"
+    );
+
+    Ok(())
+}
+
+/// Writer for the logs of a test, into a shared buffer.
+struct LogWriter(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn local_connect_timeout() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    // Once the accept queue of a listener that never accepts is full, connecting to it
+    // hangs like connecting to a non-routable address.
+    let socket = tokio::net::TcpSocket::new_v4()?;
+    socket.bind(([127, 0, 0, 1], 0).into())?;
+    let listener = socket.listen(0)?;
+    let local_addr = listener.local_addr()?;
+    let mut queued = Vec::new();
+    while let Ok(Ok(stream)) =
+        time::timeout(Duration::from_millis(100), TcpStream::connect(local_addr)).await
+    {
+        queued.push(stream);
+    }
+    let client = ClientBuilder::new("localhost")
+        .local_host("127.0.0.1")
+        .local_port(local_addr.port())
+        .local_connect_timeout(Duration::from_millis(200))
+        .build()
+        .await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    let read = time::timeout(Duration::from_secs(1), stream.read(&mut [0; 1])).await?;
+    assert_eq!(read?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn access_log_max_size() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let path = std::env::temp_dir().join(format!("bore-rotate-{}.log", std::process::id()));
+    let rotated = |n| path.with_extension(format!("log.{n}"));
+    for file in [path.clone(), rotated(1), rotated(2)] {
+        _ = std::fs::remove_file(file);
+    }
+    // Each line is longer than half the maximum size, so every one starts a new file.
+    let server = ServerBuilder::new(1024..=65535)
+        .access_log(path.clone())
+        .access_log_max_size(200)
+        .build();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let (listener, addr) = spawn_client(None).await?;
+    tokio::spawn(async move {
+        while let Ok((mut local, _)) = listener.accept().await {
+            local.write_all(b"hi").await?;
+        }
+        anyhow::Ok(())
+    });
+
+    for _ in 0..3 {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.read_to_end(&mut Vec::new()).await?;
+        drop(stream);
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    for file in [path.clone(), rotated(1), rotated(2)] {
+        let log = std::fs::read_to_string(&file)?;
+        assert_eq!(log.lines().count(), 1, "{}", file.display());
+        assert!(log.len() <= 200, "{log}");
+        _ = std::fs::remove_file(file);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_tunnels_per_client() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let addr = AdminAddr::Tcp(([127, 0, 0, 1], 7836).into());
+    let mut server = Server::new(1024..=65535, None);
+    server.set_max_tunnels_per_client(2);
+    server.set_admin_addr(([127, 0, 0, 1], 7836).into());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let mappings = vec![
+        Mapping::new("localhost", 5000, 0),
+        Mapping::new("localhost", 5001, 0),
+    ];
+    let first = with_mappings(mappings).strict(false).build().await?;
+    let result = ClientBuilder::new("localhost")
+        .local_port(5002)
+        .build()
+        .await;
+    let err = result.err().expect("third tunnel should be refused");
+    assert!(err.to_string().contains("tunnel quota exceeded"));
+    let clients = admin::clients(&addr).await?;
+    assert_eq!(clients.len(), 1);
+    assert_eq!(clients[0].tunnels, 2);
+
+    // Closing the control connection frees the client's tunnels for new ones.
+    drop(first);
+    time::sleep(Duration::from_millis(100)).await;
+    assert!(admin::clients(&addr).await?.is_empty());
+    ClientBuilder::new("localhost")
+        .local_port(5002)
+        .build()
+        .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_tunnels() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_max_tunnels(3);
+    server.set_tunnel_limits(vec![(fingerprint("alice"), 1)]);
+    server.set_secrets(Keyring::new(["alice", "bob"]));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let alice = ClientBuilder::new("localhost")
+        .local_port(5000)
+        .secret("alice")
+        .build()
+        .await?;
+    let result = ClientBuilder::new("localhost")
+        .local_port(5001)
+        .secret("alice")
+        .build()
+        .await;
+    let err = result
+        .err()
+        .expect("second tunnel of alice should be refused");
+    assert!(err.to_string().contains("tunnel quota exceeded"));
+
+    // Other clients are only held to the limit for the whole server.
+    let mappings = vec![
+        Mapping::new("localhost", 5001, 0),
+        Mapping::new("localhost", 5002, 0),
+    ];
+    let bob = with_mappings(mappings)
+        .secret("bob")
+        .strict(false)
+        .build()
+        .await?;
+    let result = ClientBuilder::new("localhost")
+        .local_port(5003)
+        .secret("bob")
+        .build()
+        .await;
+    let err = result.err().expect("fourth tunnel should be refused");
+    assert!(err.to_string().contains("server tunnel limit reached"));
+
+    drop(alice);
+    time::sleep(Duration::from_millis(100)).await;
+    ClientBuilder::new("localhost")
+        .local_port(5003)
+        .secret("bob")
+        .build()
+        .await?;
+    drop(bob);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn port_strategy() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = ServerBuilder::new(40000..=40003)
+        .port_strategy(PortStrategy::Sequential)
+        .build();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let connect = || ClientBuilder::new("localhost").local_port(5000).build();
+    let first = connect().await?;
+    let second = connect().await?;
+    assert_eq!((first.remote_port(), second.remote_port()), (40000, 40001));
+    // A released port is not handed out again until the range wraps around.
+    drop(first);
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(connect().await?.remote_port(), 40002);
+    server.shutdown().await;
+    time::sleep(Duration::from_millis(50)).await;
+
+    let server = ServerBuilder::new(40000..=40001)
+        .port_strategy(PortStrategy::Lru)
+        .port_cooldown(Duration::from_secs(30))
+        .build();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let first = connect().await?;
+    let port = first.remote_port();
+    drop(first);
+    time::sleep(Duration::from_millis(50)).await;
+    // The other port is preferred, and the released one is cooling down.
+    let second = connect().await?;
+    assert_ne!(second.remote_port(), port);
+    let err = connect().await.err().expect("range should be exhausted");
+    assert!(err.to_string().contains("failed to find an available port"));
+    // Asking for the port by number skips the cooldown.
+    ClientBuilder::new("localhost")
+        .local_port(5000)
+        .remote_port(port)
+        .build()
+        .await?;
+    server.shutdown().await;
+    time::sleep(Duration::from_millis(50)).await;
+
+    // On ranges wider than a random sample, the port that was never released is still
+    // found when it is the only one left, and then the ports released longest ago. The
+    // range is below the ephemeral ports, which the connections of the test could hold.
+    let server = ServerBuilder::new(20000..=20299)
+        .port_strategy(PortStrategy::Lru)
+        .max_frame_length(64 * 1024)
+        .build();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let connect = |count: usize| {
+        let mappings = (0..count).map(|_| Mapping::new("localhost", 5000, 0));
+        with_mappings(mappings).build()
+    };
+    let mut clients = Vec::new();
+    for count in [100, 100, 99] {
+        clients.push(connect(count).await?);
+    }
+    let ports = |client: &Client| -> HashSet<u16> {
+        let mappings = client.mappings().iter();
+        mappings.map(|mapping| mapping.remote_port).collect()
+    };
+    let oldest = ports(&clients[0]);
+    let taken: HashSet<u16> = clients.iter().flat_map(ports).collect();
+    for client in clients {
+        drop(client);
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    let fresh = connect(1).await?;
+    assert!(!taken.contains(&fresh.remote_port()));
+    assert!(oldest.contains(&connect(1).await?.remote_port()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn port_retention() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = ServerBuilder::new(40000..=40999)
+        .port_retention(Duration::from_secs(30))
+        .build();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let connect = |name: &str| {
+        ClientBuilder::new("localhost")
+            .mapping(Mapping {
+                name: Some(name.into()),
+                ..Mapping::new("localhost", 5000, 0)
+            })
+            .build()
+    };
+    let first = connect("web").await?;
+    let port = first.remote_port();
+    drop(first);
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A client coming back with the same name gets the same port.
+    let second = connect("web").await?;
+    assert_eq!(second.remote_port(), port);
+    drop(second);
+    time::sleep(Duration::from_millis(50)).await;
+
+    // If the port was taken in the meantime, the client gets a new one.
+    let taken = TcpListener::bind(("0.0.0.0", port)).await?;
+    let third = connect("web").await?;
+    assert_ne!(third.remote_port(), port);
+    drop(taken);
+
+    let err = connect(&"a".repeat(65))
+        .await
+        .err()
+        .expect("name is too long");
+    assert!(err.to_string().contains("tunnel name too long"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn control_addrs() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_control_addrs(vec!["127.0.0.1".parse()?, "::1".parse()?]);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    for to in ["127.0.0.1", "::1"] {
+        ClientBuilder::new(to).local_port(5000).build().await?;
+    }
+    server.shutdown().await;
+    time::sleep(Duration::from_millis(50)).await;
+
+    // An address that can't be bound stops the server instead of being skipped.
+    let mut server = Server::new(1024..=65535, None);
+    server.set_control_addrs(vec!["127.0.0.1".parse()?, "192.0.2.1".parse()?]);
+    let err = server.listen().await.unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("could not bind control address 192.0.2.1"));
+    assert!(TcpListener::bind(("127.0.0.1", CONTROL_PORT)).await.is_ok());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn status() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    tokio::spawn(Server::new(1024..=65535, Some("secret")).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Enough tunnels that the reply spans several batches.
+    let mut clients = Vec::new();
+    for _ in 0..3 {
+        let mappings = (0..15).map(|i| Mapping::new("localhost", 5000 + i, 0));
+        let client = with_mappings(mappings).secret("secret").build().await?;
+        clients.push(client);
+    }
+
+    let tunnels = ClientBuilder::new("localhost")
+        .secret("secret")
+        .status()
+        .await?;
+    assert_eq!(tunnels.len(), 45);
+    assert!(tunnels.iter().all(|tunnel| tunnel.connections == 0));
+    let mut ports: Vec<u16> = clients
+        .iter()
+        .flat_map(|client| client.mappings().iter().map(|m| m.remote_port))
+        .collect();
+    ports.sort();
+    assert_eq!(tunnels.iter().map(|t| t.port).collect::<Vec<_>>(), ports);
+
+    let result = ClientBuilder::new("localhost")
+        .secret("wrong")
+        .status()
+        .await;
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reclaim() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut keyring = Keyring::default();
+    keyring.insert("team-a", "alice").unwrap();
+    keyring.insert("team-b", "bob").unwrap();
+    let server = Server::new(1024..=65535, None);
+    server.set_secrets(keyring);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The first client stays connected, like one that crashed unnoticed.
+    let stale = ClientBuilder::new("localhost")
+        .local_port(5000)
+        .secret("alice")
+        .build()
+        .await?;
+    let port = stale.remote_port();
+    let stale = tokio::spawn(stale.listen());
+    let reclaim = |secret| {
+        let mapping = Mapping {
+            reclaim: true,
+            ..Mapping::new("localhost", 5000, port)
+        };
+        ClientBuilder::new("localhost")
+            .mapping(mapping)
+            .secret(secret)
+            .build()
+    };
+
+    // Only a client with the same secret can take the port over.
+    let err = reclaim("bob").await.err().expect("port should be in use");
+    assert_eq!(err.downcast_ref(), Some(&ClientError::PortInUse(port)));
+    let result = ClientBuilder::new("localhost")
+        .local_port(5000)
+        .remote_port(port)
+        .secret("alice")
+        .build()
+        .await;
+    assert!(result.is_err());
+    let client = reclaim("alice").await?;
+    assert_eq!(client.remote_port(), port);
+    // The stale tunnel's connection is told to close.
+    time::timeout(Duration::from_secs(1), stale).await???;
+
+    let status = |secret| ClientBuilder::new("localhost").secret(secret).status();
+    let tunnels = status("alice").await?;
+    assert_eq!(tunnels.len(), 1);
+    assert!(tunnels[0].owned);
+    assert!(!status("bob").await?[0].owned);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn ping() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let err = ClientBuilder::new("localhost").ping(1).await.unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&ClientError::Unreachable));
+
+    tokio::spawn(Server::new(1024..=65535, Some("secret")).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let rtts = ClientBuilder::new("localhost")
+        .secret("secret")
+        .ping(3)
+        .await?;
+    assert_eq!(rtts.len(), 3);
+    for builder in [
+        ClientBuilder::new("localhost").secret("wrong"),
+        ClientBuilder::new("localhost"),
+    ] {
+        let err = builder.ping(3).await.unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&ClientError::AuthFailed));
+    }
+
+    Ok(())
 }
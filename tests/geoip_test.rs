@@ -0,0 +1,374 @@
+use std::net::IpAddr;
+
+use anyhow::Result;
+use bore_cli::geoip::GeoIpDb;
+use rstest::*;
+
+/// A value of the data or metadata section of a database in the MaxMind DB format.
+enum Value {
+    String(&'static str),
+    Double(f64),
+    Bytes(Vec<u8>),
+    Uint16(u16),
+    Uint32(u32),
+    Uint64(u64),
+    Uint128(u128),
+    Int32(i32),
+    Boolean(bool),
+    Float(f32),
+    Map(Vec<(Value, Value)>),
+    Array(Vec<Value>),
+
+    /// A pointer to an offset of the data section, with the given size bits.
+    Pointer(usize, u8),
+}
+
+use Value::*;
+
+/// A map with string keys.
+fn map<const N: usize>(entries: [(&'static str, Value); N]) -> Value {
+    Map(entries.into_iter().map(|(k, v)| (String(k), v)).collect())
+}
+
+/// Append the control bytes of a value, with an extended type byte for types above 7.
+fn control(out: &mut Vec<u8>, kind: u8, size: usize) {
+    let kind_bits = if kind <= 7 { kind << 5 } else { 0 };
+    let (size_bits, extra) = match size {
+        0..=28 => (size as u8, vec![]),
+        29..=284 => (29, vec![(size - 29) as u8]),
+        285..=65820 => (30, ((size - 285) as u16).to_be_bytes().to_vec()),
+        _ => (31, ((size - 65821) as u32).to_be_bytes()[1..].to_vec()),
+    };
+    out.push(kind_bits | size_bits);
+    if kind > 7 {
+        out.push(kind - 7);
+    }
+    out.extend(extra);
+}
+
+/// Append an unsigned integer, without its leading zero bytes, as writers do.
+fn uint(out: &mut Vec<u8>, kind: u8, bytes: &[u8]) {
+    let bytes = &bytes[bytes.iter().take_while(|&&b| b == 0).count()..];
+    control(out, kind, bytes.len());
+    out.extend(bytes);
+}
+
+fn encode(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        String(s) => {
+            control(out, 2, s.len());
+            out.extend(s.as_bytes());
+        }
+        Double(n) => {
+            control(out, 3, 8);
+            out.extend(n.to_be_bytes());
+        }
+        Bytes(bytes) => {
+            control(out, 4, bytes.len());
+            out.extend(bytes);
+        }
+        Uint16(n) => uint(out, 5, &n.to_be_bytes()),
+        Uint32(n) => uint(out, 6, &n.to_be_bytes()),
+        Uint64(n) => uint(out, 9, &n.to_be_bytes()),
+        Uint128(n) => uint(out, 10, &n.to_be_bytes()),
+        Int32(n) => {
+            control(out, 8, 4);
+            out.extend(n.to_be_bytes());
+        }
+        Boolean(b) => control(out, 14, *b as usize),
+        Float(n) => {
+            control(out, 15, 4);
+            out.extend(n.to_be_bytes());
+        }
+        Map(entries) => {
+            control(out, 7, entries.len());
+            for (key, value) in entries {
+                encode(out, key);
+                encode(out, value);
+            }
+        }
+        Array(values) => {
+            control(out, 11, values.len());
+            values.iter().for_each(|value| encode(out, value));
+        }
+        Pointer(target, size) => {
+            let (value, len) = match size {
+                0 => (*target, 1),
+                1 => (target - 2048, 2),
+                2 => (target - 526336, 3),
+                _ => (*target, 4),
+            };
+            let high = if len == 4 {
+                0
+            } else {
+                (value >> (8 * len)) as u8
+            };
+            out.push(0x20 | size << 3 | high);
+            out.extend(&(value as u32).to_be_bytes()[4 - len..]);
+        }
+    }
+}
+
+/// Append a value to the data section, returning its offset.
+fn push(data: &mut Vec<u8>, value: Value) -> usize {
+    let offset = data.len();
+    encode(data, &value);
+    offset
+}
+
+#[derive(Clone, Copy)]
+enum Record {
+    Empty,
+    Node(usize),
+    Data(usize),
+}
+
+/// Search tree of a database, from the bits of addresses to data offsets.
+struct Tree(Vec<[Record; 2]>);
+
+impl Tree {
+    /// Map a network of `bits`-bit addresses to a data offset.
+    fn insert(&mut self, addr: u128, bits: u32, prefix: u32, offset: usize) {
+        let mut node = 0;
+        for i in 0..prefix {
+            let side = (addr >> (bits - 1 - i) & 1) as usize;
+            if i == prefix - 1 {
+                self.0[node][side] = Record::Data(offset);
+                break;
+            }
+            node = match self.0[node][side] {
+                Record::Node(next) => next,
+                _ => {
+                    self.0.push([Record::Empty; 2]);
+                    self.0[node][side] = Record::Node(self.0.len() - 1);
+                    self.0.len() - 1
+                }
+            };
+        }
+    }
+
+    fn write(&self, record_size: usize) -> Vec<u8> {
+        let count = self.0.len();
+        let value = |record: Record| match record {
+            Record::Empty => count,
+            Record::Node(node) => node,
+            Record::Data(offset) => count + 16 + offset,
+        };
+        let mut out = Vec::new();
+        for &[left, right] in &self.0 {
+            let (left, right) = (value(left) as u32, value(right) as u32);
+            match record_size {
+                24 => {
+                    out.extend(&left.to_be_bytes()[1..]);
+                    out.extend(&right.to_be_bytes()[1..]);
+                }
+                28 => {
+                    out.extend(&left.to_be_bytes()[1..]);
+                    out.push(((left >> 24) << 4 | right >> 24) as u8);
+                    out.extend(&right.to_be_bytes()[1..]);
+                }
+                _ => {
+                    out.extend(left.to_be_bytes());
+                    out.extend(right.to_be_bytes());
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Metadata in the order of real databases, with the fields that are read last.
+fn metadata(node_count: u32, record_size: u16, ip_version: u16) -> Vec<u8> {
+    let mut out = b"\xab\xcd\xefMaxMind.com".to_vec();
+    let metadata = map([
+        ("binary_format_major_version", Uint16(2)),
+        ("binary_format_minor_version", Uint16(0)),
+        ("build_epoch", Uint64(1_790_000_000)),
+        ("database_type", String("GeoIP2-Country")),
+        (
+            "description",
+            map([("en", String("GeoIP2 Country test database"))]),
+        ),
+        ("languages", Array(vec![String("en"), String("de")])),
+        ("ip_version", Uint16(ip_version)),
+        ("record_size", Uint16(record_size)),
+        ("node_count", Uint32(node_count)),
+    ]);
+    encode(&mut out, &metadata);
+    out
+}
+
+/// Build a database with countries stored like in GeoIP2 files: shared through
+/// pointers of every size, with keys deduplicated and other fields around them.
+///
+/// 127.0.0.2 is in DE, 127.0.0.3 in US, 127.0.0.4 and 127.0.0.5 only have a registered
+/// country of FR, and 127.0.0.6 and 127.0.0.9 are in JP, as is 2001:db8::/32 in a
+/// database of IPv6 addresses.
+fn build(record_size: usize, ip_version: u16) -> Vec<u8> {
+    let mut data = Vec::new();
+    let key = push(&mut data, String("iso_code"));
+    let country = |code, id| {
+        Map(vec![
+            (String("geoname_id"), Uint32(id)),
+            (String("is_in_european_union"), Boolean(id != 6252001)),
+            (Pointer(key, 0), String(code)),
+            (
+                String("names"),
+                map([("en", String(code)), ("de", String(code))]),
+            ),
+        ])
+    };
+    let de = push(&mut data, country("DE", 2921044));
+    let fr = push(&mut data, country("FR", 3017382));
+    // Long values in front of the countries push later ones past the offsets that
+    // need longer pointers, and past 24 bits for the high bits of 28-bit records.
+    let de_record = push(
+        &mut data,
+        map([
+            (
+                "continent",
+                map([("code", String("EU")), ("geoname_id", Uint32(6255148))]),
+            ),
+            ("description", String(" ".repeat(3000).leak())),
+            ("name", String(" ".repeat(100).leak())),
+            ("country", Pointer(de, 0)),
+            (
+                "location",
+                map([
+                    ("accuracy_radius", Uint16(1000)),
+                    ("latitude", Double(51.2993)),
+                    ("longitude", Float(9.491)),
+                    ("metro_code", Int32(-1)),
+                ]),
+            ),
+            ("registered_country", Pointer(fr, 3)),
+            (
+                "traits",
+                map([("is_anycast", Boolean(false)), ("id", Uint128(u128::MAX))]),
+            ),
+        ]),
+    );
+    let us = push(&mut data, country("US", 6252001));
+    let padding = if record_size == 24 { 530_000 } else { 1 << 24 };
+    let us_record = push(
+        &mut data,
+        map([
+            ("padding", Bytes(vec![0; padding])),
+            ("country", Pointer(us, 1)),
+            ("registered_country", Pointer(us, 1)),
+        ]),
+    );
+    let jp = push(&mut data, country("JP", 1861060));
+    let fr_record = push(&mut data, map([("registered_country", Pointer(fr, 3))]));
+    let fr_fallback = push(
+        &mut data,
+        map([("country", map([])), ("registered_country", Pointer(fr, 0))]),
+    );
+    let jp_record = push(
+        &mut data,
+        map([
+            ("represented_country", Pointer(us, 3)),
+            ("country", Pointer(jp, 2)),
+        ]),
+    );
+
+    let mut tree = Tree(vec![[Record::Empty; 2]]);
+    let (bits, v4_prefix) = if ip_version == 6 { (128, 96) } else { (32, 0) };
+    let records = [
+        (2, de_record),
+        (3, us_record),
+        (4, fr_record),
+        (5, fr_fallback),
+        (6, jp_record),
+        // The left record of its node is empty, unlike that of 127.0.0.7.
+        (9, jp_record),
+    ];
+    for (host, offset) in records {
+        tree.insert(0x7f00_0000 + host, bits, v4_prefix + 32, offset);
+    }
+    if ip_version == 6 {
+        tree.insert(0x2001_0db8 << 96, 128, 32, jp_record);
+    }
+    [
+        tree.write(record_size),
+        vec![0; 16],
+        data,
+        metadata(tree.0.len() as u32, record_size as u16, ip_version),
+    ]
+    .concat()
+}
+
+#[rstest]
+fn geoip_lookup(
+    #[values(24, 28, 32)] record_size: usize,
+    #[values(4, 6)] ip_version: u16,
+) -> Result<()> {
+    let db = GeoIpDb::from_bytes(build(record_size, ip_version))?;
+    let country = |ip: &str| db.country(ip.parse::<IpAddr>().unwrap());
+
+    assert_eq!(country("127.0.0.2"), Some("DE"));
+    assert_eq!(country("127.0.0.3"), Some("US"));
+    assert_eq!(country("127.0.0.4"), Some("FR"));
+    assert_eq!(country("127.0.0.5"), Some("FR"));
+    assert_eq!(country("127.0.0.6"), Some("JP"));
+    assert_eq!(country("127.0.0.7"), None);
+    assert_eq!(country("127.0.0.8"), None);
+    assert_eq!(country("127.0.0.9"), Some("JP"));
+    assert_eq!(country("10.0.0.1"), None);
+    // IPv4-mapped addresses are looked up as IPv4 addresses.
+    assert_eq!(country("::ffff:127.0.0.3"), Some("US"));
+    if ip_version == 6 {
+        assert_eq!(country("2001:db8::1"), Some("JP"));
+        assert_eq!(country("2001:db9::1"), None);
+    } else {
+        assert_eq!(country("2001:db8::1"), None);
+    }
+    Ok(())
+}
+
+#[rstest]
+#[case(b"not a database".to_vec(), "no metadata section")]
+#[case([vec![0; 6], vec![0; 16], metadata(1, 20, 4)].concat(), "unsupported record size 20")]
+#[case([vec![0; 6], vec![0; 16], metadata(1, 24, 5)].concat(), "unsupported IP version 5")]
+#[case([vec![0; 6], vec![0; 16], metadata(9, 24, 4)].concat(), "search tree is larger")]
+#[case(b"\xab\xcd\xefMaxMind.com\xe0".to_vec(), "no node count")]
+fn geoip_invalid(#[case] db: Vec<u8>, #[case] error: &str) {
+    let err = GeoIpDb::from_bytes(db)
+        .err()
+        .expect("database should be invalid");
+    assert!(err.to_string().contains(error), "{err}");
+}
+
+#[test]
+fn geoip_corrupt_records() -> Result<()> {
+    // Records that point past the data, or into the middle of values, find nothing.
+    let mut tree = Tree(vec![[Record::Empty; 2]]);
+    tree.insert(0x7f00_0002, 32, 32, 1 << 20);
+    tree.insert(0x7f00_0003, 32, 32, 3);
+    let mut data = Vec::new();
+    push(&mut data, map([("country", Pointer(1, 0))]));
+    let db = [
+        tree.write(24),
+        vec![0; 16],
+        data,
+        metadata(tree.0.len() as u32, 24, 4),
+    ];
+    let db = GeoIpDb::from_bytes(db.concat())?;
+    assert_eq!(db.country("127.0.0.2".parse()?), None);
+    assert_eq!(db.country("127.0.0.3".parse()?), None);
+    Ok(())
+}
+
+#[test]
+fn geoip_open() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("bore-test-{}.mmdb", std::process::id()));
+    std::fs::write(&path, build(24, 6))?;
+    let db = GeoIpDb::open(&path);
+    std::fs::write(&path, "")?;
+    let empty = GeoIpDb::open(&path);
+    std::fs::remove_file(&path)?;
+    assert_eq!(db?.country("127.0.0.2".parse()?), Some("DE"));
+    assert!(format!("{:#}", empty.err().unwrap()).contains("no metadata section"));
+    assert!(GeoIpDb::open(&path).is_err());
+    Ok(())
+}